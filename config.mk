@@ -2,16 +2,36 @@ ARCH ?= x86_64
 BUILD_TYPE ?= debug
 BUILD_TARGET := $(ARCH)-unknown-veos-gnu
 
-MODULES := kernel init test mkinitramfs
+MODULES := kernel init test shell mkinitramfs
 
 TARGET_DIR := target
 
 ISO := image.iso
 
+# The raw disk image that is attached to the AHCI controller.
+DISK_IMAGE := disk.img
+DISK_IMAGE_SIZE := 1M
+
 RUST_COMPILER_FLAGS := --target $(BUILD_TARGET)
 RUST_COMPILER := xargo
 
 LINKER := ld
 LINKER_FLAGS := --gc-sections
 
-QEMU_FLAGS := --no-reboot -smp cores=4 -s -serial stdio
\ No newline at end of file
+# Additional options on the kernel command line, like `selftest`.
+KERNEL_COMMAND_LINE ?=
+
+# The cargo features the kernel is built with, like `selftest`.
+KERNEL_FEATURES ?=
+
+QEMU_FLAGS := --no-reboot -smp cores=4 -s -serial stdio
+
+# The disk image is attached to an AHCI controller as the first SATA disk.
+QEMU_FLAGS += -drive id=disk,file=$(DISK_IMAGE),if=none,format=raw -device ahci,id=ahci \
+	-device ide-hd,drive=disk,bus=ahci.0
+
+# The isa-debug-exit device lets self-test builds report their result as the exit status.
+SELFTEST_QEMU_FLAGS := -device isa-debug-exit,iobase=0xf4,iosize=0x04
+
+# The exit status of QEMU if all self-tests passed, see `kernel/src/arch/x86_64/qemu.rs`.
+SELFTEST_SUCCESS_STATUS := 33
\ No newline at end of file
@@ -1,6 +1,5 @@
 #![no_std]
 
-#[macro_use]
 extern crate veos_std;
 #[allow(unused_extern_crates)]
 extern crate rlibc;
@@ -9,10 +8,11 @@ use core::time::Duration;
 
 #[no_mangle]
 pub fn main() {
+    // The tests only print their results, so they run next to the shell.
     veos_std::process::exec("/bin/test").unwrap();
+    veos_std::process::exec("/bin/shell").unwrap();
 
     loop {
         veos_std::thread::sleep(Duration::from_millis(500));
-        println!("Test");
     }
 }
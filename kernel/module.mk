@@ -11,6 +11,9 @@ KERNEL_LIB := target/$(KERNEL_BUILD_TARGET)/$(BUILD_TYPE)/libveos.a
 KERNEL_BINARY := target/$(KERNEL_BUILD_TARGET)/build/kernel-$(ARCH).bin
 
 KERNEL_RUST_COMPILER_FLAGS := --target $(KERNEL_BUILD_TARGET)
+ifneq ($(KERNEL_FEATURES),)
+	KERNEL_RUST_COMPILER_FLAGS += --features "$(KERNEL_FEATURES)"
+endif
 ifeq ($(BUILD_TYPE),release)
 	KERNEL_RUST_COMPILER_FLAGS += --release
 endif
@@ -21,9 +24,22 @@ ASSEMBLY_OBJECT_FILES := $(patsubst kernel/src/%.asm,target/$(KERNEL_BUILD_TARGE
 ASSEMBLER := nasm
 ASSEMBLER_FLAGS := -felf64
 
-$(TARGET_DIR)/boot/grub/grub.cfg: kernel/src/arch/$(ARCH)/grub.cfg
+# Records the kernel configuration, so that changing it rebuilds the files depending on it.
+KERNEL_CONFIG_STAMP := target/kernel-config
+KERNEL_CONFIG := features=$(KERNEL_FEATURES) command_line=$(KERNEL_COMMAND_LINE)
+
+# The stamp is only rewritten if the configuration changed, which keeps the dependent files
+# up to date otherwise.
+.PHONY: FORCE
+FORCE:
+
+$(KERNEL_CONFIG_STAMP): FORCE
 	@mkdir -p $(shell dirname $@)
-	cp $< $@
+	@echo '$(KERNEL_CONFIG)' | cmp -s - $@ || echo '$(KERNEL_CONFIG)' > $@
+
+$(TARGET_DIR)/boot/grub/grub.cfg: kernel/src/arch/$(ARCH)/grub.cfg $(KERNEL_CONFIG_STAMP)
+	@mkdir -p $(shell dirname $@)
+	sed "s|/boot/kernel.bin|/boot/kernel.bin $(KERNEL_COMMAND_LINE)|" $< > $@
 
 $(TARGET_DIR)/boot/kernel.bin: $(KERNEL_BINARY)
 	@mkdir -p $(shell dirname $@)
@@ -32,7 +48,8 @@ $(TARGET_DIR)/boot/kernel.bin: $(KERNEL_BINARY)
 $(KERNEL_BINARY): $(ASSEMBLY_OBJECT_FILES) $(KERNEL_LINKER_SCRIPT) $(KERNEL_LIB)
 	$(LINKER) $(KERNEL_LINKER_FLAGS) -o $@ $(ASSEMBLY_OBJECT_FILES) $(KERNEL_LIB)
 
-$(KERNEL_LIB): $(shell find kernel/src -name "*.rs") kernel/Cargo.toml kernel/Xargo.toml
+$(KERNEL_LIB): $(shell find kernel/src -name "*.rs") kernel/Cargo.toml kernel/Xargo.toml \
+		$(KERNEL_CONFIG_STAMP)
 	cd kernel && $(RUST_COMPILER) build $(KERNEL_RUST_COMPILER_FLAGS)
 
 $(ASSEMBLY_OBJECT_FILES): target/$(KERNEL_BUILD_TARGET)/build/%.o : kernel/src/%.asm
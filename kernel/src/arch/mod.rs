@@ -3,7 +3,9 @@
 //! The job of this module is to have submodules for each architecture and to
 //! provide interfaces to them.
 
+use alloc::Vec;
 use core::time::Duration;
+use crate::interrupts::InterruptCount;
 use crate::memory::address_space::AddressSpace;
 use crate::memory::{MemoryArea, PageFlags, PhysicalAddress, VirtualAddress};
 use crate::multitasking::stack::StackType;
@@ -52,6 +54,12 @@ pub trait Architecture {
     /// Returns the ID of the currently running CPU.
     fn get_cpu_id() -> usize;
 
+    /// Returns the ID of the currently running CPU from the per-CPU state.
+    ///
+    /// This is cheaper than `get_cpu_id`, but must not be used in interrupt
+    /// handlers before they switched to the per-CPU state of the kernel.
+    fn get_cached_cpu_id() -> usize;
+
     /// Invokes the scheduler.
     ///
     /// This function changes the currently running thread on the current CPU
@@ -59,6 +67,22 @@ pub trait Architecture {
     /// same).
     fn invoke_scheduler();
 
+    /// Invokes the scheduler on the given CPU.
+    ///
+    /// This can be used to make another CPU pick up a thread immediately.
+    fn invoke_scheduler_on(cpu_id: usize);
+
+    /// Makes the given CPU print the state it was interrupted in.
+    ///
+    /// This works even if the CPU is stuck with interrupts disabled, so a
+    /// watchdog can use it to report a stuck CPU.
+    fn dump_cpu_state(cpu_id: usize);
+
+    /// Returns how often each interrupt vector fired on each CPU.
+    ///
+    /// Vectors that never fired on a CPU are left out.
+    fn interrupt_counts() -> Vec<InterruptCount>;
+
     /// This function enters user mode for the first time.
     ///
     /// It's job is to transition from the system initialization to normal
@@ -84,6 +108,22 @@ pub trait Architecture {
     /// Returns true if interrupts are enabled and false otherwise.
     fn get_interrupt_state() -> bool;
 
+    /// Returns the current stack pointer.
+    fn get_stack_pointer() -> VirtualAddress;
+
+    /// Returns the base address of the thread local storage of the current
+    /// thread.
+    fn get_tls_base() -> VirtualAddress;
+
+    /// Sets the base address of the thread local storage of the current
+    /// thread.
+    ///
+    /// The base is part of the context of the thread from then on.
+    ///
+    /// # Safety
+    /// - The address must be a userspace address.
+    unsafe fn set_tls_base(base: VirtualAddress);
+
     /// Disables all interrupts.
     ///
     /// # Safety
@@ -120,6 +160,17 @@ pub trait Architecture {
     /// Returns the size of usable free memory in bytes.
     fn get_free_memory_size() -> usize;
 
+    /// Allocates a page frame for kernel use.
+    ///
+    /// Returns the address at which the frame is accessible to the kernel.
+    fn allocate_kernel_frame() -> VirtualAddress;
+
+    /// Frees a page frame from `allocate_kernel_frame`.
+    ///
+    /// # Safety
+    /// - Nothing may use the frame afterwards.
+    unsafe fn free_kernel_frame(address: VirtualAddress);
+
     /// Maps the page that contains the given address and the given flags.
     // TODO: Move this into the AddressSpaceManager?
     fn map_page(page_address: VirtualAddress, flags: PageFlags);
@@ -127,6 +178,15 @@ pub trait Architecture {
     /// Unmaps the page that contains the given address.
     unsafe fn unmap_page(page_address: VirtualAddress);
 
+    /// Maps the given physical device memory uncached and returns its address.
+    fn map_device_memory(address: PhysicalAddress, length: usize) -> VirtualAddress;
+
+    /// Unmaps device memory that was mapped with `map_device_memory`.
+    ///
+    /// # Safety
+    /// - The memory must not be accessed through the mapping anymore.
+    unsafe fn unmap_device_memory(address: PhysicalAddress, length: usize);
+
     /// Returns the physical memory area where the kernel is loaded.
     fn get_kernel_area() -> MemoryArea<PhysicalAddress>;
 
@@ -139,18 +199,73 @@ pub trait Architecture {
     /// Returns whether the given address is a userspace address.
     fn is_userspace_address(address: VirtualAddress) -> bool;
 
+    /// Returns the virtual address at which the kernel can access the given
+    /// physical address.
+    ///
+    /// The address must lie in usable memory or the kernel image. Device
+    /// memory has to be mapped with `map_device_memory` instead.
+    fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress;
+
+    /// Returns the physical address the given virtual address is mapped to.
+    fn virtual_to_physical(address: VirtualAddress) -> Option<PhysicalAddress>;
+
     /// The size, in bytes, of a virtual page on the target architecture.
     const PAGE_SIZE: usize;
 
     /// The memory area where the heap is located.
     const HEAP_AREA: MemoryArea<VirtualAddress>;
 
+    /// The memory area of user address spaces where shared memory is mapped.
+    const USER_MMAP_AREA: MemoryArea<VirtualAddress>;
+
+    /// The address at which position independent executables are loaded.
+    const PIE_LOAD_BASE: VirtualAddress;
+
     /// Writes the formatted arguments.
     ///
     /// This takes arguments as dictated by `core::fmt` and prints them to the
     /// screen.
     fn write_fmt(args: fmt::Arguments);
 
+    /// Tries to write the formatted arguments to the screen.
+    ///
+    /// Instead of waiting for the screen to become available, this returns
+    /// `false` if it is in use.
+    fn try_write_fmt(args: fmt::Arguments) -> bool;
+
+    /// Tries to write the formatted arguments to the serial port.
+    ///
+    /// Instead of waiting for the serial port to become available, this
+    /// returns `false` if it is in use.
+    fn try_write_serial_fmt(args: fmt::Arguments) -> bool;
+
+    /// Releases the locks on the screen and the serial port.
+    ///
+    /// # Safety
+    /// - Only use this on the panic path, after which the interrupted holders
+    /// of the locks will never run again.
+    unsafe fn force_unlock_output();
+
+    /// Prints the return addresses of the calls leading up to this one.
+    ///
+    /// # Safety
+    /// - Only use this on the panic path, as corrupted stacks can't always be
+    /// detected.
+    unsafe fn print_backtrace();
+
+    /// Writes the formatted arguments to the serial port.
+    fn write_serial_fmt(args: fmt::Arguments);
+
+    /// Reads the 32-bit PCI configuration register at the given offset.
+    ///
+    /// The offset is rounded down to a multiple of four.
+    fn read_pci_config(bus: u8, device: u8, function: u8, offset: u8) -> u32;
+
+    /// Writes the 32-bit PCI configuration register at the given offset.
+    ///
+    /// The offset is rounded down to a multiple of four.
+    fn write_pci_config(bus: u8, device: u8, function: u8, offset: u8, value: u32);
+
     /// Sets the state of being interruptable to the given state.
     ///
     /// # Safety
@@ -189,6 +304,12 @@ pub type Current = x86_64::X86_64;
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64::vga_buffer;
 
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::cpu_local;
+
+#[cfg(all(target_arch = "x86_64", feature = "selftest"))]
+pub use self::x86_64::qemu;
+
 use core::fmt;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
@@ -200,3 +321,8 @@ mod x86_64;
 pub fn schedule() {
     Current::invoke_scheduler()
 }
+
+/// Invokes the scheduler on the given CPU.
+pub fn schedule_on(cpu_id: usize) {
+    Current::invoke_scheduler_on(cpu_id)
+}
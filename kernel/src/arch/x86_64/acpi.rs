@@ -0,0 +1,336 @@
+//! Reads the interrupt configuration from the ACPI tables.
+//!
+//! Only the multiple APIC description table (MADT) is used, which describes
+//! how the interrupt controllers are set up.
+
+use super::memory::{is_mapped, map_page_at_unchecked, PAGE_SIZE};
+use alloc::Vec;
+use core::slice;
+use crate::memory::reserved::{self, ReservedKind};
+use crate::memory::{Address, MemoryArea, PageFlags, PhysicalAddress};
+use spin::Once;
+
+/// The signature of the root system description pointer (RSDP).
+const RSDP_SIGNATURE: &'static [u8] = b"RSD PTR ";
+
+/// The signature of the MADT.
+const MADT_SIGNATURE: &'static [u8] = b"APIC";
+
+/// The size of the RSDP of ACPI 1.0.
+const RSDP_SIZE: usize = 20;
+
+/// The size of the RSDP of ACPI 2.0 and later.
+const EXTENDED_RSDP_SIZE: usize = 36;
+
+/// The size of the header of each system description table.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// The offset of the first entry in the MADT.
+const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+/// The type of an I/O APIC entry in the MADT.
+const IO_APIC_TYPE: u8 = 1;
+
+/// The size of an I/O APIC entry in the MADT.
+const IO_APIC_SIZE: usize = 12;
+
+/// The type of an interrupt source override entry in the MADT.
+const INTERRUPT_SOURCE_OVERRIDE_TYPE: u8 = 2;
+
+/// The size of an interrupt source override entry in the MADT.
+const INTERRUPT_SOURCE_OVERRIDE_SIZE: usize = 10;
+
+/// The physical address that holds the segment of the extended BIOS data area.
+const EBDA_SEGMENT_POINTER: usize = 0x40e;
+
+/// The size of the area at the start of the EBDA that may contain the RSDP.
+const EBDA_SEARCH_SIZE: usize = 1024;
+
+/// The start of the BIOS area that may contain the RSDP.
+const BIOS_AREA_START: usize = 0xe0000;
+
+/// The end of the BIOS area that may contain the RSDP.
+const BIOS_AREA_END: usize = 0x100000;
+
+/// The MADT, once it was searched.
+static MADT: Once<Option<&'static [u8]>> = Once::new();
+
+/// Describes an I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoApicInfo {
+    /// The ID of the I/O APIC.
+    pub id: u8,
+    /// The physical address of its registers.
+    pub address: PhysicalAddress,
+    /// The global system interrupt of its first pin.
+    pub gsi_base: u32
+}
+
+/// Describes that a legacy IRQ is connected to a different global system
+/// interrupt (GSI) than its number suggests.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    /// The legacy IRQ number.
+    pub irq: u8,
+    /// The global system interrupt the IRQ is connected to.
+    pub gsi: u32,
+    /// The polarity and trigger mode of the interrupt.
+    pub flags: u16
+}
+
+/// Returns the interrupt source overrides of the MADT.
+///
+/// If there is no MADT, no overrides are returned.
+pub fn interrupt_source_overrides() -> Vec<InterruptSourceOverride> {
+    match madt() {
+        Some(madt) => parse_interrupt_source_overrides(madt),
+        None => {
+            warn!("No MADT found, assuming identity mapped IRQs.");
+            Vec::new()
+        }
+    }
+}
+
+/// Returns the I/O APICs of the MADT.
+///
+/// If there is no MADT, no I/O APICs are returned.
+pub fn io_apics() -> Vec<IoApicInfo> {
+    match madt() {
+        Some(madt) => parse_io_apics(madt),
+        None => Vec::new()
+    }
+}
+
+/// Extracts the interrupt source overrides from the given MADT.
+fn parse_interrupt_source_overrides(madt: &[u8]) -> Vec<InterruptSourceOverride> {
+    let mut overrides = Vec::new();
+
+    for_each_madt_entry(madt, |entry| {
+        if entry[0] == INTERRUPT_SOURCE_OVERRIDE_TYPE
+            && entry.len() >= INTERRUPT_SOURCE_OVERRIDE_SIZE
+        {
+            overrides.push(InterruptSourceOverride {
+                irq: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8)
+            });
+        }
+    });
+
+    overrides
+}
+
+/// Extracts the I/O APICs from the given MADT.
+fn parse_io_apics(madt: &[u8]) -> Vec<IoApicInfo> {
+    let mut io_apics = Vec::new();
+
+    for_each_madt_entry(madt, |entry| {
+        if entry[0] == IO_APIC_TYPE && entry.len() >= IO_APIC_SIZE {
+            io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysicalAddress::from_usize(read_u32(entry, 4) as usize),
+                gsi_base: read_u32(entry, 8)
+            });
+        }
+    });
+
+    io_apics
+}
+
+/// Calls the given function with each entry of the MADT.
+///
+/// Each entry starts with its type and its length. Parsing stops at the
+/// first malformed entry.
+fn for_each_madt_entry<F>(madt: &[u8], mut action: F)
+where
+    F: FnMut(&[u8])
+{
+    let mut offset = MADT_ENTRIES_OFFSET;
+
+    while offset + 2 <= madt.len() {
+        let entry_length = madt[offset + 1] as usize;
+
+        if entry_length < 2 || offset + entry_length > madt.len() {
+            break;
+        }
+
+        action(&madt[offset..offset + entry_length]);
+
+        offset += entry_length;
+    }
+}
+
+/// Returns the MADT, searching it on the first call.
+fn madt() -> Option<&'static [u8]> {
+    *MADT.call_once(find_madt)
+}
+
+/// Finds the MADT in the ACPI tables.
+fn find_madt() -> Option<&'static [u8]> {
+    let rsdp = find_rsdp()?;
+    let revision = rsdp[15];
+
+    // ACPI 2.0 introduced the XSDT, which uses 64-bit pointers.
+    let (root_address, pointer_size) = if revision >= 2 {
+        (read_u64(rsdp, 24) as usize, 8)
+    } else {
+        (read_u32(rsdp, 16) as usize, 4)
+    };
+
+    let root_table = find_table(PhysicalAddress::from_usize(root_address))?;
+    let mut offset = SDT_HEADER_SIZE;
+
+    while offset + pointer_size <= root_table.len() {
+        let table_address = if pointer_size == 8 {
+            read_u64(root_table, offset) as usize
+        } else {
+            read_u32(root_table, offset) as usize
+        };
+
+        if let Some(table) = find_table(PhysicalAddress::from_usize(table_address)) {
+            if &table[0..4] == MADT_SIGNATURE {
+                return Some(table);
+            }
+        }
+
+        offset += pointer_size;
+    }
+
+    None
+}
+
+/// Returns the system description table at the given address, if it is valid.
+fn find_table(address: PhysicalAddress) -> Option<&'static [u8]> {
+    let header = physical_bytes(address, SDT_HEADER_SIZE);
+    let length = read_u32(header, 4) as usize;
+
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+
+    let table = physical_bytes(address, length);
+
+    if checksum_valid(table) {
+        reserved::reserve(MemoryArea::new(address, length), ReservedKind::Acpi);
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// Searches the RSDP in the areas the BIOS may put it in.
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda_pointer = physical_bytes(PhysicalAddress::from_usize(EBDA_SEGMENT_POINTER), 2);
+    let ebda_segment = read_u16(ebda_pointer, 0);
+    let ebda_start = (ebda_segment as usize) << 4;
+
+    let search_areas = [
+        (ebda_start, EBDA_SEARCH_SIZE),
+        (BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START)
+    ];
+
+    for &(start, length) in search_areas.iter().filter(|&&(start, _)| start != 0) {
+        let area = physical_bytes(PhysicalAddress::from_usize(start), length);
+        let mut offset = 0;
+
+        // The RSDP is always 16 byte aligned.
+        while offset + RSDP_SIZE <= length {
+            let candidate = &area[offset..];
+
+            if &candidate[0..8] == RSDP_SIGNATURE && checksum_valid(&candidate[..RSDP_SIZE]) {
+                let size = if candidate[15] >= 2 && candidate.len() >= EXTENDED_RSDP_SIZE {
+                    EXTENDED_RSDP_SIZE
+                } else {
+                    RSDP_SIZE
+                };
+
+                return Some(&candidate[..size]);
+            }
+
+            offset += 16;
+        }
+    }
+
+    None
+}
+
+/// Returns the physical memory area as a slice, mapping it if necessary.
+///
+/// The BIOS areas that are searched aren't necessarily reserved, so the
+/// memory is mapped without checking it.
+fn physical_bytes(start: PhysicalAddress, length: usize) -> &'static [u8] {
+    let first_page = start.as_usize() / PAGE_SIZE;
+    let last_page = (start.as_usize() + length - 1) / PAGE_SIZE;
+
+    for page in first_page..last_page + 1 {
+        let physical_address = PhysicalAddress::from_usize(page * PAGE_SIZE);
+        let virtual_address = physical_address.to_virtual();
+
+        if !is_mapped(virtual_address) {
+            // The memory belongs to the firmware and is only read.
+            unsafe {
+                map_page_at_unchecked(virtual_address, physical_address, PageFlags::READABLE);
+            }
+        }
+    }
+
+    unsafe { slice::from_raw_parts(start.to_virtual().as_ptr(), length) }
+}
+
+/// Checks that all the bytes sum up to zero.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Reads a little endian `u16` at the given offset.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+/// Reads a little endian `u32` at the given offset.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+/// Reads a little endian `u64` at the given offset.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Tests for parsing the MADT.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that I/O APICs and overrides are found between other entries.
+    #[test]
+    fn test_parse_madt() {
+        let mut madt = [0u8; MADT_ENTRIES_OFFSET + 40];
+        let entries: [u8; 40] = [
+            // A local APIC.
+            0, 8, 0, 0, 1, 0, 0, 0,
+            // An I/O APIC at 0xfec0_0000 for GSIs starting at 0.
+            1, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0,
+            // IRQ 0 is connected to GSI 2.
+            2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+            // An I/O APIC whose length is cut off, which ends the parsing.
+            1, 12, 3, 0, 0, 0, 0, 0, 0, 0
+        ];
+
+        madt[MADT_ENTRIES_OFFSET..].copy_from_slice(&entries);
+
+        let io_apics = parse_io_apics(&madt);
+        let overrides = parse_interrupt_source_overrides(&madt);
+
+        assert_eq!(
+            &io_apics[..],
+            &[IoApicInfo {
+                id: 2,
+                address: PhysicalAddress::from_usize(0xfec0_0000),
+                gsi_base: 0
+            }]
+        );
+        assert_eq!(overrides.len(), 1);
+        assert_eq!((overrides[0].irq, overrides[0].gsi), (0, 2));
+    }
+}
@@ -0,0 +1,80 @@
+//! Walks the frame pointer chain to print backtraces.
+//!
+//! The kernel is compiled with frame pointers, so each frame starts with the
+//! saved `rbp` of its caller, followed by the return address.
+
+use super::memory::KERNEL_STACK_MAX_SIZE;
+
+/// The maximum number of frames that are printed.
+const MAX_FRAMES: usize = 32;
+
+/// The lowest address of the higher half, where all kernel stacks live.
+const KERNEL_HALF_START: usize = 0xffff_8000_0000_0000;
+
+/// Prints the return addresses of the frames on the current stack.
+///
+/// # Safety
+/// - Only use this on the panic path, as the walk can't tell a corrupted
+/// frame pointer from a valid one in all cases.
+pub unsafe fn print() {
+    let mut frame_pointer: usize;
+    asm!("mov $0, rbp" : "=r"(frame_pointer) : : : "intel", "volatile");
+
+    error!("Backtrace:");
+
+    if !is_kernel_frame(frame_pointer) {
+        return;
+    }
+
+    for _ in 0..MAX_FRAMES {
+        let return_address = *((frame_pointer + 8) as *const usize);
+        let next_frame_pointer = *(frame_pointer as *const usize);
+
+        if return_address == 0 {
+            return;
+        }
+
+        error!("    {:#x}", return_address);
+
+        if !is_caller_frame(frame_pointer, next_frame_pointer) {
+            return;
+        }
+        frame_pointer = next_frame_pointer;
+    }
+
+    error!("    ...");
+}
+
+/// Checks if the given frame pointer can point into a kernel stack.
+fn is_kernel_frame(frame_pointer: usize) -> bool {
+    frame_pointer >= KERNEL_HALF_START && frame_pointer % 8 == 0
+}
+
+/// Checks if `next` can be the frame pointer of the caller of the frame at
+/// `current`.
+///
+/// Callers live higher up on the same stack, so the walk always ends.
+/// Threads start with a zero frame pointer, which ends the walk as well.
+fn is_caller_frame(current: usize, next: usize) -> bool {
+    is_kernel_frame(next) && next > current && next - current < KERNEL_STACK_MAX_SIZE
+}
+
+/// Tests for the frame pointer walk.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that only frames higher up on a kernel stack are accepted.
+    #[test]
+    fn test_is_caller_frame() {
+        let current = 0xffff_fe7f_ffff_f000;
+
+        assert!(is_caller_frame(current, current + 0x40));
+        assert!(!is_caller_frame(current, 0));
+        assert!(!is_caller_frame(current, current));
+        assert!(!is_caller_frame(current, current - 0x40));
+        assert!(!is_caller_frame(current, current + 0x41));
+        assert!(!is_caller_frame(current, current + KERNEL_STACK_MAX_SIZE));
+        assert!(!is_caller_frame(0x7fff_f000, 0x7fff_f040));
+    }
+}
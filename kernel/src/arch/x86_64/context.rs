@@ -1,16 +1,22 @@
 //! Provides saving and restoring of architecture specific execution context.
 
 use super::gdt::{TSS, USER_CODE_SEGMENT, USER_DATA_SEGMENT};
-use super::interrupts::lapic;
+use super::interrupts::{lapic, THREAD_PRIORITY};
+use super::cpu_local;
 use crate::arch;
 use core::mem::size_of;
 use crate::memory::address_space::AddressSpace;
 use crate::memory::{Address, PhysicalAddress, VirtualAddress};
 use crate::multitasking::scheduler::{after_context_switch, idle};
 use crate::multitasking::Stack;
-use x86_64::registers::control_regs::cr3;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use x86_64::registers::control_regs::{self, cr3};
+use x86_64::registers::msr::{rdmsr, wrmsr, IA32_FS_BASE, IA32_KERNEL_GS_BASE};
 use x86_64::structures::idt::ExceptionStackFrame;
 
+/// Whether the `rdfsbase` family of instructions is enabled.
+static FSGSBASE_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
 // TODO: Floating point state is not saved yet.
 /// Saves the an execution context.
 #[derive(Debug)]
@@ -18,6 +24,10 @@ pub struct Context {
     pub kernel_stack_pointer: VirtualAddress,
     base_pointer: VirtualAddress,
     page_table_address: PhysicalAddress,
+    /// The FS base, which holds the thread local storage base.
+    fs_base: VirtualAddress,
+    /// The GS base of user mode.
+    gs_base: VirtualAddress,
 }
 
 impl arch::Context for Context {
@@ -60,6 +70,8 @@ impl arch::Context for Context {
             kernel_stack_pointer,
             base_pointer: kernel_stack_pointer,
             page_table_address: unsafe { address_space.get_page_table_address() },
+            fs_base: VirtualAddress::default(),
+            gs_base: VirtualAddress::default(),
         }
     }
 
@@ -73,6 +85,8 @@ impl arch::Context for Context {
             kernel_stack_pointer: stack_pointer,
             base_pointer: stack_pointer,
             page_table_address: PhysicalAddress::from_usize(cr3().0 as usize),
+            fs_base: VirtualAddress::default(),
+            gs_base: VirtualAddress::default(),
         }
     }
 }
@@ -81,7 +95,8 @@ impl arch::Context for Context {
 #[naked]
 unsafe fn enter_thread() -> ! {
     after_context_switch();
-    lapic::set_priority(0x0);
+    // The thread that was switched out raised the priority.
+    lapic::set_priority(THREAD_PRIORITY);
     asm!("xor r15, r15
           xor r14, r14
           xor r13, r13
@@ -97,6 +112,8 @@ unsafe fn enter_thread() -> ! {
           pop rdx
           pop rcx
           pop r8
+          // Threads always start in user mode, so the user gs base is needed.
+          swapgs
           iretq" : : : : "intel", "volatile");
     unreachable!();
 }
@@ -133,6 +150,123 @@ unsafe fn set_initial_stack(
     Stack::push_in(address_space, stack_pointer, enter_thread as usize);
 }
 
+/// Sets the stack pointer that is loaded when entering the kernel from user
+/// mode.
+///
+/// This covers both interrupts, which use the TSS, and syscalls.
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+pub unsafe fn set_kernel_stack(stack_pointer: VirtualAddress) {
+    TSS.as_mut().privilege_stack_table[0] = ::x86_64::VirtualAddress(stack_pointer.as_usize());
+    cpu_local::set_kernel_stack_pointer(stack_pointer);
+}
+
+/// Enables the `rdfsbase`, `wrfsbase`, `rdgsbase` and `wrgsbase`
+/// instructions.
+///
+/// This also allows user mode to change its FS and GS bases, which is why
+/// they are saved on every context switch.
+pub fn enable_fsgsbase() {
+    unsafe {
+        control_regs::cr4_write(control_regs::cr4() | control_regs::Cr4::ENABLE_FSGSBASE);
+    }
+    FSGSBASE_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns the current FS base.
+pub fn read_fs_base() -> VirtualAddress {
+    let base: usize;
+
+    if FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+        unsafe {
+            asm!("rdfsbase $0" : "=r"(base) : : : "intel", "volatile");
+        }
+    } else {
+        base = unsafe { rdmsr(IA32_FS_BASE) } as usize;
+    }
+
+    VirtualAddress::from_usize(base)
+}
+
+/// Sets the FS base.
+///
+/// # Safety
+/// - The address must be canonical.
+pub unsafe fn write_fs_base(base: VirtualAddress) {
+    if FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+        asm!("wrfsbase $0" : : "r"(base.as_usize()) : : "intel", "volatile");
+    } else {
+        wrmsr(IA32_FS_BASE, base.as_usize() as u64);
+    }
+}
+
+/// Returns the GS base of user mode.
+///
+/// While the kernel runs, this is held in the kernel GS base (see
+/// `cpu_local`).
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+unsafe fn read_user_gs_base() -> VirtualAddress {
+    let base: usize;
+
+    if FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+        asm!("swapgs
+              rdgsbase $0
+              swapgs" : "=r"(base) : : "memory" : "intel", "volatile");
+    } else {
+        base = rdmsr(IA32_KERNEL_GS_BASE) as usize;
+    }
+
+    VirtualAddress::from_usize(base)
+}
+
+/// Sets the GS base of user mode.
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+/// - The address must be canonical.
+unsafe fn write_user_gs_base(base: VirtualAddress) {
+    if FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+        asm!("swapgs
+              wrgsbase $0
+              swapgs" : : "r"(base.as_usize()) : "memory" : "intel", "volatile");
+    } else {
+        wrmsr(IA32_KERNEL_GS_BASE, base.as_usize() as u64);
+    }
+}
+
+/// Saves the FS and user GS bases to the context.
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+unsafe fn save_segment_bases(context: &mut Context) {
+    context.fs_base = read_fs_base();
+    context.gs_base = read_user_gs_base();
+}
+
+/// Loads the FS and user GS bases from the context.
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+pub unsafe fn load_segment_bases(context: &Context) {
+    write_fs_base(context.fs_base);
+    write_user_gs_base(context.gs_base);
+}
+
+/// Returns the current stack pointer.
+#[inline(always)]
+pub fn get_stack_pointer() -> VirtualAddress {
+    let stack_pointer: usize;
+
+    unsafe {
+        asm!("mov $0, rsp" : "=r"(stack_pointer) : : : "intel", "volatile");
+    }
+
+    VirtualAddress::from_usize(stack_pointer)
+}
+
 /// Switches the context from the old thread to the current thread.
 ///
 /// # Safety
@@ -166,11 +300,15 @@ pub unsafe fn switch_context(old_context: &mut Context, new_context: &Context) {
 
     let new_sp = new_context.kernel_stack_pointer;
     let new_bp = new_context.base_pointer;
-    let base_sp = crate::multitasking::CURRENT_THREAD
-        .lock()
-        .kernel_stack
-        .base_stack_pointer;
-    TSS.as_mut().privilege_stack_table[0] = ::x86_64::VirtualAddress(base_sp.as_usize());
+    {
+        let current_thread = crate::multitasking::CURRENT_THREAD.lock();
+        set_kernel_stack(current_thread.kernel_stack.base_stack_pointer);
+        cpu_local::set_current_thread(&**current_thread);
+    }
+
+    // User mode may change its segment bases itself if FSGSBASE is enabled.
+    save_segment_bases(old_context);
+    load_segment_bases(new_context);
 
     switch(
         &mut old_context.kernel_stack_pointer,
@@ -0,0 +1,180 @@
+//! Provides the low-level per-CPU block that is accessed through the GS base.
+//!
+//! While running in the kernel, `IA32_GS_BASE` always points to the block of
+//! the current CPU and `IA32_KERNEL_GS_BASE` holds the GS base of the user
+//! thread. Every transition between user mode and the kernel executes
+//! `swapgs` once: the syscall entry and return, the interrupt handlers
+//! through `KernelGsGuard` and the first entry of a new thread.
+//!
+//! The `cpu_local!` macro is the general way to define per-CPU values. It
+//! finds the value of the current CPU through its ID, which requires the
+//! `cpuid` instruction, and creates values lazily. The block in this module
+//! in contrast has a fixed layout and is set up eagerly, so that assembly
+//! stubs can reach it with a single GS-relative access before any lock or
+//! lazily initialized value is usable.
+
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use crate::arch::{self, Architecture};
+use crate::memory::{Address, VirtualAddress};
+use crate::multitasking::TCB;
+use x86_64::registers::msr::{rdmsr, wrmsr, IA32_GS_BASE, IA32_KERNEL_GS_BASE};
+use x86_64::structures::idt::ExceptionStackFrame;
+
+/// The per-CPU data that is reachable through the GS base.
+///
+/// The layout is accessed from assembly, so the field order must not change.
+#[repr(C)]
+pub struct CpuBlock {
+    /// The address of the block itself (at offset 0).
+    this: *const CpuBlock,
+    /// The stack pointer to load when entering the kernel through a syscall
+    /// (at offset 8).
+    kernel_stack_pointer: usize,
+    /// Holds the user stack pointer while the syscall entry switches stacks
+    /// (at offset 16).
+    user_stack_pointer: usize,
+    /// The TCB of the thread that currently runs on this CPU (at offset 24).
+    current_thread: *const TCB,
+    /// The ID of this CPU (at offset 32).
+    cpu_id: usize
+}
+
+/// Whether the block was set up.
+///
+/// Only the boot CPU runs the kernel, so a single flag covers all blocks.
+static BLOCK_READY: AtomicBool = ATOMIC_BOOL_INIT;
+
+cpu_local! {
+    /// The blocks of all CPUs.
+    ///
+    /// Each block is only ever accessed by its own CPU.
+    static mut ref CPU_BLOCK: CpuBlock = |cpu_id| CpuBlock {
+        this: 0 as *const CpuBlock,
+        kernel_stack_pointer: 0,
+        user_stack_pointer: 0,
+        current_thread: 0 as *const TCB,
+        cpu_id
+    };
+}
+
+/// Sets up the block of the current CPU and points the GS base to it.
+///
+/// This must be called once on each CPU, before anything uses the block.
+pub fn init() {
+    unsafe {
+        let block = CPU_BLOCK.as_mut();
+        block.this = block as *const CpuBlock;
+
+        wrmsr(IA32_GS_BASE, block.this as u64);
+        // No user thread has set a GS base yet.
+        wrmsr(IA32_KERNEL_GS_BASE, 0);
+    }
+
+    BLOCK_READY.store(true, Ordering::Release);
+}
+
+/// Returns the block of the current CPU.
+#[inline(always)]
+fn block() -> *mut CpuBlock {
+    let block: *mut CpuBlock;
+
+    unsafe {
+        asm!("mov $0, gs:[0]" : "=r"(block) : : : "intel", "volatile");
+    }
+
+    block
+}
+
+/// Returns the ID of the current CPU without executing `cpuid`.
+///
+/// Falls back to `cpuid` until the block is set up. Must only be used while
+/// the kernel GS base is active.
+pub fn cpu_id() -> usize {
+    if BLOCK_READY.load(Ordering::Acquire) {
+        unsafe { (*block()).cpu_id }
+    } else {
+        arch::Current::get_cpu_id()
+    }
+}
+
+/// Returns the stack pointer that the next syscall on this CPU starts with.
+pub fn kernel_stack_pointer() -> VirtualAddress {
+    VirtualAddress::from_usize(unsafe { (*block()).kernel_stack_pointer })
+}
+
+/// Sets the stack pointer that the next syscall on this CPU starts with.
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+pub unsafe fn set_kernel_stack_pointer(stack_pointer: VirtualAddress) {
+    (*block()).kernel_stack_pointer = stack_pointer.as_usize();
+}
+
+/// Returns the TCB of the thread that currently runs on this CPU.
+///
+/// The pointer is null until the first thread was entered.
+pub fn current_thread() -> *const TCB {
+    unsafe { (*block()).current_thread }
+}
+
+/// Sets the TCB of the thread that currently runs on this CPU.
+///
+/// # Safety
+/// - Must only be called with interrupts disabled.
+/// - The TCB must stay at its address while it is running.
+pub unsafe fn set_current_thread(thread: *const TCB) {
+    (*block()).current_thread = thread;
+}
+
+/// Makes sure that the GS base points to the block while it lives.
+///
+/// Interrupt handlers that may interrupt user mode must create one before
+/// anything uses the block and must drop it with interrupts disabled.
+pub struct KernelGsGuard {
+    /// Whether `swapgs` was executed on creation.
+    swapped: bool
+}
+
+impl KernelGsGuard {
+    /// Swaps in the kernel GS base if the interrupted code ran in user mode.
+    #[inline(always)]
+    pub fn enter(stack_frame: &ExceptionStackFrame) -> KernelGsGuard {
+        KernelGsGuard::swap_if(stack_frame.code_segment & 3 != 0)
+    }
+
+    /// Swaps in the kernel GS base if it isn't active already.
+    ///
+    /// Non-maskable exceptions can arrive in the kernel before the syscall
+    /// entry executed `swapgs`, so the code segment doesn't tell which base
+    /// is active. User mode can set any GS base if FSGSBASE is enabled, so
+    /// the active base is compared to the block of this CPU, which is found
+    /// without the GS base.
+    pub fn enter_paranoid() -> KernelGsGuard {
+        let gs_base = unsafe { rdmsr(IA32_GS_BASE) };
+
+        KernelGsGuard::swap_if(gs_base != &*CPU_BLOCK as *const CpuBlock as u64)
+    }
+
+    /// Executes `swapgs` if the condition is true.
+    #[inline(always)]
+    fn swap_if(condition: bool) -> KernelGsGuard {
+        if condition {
+            unsafe {
+                asm!("swapgs" : : : "memory" : "intel", "volatile");
+            }
+        }
+
+        KernelGsGuard { swapped: condition }
+    }
+}
+
+impl Drop for KernelGsGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe {
+                asm!("swapgs" : : : "memory" : "intel", "volatile");
+            }
+        }
+    }
+}
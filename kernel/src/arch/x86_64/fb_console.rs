@@ -0,0 +1,346 @@
+//! Handles text output on a linear framebuffer.
+//!
+//! This is used when the boot loader set up a graphics mode, so the VGA text
+//! buffer isn't available. The text is rendered using a built-in bitmap font.
+
+use super::memory::{is_mapped, map_page_at, PAGE_SIZE};
+use super::vga_buffer::{Info, VideoMode};
+use crate::boot;
+use core::fmt;
+use core::ptr;
+use crate::memory::{round_up_to_page_size, Address, PageFlags, VirtualAddress};
+use crate::sync::Mutex;
+
+/// The width of a character in pixels.
+const CHAR_WIDTH: usize = 8;
+
+/// The height of a character in pixels.
+///
+/// Every row of the 8x8 font is drawn twice to keep the text readable.
+const CHAR_HEIGHT: usize = 16;
+
+/// The largest width or height that is considered plausible for a framebuffer.
+const MAX_DIMENSION: usize = 16384;
+
+/// The color used for the text.
+const FOREGROUND: u32 = 0x00aa_aaaa;
+
+/// The color used for the background.
+const BACKGROUND: u32 = 0x0000_0000;
+
+/// The writer for the framebuffer, if the framebuffer console is active.
+pub static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// The writer is used to write text to a linear framebuffer.
+pub struct Writer {
+    /// The current column position in characters.
+    column_position: usize,
+    /// The current row position in characters.
+    row_position: usize,
+    /// The number of characters per line.
+    columns: usize,
+    /// The number of lines.
+    rows: usize,
+    /// The start of the framebuffer.
+    address: VirtualAddress,
+    /// The number of bytes per line of pixels.
+    pitch: usize,
+    /// The number of bytes per pixel.
+    bytes_per_pixel: usize
+}
+
+impl Writer {
+    /// Writes the given character to the framebuffer.
+    pub fn write_char(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            0x08 => {
+                // Backspace only moves the cursor back within the line.
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                }
+            },
+            byte => {
+                if self.column_position >= self.columns {
+                    self.new_line();
+                }
+
+                let row_position = self.row_position;
+                let column_position = self.column_position;
+                self.draw_char(row_position, column_position, byte);
+
+                self.column_position += 1;
+            }
+        }
+    }
+
+    /// Writes the given string to the framebuffer.
+    pub fn write_string(&mut self, string: &str) {
+        for byte in string.bytes() {
+            self.write_char(byte);
+        }
+    }
+
+    /// Inserts a new line character.
+    fn new_line(&mut self) {
+        if self.row_position >= self.rows - 1 {
+            self.scroll();
+        } else {
+            self.row_position += 1;
+        }
+
+        self.column_position = 0;
+    }
+
+    /// Moves all lines up by one and clears the last line.
+    fn scroll(&mut self) {
+        let line_size = self.pitch * CHAR_HEIGHT;
+
+        unsafe {
+            ptr::copy(
+                (self.address + line_size).as_ptr::<u8>(),
+                self.address.as_mut_ptr::<u8>(),
+                line_size * (self.rows - 1)
+            );
+        }
+
+        let last_row = self.rows - 1;
+        for column in 0..self.columns {
+            self.draw_char(last_row, column, b' ');
+        }
+    }
+
+    /// Clears the whole screen.
+    fn clear_screen(&mut self) {
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                self.draw_char(row, column, b' ');
+            }
+        }
+
+        self.column_position = 0;
+        self.row_position = 0;
+    }
+
+    /// Draws the character at the given position.
+    fn draw_char(&mut self, row_position: usize, column_position: usize, character: u8) {
+        let glyph = glyph(character);
+
+        for y in 0..CHAR_HEIGHT {
+            let bits = glyph[y * 8 / CHAR_HEIGHT];
+
+            for x in 0..CHAR_WIDTH {
+                let color = if bits & (1 << x) != 0 {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+
+                self.set_pixel(
+                    column_position * CHAR_WIDTH + x,
+                    row_position * CHAR_HEIGHT + y,
+                    color
+                );
+            }
+        }
+    }
+
+    /// Sets the pixel at the given position to the given RGB color.
+    fn set_pixel(&mut self, x: usize, y: usize, color: u32) {
+        let offset = y * self.pitch + x * self.bytes_per_pixel;
+        let pixel = self.address + offset;
+
+        unsafe {
+            match self.bytes_per_pixel {
+                4 => ptr::write_volatile(pixel.as_mut_ptr::<u32>(), color),
+                _ => {
+                    // 24 bit pixels are stored as blue, green, red.
+                    ptr::write_volatile(pixel.as_mut_ptr::<u8>(), color as u8);
+                    ptr::write_volatile((pixel + 1).as_mut_ptr::<u8>(), (color >> 8) as u8);
+                    ptr::write_volatile((pixel + 2).as_mut_ptr::<u8>(), (color >> 16) as u8);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.write_string(string);
+
+        Ok(())
+    }
+}
+
+/// Initializes the framebuffer console, if the boot loader set up a graphics mode.
+///
+/// This needs to be called after paging was set up, as the framebuffer is
+/// mapped here.
+pub fn init() {
+    assert_has_not_been_called!("The framebuffer console should only be initialized once.");
+
+    let info = boot::get_vga_info();
+
+    if info.mode != VideoMode::Graphics {
+        return;
+    }
+
+    if !is_supported(&info) {
+        warn!(
+            "Unsupported framebuffer ({}x{}x{} at {:?}), keeping the text mode.",
+            info.width, info.height, info.bpp, info.address
+        );
+        return;
+    }
+
+    let address = info.address.to_virtual();
+    let size = info.pitch * info.height;
+
+    for page in 0..round_up_to_page_size(size) / PAGE_SIZE {
+        let page_address = address + page * PAGE_SIZE;
+
+        if !is_mapped(page_address) {
+            map_page_at(
+                page_address,
+                info.address + page * PAGE_SIZE,
+                PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::NO_CACHE
+            );
+        }
+    }
+
+    let mut writer = Writer {
+        column_position: 0,
+        row_position: 0,
+        columns: info.width / CHAR_WIDTH,
+        rows: info.height / CHAR_HEIGHT,
+        address,
+        pitch: info.pitch,
+        bytes_per_pixel: info.bpp as usize / 8
+    };
+    writer.clear_screen();
+
+    *WRITER.lock() = Some(writer);
+}
+
+/// Checks if the framebuffer can be used by the console.
+fn is_supported(info: &Info) -> bool {
+    let bytes_per_pixel = info.bpp as usize / 8;
+
+    // At least two lines of text are needed for scrolling.
+    (info.bpp == 24 || info.bpp == 32)
+        && info.width >= CHAR_WIDTH
+        && info.width <= MAX_DIMENSION
+        && info.height >= 2 * CHAR_HEIGHT
+        && info.height <= MAX_DIMENSION
+        && info.pitch >= info.width * bytes_per_pixel
+        && info.address.is_page_aligned()
+}
+
+/// Returns the glyph for the given character.
+///
+/// Each byte is one row of the glyph, with the lowest bit being the leftmost pixel.
+fn glyph(character: u8) -> &'static [u8; 8] {
+    match character {
+        0x20..=0x7e => &FONT[(character - 0x20) as usize],
+        _ => &FONT[(b'?' - 0x20) as usize]
+    }
+}
+
+/// An 8x8 bitmap font for the printable ASCII characters.
+const FONT: [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3c, 0x3c, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7f, 0x36, 0x7f, 0x36, 0x36, 0x00], // '#'
+    [0x0c, 0x3e, 0x03, 0x1e, 0x30, 0x1f, 0x0c, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0c, 0x66, 0x63, 0x00], // '%'
+    [0x1c, 0x36, 0x1c, 0x6e, 0x3b, 0x33, 0x6e, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '''
+    [0x18, 0x0c, 0x06, 0x06, 0x06, 0x0c, 0x18, 0x00], // '('
+    [0x06, 0x0c, 0x18, 0x18, 0x18, 0x0c, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3c, 0xff, 0x3c, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0c, 0x0c, 0x3f, 0x0c, 0x0c, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0c, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3e, 0x63, 0x73, 0x7b, 0x6f, 0x67, 0x3e, 0x00], // '0'
+    [0x0c, 0x0e, 0x0c, 0x0c, 0x0c, 0x0c, 0x3f, 0x00], // '1'
+    [0x1e, 0x33, 0x30, 0x1c, 0x06, 0x33, 0x3f, 0x00], // '2'
+    [0x1e, 0x33, 0x30, 0x1c, 0x30, 0x33, 0x1e, 0x00], // '3'
+    [0x38, 0x3c, 0x36, 0x33, 0x7f, 0x30, 0x78, 0x00], // '4'
+    [0x3f, 0x03, 0x1f, 0x30, 0x30, 0x33, 0x1e, 0x00], // '5'
+    [0x1c, 0x06, 0x03, 0x1f, 0x33, 0x33, 0x1e, 0x00], // '6'
+    [0x3f, 0x33, 0x30, 0x18, 0x0c, 0x0c, 0x0c, 0x00], // '7'
+    [0x1e, 0x33, 0x33, 0x1e, 0x33, 0x33, 0x1e, 0x00], // '8'
+    [0x1e, 0x33, 0x33, 0x3e, 0x30, 0x18, 0x0e, 0x00], // '9'
+    [0x00, 0x0c, 0x0c, 0x00, 0x00, 0x0c, 0x0c, 0x00], // ':'
+    [0x00, 0x0c, 0x0c, 0x00, 0x00, 0x0c, 0x0c, 0x06], // ';'
+    [0x18, 0x0c, 0x06, 0x03, 0x06, 0x0c, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3f, 0x00, 0x00, 0x3f, 0x00, 0x00], // '='
+    [0x06, 0x0c, 0x18, 0x30, 0x18, 0x0c, 0x06, 0x00], // '>'
+    [0x1e, 0x33, 0x30, 0x18, 0x0c, 0x00, 0x0c, 0x00], // '?'
+    [0x3e, 0x63, 0x7b, 0x7b, 0x7b, 0x03, 0x1e, 0x00], // '@'
+    [0x0c, 0x1e, 0x33, 0x33, 0x3f, 0x33, 0x33, 0x00], // 'A'
+    [0x3f, 0x66, 0x66, 0x3e, 0x66, 0x66, 0x3f, 0x00], // 'B'
+    [0x3c, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3c, 0x00], // 'C'
+    [0x1f, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1f, 0x00], // 'D'
+    [0x7f, 0x46, 0x16, 0x1e, 0x16, 0x46, 0x7f, 0x00], // 'E'
+    [0x7f, 0x46, 0x16, 0x1e, 0x16, 0x06, 0x0f, 0x00], // 'F'
+    [0x3c, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7c, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3f, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1e, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1e, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0f, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7f, 0x00], // 'L'
+    [0x63, 0x77, 0x7f, 0x7f, 0x6b, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6f, 0x7b, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1c, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1c, 0x00], // 'O'
+    [0x3f, 0x66, 0x66, 0x3e, 0x06, 0x06, 0x0f, 0x00], // 'P'
+    [0x1e, 0x33, 0x33, 0x33, 0x3b, 0x1e, 0x38, 0x00], // 'Q'
+    [0x3f, 0x66, 0x66, 0x3e, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1e, 0x33, 0x07, 0x0e, 0x38, 0x33, 0x1e, 0x00], // 'S'
+    [0x3f, 0x2d, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3f, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1e, 0x0c, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6b, 0x7f, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1c, 0x1c, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1e, 0x0c, 0x0c, 0x1e, 0x00], // 'Y'
+    [0x7f, 0x63, 0x31, 0x18, 0x4c, 0x66, 0x7f, 0x00], // 'Z'
+    [0x1e, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1e, 0x00], // '['
+    [0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x40, 0x00], // '\'
+    [0x1e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1e, 0x00], // ']'
+    [0x08, 0x1c, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff], // '_'
+    [0x0c, 0x0c, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x1e, 0x30, 0x3e, 0x33, 0x6e, 0x00], // 'a'
+    [0x07, 0x06, 0x06, 0x3e, 0x66, 0x66, 0x3b, 0x00], // 'b'
+    [0x00, 0x00, 0x1e, 0x33, 0x03, 0x33, 0x1e, 0x00], // 'c'
+    [0x38, 0x30, 0x30, 0x3e, 0x33, 0x33, 0x6e, 0x00], // 'd'
+    [0x00, 0x00, 0x1e, 0x33, 0x3f, 0x03, 0x1e, 0x00], // 'e'
+    [0x1c, 0x36, 0x06, 0x0f, 0x06, 0x06, 0x0f, 0x00], // 'f'
+    [0x00, 0x00, 0x6e, 0x33, 0x33, 0x3e, 0x30, 0x1f], // 'g'
+    [0x07, 0x06, 0x36, 0x6e, 0x66, 0x66, 0x67, 0x00], // 'h'
+    [0x0c, 0x00, 0x0e, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'i'
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1e], // 'j'
+    [0x07, 0x06, 0x66, 0x36, 0x1e, 0x36, 0x67, 0x00], // 'k'
+    [0x0e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'l'
+    [0x00, 0x00, 0x33, 0x7f, 0x7f, 0x6b, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x1f, 0x33, 0x33, 0x33, 0x33, 0x00], // 'n'
+    [0x00, 0x00, 0x1e, 0x33, 0x33, 0x33, 0x1e, 0x00], // 'o'
+    [0x00, 0x00, 0x3b, 0x66, 0x66, 0x3e, 0x06, 0x0f], // 'p'
+    [0x00, 0x00, 0x6e, 0x33, 0x33, 0x3e, 0x30, 0x78], // 'q'
+    [0x00, 0x00, 0x3b, 0x6e, 0x66, 0x06, 0x0f, 0x00], // 'r'
+    [0x00, 0x00, 0x3e, 0x03, 0x1e, 0x30, 0x1f, 0x00], // 's'
+    [0x08, 0x0c, 0x3e, 0x0c, 0x0c, 0x2c, 0x18, 0x00], // 't'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6e, 0x00], // 'u'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1e, 0x0c, 0x00], // 'v'
+    [0x00, 0x00, 0x63, 0x6b, 0x7f, 0x7f, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x63, 0x36, 0x1c, 0x36, 0x63, 0x00], // 'x'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3e, 0x30, 0x1f], // 'y'
+    [0x00, 0x00, 0x3f, 0x19, 0x0c, 0x26, 0x3f, 0x00], // 'z'
+    [0x38, 0x0c, 0x0c, 0x07, 0x0c, 0x0c, 0x38, 0x00], // '{'
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x07, 0x0c, 0x0c, 0x38, 0x0c, 0x0c, 0x07, 0x00], // '}'
+    [0x6e, 0x3b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
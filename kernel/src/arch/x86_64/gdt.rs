@@ -2,7 +2,7 @@
 
 use super::memory::{
     DOUBLE_FAULT_STACK_AREA_BASE, DOUBLE_FAULT_STACK_MAX_SIZE, DOUBLE_FAULT_STACK_OFFSET,
-    FINAL_STACK_TOP
+    FINAL_STACK_TOP, NMI_STACK_AREA_BASE, NMI_STACK_MAX_SIZE, NMI_STACK_OFFSET
 };
 use core::mem::size_of;
 use crate::memory::Address;
@@ -46,6 +46,12 @@ pub const USER_CODE_SEGMENT: SegmentSelector = SegmentSelector::new(5, Privilege
 #[allow(dead_code)]
 pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(6, PrivilegeLevel::Ring0);
 
+/// The index of the double fault stack in the interrupt stack table.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// The index of the NMI stack in the interrupt stack table.
+pub const NMI_IST_INDEX: u16 = 1;
+
 /// Represents the GDT.
 pub struct Gdt {
     /// The actual entries of the GDT.
@@ -59,7 +65,8 @@ cpu_local! {
     pub static mut ref TSS: TaskStateSegment = |cpu_id| {
         let mut tss = TaskStateSegment::new();
         tss.privilege_stack_table[0] = VirtualAddress(FINAL_STACK_TOP.as_usize());
-        tss.interrupt_stack_table[0] = VirtualAddress(DOUBLE_FAULT_STACK.get_specific(cpu_id).base_stack_pointer.as_usize());
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = VirtualAddress(DOUBLE_FAULT_STACK.get_specific(cpu_id).base_stack_pointer.as_usize());
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = VirtualAddress(NMI_STACK.get_specific(cpu_id).base_stack_pointer.as_usize());
         tss
     };
 }
@@ -84,6 +91,14 @@ cpu_local! {
     pub static ref DOUBLE_FAULT_STACK: Stack = |cpu_id| Stack::new(DOUBLE_FAULT_STACK_MAX_SIZE, DOUBLE_FAULT_STACK_MAX_SIZE, DOUBLE_FAULT_STACK_AREA_BASE + DOUBLE_FAULT_STACK_OFFSET * cpu_id, AccessType::KernelOnly, None);
 }
 
+cpu_local! {
+    /// The stack for the NMI handler of each cpu.
+    ///
+    /// NMIs can arrive while the kernel stack is being switched or is
+    /// exhausted, so they always run on their own stack.
+    pub static ref NMI_STACK: Stack = |cpu_id| Stack::new(NMI_STACK_MAX_SIZE, NMI_STACK_MAX_SIZE, NMI_STACK_AREA_BASE + NMI_STACK_OFFSET * cpu_id, AccessType::KernelOnly, None);
+}
+
 impl Gdt {
     /// Creates a new zeroed global descriptor table.
     fn new() -> Gdt {
@@ -1,23 +1,181 @@
 //! Deals with configuring the I/O APIC.
 
-use super::super::memory::map_page_at;
+use super::super::acpi::{self, InterruptSourceOverride, IoApicInfo};
 use super::IRQ_INTERRUPT_NUMS;
+use alloc::Vec;
+use core::cmp::min;
 use core::fmt;
-use crate::memory::{PageFlags, PhysicalAddress, VirtualAddress};
+use crate::memory::{map_mmio, PhysicalAddress, VirtualAddress};
+use spin::Once;
 use x86_64::instructions::port::outb;
 
-/// The physical base address of the memory mapped I/O APIC.
-const IO_APIC_BASE: PhysicalAddress = PhysicalAddress::from_const(0xfec0_0000);
+/// The physical base address of the I/O APIC, if the MADT doesn't list any.
+const DEFAULT_IO_APIC_BASE: PhysicalAddress = PhysicalAddress::from_const(0xfec0_0000);
+
+/// The size of the registers of an I/O APIC.
+const IO_APIC_REGISTERS_SIZE: usize = 0x20;
+
+/// The register that holds the number of redirection entries.
+const VERSION_REGISTER: u8 = 0x01;
+
+/// The shift of the highest redirection entry in the version register.
+const MAX_REDIRECTION_ENTRY_SHIFT: u32 = 16;
+
+/// The first redirection table register.
+const REDIRECTION_TABLE_REGISTER: u8 = 0x10;
+
+/// The number of pins whose two redirection table registers can be indexed
+/// by the 8 bit register index.
+const MAX_PIN_COUNT: u32 = 120;
+
+/// The legacy IRQ the slave PIC was cascaded on, which never fires.
+const CASCADE_IRQ: u8 = 2;
+
+/// The mask of the polarity bits in the flags of an interrupt source override.
+const OVERRIDE_POLARITY_MASK: u16 = 0b11;
+
+/// The polarity bits of an active low interrupt source override.
+const OVERRIDE_ACTIVE_LOW: u16 = 0b11;
+
+/// The mask of the trigger mode bits in the flags of an interrupt source override.
+const OVERRIDE_TRIGGER_MASK: u16 = 0b11 << 2;
+
+/// The trigger mode bits of a level triggered interrupt source override.
+const OVERRIDE_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+/// The routes of the legacy IRQs, indexed by the IRQ number.
+static IRQ_ROUTES: Once<[Option<IrqRoute>; 16]> = Once::new();
+
+/// The I/O APICs of the system.
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+
+/// A mapped I/O APIC.
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    /// The address of its registers.
+    registers: VirtualAddress,
+    /// The global system interrupt of its first pin.
+    gsi_base: u32,
+    /// The number of its pins.
+    pin_count: u32
+}
+
+impl IoApic {
+    /// Maps the registers of the given I/O APIC.
+    ///
+    /// Returns `None` if the registers can't be mapped.
+    fn map(info: IoApicInfo) -> Option<IoApic> {
+        let registers = match map_mmio(info.address, IO_APIC_REGISTERS_SIZE) {
+            Ok(registers) => registers,
+            Err(error) => {
+                warn!("Couldn't map the I/O APIC {}: {:?}", info.id, error);
+                return None;
+            }
+        };
+
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base: info.gsi_base,
+            pin_count: 0
+        };
+        let version = io_apic.get_register(VERSION_REGISTER);
+        io_apic.pin_count = pin_count(version);
+
+        Some(io_apic)
+    }
+
+    /// Checks whether the given global system interrupt is one of its pins.
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.pin_count
+    }
+
+    /// Writes a register.
+    fn set_register(&self, reg: u8, value: u32) {
+        unsafe {
+            *self.registers.as_mut_ptr() = reg as u32;
+            *(self.registers + 0x10).as_mut_ptr() = value;
+        }
+    }
+
+    /// Reads a register.
+    fn get_register(&self, reg: u8) -> u32 {
+        unsafe {
+            *self.registers.as_mut_ptr() = reg as u32;
+            *(self.registers + 0x10).as_ptr()
+        }
+    }
+
+    /// Returns the redirection table register of the given global system
+    /// interrupt.
+    fn redirection_register(&self, gsi: u32) -> u8 {
+        debug_assert!(self.handles(gsi));
+
+        (REDIRECTION_TABLE_REGISTER as u32 + (gsi - self.gsi_base) * 2) as u8
+    }
+}
+
+/// Returns the number of usable pins of an I/O APIC with the given version
+/// register.
+///
+/// Pins beyond `MAX_PIN_COUNT` can't be indexed, so they are left out.
+fn pin_count(version: u32) -> u32 {
+    let reported = ((version >> MAX_REDIRECTION_ENTRY_SHIFT) & 0xff) + 1;
+
+    if reported > MAX_PIN_COUNT {
+        warn!("Only {} of the {} I/O APIC pins can be used.", MAX_PIN_COUNT, reported);
+    }
+
+    min(reported, MAX_PIN_COUNT)
+}
+
+/// Describes how a legacy IRQ is connected to the I/O APIC.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct IrqRoute {
+    /// The global system interrupt, which is the pin of the I/O APIC.
+    gsi: u32,
+    /// Whether the interrupt is active when the pin is low.
+    active_low: bool,
+    /// Whether the interrupt is level triggered.
+    level_triggered: bool
+}
+
+/// Determines which pin the given legacy IRQ is connected to.
+///
+/// Without an override, IRQs are identity mapped and use the ISA defaults of
+/// active high, edge triggered interrupts. `None` is returned for IRQs that
+/// aren't connected, because their pin is used by another IRQ.
+fn route_irq(irq: u8, overrides: &[InterruptSourceOverride]) -> Option<IrqRoute> {
+    if let Some(entry) = overrides.iter().find(|entry| entry.irq == irq) {
+        return Some(IrqRoute {
+            gsi: entry.gsi,
+            active_low: entry.flags & OVERRIDE_POLARITY_MASK == OVERRIDE_ACTIVE_LOW,
+            level_triggered: entry.flags & OVERRIDE_TRIGGER_MASK == OVERRIDE_LEVEL_TRIGGERED
+        });
+    }
+
+    let pin_taken = overrides.iter().any(|entry| entry.gsi == irq as u32);
+
+    if irq == CASCADE_IRQ || pin_taken {
+        None
+    } else {
+        Some(IrqRoute {
+            gsi: irq as u32,
+            active_low: false,
+            level_triggered: false
+        })
+    }
+}
 
 /// Initializes the I/O APIC.
 pub fn init() {
     assert_has_not_been_called!("The I/O APIC should only be initialized once.");
 
-    map_page_at(
-        get_ioapic_base(),
-        IO_APIC_BASE,
-        PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::NO_CACHE
-    );
+    let io_apics = IO_APICS.call_once(|| {
+        discover_io_apics()
+            .into_iter()
+            .filter_map(IoApic::map)
+            .collect()
+    });
 
     // Disable the 8259 PIC.
     unsafe {
@@ -25,16 +183,48 @@ pub fn init() {
         outb(0xa1, 0xff);
     }
 
-    for (i, irq_num) in IRQ_INTERRUPT_NUMS.iter().enumerate().take(16) {
-        let mut irq = IORedirectionEntry::new();
-        irq.set_vector(*irq_num);
-        set_irq(i as u8, irq);
+    // Mask all pins, so pins without a legacy IRQ stay quiet.
+    for io_apic in io_apics {
+        for pin in 0..io_apic.pin_count {
+            let mut entry = IORedirectionEntry::new();
+            entry.set_inactive();
+            set_irq(io_apic.gsi_base + pin, entry);
+        }
+    }
+
+    let overrides = acpi::interrupt_source_overrides();
+    let mut routes = [None; 16];
+
+    for (irq, irq_num) in IRQ_INTERRUPT_NUMS.iter().enumerate() {
+        let route = match route_irq(irq as u8, &overrides) {
+            Some(route) if find_io_apic(io_apics, route.gsi).is_some() => route,
+            Some(route) => {
+                warn!("IRQ {} is routed to unsupported GSI {}.", irq, route.gsi);
+                continue;
+            },
+            None => continue
+        };
+
+        let mut entry = IORedirectionEntry::new();
+        entry.set_vector(*irq_num);
+
+        if route.active_low {
+            entry.set_polarity(IORedirectionEntryFlags::LOW_ACTIVE_PIN_POLARITY);
+        }
+        if route.level_triggered {
+            entry.set_trigger_mode(IORedirectionEntryFlags::LEVEL_SENSITIVE);
+        }
+
+        // The PIT is only used as a fallback timer, so irq0 starts out masked.
+        if irq == 0 {
+            entry.set_inactive();
+        }
+
+        set_irq(route.gsi, entry);
+        routes[irq] = Some(route);
     }
 
-    // Deactivate irq2.
-    let mut irq2 = IORedirectionEntry::new();
-    irq2.set_inactive();
-    set_irq(2, irq2);
+    IRQ_ROUTES.call_once(|| routes);
 
     // Reroute interrupts to the IOAPIC.
     unsafe {
@@ -43,30 +233,65 @@ pub fn init() {
     }
 }
 
-/// Writes an I/O APIC register.
-fn set_register(reg: u8, value: u32) {
-    unsafe {
-        *get_ioapic_base().as_mut_ptr() = reg as u32;
-        *(get_ioapic_base() + 0x10).as_mut_ptr() = value;
+/// Returns the I/O APICs listed in the MADT.
+///
+/// Without a MADT, a single I/O APIC at the default address is assumed.
+fn discover_io_apics() -> Vec<IoApicInfo> {
+    let mut io_apics = acpi::io_apics();
+
+    if io_apics.is_empty() {
+        warn!("No I/O APIC found, assuming one at {:?}.", DEFAULT_IO_APIC_BASE);
+        io_apics.push(IoApicInfo {
+            id: 0,
+            address: DEFAULT_IO_APIC_BASE,
+            gsi_base: 0
+        });
     }
+
+    io_apics
 }
 
-/// Sets the given IRQ number to the specified value.
-fn set_irq(number: u8, value: IORedirectionEntry) {
-    assert!(number < 24);
+/// Returns the I/O APIC that the given global system interrupt is a pin of.
+fn find_io_apic(io_apics: &[IoApic], gsi: u32) -> Option<&IoApic> {
+    io_apics.iter().find(|io_apic| io_apic.handles(gsi))
+}
 
-    let reg = 0x10 + number * 2;
+/// Returns the I/O APIC of the given global system interrupt.
+fn io_apic_of(gsi: u32) -> &'static IoApic {
+    IO_APICS
+        .try()
+        .and_then(|io_apics| find_io_apic(io_apics, gsi))
+        .unwrap_or_else(|| panic!("GSI {} isn't handled by an I/O APIC.", gsi))
+}
 
-    // Disable the entry, before setting the destination.
-    set_register(reg, IORedirectionEntryFlags::MASK.bits() as u32);
+/// Lets the interrupts of the given legacy IRQ through.
+pub fn unmask_irq(irq: u8) {
+    let route = IRQ_ROUTES
+        .try()
+        .and_then(|routes| routes.get(irq as usize).cloned())
+        .and_then(|route| route);
+    let route = match route {
+        Some(route) => route,
+        None => panic!("IRQ {} isn't routed to the I/O APIC.", irq)
+    };
+
+    let io_apic = io_apic_of(route.gsi);
+    let reg = io_apic.redirection_register(route.gsi);
+    let value = io_apic.get_register(reg) & !(IORedirectionEntryFlags::MASK.bits() as u32);
 
-    set_register(reg + 1, (value.0 >> 32) as u32);
-    set_register(reg, value.0 as u32);
+    io_apic.set_register(reg, value);
 }
 
-/// Returns the base address for the I/O APIC.
-fn get_ioapic_base() -> VirtualAddress {
-    IO_APIC_BASE.to_virtual()
+/// Sets the redirection entry of the given global system interrupt.
+fn set_irq(gsi: u32, value: IORedirectionEntry) {
+    let io_apic = io_apic_of(gsi);
+    let reg = io_apic.redirection_register(gsi);
+
+    // Disable the entry, before setting the destination.
+    io_apic.set_register(reg, IORedirectionEntryFlags::MASK.bits() as u32);
+
+    io_apic.set_register(reg + 1, (value.0 >> 32) as u32);
+    io_apic.set_register(reg, value.0 as u32);
 }
 
 /// Represents an entry in the I/O APIC redirection table.
@@ -199,3 +424,113 @@ impl fmt::Debug for IORedirectionEntry {
         )
     }
 }
+
+/// Self-tests for the I/O APIC discovery.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+
+    /// Checks that the single I/O APIC of QEMU was found at its usual place.
+    fn test_discovered_io_apics() -> Result<(), &'static str> {
+        let discovered = acpi::io_apics();
+
+        if discovered.len() != 1 {
+            return Err("The MADT doesn't list exactly one I/O APIC.");
+        }
+
+        if discovered[0].address != DEFAULT_IO_APIC_BASE || discovered[0].gsi_base != 0 {
+            return Err("The I/O APIC isn't at the default address.");
+        }
+
+        let io_apics = IO_APICS.try().ok_or("The I/O APICs aren't initialized.")?;
+
+        match io_apics.first() {
+            Some(io_apic) if io_apics.len() == 1 => {
+                if io_apic.registers.to_physical() != Some(DEFAULT_IO_APIC_BASE) {
+                    return Err("The I/O APIC registers are mapped wrongly.");
+                }
+
+                // The I/O APIC of QEMU has 24 pins.
+                if io_apic.pin_count != 24 || find_io_apic(io_apics, 23).is_none() {
+                    return Err("The number of I/O APIC pins is wrong.");
+                }
+
+                Ok(())
+            },
+            _ => Err("Not exactly one I/O APIC is in use.")
+        }
+    }
+
+    register_selftest!(DISCOVERED_IO_APICS, test_discovered_io_apics);
+}
+
+/// Tests for the IRQ routing.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Address;
+
+    /// Creates an override for the given IRQ.
+    fn override_entry(irq: u8, gsi: u32, flags: u16) -> InterruptSourceOverride {
+        InterruptSourceOverride { irq, gsi, flags }
+    }
+
+    /// Creates a route to the given GSI.
+    fn route(gsi: u32, active_low: bool, level_triggered: bool) -> Option<IrqRoute> {
+        Some(IrqRoute {
+            gsi,
+            active_low,
+            level_triggered
+        })
+    }
+
+    /// Tests the routing of IRQs with different sets of overrides.
+    #[test]
+    fn test_route_irq() {
+        // The overrides QEMU reports for its emulated chipset.
+        let qemu = [
+            override_entry(0, 2, 0),
+            override_entry(5, 5, 0xd),
+            override_entry(9, 9, 0xd),
+            override_entry(10, 10, 0xd),
+            override_entry(11, 11, 0xd)
+        ];
+        let active_low = [override_entry(11, 20, 0xf)];
+
+        let table: &[(&[InterruptSourceOverride], u8, Option<IrqRoute>)] = &[
+            (&[], 0, route(0, false, false)),
+            (&[], 1, route(1, false, false)),
+            (&[], 2, None),
+            (&[], 8, route(8, false, false)),
+            (&qemu, 0, route(2, false, false)),
+            (&qemu, 1, route(1, false, false)),
+            (&qemu, 2, None),
+            (&qemu, 9, route(9, false, true)),
+            (&active_low, 11, route(20, true, true)),
+            (&active_low, 12, route(12, false, false))
+        ];
+
+        for &(overrides, irq, expected) in table {
+            assert_eq!(route_irq(irq, overrides), expected, "IRQ {}", irq);
+        }
+    }
+
+    /// Tests that only pins with an indexable redirection register are used.
+    #[test]
+    fn test_redirection_registers() {
+        let version = |max_entry: u32| max_entry << MAX_REDIRECTION_ENTRY_SHIFT | 0x11;
+
+        assert_eq!(pin_count(version(23)), 24);
+        assert_eq!(pin_count(version(119)), MAX_PIN_COUNT);
+        assert_eq!(pin_count(version(0xff)), MAX_PIN_COUNT);
+
+        let io_apic = IoApic {
+            registers: VirtualAddress::from_usize(0),
+            gsi_base: 24,
+            pin_count: MAX_PIN_COUNT
+        };
+
+        assert_eq!(io_apic.redirection_register(24), REDIRECTION_TABLE_REGISTER);
+        assert_eq!(io_apic.redirection_register(24 + MAX_PIN_COUNT - 1), 0xfe);
+    }
+}
@@ -1,64 +1,120 @@
 //! Handles configuration of the Local Advanced Programmable Interrupt
 //! Controller (LAPIC).
 
-use super::super::memory::map_page_at;
-use super::{IRQ8_INTERRUPT_TICKS, SPURIOUS_INTERRUPT_HANDLER_NUM, TIMER_INTERRUPT_HANDLER_NUM};
-use crate::memory::{PageFlags, PhysicalAddress, VirtualAddress};
+use super::super::memory::PAGE_SIZE;
+use super::pit;
+use super::{SPURIOUS_INTERRUPT_HANDLER_NUM, TIMER_INTERRUPT_HANDLER_NUM};
+use crate::memory::{map_mmio, Address, PhysicalAddress, VirtualAddress};
+use crate::multitasking::get_cpu_num;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use raw_cpuid::CpuId;
+use spin::Once;
 use crate::sync::{disable_preemption, restore_preemption_state};
-use x86_64::instructions::interrupts;
+use x86_64::instructions::{rdmsr, wrmsr};
 use x86_64::instructions::port::{inb, outb};
 
-/// The physical base address of the memory mapped LAPIC.
-const LAPIC_BASE: PhysicalAddress = PhysicalAddress::from_const(0xfee0_0000);
+/// The physical base address of the LAPIC, if the APIC base MSR holds none.
+const DEFAULT_LAPIC_BASE: PhysicalAddress = PhysicalAddress::from_const(0xfee0_0000);
 
 /// The offset for the CMCI interrupt LVT register.
-const CMCI_INTERRUPT: usize = 0x2f0;
+const CMCI_INTERRUPT: LapicRegister = LapicRegister(0x2f0);
 
 /// The offset for the timer interrupt LVT register.
-const TIMER_INTERRUPT: usize = 0x320;
+const TIMER_INTERRUPT: LapicRegister = LapicRegister(0x320);
 
 /// The offset for the thernal sensor interrupt LVT register.
-const THERMAL_SENSOR_INTERRUPT: usize = 0x330;
+const THERMAL_SENSOR_INTERRUPT: LapicRegister = LapicRegister(0x330);
 
 /// The offset for the performance counter interrupt LVT register.
-const PERFORMANCE_COUNTER_INTERRUPT: usize = 0x340;
+const PERFORMANCE_COUNTER_INTERRUPT: LapicRegister = LapicRegister(0x340);
 
 /// The offset for the local interrupt 0 LVT register.
-const LINT0_INTERRUPT: usize = 0x350;
+const LINT0_INTERRUPT: LapicRegister = LapicRegister(0x350);
 
 /// The offset for the local interrupt 1 LVT register.
-const LINT1_INTERRUPT: usize = 0x360;
+const LINT1_INTERRUPT: LapicRegister = LapicRegister(0x360);
 
 /// The offset for the error interrupt LVT register.
-const ERROR_INTERRUPT: usize = 0x370;
+const ERROR_INTERRUPT: LapicRegister = LapicRegister(0x370);
 
 /// The offset for the spurious interrupt register.
-const SPURIOUS_INTERRUPT: usize = 0xf0;
+const SPURIOUS_INTERRUPT: LapicRegister = LapicRegister(0xf0);
+
+/// The bit in the spurious interrupt register that enables the LAPIC.
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// The bits of the spurious interrupt register that hold the vector.
+const SPURIOUS_VECTOR_MASK: u32 = 0xff;
 
 /// The offset for the timer inital count register.
-const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_INITIAL_COUNT: LapicRegister = LapicRegister(0x380);
 
 /// The offset for the timer current count register.
-const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_CURRENT_COUNT: LapicRegister = LapicRegister(0x390);
 
 /// The offset for the task priority register.
-const TASK_PRIORITY_REGISTER: usize = 0x80;
+const TASK_PRIORITY_REGISTER: LapicRegister = LapicRegister(0x80);
 
 /// The offset for the interrupt command register (bits 0-31).
-const INTERRUPT_COMMAND_REGISTER_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_REGISTER_LOW: LapicRegister = LapicRegister(0x300);
 
 /// The offset for the interrupt command register (bits 32-63).
-const INTERRUPT_COMMAND_REGISTER_HIGH: usize = 0x310;
+const INTERRUPT_COMMAND_REGISTER_HIGH: LapicRegister = LapicRegister(0x310);
+
+/// The offset of the first of the eight in-service registers.
+///
+/// Each register holds 32 vectors and is 16 bytes apart from the next one.
+const IN_SERVICE_REGISTER_BASE: usize = 0x100;
 
 /// The offset for the end of interrupt register.
-const END_OF_INTERRUPT: usize = 0xb0;
+const END_OF_INTERRUPT: LapicRegister = LapicRegister(0xb0);
+
+/// The delivery status bit of the interrupt command register.
+///
+/// It is set while the last interrupt wasn't accepted yet.
+const ICR_DELIVERY_STATUS: u32 = 1 << 12;
+
+/// The delivery mode of the interrupt command register for NMIs.
+///
+/// The vector is ignored for this mode.
+const NMI_DELIVERY_MODE: u32 = 0b100 << 8;
+
+/// The shift of the destination field in the high half of the interrupt
+/// command register in xAPIC mode.
+const XAPIC_DESTINATION_SHIFT: u32 = 24;
+
+/// The shift of the destination field in the interrupt command register in
+/// x2APIC mode.
+const X2APIC_DESTINATION_SHIFT: u64 = 32;
+
+/// The MSR that holds the APIC base address and mode.
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+
+/// The bit in the APIC base MSR that enables the APIC globally.
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// The bit in the APIC base MSR that enables the x2APIC mode.
+const X2APIC_ENABLE: u64 = 1 << 10;
+
+/// The bits of the APIC base MSR that hold the physical base address.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The first MSR of the x2APIC registers.
+///
+/// The MSR of a register is this plus its xAPIC offset divided by 16.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// The lowest vector that can be used for fixed interrupts.
+///
+/// The vectors below are reserved for exceptions.
+const MIN_INTERRUPT_VECTOR: u8 = 0x20;
 
 /// The offset of the logical destination register.
-const LOGICAL_DESTINATION_REGISTER: usize = 0xd0;
+const LOGICAL_DESTINATION_REGISTER: LapicRegister = LapicRegister(0xd0);
 
 /// The offset of the destination format register.
-const DESTINATION_FORMAT_REGISTER: usize = 0xe0;
+const DESTINATION_FORMAT_REGISTER: LapicRegister = LapicRegister(0xe0);
 
 // TODO: This assumes the LAPICS on all CPUs have the same frequency.
 /// The amount of LAPIC timer ticks per milliseconds. Measured at runtime.
@@ -66,15 +122,41 @@ const DESTINATION_FORMAT_REGISTER: usize = 0xe0;
 /// This value is initialized to the value that qemu uses.
 static mut TICKS_PER_MS: u32 = 1_000_000;
 
-/// Initializes the LAPIC.
-pub fn init() {
-    assert_has_not_been_called!("The LAPIC should only be initialized once.");
+/// Whether the LAPIC is accessed in x2APIC mode instead of through MMIO.
+static X2APIC_MODE: AtomicBool = ATOMIC_BOOL_INIT;
 
-    map_page_at(
-        get_lapic_base(),
-        LAPIC_BASE,
-        PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::NO_CACHE
-    );
+/// The virtual address of the LAPIC registers, once they are mapped.
+///
+/// Every CPU sees its own LAPIC at the same address, so the registers are
+/// only mapped once.
+static LAPIC_ADDRESS: Once<VirtualAddress> = Once::new();
+
+/// Makes the LAPIC use the x2APIC mode.
+///
+/// This must only be called if the CPU supports x2APIC and before `init`.
+pub fn use_x2apic() {
+    X2APIC_MODE.store(true, Ordering::Relaxed);
+}
+
+/// Checks whether the LAPIC is used in x2APIC mode.
+fn x2apic_mode() -> bool {
+    X2APIC_MODE.load(Ordering::Relaxed)
+}
+
+/// Initializes the LAPIC of the current CPU.
+pub fn init() {
+    if x2apic_mode() {
+        // The x2APIC mode can only be entered from the enabled xAPIC mode.
+        unsafe {
+            let apic_base = rdmsr(IA32_APIC_BASE_MSR) | APIC_GLOBAL_ENABLE;
+            wrmsr(IA32_APIC_BASE_MSR, apic_base);
+            wrmsr(IA32_APIC_BASE_MSR, apic_base | X2APIC_ENABLE);
+        }
+    } else {
+        LAPIC_ADDRESS.call_once(|| {
+            map_mmio(discover_base(), PAGE_SIZE).expect("Couldn't map the LAPIC.")
+        });
+    }
 
     let cpu_id = CpuId::new()
         .get_feature_info()
@@ -110,24 +192,25 @@ pub fn init() {
 
         // Set the timer interrupt register.
         set_lvt_register(TIMER_INTERRUPT, timer_register);
-        set_register(TIMER_INITIAL_COUNT, 0);
+        TIMER_INITIAL_COUNT.write(0);
 
         // Enable the LAPIC.
-        set_register(
-            SPURIOUS_INTERRUPT,
-            0x100 + SPURIOUS_INTERRUPT_HANDLER_NUM as u32
-        );
+        SPURIOUS_INTERRUPT.write(APIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_HANDLER_NUM as u32);
+        verify_spurious_register();
 
         // Set the local interrupt registers again, to make sure they have the right
         // value.
         set_lvt_register(LINT0_INTERRUPT, lint0_register);
         set_lvt_register(LINT1_INTERRUPT, lint1_register);
 
-        // Use flat logical destinations.
-        set_register(DESTINATION_FORMAT_REGISTER, 0b1111 << 28);
+        // In x2APIC mode the logical destination is fixed by the hardware.
+        if !x2apic_mode() {
+            // Use flat logical destinations.
+            DESTINATION_FORMAT_REGISTER.write(0b1111 << 28);
 
-        // Set the processor to its logical destination address.
-        set_register(LOGICAL_DESTINATION_REGISTER, (logical_id as u32) << 24);
+            // Set the processor to its logical destination address.
+            LOGICAL_DESTINATION_REGISTER.write((logical_id as u32) << 24);
+        }
     }
 }
 
@@ -140,8 +223,8 @@ pub fn calibrate_timer() {
         measure_accuracy_in_ms
     );
 
-    // Use the RTC to calibrate the LAPIC timer.
     unsafe {
+        // The RTC interrupts drive the clock, so they are enabled here as well.
         // Save the NMI enable state to restore it later.
         let nmi_bit = inb(0x70) & 0x80;
 
@@ -157,27 +240,17 @@ pub fn calibrate_timer() {
         outb(0x70, 0x8c);
         inb(0x71);
 
-        let start_tick = *IRQ8_INTERRUPT_TICKS.lock();
-        let end_tick = start_tick + 1024 * measure_accuracy_in_ms / 1000;
-
-        // Enable interrupts.
-        interrupts::enable();
-
         // Start LAPIC timer for comparison.
-        set_register(TIMER_INITIAL_COUNT, <u32>::max_value());
+        TIMER_INITIAL_COUNT.write(<u32>::max_value());
 
-        // Wait until the specified amount of time has passed.
-        while *IRQ8_INTERRUPT_TICKS.lock() < end_tick {
-            asm!("pause" : : : : "intel", "volatile");
-        }
+        // Use the PIT as the reference.
+        pit::wait_ms(measure_accuracy_in_ms);
 
         // Measure LAPIC timer ticks.
-        let timer_ticks_passed = <u32>::max_value() - get_register(TIMER_CURRENT_COUNT);
+        let timer_ticks_passed = <u32>::max_value() - TIMER_CURRENT_COUNT.read();
+        TIMER_INITIAL_COUNT.write(0);
 
-        // Disable interrupts again.
-        interrupts::disable();
-
-        TICKS_PER_MS = timer_ticks_passed / measure_accuracy_in_ms as u32;
+        TICKS_PER_MS = timer_ticks_passed / measure_accuracy_in_ms;
 
         // Restore the NMI state.
         outb(0x70, nmi_bit);
@@ -186,71 +259,151 @@ pub fn calibrate_timer() {
     }
 }
 
+/// Checks whether the LAPIC timer works.
+///
+/// This is only meaningful after the timer was calibrated.
+pub fn timer_available() -> bool {
+    unsafe { TICKS_PER_MS != 0 }
+}
+
 /// Signals the end of the interrupt handler to the LAPIC.
 pub fn signal_eoi() {
+    // All memory accesses of the handler must be complete before the EOI,
+    // because afterwards the same interrupt can be delivered again and its
+    // handler must see the effects of this one.
+    fence(Ordering::SeqCst);
+
     unsafe {
-        set_register(END_OF_INTERRUPT, 0);
+        END_OF_INTERRUPT.write(0);
     }
 }
 
+/// Returns the highest vector whose interrupt is in service.
+///
+/// The LAPIC marks an interrupt as in service when it is delivered. An EOI
+/// ends the highest one that is in service.
+pub fn highest_in_service() -> Option<u8> {
+    let mut registers = [0u32; 8];
+
+    for (index, register) in registers.iter_mut().enumerate() {
+        *register = unsafe { LapicRegister(IN_SERVICE_REGISTER_BASE + index * 0x10).read() };
+    }
+
+    highest_vector(&registers)
+}
+
+/// Returns the highest vector that is set in the given bitmap of all vectors.
+fn highest_vector(bitmap: &[u32; 8]) -> Option<u8> {
+    bitmap
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|&(_, &bits)| bits != 0)
+        .map(|(index, &bits)| (index * 32 + 31 - bits.leading_zeros() as usize) as u8)
+}
+
 /// Sets the periodic lapic timer to the specified delay in milliseconds.
 pub fn set_timer(delay: u32) {
     unsafe {
-        set_register(TIMER_INITIAL_COUNT, delay * TICKS_PER_MS);
+        TIMER_INITIAL_COUNT.write(delay * TICKS_PER_MS);
     }
 }
 
 /// Sets the task priority for the local APIC.
 pub fn set_priority(value: u8) {
     unsafe {
-        set_register(TASK_PRIORITY_REGISTER, value as u32);
+        TASK_PRIORITY_REGISTER.write(value as u32);
     }
 }
 
 /// Gets the current task priority for the local APIC.
 pub fn get_priority() -> u8 {
-    unsafe { get_register(TASK_PRIORITY_REGISTER) as u8 }
+    unsafe { TASK_PRIORITY_REGISTER.read() as u8 }
 }
 
-/// Sets the ICR to the specified value.
-fn set_icr(value: u64) {
-    let value_low = value as u32;
-    let value_high = (value >> 32) as u32;
-
+/// Sets the ICR to the specified command for the given destination.
+fn set_icr(command: u32, destination: u32) {
     unsafe {
         let preemption_state = disable_preemption();
 
-        set_register(INTERRUPT_COMMAND_REGISTER_HIGH, value_high);
-        set_register(INTERRUPT_COMMAND_REGISTER_LOW, value_low);
+        if x2apic_mode() {
+            let value = (destination as u64) << X2APIC_DESTINATION_SHIFT | command as u64;
+
+            // Writing the ICR MSR isn't serializing, so the fence is needed for
+            // everything written before to be visible to the target.
+            fence(Ordering::SeqCst);
+
+            // The x2APIC has no delivery status, the whole ICR is written at once.
+            wrmsr(INTERRUPT_COMMAND_REGISTER_LOW.msr(), value);
+        } else {
+            // A new interrupt can only be sent once the previous one was accepted.
+            wait_for_delivery();
+
+            // Everything written before sending the interrupt must be visible to
+            // the target when it handles the interrupt.
+            fence(Ordering::SeqCst);
+
+            // Writing the low half sends the interrupt, so it has to come last.
+            INTERRUPT_COMMAND_REGISTER_HIGH.write(destination << XAPIC_DESTINATION_SHIFT);
+            INTERRUPT_COMMAND_REGISTER_LOW.write(command);
+
+            wait_for_delivery();
+        }
 
         restore_preemption_state(&preemption_state);
     }
 }
 
-/// Returns the base address for the LAPIC of this CPU.
-fn get_lapic_base() -> VirtualAddress {
-    LAPIC_BASE.to_virtual()
-}
-
-/// Sets a LAPIC register.
+/// Waits until the last interrupt sent was accepted.
 ///
 /// # Safety
 /// - Ensure the LAPIC is mapped.
-/// - Setting registers incorrectly can cause interrupts to behave unexpected.
-unsafe fn set_register(offset: usize, value: u32) {
-    assert!(offset < 0x1000);
+unsafe fn wait_for_delivery() {
+    while INTERRUPT_COMMAND_REGISTER_LOW.read() & ICR_DELIVERY_STATUS != 0 {
+        asm!("pause" : : : : "intel", "volatile");
+    }
+}
+
+/// Checks that the LAPIC is enabled and uses the spurious interrupt vector.
+///
+/// Some older CPUs hardwire the low four bits of the spurious vector to ones,
+/// so any other vector would silently be delivered elsewhere.
+fn verify_spurious_register() {
+    debug_assert_eq!(
+        SPURIOUS_INTERRUPT_HANDLER_NUM & 0xf,
+        0xf,
+        "The low bits of the spurious interrupt vector must be set."
+    );
 
-    *(get_lapic_base() + offset).as_mut_ptr() = value;
+    let value = unsafe { SPURIOUS_INTERRUPT.read() };
+
+    assert!(value & APIC_SOFTWARE_ENABLE != 0, "The LAPIC couldn't be enabled.");
+    assert_eq!(
+        value & SPURIOUS_VECTOR_MASK,
+        SPURIOUS_INTERRUPT_HANDLER_NUM as u32,
+        "The LAPIC uses the wrong spurious interrupt vector."
+    );
 }
 
-/// Gets a LAPIC register.
+/// Reads the physical base address of the LAPIC from the APIC base MSR.
 ///
-/// # Safety
-/// - Ensure the LAPIC is mapped.
-unsafe fn get_register(offset: usize) -> u32 {
-    assert!(offset < 0x1000);
+/// Every CPU sees its own LAPIC at this address.
+fn discover_base() -> PhysicalAddress {
+    let base = unsafe { rdmsr(IA32_APIC_BASE_MSR) } & APIC_BASE_ADDRESS_MASK;
+
+    if base == 0 {
+        warn!("The APIC base MSR holds no address, assuming {:?}.", DEFAULT_LAPIC_BASE);
+        DEFAULT_LAPIC_BASE
+    } else {
+        PhysicalAddress::from_usize(base as usize)
+    }
+}
 
-    *(get_lapic_base() + offset).as_mut_ptr()
+/// Returns the base address for the LAPIC of this CPU.
+fn get_lapic_base() -> VirtualAddress {
+    debug_assert!(!x2apic_mode(), "The LAPIC registers aren't mapped in x2APIC mode.");
+
+    *LAPIC_ADDRESS.try().expect("The LAPIC registers aren't mapped yet.")
 }
 
 /// Sets an LVT register.
@@ -258,8 +411,57 @@ unsafe fn get_register(offset: usize) -> u32 {
 /// # Safety
 /// - Ensure the LAPIC is mapped.
 /// - Setting registers incorrectly can cause interrupts to behave unexpected.
-unsafe fn set_lvt_register(offset: usize, register: LVTRegister) {
-    set_register(offset, register.0);
+unsafe fn set_lvt_register(lvt_register: LapicRegister, register: LVTRegister) {
+    lvt_register.write(register.0);
+}
+
+/// A register of the LAPIC, identified by its offset from the LAPIC base.
+///
+/// All accesses are volatile, so the compiler can neither elide nor reorder
+/// them. This matters for registers like the task priority register, whose
+/// value is saved and restored around interrupt handlers.
+///
+/// In x2APIC mode the register is accessed through its MSR instead.
+#[derive(Clone, Copy)]
+struct LapicRegister(usize);
+
+impl LapicRegister {
+    /// Returns the address of this register.
+    fn address(self) -> VirtualAddress {
+        debug_assert!(self.0 < 0x1000);
+
+        get_lapic_base() + self.0
+    }
+
+    /// Returns the MSR of this register in x2APIC mode.
+    fn msr(self) -> u32 {
+        X2APIC_MSR_BASE + (self.0 >> 4) as u32
+    }
+
+    /// Reads the value of this register.
+    ///
+    /// # Safety
+    /// - Ensure the LAPIC is mapped.
+    unsafe fn read(self) -> u32 {
+        if x2apic_mode() {
+            rdmsr(self.msr()) as u32
+        } else {
+            ptr::read_volatile(self.address().as_ptr())
+        }
+    }
+
+    /// Writes the given value to this register.
+    ///
+    /// # Safety
+    /// - Ensure the LAPIC is mapped.
+    /// - Setting registers incorrectly can cause interrupts to behave unexpected.
+    unsafe fn write(self, value: u32) {
+        if x2apic_mode() {
+            wrmsr(self.msr(), value as u64);
+        } else {
+            ptr::write_volatile(self.address().as_mut_ptr(), value);
+        }
+    }
 }
 
 /// Issues an interrupt to the current CPU.
@@ -267,6 +469,51 @@ pub fn issue_self_interrupt(vector: u8) {
     issue_interrupt(InterruptDestinationMode::SELF, vector);
 }
 
+/// Sends an inter-processor interrupt to the CPU with the given id.
+///
+/// # Panics
+/// Panics if there is no CPU with the given id, because the interrupt would
+/// silently be dropped otherwise.
+pub fn send_ipi(target_cpu_id: usize, vector: u8) {
+    // The CPU id is the APIC id, which is only 8 bits wide in xAPIC mode.
+    let max_apic_id = if x2apic_mode() {
+        u32::max_value() as usize
+    } else {
+        0xff
+    };
+    assert!(
+        target_cpu_id < get_cpu_num() && target_cpu_id <= max_apic_id,
+        "Sending an IPI to the non-existent CPU {}.",
+        target_cpu_id
+    );
+    assert!(vector >= MIN_INTERRUPT_VECTOR);
+
+    let mut icr = InterruptDestinationMode::PHYSICAL.bits();
+    icr |= vector as u32;
+
+    set_icr(icr, target_cpu_id as u32);
+}
+
+/// Sends a non-maskable interrupt to the given CPU.
+///
+/// The NMI is delivered even if the CPU has interrupts disabled.
+pub fn send_nmi(target_cpu_id: usize) {
+    assert!(
+        target_cpu_id < get_cpu_num(),
+        "Sending an NMI to the non-existent CPU {}.",
+        target_cpu_id
+    );
+
+    let icr = InterruptDestinationMode::PHYSICAL.bits() | NMI_DELIVERY_MODE;
+
+    set_icr(icr, target_cpu_id as u32);
+}
+
+/// Sends an inter-processor interrupt to all CPUs except the current one.
+pub fn send_ipi_all_but_self(vector: u8) {
+    issue_interrupt(InterruptDestinationMode::ALL_EXCLUDING_SELF, vector);
+}
+
 /// Issues the given interrupt for the given target(s).
 fn issue_interrupt(target: InterruptDestinationMode, vector: u8) {
     assert!(target.intersects(
@@ -274,16 +521,17 @@ fn issue_interrupt(target: InterruptDestinationMode, vector: u8) {
             | InterruptDestinationMode::ALL
             | InterruptDestinationMode::ALL_EXCLUDING_SELF
     ));
+    assert!(vector >= MIN_INTERRUPT_VECTOR);
 
     let mut icr = target.bits();
-    icr |= vector as u64;
+    icr |= vector as u32;
 
-    set_icr(icr);
+    set_icr(icr, 0);
 }
 
 bitflags! {
     /// The possible destination modes for interrupts.
-    struct InterruptDestinationMode: u64 {
+    struct InterruptDestinationMode: u32 {
         /// The destination address for the interrupt is logical.
         const LOGICAL = 1 << 11;
         /// The destination address for the interrupt is physical.
@@ -398,3 +646,46 @@ impl LVTRegister {
         self.0 |= timer_mode.bits();
     }
 }
+
+/// Tests for decoding LAPIC registers.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests finding the highest vector in a vector bitmap.
+    #[test]
+    fn test_highest_vector() {
+        let mut bitmap = [0u32; 8];
+        assert_eq!(highest_vector(&bitmap), None);
+
+        bitmap[1] = 1;
+        assert_eq!(highest_vector(&bitmap), Some(0x20));
+
+        bitmap[1] |= 1 << 16;
+        assert_eq!(highest_vector(&bitmap), Some(0x30));
+
+        bitmap[7] = 1 << 31;
+        assert_eq!(highest_vector(&bitmap), Some(0xff));
+    }
+}
+
+/// Self-tests for the LAPIC discovery.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+
+    /// Checks that the LAPIC of QEMU was found at its usual place.
+    fn test_discovered_base() -> Result<(), &'static str> {
+        if discover_base() != DEFAULT_LAPIC_BASE {
+            return Err("The LAPIC isn't at the default address.");
+        }
+
+        if !x2apic_mode() && get_lapic_base().to_physical() != Some(DEFAULT_LAPIC_BASE) {
+            return Err("The LAPIC registers are mapped wrongly.");
+        }
+
+        Ok(())
+    }
+
+    register_selftest!(DISCOVERED_LAPIC_BASE, test_discovered_base);
+}
@@ -2,34 +2,85 @@
 
 mod ioapic;
 pub mod lapic;
+mod pit;
+mod stats;
 
-pub use self::lapic::issue_self_interrupt;
+pub use self::lapic::{issue_self_interrupt, send_ipi};
+pub use self::stats::counts as interrupt_counts;
+use super::cpu_local::{self, KernelGsGuard};
+use super::gdt::{DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX};
 use super::sync::CLOCK;
+use super::{X86_64, COM1};
+use crate::arch::Architecture;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering, ATOMIC_BOOL_INIT, ATOMIC_U64_INIT};
 use core::time::Duration;
 use crate::memory::{Address, VirtualAddress};
 use crate::multitasking::scheduler::schedule_next_thread;
-use crate::sync::Mutex;
 use x86_64::instructions::interrupts;
 use x86_64::instructions::port::{inb, outb};
 use x86_64::registers::control_regs;
-use x86_64::structures::idt::{ExceptionStackFrame, Idt, PageFaultErrorCode};
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc, Idt, PageFaultErrorCode};
 
 /// The vector for the scheduling interrupt.
 pub const SCHEDULE_INTERRUPT_NUM: u8 = 0x20;
 
+/// The vector for reschedule requests from other CPUs.
+pub const RESCHEDULE_INTERRUPT_NUM: u8 = 0x21;
+
 /// The vectors for the IRQs.
 const IRQ_INTERRUPT_NUMS: [u8; 16] = [
     0xEC, 0xE4, 0xFF, 0x94, 0x8C, 0x84, 0x7C, 0x74, 0xD4, 0xCC, 0xC4, 0xBC, 0xB4, 0xAC, 0xA4, 0x9C,
 ];
 
+/// The task priority interrupt handlers run with.
+///
+/// It blocks the schedule and reschedule interrupts, so that no thread switch
+/// happens in the middle of a handler.
+const HANDLER_PRIORITY: u8 = 0x20;
+
+/// The task priority threads run with, which lets all interrupts through.
+pub const THREAD_PRIORITY: u8 = 0x0;
+
 /// The vector for the LAPIC timer interrupt.
 const TIMER_INTERRUPT_HANDLER_NUM: u8 = 0x30;
 
 /// The handler number for the spurious interrupt.
+///
+/// The low four bits must be set, since some CPUs hardwire them.
 const SPURIOUS_INTERRUPT_HANDLER_NUM: u8 = 0x2f;
 
-/// The number of IRQ8 interrupt ticks that have passed since it was enabled.
-static IRQ8_INTERRUPT_TICKS: Mutex<u64> = Mutex::new(0);
+/// The vector of the interrupts sent by the self-tests.
+///
+/// It lies above all IRQ vectors, so a task priority just below it keeps
+/// every other interrupt pending.
+#[cfg(feature = "selftest")]
+const SELFTEST_INTERRUPT_NUM: u8 = 0xf0;
+
+/// The number of self-test interrupts that were handled.
+#[cfg(feature = "selftest")]
+static SELFTEST_INTERRUPTS: AtomicU64 = ATOMIC_U64_INIT;
+
+/// Whether the PIT is used as the timer instead of the LAPIC timer.
+static PIT_TIMER_FALLBACK: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The CPUs whose next NMI was sent to dump their state, one bit per CPU.
+///
+/// This is a plain atomic, because the NMI handler can't initialize lazy
+/// values.
+static STATE_DUMP_REQUESTS: AtomicU64 = ATOMIC_U64_INIT;
+
+/// The number of CPUs whose state can be dumped.
+const MAX_DUMPABLE_CPUS: usize = 64;
+
+/// The system control port B, which reports the causes of hardware NMIs.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+
+/// Set in system control port B after a memory parity error.
+const PARITY_ERROR: u8 = 1 << 7;
+
+/// Set in system control port B after an IO channel check error.
+const CHANNEL_CHECK_ERROR: u8 = 1 << 6;
 
 lazy_static! {
     /// The interrupt descriptor table used by the kernel.
@@ -42,26 +93,36 @@ lazy_static! {
         idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(0);
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(NMI_IST_INDEX);
         }
 
         // IRQ interrupts that are not explicitly handled.
         for i in 0..16 {
-            idt[IRQ_INTERRUPT_NUMS[i] as usize].set_handler_fn(empty_handler);
+            idt[IRQ_INTERRUPT_NUMS[i] as usize].set_handler_fn(UNHANDLED_IRQ_HANDLERS[i]);
         }
 
         // IRQ interrupts that are explicitly handled.
+        idt[IRQ_INTERRUPT_NUMS[0] as usize].set_handler_fn(irq0_handler);
         idt[IRQ_INTERRUPT_NUMS[1] as usize].set_handler_fn(irq1_handler);
+        idt[IRQ_INTERRUPT_NUMS[4] as usize].set_handler_fn(irq4_handler);
         idt[IRQ_INTERRUPT_NUMS[8] as usize].set_handler_fn(irq8_handler);
 
         // The schedule interrupt is invoked for every reschedule.
         idt[SCHEDULE_INTERRUPT_NUM as usize].set_handler_fn(schedule_interrupt)
             .disable_interrupts(false);
 
+        // Other CPUs use this interrupt to request a reschedule.
+        idt[RESCHEDULE_INTERRUPT_NUM as usize].set_handler_fn(reschedule_handler);
+
         // LAPIC specific interrupts.
-        idt[SPURIOUS_INTERRUPT_HANDLER_NUM as usize].set_handler_fn(empty_handler);
+        idt[SPURIOUS_INTERRUPT_HANDLER_NUM as usize].set_handler_fn(spurious_handler);
         idt[TIMER_INTERRUPT_HANDLER_NUM as usize].set_handler_fn(timer_handler);
 
+        #[cfg(feature = "selftest")]
+        idt[SELFTEST_INTERRUPT_NUM as usize].set_handler_fn(selftest_handler);
+
         idt
     };
 }
@@ -70,6 +131,8 @@ lazy_static! {
 pub fn init() {
     assert_has_not_been_called!("Interrupts should only be initialized once.");
 
+    stats::init();
+
     IDT.load();
 
     lapic::init();
@@ -77,20 +140,43 @@ pub fn init() {
     ioapic::init();
 
     lapic::calibrate_timer();
+
+    if !lapic::timer_available() {
+        warn!("The LAPIC timer is unavailable, falling back to the PIT.");
+        PIT_TIMER_FALLBACK.store(true, Ordering::Relaxed);
+        ioapic::unmask_irq(0);
+    }
+}
+
+/// Causes a timer interrupt after the given amount of milliseconds.
+pub fn set_timer(delay: u32) {
+    if PIT_TIMER_FALLBACK.load(Ordering::Relaxed) {
+        pit::interrupt_in(delay);
+    } else {
+        lapic::set_timer(delay);
+    }
 }
 
 macro_rules! irq_interrupt {
-    ($(#[$attr: meta])* fn $name: ident $content: tt) => {
+    ($(#[$attr: meta])* fn $name: ident ($vector: expr) $content: tt) => {
         $(#[$attr])*
-        extern "x86-interrupt" fn $name(_: &mut ExceptionStackFrame) {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            let _gs_guard = KernelGsGuard::enter(stack_frame);
+            stats::count($vector);
             let old_priority = lapic::get_priority();
-            lapic::set_priority(0x20);
+            lapic::set_priority(HANDLER_PRIORITY);
             unsafe {
                 interrupts::enable();
             }
 
+            // Interrupts nest on the current stack, so they see how deep it is.
+            crate::multitasking::observe_kernel_stack();
+
             $content
 
+            // The schedule interrupt stays pending until the priority is restored.
+            crate::interrupts::before_interrupt_return();
+
             unsafe {
                 interrupts::disable();
             }
@@ -102,6 +188,7 @@ macro_rules! irq_interrupt {
 
 /// The divide by zero exception handler of the kernel.
 extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut ExceptionStackFrame) {
+    let _gs_guard = KernelGsGuard::enter(stack_frame);
     error!("Divide by zero exception.");
     error!("{:?}", stack_frame);
     loop {}
@@ -109,6 +196,7 @@ extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut ExceptionStac
 
 /// The breakpoint exception handler of the kernel.
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
+    let _gs_guard = KernelGsGuard::enter(stack_frame);
     error!("Breakpoint exception.");
     error!("{:?}", stack_frame);
     loop {}
@@ -119,51 +207,222 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut ExceptionStackFrame,
     error_code: u64
 ) {
+    // A double fault can happen anywhere, even in the middle of a syscall entry.
+    let _gs_guard = KernelGsGuard::enter_paranoid();
     error!("DOUBLE FAULT!");
     error!("{:?}", stack_frame);
     error!("Error code: 0x{:x}", error_code);
+    use crate::memory::slab::SlabBox;
     use crate::multitasking::{CURRENT_THREAD, TCB};
-    let tcb: &crate::sync::Mutex<TCB> = &CURRENT_THREAD;
+    let tcb: &crate::sync::Mutex<SlabBox<TCB>> = &CURRENT_THREAD;
     error!("Running thread: {:?}", tcb);
     loop {}
 }
 
+/// Makes the given CPU dump its state through an NMI.
+///
+/// This works even if the CPU is stuck with interrupts disabled, so it is
+/// meant for a watchdog that detected a stuck CPU.
+pub fn request_state_dump(cpu_id: usize) {
+    assert!(
+        cpu_id < MAX_DUMPABLE_CPUS,
+        "The state of CPU {} can't be dumped.",
+        cpu_id
+    );
+
+    STATE_DUMP_REQUESTS.fetch_or(1 << cpu_id, Ordering::SeqCst);
+    lapic::send_nmi(cpu_id);
+}
+
+/// Writes the arguments to the screen and the serial port, skipping outputs
+/// that are in use.
+///
+/// This never waits for a lock, so it is safe to use in the NMI handler.
+fn print_without_locking(args: fmt::Arguments) {
+    X86_64::try_write_fmt(args);
+
+    if let Some(mut serial) = COM1.try_lock() {
+        let _ = serial.write_fmt(args);
+    }
+}
+
+/// The NMI handler of the kernel.
+///
+/// # Reentrancy
+/// An NMI can interrupt any code, even while interrupts are disabled and
+/// locks are held. It can also arrive in the middle of the syscall entry or
+/// of another exception handler. Therefore the handler
+/// - runs on its own stack, as the current stack may not be usable,
+/// - determines the GS base without trusting the code segment,
+/// - never waits for a lock, since the interrupted code may hold it, and
+/// - doesn't allocate or touch lazily initialized values, which take locks.
+///
+/// Further NMIs are blocked until the handler returns, but any exception in
+/// the handler unblocks them, so it must not fault.
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut ExceptionStackFrame) {
+    let _gs_guard = KernelGsGuard::enter_paranoid();
+    let cpu_id = X86_64::get_cpu_id();
+    let cpu_bit = if cpu_id < MAX_DUMPABLE_CPUS {
+        1 << cpu_id
+    } else {
+        0
+    };
+
+    if STATE_DUMP_REQUESTS.fetch_and(!cpu_bit, Ordering::SeqCst) & cpu_bit != 0 {
+        let thread = cpu_local::current_thread();
+
+        print_without_locking(format_args!("CPU {} is stuck, dumping its state.\n", cpu_id));
+        print_without_locking(format_args!("{:?}\n", stack_frame));
+
+        if !thread.is_null() {
+            print_without_locking(format_args!("Running thread: {:?}\n", unsafe { &*thread }));
+        }
+    } else {
+        let reason = unsafe { inb(SYSTEM_CONTROL_PORT_B) };
+
+        print_without_locking(format_args!(
+            "Non-maskable interrupt on CPU {} (parity error: {}, channel check error: {}).\n",
+            cpu_id,
+            reason & PARITY_ERROR != 0,
+            reason & CHANNEL_CHECK_ERROR != 0
+        ));
+        print_without_locking(format_args!("{:?}\n", stack_frame));
+    }
+}
+
 /// The page fault handler of the kernel.
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut ExceptionStackFrame,
-    _error_code: PageFaultErrorCode
+    error_code: PageFaultErrorCode
 ) {
+    let _gs_guard = KernelGsGuard::enter(stack_frame);
     crate::interrupts::page_fault_handler(
         VirtualAddress::from_usize(control_regs::cr2().0),
-        VirtualAddress::from_usize(stack_frame.instruction_pointer.0)
+        VirtualAddress::from_usize(stack_frame.instruction_pointer.0),
+        error_code.contains(PageFaultErrorCode::USER_MODE),
+        error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
     );
 }
 
 /// The software interrupt handler that invokes schedule operations.
-extern "x86-interrupt" fn schedule_interrupt(_: &mut ExceptionStackFrame) {
-    lapic::set_priority(0x20);
+///
+/// # EOI and task priority
+/// The handler may switch to another thread, after which this invocation
+/// doesn't return until the current thread is switched back to. This is why
+/// - the EOI is sent before the switch, so that the LAPIC doesn't keep the
+/// vector in service while other threads run,
+/// - the task priority is raised before the EOI, so that schedule interrupts
+/// that arrive in the meantime stay pending instead of nesting into the
+/// switch, and
+/// - exactly one EOI is sent. Schedule interrupts are blocked while other
+/// handlers run, so this vector is the only one in service and the EOI
+/// can't end the interrupt of another handler.
+///
+/// Every thread is switched out in this handler, except new threads, which
+/// start in `context::enter_thread`. The switched-to thread therefore either
+/// resumes here with the raised priority and restores the priority it was
+/// interrupted with, or starts with the priority of threads. Interrupts that
+/// arrived during the switch are delivered once the priority is lowered.
+extern "x86-interrupt" fn schedule_interrupt(stack_frame: &mut ExceptionStackFrame) {
+    let _gs_guard = KernelGsGuard::enter(stack_frame);
+    stats::count(SCHEDULE_INTERRUPT_NUM);
+    let old_priority = lapic::get_priority();
+    lapic::set_priority(HANDLER_PRIORITY);
+
+    debug_assert_eq!(
+        lapic::highest_in_service(),
+        Some(SCHEDULE_INTERRUPT_NUM),
+        "Another interrupt was in service while scheduling."
+    );
     lapic::signal_eoi();
+
     unsafe {
         schedule_next_thread();
         interrupts::disable();
     }
-    lapic::set_priority(0x0);
+
+    debug_assert_eq!(
+        lapic::get_priority(),
+        HANDLER_PRIORITY,
+        "The task priority changed across a thread switch."
+    );
+    debug_assert_eq!(
+        lapic::highest_in_service(),
+        None,
+        "An interrupt stayed in service across a thread switch."
+    );
+    lapic::set_priority(old_priority);
 }
 
-/// An interrupt handler that does nothing.
-extern "x86-interrupt" fn empty_handler(_: &mut ExceptionStackFrame) {}
+/// The handler for spurious interrupts of the LAPIC, which only counts them.
+///
+/// A spurious interrupt is delivered when an interrupt is withdrawn while
+/// the CPU acknowledges it, for example because its priority was raised in
+/// the meantime. The LAPIC doesn't mark the spurious vector as in service, so
+/// no EOI may be sent. An EOI would end the highest priority interrupt that
+/// is in service instead, which is the interrupted handler if there is one.
+///
+/// Occasional spurious interrupts are normal. A high count in the interrupt
+/// statistics usually points to an interrupt that is masked or prioritized
+/// wrongly.
+extern "x86-interrupt" fn spurious_handler(_: &mut ExceptionStackFrame) {
+    stats::count(SPURIOUS_INTERRUPT_HANDLER_NUM);
+}
+
+/// The handler for the interrupts sent by the self-tests.
+#[cfg(feature = "selftest")]
+extern "x86-interrupt" fn selftest_handler(_: &mut ExceptionStackFrame) {
+    stats::count(SELFTEST_INTERRUPT_NUM);
+    SELFTEST_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    lapic::signal_eoi();
+}
+
+/// Defines handlers for IRQs without a driver, which only count them.
+macro_rules! unhandled_irqs {
+    ($($irq: expr => $name: ident),*) => {
+        $(
+            /// A handler for an IRQ without a driver.
+            extern "x86-interrupt" fn $name(_: &mut ExceptionStackFrame) {
+                stats::count(IRQ_INTERRUPT_NUMS[$irq]);
+            }
+        )*
+
+        /// The handlers for IRQs without a driver, indexed by the IRQ number.
+        const UNHANDLED_IRQ_HANDLERS: [HandlerFunc; 16] = [$($name),*];
+    };
+}
+
+unhandled_irqs!(
+    0 => unhandled_irq0, 1 => unhandled_irq1, 2 => unhandled_irq2, 3 => unhandled_irq3,
+    4 => unhandled_irq4, 5 => unhandled_irq5, 6 => unhandled_irq6, 7 => unhandled_irq7,
+    8 => unhandled_irq8, 9 => unhandled_irq9, 10 => unhandled_irq10, 11 => unhandled_irq11,
+    12 => unhandled_irq12, 13 => unhandled_irq13, 14 => unhandled_irq14, 15 => unhandled_irq15
+);
 
 irq_interrupt!(
 /// The handler for the lapic timer interrupt.
-fn timer_handler {
+fn timer_handler(TIMER_INTERRUPT_HANDLER_NUM) {
+    crate::interrupts::timer_interrupt();
+});
+
+irq_interrupt!(
+/// The handler for reschedule requests from other CPUs.
+fn reschedule_handler(RESCHEDULE_INTERRUPT_NUM) {
+    crate::interrupts::reschedule_interrupt();
+});
+
+irq_interrupt!(
+/// The handler for IRQ0.
+///
+/// This is only unmasked if the PIT is used as the timer.
+fn irq0_handler(IRQ_INTERRUPT_NUMS[0]) {
     crate::interrupts::timer_interrupt();
 });
 
 irq_interrupt!(
 /// The handler for IRQ8.
-fn irq8_handler {
+fn irq8_handler(IRQ_INTERRUPT_NUMS[8]) {
     unsafe {
-        *IRQ8_INTERRUPT_TICKS.lock() += 1;
         // TODO: Find a better time source, that isn't relying on interrupts.
         CLOCK += Duration::new(0, 1_000_000_000 / 1024);
 
@@ -176,8 +435,73 @@ fn irq8_handler {
 
 irq_interrupt!(
 /// The handler for IRQ1.
-fn irq1_handler {
+fn irq1_handler(IRQ_INTERRUPT_NUMS[1]) {
     let scancode = unsafe { ::x86_64::instructions::port::inb(0x60) };
 
     crate::interrupts::keyboard_interrupt(scancode);
 });
+
+irq_interrupt!(
+/// The handler for IRQ4.
+fn irq4_handler(IRQ_INTERRUPT_NUMS[4]) {
+    loop {
+        // Don't hold the lock while handling the byte.
+        let byte = super::COM1.lock().receive();
+
+        match byte {
+            Some(byte) => crate::interrupts::serial_interrupt(byte),
+            None => break
+        }
+    }
+});
+
+/// Self-tests for the interrupt delivery.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+
+    /// The number of times the tests check whether an interrupt arrived.
+    const WAIT_ITERATIONS: usize = 1_000_000;
+
+    /// Checks that an IPI sent through the ICR reaches its handler.
+    ///
+    /// The other CPUs are never started, so the IPI is addressed to the
+    /// APIC id of the current CPU, just like one to another CPU would be.
+    fn test_ipi_to_self() -> Result<(), &'static str> {
+        let received = SELFTEST_INTERRUPTS.load(Ordering::SeqCst);
+        let old_priority = lapic::get_priority();
+        let interrupts_were_enabled = super::super::sync::interrupts_enabled();
+
+        // The scheduler doesn't run yet, so only the test interrupt may arrive.
+        lapic::set_priority(SELFTEST_INTERRUPT_NUM - 0x10);
+        send_ipi(X86_64::get_cpu_id(), SELFTEST_INTERRUPT_NUM);
+
+        unsafe {
+            interrupts::enable();
+        }
+
+        let mut arrived = false;
+        for _ in 0..WAIT_ITERATIONS {
+            if SELFTEST_INTERRUPTS.load(Ordering::SeqCst) != received {
+                arrived = true;
+                break;
+            }
+            X86_64::cpu_relax();
+        }
+
+        if !interrupts_were_enabled {
+            unsafe {
+                interrupts::disable();
+            }
+        }
+        lapic::set_priority(old_priority);
+
+        if arrived {
+            Ok(())
+        } else {
+            Err("The IPI to the current CPU never arrived.")
+        }
+    }
+
+    register_selftest!(IPI_TO_SELF, test_ipi_to_self);
+}
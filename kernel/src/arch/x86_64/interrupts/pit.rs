@@ -0,0 +1,107 @@
+//! Handles the 8254 programmable interval timer (PIT).
+//!
+//! The PIT runs at a known frequency, so it is used as the reference to
+//! calibrate the LAPIC timer. It also serves as the timer if the LAPIC timer
+//! is unavailable.
+
+use core::cmp::{max, min};
+use crate::sync::cpu_relax;
+use x86_64::instructions::port::{inb, outb};
+
+/// The frequency of the PIT in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// The data port of channel 0, which is connected to IRQ0.
+const CHANNEL0_DATA: u16 = 0x40;
+
+/// The data port of channel 2, whose output can be polled.
+const CHANNEL2_DATA: u16 = 0x42;
+
+/// The mode/command port.
+const COMMAND: u16 = 0x43;
+
+/// The port that controls the gate of channel 2.
+const CHANNEL2_CONTROL: u16 = 0x61;
+
+/// Selects channel 0 in mode 0 (interrupt on terminal count).
+///
+/// The count is written as the low byte followed by the high byte.
+const CHANNEL0_ONE_SHOT: u8 = 0b0011_0000;
+
+/// Selects channel 2 in mode 0 (interrupt on terminal count).
+///
+/// The count is written as the low byte followed by the high byte.
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// The bit in the control port that enables the gate of channel 2.
+const CHANNEL2_GATE: u8 = 1 << 0;
+
+/// The bit in the control port that connects channel 2 to the speaker.
+const SPEAKER_ENABLE: u8 = 1 << 1;
+
+/// The bit in the control port that reflects the output of channel 2.
+const CHANNEL2_OUTPUT: u8 = 1 << 5;
+
+/// The longest delay in milliseconds that fits into a single count.
+const MAX_DELAY_MS: u32 = (0xffff * 1000 / PIT_FREQUENCY) as u32;
+
+/// Busy waits for the given amount of milliseconds.
+///
+/// This doesn't need interrupts, so it can be used during calibration.
+pub fn wait_ms(ms: u32) {
+    let mut remaining = ms;
+
+    while remaining > 0 {
+        let delay = min(remaining, MAX_DELAY_MS);
+
+        unsafe {
+            wait_count(ms_to_count(delay));
+        }
+
+        remaining -= delay;
+    }
+}
+
+/// Lets the PIT raise IRQ0 once after the given amount of milliseconds.
+///
+/// Delays longer than the PIT can count are cut short.
+pub fn interrupt_in(ms: u32) {
+    let count = ms_to_count(max(min(ms, MAX_DELAY_MS), 1));
+
+    unsafe {
+        outb(COMMAND, CHANNEL0_ONE_SHOT);
+        outb(CHANNEL0_DATA, count as u8);
+        outb(CHANNEL0_DATA, (count >> 8) as u8);
+    }
+}
+
+/// Waits until channel 2 counted down from the given count.
+///
+/// # Safety
+/// - Nothing else may use channel 2 at the same time.
+unsafe fn wait_count(count: u16) {
+    let control = inb(CHANNEL2_CONTROL);
+
+    // Stop the channel and keep the speaker quiet while programming it.
+    outb(CHANNEL2_CONTROL, control & !(CHANNEL2_GATE | SPEAKER_ENABLE));
+
+    outb(COMMAND, CHANNEL2_ONE_SHOT);
+    outb(CHANNEL2_DATA, count as u8);
+    outb(CHANNEL2_DATA, (count >> 8) as u8);
+
+    // Start counting. The output goes high once the count reaches zero.
+    outb(CHANNEL2_CONTROL, (control & !SPEAKER_ENABLE) | CHANNEL2_GATE);
+
+    while inb(CHANNEL2_CONTROL) & CHANNEL2_OUTPUT == 0 {
+        cpu_relax();
+    }
+
+    outb(CHANNEL2_CONTROL, control);
+}
+
+/// Converts the given amount of milliseconds to a PIT count.
+fn ms_to_count(ms: u32) -> u16 {
+    debug_assert!(ms <= MAX_DELAY_MS);
+
+    (ms as u64 * PIT_FREQUENCY / 1000) as u16
+}
@@ -0,0 +1,65 @@
+//! Counts the interrupts of each vector on each CPU.
+//!
+//! Each CPU only increments its own counters, so counting is a single atomic
+//! add that never contends and takes no lock. The counters are atomics only
+//! so that other CPUs can read them at any time.
+
+use alloc::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::interrupts::InterruptCount;
+use crate::multitasking::get_cpu_num;
+
+/// The number of interrupt vectors.
+const VECTOR_COUNT: usize = 256;
+
+cpu_local! {
+    /// The interrupt counters of each CPU, indexed by the vector.
+    static ref INTERRUPT_COUNTS: Vec<AtomicU64> = |_| new_counters();
+}
+
+/// Creates a zeroed counter for each vector.
+fn new_counters() -> Vec<AtomicU64> {
+    (0..VECTOR_COUNT).map(|_| AtomicU64::new(0)).collect()
+}
+
+/// Creates the counters of the current CPU.
+///
+/// This must be called before interrupts are enabled, so that the interrupt
+/// handlers never allocate.
+pub fn init() {
+    assert_eq!(INTERRUPT_COUNTS.len(), VECTOR_COUNT);
+}
+
+/// Counts an interrupt of the given vector on the current CPU.
+#[inline(always)]
+pub fn count(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of interrupts of each vector on each CPU.
+///
+/// Vectors that never fired on a CPU are left out.
+pub fn counts() -> Vec<InterruptCount> {
+    let mut counts = Vec::new();
+
+    for cpu_id in 0..get_cpu_num() {
+        let counters = match INTERRUPT_COUNTS.try_get(cpu_id) {
+            Some(counters) => counters,
+            None => continue
+        };
+
+        for (vector, counter) in counters.iter().enumerate() {
+            let count = counter.load(Ordering::Relaxed);
+
+            if count > 0 {
+                counts.push(InterruptCount {
+                    cpu_id: cpu_id as u32,
+                    vector: vector as u32,
+                    count
+                });
+            }
+        }
+    }
+
+    counts
+}
@@ -3,13 +3,18 @@
 use super::paging::inactive_page_table::InactivePageTable;
 use super::paging::page_table_entry::*;
 use super::paging::page_table_manager::PageTableManager;
-use super::paging::{convert_flags, Page, PageFrame, CURRENT_PAGE_TABLE};
+use super::paging::{
+    convert_entry_flags, convert_flags, may_map_frame, with_frame_access, Page, PageFrame,
+};
 use super::PAGE_SIZE;
 use super::{
     KERNEL_STACK_AREA_BASE, KERNEL_STACK_MAX_SIZE, KERNEL_STACK_OFFSET, USER_STACK_AREA_BASE,
     USER_STACK_MAX_SIZE, USER_STACK_OFFSET,
 };
+use alloc::Vec;
+use core::cmp::min;
 use core::ptr;
+use crate::memory::address_space::{add_mapped_page, Mapping, MemoryUsage};
 use crate::memory::{
     address_space_manager, Address, AddressSpace, PageFlags, PhysicalAddress, VirtualAddress,
 };
@@ -18,18 +23,22 @@ use crate::multitasking::{Stack, ThreadID};
 
 pub struct AddressSpaceManager {
     table: InactivePageTable,
+    /// The pages mapped through this manager.
+    usage: MemoryUsage,
 }
 
 impl address_space_manager::AddressSpaceManager for AddressSpaceManager {
     fn new() -> AddressSpaceManager {
         AddressSpaceManager {
             table: InactivePageTable::copy_from_current(),
+            usage: MemoryUsage::default(),
         }
     }
 
     fn idle() -> AddressSpaceManager {
         AddressSpaceManager {
             table: InactivePageTable::from_current_table(),
+            usage: MemoryUsage::default(),
         }
     }
 
@@ -46,6 +55,10 @@ impl address_space_manager::AddressSpaceManager for AddressSpaceManager {
         for page_num in start_page_num..end_page_num {
             let page_address = VirtualAddress::from_page_num(page_num);
 
+            if self.table.translate_address(page_address).is_none() {
+                self.usage.add_page(false);
+            }
+
             // First map with write permissions.
             self.table.change_permissions_or_map(
                 Page::from_address(page_address),
@@ -59,10 +72,9 @@ impl address_space_manager::AddressSpaceManager for AddressSpaceManager {
                 .expect("The just mapped page isn't mapped.");
 
             // Write to the physical address.
-            let (new_current_buffer_position, new_current_offset) = CURRENT_PAGE_TABLE
-                .lock()
-                .with_temporary_page(PageFrame::from_address(physical_address), |page| {
-                    let start_address = page.get_address() + current_offset;
+            let (new_current_buffer_position, new_current_offset) =
+                with_frame_access(PageFrame::from_address(physical_address), |page_address| {
+                    let start_address = page_address + current_offset;
 
                     let write_length =
                         if (PAGE_SIZE - current_offset) >= buffer.len() - current_buffer_position {
@@ -75,7 +87,7 @@ impl address_space_manager::AddressSpaceManager for AddressSpaceManager {
 
                     unsafe {
                         ptr::copy_nonoverlapping(
-                            buffer.as_ptr(),
+                            buffer[current_buffer_position..].as_ptr(),
                             start_address.as_mut_ptr(),
                             write_length,
                         );
@@ -97,31 +109,144 @@ impl address_space_manager::AddressSpaceManager for AddressSpaceManager {
         self.table.unmap();
     }
 
+    fn read_from(
+        &mut self,
+        buffer: &mut [u8],
+        address: VirtualAddress,
+    ) -> Result<(), VirtualAddress> {
+        let mut current_buffer_position = 0;
+
+        while current_buffer_position < buffer.len() {
+            let current_address = address + current_buffer_position;
+            let physical_address = match self.table.translate_address(current_address) {
+                Some(physical_address) => physical_address,
+                None => {
+                    self.table.unmap();
+                    return Err(current_address);
+                }
+            };
+
+            let read_length = min(
+                PAGE_SIZE - current_address.offset_in_page(),
+                buffer.len() - current_buffer_position,
+            );
+            let destination = buffer[current_buffer_position..].as_mut_ptr();
+
+            with_frame_access(PageFrame::from_address(physical_address), |page_address| {
+                let source = page_address + physical_address.offset_in_page();
+
+                unsafe {
+                    ptr::copy_nonoverlapping(source.as_ptr(), destination, read_length);
+                }
+            });
+
+            current_buffer_position += read_length;
+        }
+
+        self.table.unmap();
+
+        Ok(())
+    }
+
     unsafe fn get_page_table_address(&self) -> PhysicalAddress {
         self.table.get_frame().get_address()
     }
 
+    fn is_mapped(&mut self, page_address: VirtualAddress) -> bool {
+        let mapped = self.table.translate_address(page_address).is_some();
+
+        self.table.unmap();
+
+        mapped
+    }
+
     fn map_page(&mut self, page_address: VirtualAddress, flags: PageFlags) {
         let flags = convert_flags(flags);
 
         self.table.map_page(Page::from_address(page_address), flags);
+        self.usage.add_page(false);
+
+        self.table.unmap();
+    }
+
+    fn map_page_at(
+        &mut self,
+        page_address: VirtualAddress,
+        frame_address: PhysicalAddress,
+        flags: PageFlags,
+    ) {
+        debug_assert!(
+            may_map_frame(frame_address),
+            "Trying to map {:?}, which is neither reserved nor allocated.",
+            frame_address
+        );
+
+        let flags = convert_flags(flags);
+
+        self.table.map_page_at(
+            Page::from_address(page_address),
+            PageFrame::from_address(frame_address),
+            flags,
+        );
+        // The frame isn't owned by this address space.
+        self.usage.add_page(true);
 
         self.table.unmap();
     }
 
     unsafe fn unmap_page(&mut self, start_address: VirtualAddress) {
         self.table.unmap_page(Page::from_address(start_address));
+        self.usage.remove_page(false);
 
         self.table.unmap();
     }
 
     unsafe fn unmap_page_unchecked(&mut self, start_address: VirtualAddress) {
+        if self.table.translate_address(start_address).is_some() {
+            self.usage.remove_page(false);
+        }
+
         self.table
             .unmap_page_unchecked(Page::from_address(start_address));
 
         self.table.unmap();
     }
 
+    unsafe fn unmap_shared_page(&mut self, start_address: VirtualAddress) {
+        // File backed pages are only mapped once they are accessed.
+        if self.table.translate_address(start_address).is_some() {
+            self.table.unmap_shared_page(Page::from_address(start_address));
+            self.usage.remove_page(true);
+        }
+
+        self.table.unmap();
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        self.usage
+    }
+
+    fn mappings(&mut self) -> Vec<Mapping> {
+        let mut mappings = Vec::new();
+
+        {
+            let mut visitor = |address, size, entry_flags| {
+                // Present pages are always readable on x86_64. All listed
+                // pages are present, so that flag carries no information.
+                let flags =
+                    (convert_entry_flags(entry_flags) | PageFlags::READABLE) - PageFlags::PRESENT;
+
+                add_mapped_page(&mut mappings, address, size, flags);
+            };
+
+            self.table.for_each_lower_half_page(&mut visitor);
+        }
+
+        self.table.unmap();
+
+        mappings
+    }
+
     fn create_kernel_stack(id: ThreadID, address_space: &mut AddressSpace) -> Stack {
         let tid: usize = id.into();
         Stack::new(
@@ -133,14 +258,14 @@ impl address_space_manager::AddressSpaceManager for AddressSpaceManager {
         )
     }
 
-    fn create_user_stack(id: ThreadID, address_space: &mut AddressSpace) -> Stack {
+    fn create_user_stack(id: ThreadID, address_space: &mut AddressSpace) -> Option<Stack> {
         let tid: usize = id.into();
-        Stack::new(
+        Stack::in_address_space(
             0x2000,
             USER_STACK_MAX_SIZE,
             USER_STACK_AREA_BASE + USER_STACK_OFFSET * tid,
             AccessType::UserAccessible,
-            Some(address_space),
+            address_space,
         )
     }
 
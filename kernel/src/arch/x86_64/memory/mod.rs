@@ -5,7 +5,9 @@ use crate::memory::{Address, MemoryArea, PageFlags, PhysicalAddress, VirtualAddr
 pub mod address_space_manager;
 mod paging;
 
-pub use self::paging::get_free_memory_size;
+pub use self::paging::{
+    allocate_direct_mapped_frame, free_direct_mapped_frame, get_free_memory_size
+};
 
 /// The maximum address of the lower part of the virtual address space.
 const VIRTUAL_LOW_MAX_ADDRESS: VirtualAddress = VirtualAddress::from_const(0x0000_7fff_ffff_ffff);
@@ -26,6 +28,15 @@ pub const DOUBLE_FAULT_STACK_OFFSET: usize = 0x2000;
 /// The maximum size of a double fault stack.
 pub const DOUBLE_FAULT_STACK_MAX_SIZE: usize = 0x1000;
 
+/// The start address for the NMI stack area.
+pub const NMI_STACK_AREA_BASE: VirtualAddress = VirtualAddress::from_const(0xffff_fd40_0000_0000);
+
+/// The distance between two NMI stack tops.
+pub const NMI_STACK_OFFSET: usize = 0x2000;
+
+/// The maximum size of an NMI stack.
+pub const NMI_STACK_MAX_SIZE: usize = 0x1000;
+
 /// The base address of the kernel stack area.
 pub const KERNEL_STACK_AREA_BASE: VirtualAddress =
     VirtualAddress::from_const(0xffff_fe00_0000_0000);
@@ -45,6 +56,17 @@ pub const USER_STACK_OFFSET: usize = 0x40_0000;
 /// The maximum size of a thread stack.
 pub const USER_STACK_MAX_SIZE: usize = 0x20_0000;
 
+/// The base address of the area where shared memory is mapped into processes.
+pub const USER_MMAP_AREA_BASE: VirtualAddress = VirtualAddress::from_const(0x0000_7000_0000_0000);
+
+/// The size of the area where shared memory is mapped into processes.
+///
+/// This is the amount of space a level 3 page table manages.
+pub const USER_MMAP_AREA_MAX_SIZE: usize = PAGE_SIZE * 512 * 512 * 512;
+
+/// The address at which position independent executables are loaded.
+pub const USER_PIE_LOAD_BASE: VirtualAddress = VirtualAddress::from_const(0x0000_5555_5555_4000);
+
 /// The start address of the heap.
 pub const HEAP_START: VirtualAddress = VirtualAddress::from_const(0xffff_fd80_0000_0000);
 
@@ -53,9 +75,22 @@ pub const HEAP_START: VirtualAddress = VirtualAddress::from_const(0xffff_fd80_00
 /// This is the amount of space a level 3 page table manages.
 pub const HEAP_MAX_SIZE: usize = PAGE_SIZE * 512 * 512 * 512;
 
+/// The start address of the direct mapping of physical memory.
+pub const DIRECT_MAP_START: VirtualAddress = VirtualAddress::from_const(0xffff_c000_0000_0000);
+
+/// The maximum amount of physical memory in the direct map.
+///
+/// This is the amount of space a level 3 page table manages.
+pub const DIRECT_MAP_MAX_SIZE: usize = PAGE_SIZE * 512 * 512 * 512;
+
 /// The size of a single page.
 pub const PAGE_SIZE: usize = 0x1000;
 
+/// The size of a huge page.
+///
+/// This is the amount of space a level 1 page table manages.
+pub const HUGE_PAGE_SIZE: usize = PAGE_SIZE * 512;
+
 /// The area where the initramfs will be mapped.
 const INITRAMFS_MAP_AREA_START: VirtualAddress =
     VirtualAddress::from_const(0xffff_8000_0000_0000 + 512 * 512 * 512);
@@ -98,7 +133,8 @@ extern "C" {
 pub fn get_kernel_area() -> MemoryArea<PhysicalAddress> {
     let start = unsafe { TEXT_START };
     let end = unsafe { KERNEL_END };
-    MemoryArea::from_start_and_end(start, end)
+    MemoryArea::try_from_start_and_end(start, end)
+        .expect("The linker script places the end of the kernel before its start.")
 }
 
 /// Initializes the memory manager.
@@ -126,15 +162,130 @@ pub fn map_page(page_address: VirtualAddress, flags: PageFlags) {
 }
 
 /// Maps the given page to the given frame using the given flags.
+///
+/// In debug builds this panics if the frame is neither reserved nor
+/// allocated.
 pub fn map_page_at(page_address: VirtualAddress, frame_address: PhysicalAddress, flags: PageFlags) {
     paging::map_page_at(page_address, frame_address, flags);
 }
 
+/// Maps the given page to the given frame using the given flags, without
+/// checking the frame.
+///
+/// This is meant for raw physical memory that is neither reserved nor
+/// allocated, like the BIOS areas that are searched for ACPI tables.
+///
+/// # Safety
+/// - The frame must not be owned by the frame allocator or anything else
+/// that doesn't expect the mapping.
+pub unsafe fn map_page_at_unchecked(
+    page_address: VirtualAddress,
+    frame_address: PhysicalAddress,
+    flags: PageFlags
+) {
+    paging::map_page_at_unchecked(page_address, frame_address, flags);
+}
+
+/// Maps the given physical device memory uncached and returns its address.
+///
+/// The memory is mapped at its place in the direct map, where device memory
+/// is left out.
+pub fn map_device_memory(address: PhysicalAddress, length: usize) -> VirtualAddress {
+    let first_page = address.page_align_down();
+    let end = address.as_usize() + length;
+    let mut frame = first_page;
+
+    while frame.as_usize() < end {
+        let page = frame.to_virtual();
+
+        if !is_mapped(page) {
+            map_page_at(
+                page,
+                frame,
+                PageFlags::READABLE | PageFlags::WRITABLE | PageFlags::NO_CACHE
+            );
+        }
+
+        frame += PAGE_SIZE;
+    }
+
+    address.to_virtual()
+}
+
+/// Unmaps device memory that was mapped with `map_device_memory`.
+///
+/// # Safety
+/// - The memory must not be accessed through the mapping anymore.
+pub unsafe fn unmap_device_memory(address: PhysicalAddress, length: usize) {
+    let first_page = address.page_align_down();
+    let end = address.as_usize() + length;
+    let mut frame = first_page;
+
+    while frame.as_usize() < end {
+        let page = frame.to_virtual();
+
+        if is_mapped(page) {
+            paging::unmap_device_page(page);
+        }
+
+        frame += PAGE_SIZE;
+    }
+}
+
 /// Returns the flags of the given page.
 pub fn get_page_flags(page_address: VirtualAddress) -> PageFlags {
     paging::get_page_flags(page_address)
 }
 
+/// Returns the address of the given physical address in the direct map.
+///
+/// This returns `None` before the direct map is set up or if the address lies
+/// outside of it.
+pub fn direct_map_address(address: PhysicalAddress) -> Option<VirtualAddress> {
+    paging::direct_map_address(address)
+}
+
+/// Returns the address of the given physical address in the kernel mapping.
+///
+/// During boot this mapping covers the first gigabyte of physical memory,
+/// afterwards only the kernel image is mapped there.
+pub fn kernel_virtual_address(address: PhysicalAddress) -> VirtualAddress {
+    VirtualAddress::from_usize(to_virtual!(address.as_usize()))
+}
+
+/// Returns the physical address of the given address in the kernel mapping.
+pub fn kernel_physical_address(address: VirtualAddress) -> PhysicalAddress {
+    PhysicalAddress::from_usize(address.as_usize() - to_virtual!(0))
+}
+
+/// Returns the virtual address at which the kernel can access the given
+/// physical address.
+///
+/// Only usable memory and the kernel image are covered. Device memory must
+/// be mapped with `map_device_memory` instead.
+pub fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
+    direct_map_address(address).unwrap_or_else(|| kernel_virtual_address(address))
+}
+
+/// Returns the physical address the given virtual address is mapped to.
+pub fn virtual_to_physical(address: VirtualAddress) -> Option<PhysicalAddress> {
+    if !valid_address!(address) {
+        return None;
+    }
+
+    match paging::direct_map_physical_address(address) {
+        // Holes in the direct map are not mapped.
+        Some(physical_address) if paging::is_mapped(address) => Some(physical_address),
+        Some(_) => None,
+        None => paging::translate_address(address)
+    }
+}
+
+/// Checks whether the given address is mapped.
+pub fn is_mapped(address: VirtualAddress) -> bool {
+    paging::is_mapped(address)
+}
+
 /// Unmaps the given page.
 ///
 /// # Safety
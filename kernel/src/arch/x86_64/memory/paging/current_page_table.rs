@@ -4,7 +4,7 @@ use super::inactive_page_table::InactivePageTable;
 use super::page_table::{Level1, Level4, PageTable};
 use super::page_table_entry::*;
 use super::page_table_manager::PageTableManager;
-use super::{Page, PageFrame};
+use super::{direct_map_address, Page, PageFrame, PAGE_SIZE};
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::ptr;
@@ -101,6 +101,23 @@ impl PageTableManager for CurrentPageTable {
     fn get_l4(&mut self) -> &mut PageTable<Level4> {
         unsafe { self.l4_table.as_mut() }
     }
+
+    /// Zeros the frame through the direct map if it is set up already.
+    ///
+    /// Otherwise the frame is zeroed through a temporary mapping, which
+    /// doesn't need the lock again, as it is held already.
+    fn zero_frame(&mut self, frame: &PageFrame) {
+        if let Some(virtual_address) = direct_map_address(frame.get_address()) {
+            unsafe {
+                ptr::write_bytes(virtual_address.as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+            }
+            return;
+        }
+
+        self.with_temporary_page(PageFrame::from_address(frame.get_address()), |page| unsafe {
+            ptr::write_bytes(page.get_address().as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+        });
+    }
 }
 
 impl CurrentPageTable {
@@ -168,7 +185,7 @@ impl CurrentPageTable {
         let entry = &mut temporary_map_table[index];
         let preemption_state = entry.lock();
 
-        let virtual_address = TEMPORARY_ADDRESS_BASE + (index << 12);
+        let virtual_address = TEMPORARY_ADDRESS_BASE + index * PAGE_SIZE;
 
         if entry.points_to() != Some(frame.get_address()) {
             tlb::flush(::x86_64::VirtualAddress(virtual_address.as_usize()));
@@ -191,11 +208,20 @@ impl CurrentPageTable {
     }
 
     /// Writes the given value to the given physical address.
+    ///
+    /// This uses the direct map if it is set up already.
     pub fn write_at_physical<T: Sized + Copy>(
         &mut self,
         physical_address: PhysicalAddress,
         data: T,
     ) {
+        if let Some(virtual_address) = direct_map_address(physical_address) {
+            unsafe {
+                ptr::write(virtual_address.as_mut_ptr(), data);
+            }
+            return;
+        }
+
         self.with_temporary_page(PageFrame::from_address(physical_address), |page| {
             let virtual_address =
                 page.get_address().as_usize() | (physical_address.offset_in_page());
@@ -207,7 +233,13 @@ impl CurrentPageTable {
     }
 
     /// Reads from the given physical address.
+    ///
+    /// This uses the direct map if it is set up already.
     pub fn read_from_physical<T: Sized + Copy>(&mut self, physical_address: PhysicalAddress) -> T {
+        if let Some(virtual_address) = direct_map_address(physical_address) {
+            return unsafe { ptr::read(virtual_address.as_ptr()) };
+        }
+
         self.with_temporary_page(PageFrame::from_address(physical_address), |page| {
             let virtual_address =
                 page.get_address().as_usize() | (physical_address.offset_in_page());
@@ -247,7 +279,7 @@ impl CurrentPageTable {
 /// by better utilizing the available space.
 fn page_frame_hash(frame: PageFrame) -> usize {
     // UNOPTIMIZED: Possibly use a better hash algorithm here?
-    let mut address = frame.get_address().as_usize() >> 12;
+    let mut address = frame.get_address().page_num();
     address *= 101_489;
     address % 512
 }
@@ -3,7 +3,8 @@
 use super::current_page_table::CURRENT_PAGE_TABLE;
 use super::PAGE_SIZE;
 use crate::boot;
-use crate::memory::{MemoryArea, PhysicalAddress};
+use crate::memory::reserved;
+use crate::memory::{Address, MemoryArea, PhysicalAddress};
 use crate::sync::mutex::MutexGuard;
 use crate::sync::Mutex;
 
@@ -205,6 +206,11 @@ impl<'a> Iterator for FreeListIterator<'a> {
     }
 }
 
+/// Returns true if any part of the given area is free.
+pub fn is_free(area: MemoryArea<PhysicalAddress>) -> bool {
+    FreeListIterator::new().any(|free_area| free_area.overlaps_with(area))
+}
+
 /// Initializes the list of free page frames.
 pub fn init() {
     assert_has_not_been_called!("The free list should only be initialized once.");
@@ -212,6 +218,14 @@ pub fn init() {
     let mut free_list = FREE_LIST.lock();
 
     for entry in boot::get_memory_map() {
-        unsafe { free_list.insert(entry) }
+        // Usable memory may still contain device memory the firmware forgot.
+        reserved::for_each_unreserved_part(entry, |part| {
+            let start = part.start_address().page_align_up();
+            let end = part.end_address().page_align_down();
+
+            if start < end {
+                unsafe { free_list.insert(MemoryArea::from_start_and_end(start, end)) }
+            }
+        });
     }
 }
@@ -6,7 +6,8 @@ use super::frame_allocator::FRAME_ALLOCATOR;
 use super::page_table::{Level4, PageTable};
 use super::page_table_entry::*;
 use super::page_table_manager::PageTableManager;
-use super::PageFrame;
+use super::{with_frame_access, PageFrame, PAGE_SIZE};
+use core::ptr;
 use core::ptr::Unique;
 use crate::memory::{Address, PhysicalAddress};
 use crate::sync::PreemptionState;
@@ -33,6 +34,14 @@ impl PageTableManager for InactivePageTable {
             self.l4_table.as_mut()
         }
     }
+
+    /// Zeros the frame through the direct map or a temporary mapping in the
+    /// current page table.
+    fn zero_frame(&mut self, frame: &PageFrame) {
+        with_frame_access(PageFrame::from_address(frame.get_address()), |address| unsafe {
+            ptr::write_bytes(address.as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+        });
+    }
 }
 
 impl Drop for InactivePageTable {
@@ -87,6 +96,7 @@ impl InactivePageTable {
 
         table[256] = CURRENT_PAGE_TABLE.lock().get_l4()[256].clone();
         table[257] = CURRENT_PAGE_TABLE.lock().get_l4()[257].clone();
+        table[384] = CURRENT_PAGE_TABLE.lock().get_l4()[384].clone();
         table[506] = CURRENT_PAGE_TABLE.lock().get_l4()[506].clone();
         table[507] = CURRENT_PAGE_TABLE.lock().get_l4()[507].clone();
 
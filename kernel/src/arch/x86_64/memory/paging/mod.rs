@@ -9,11 +9,33 @@ pub mod page_table_manager;
 
 pub use self::current_page_table::CURRENT_PAGE_TABLE;
 use self::frame_allocator::FRAME_ALLOCATOR;
+use self::page_table::{Level3, PageTable, ENTRY_NUMBER};
 use self::page_table_entry::*;
 use self::page_table_manager::PageTableManager;
 use super::*;
+use core::cmp::{max, min};
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use crate::boot;
+use crate::memory::reserved;
 use crate::memory::{Address, PageFlags, PhysicalAddress, VirtualAddress};
+use x86_64::instructions::tlb;
+
+/// Set once the direct map of physical memory can be used.
+static DIRECT_MAP_ACTIVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The maximum number of physical memory ranges in the direct map.
+const MAX_DIRECT_MAP_RANGES: usize = 64;
+
+/// The physical memory ranges that `map_direct_map` mapped.
+///
+/// They are only written before `DIRECT_MAP_ACTIVE` is set and never change
+/// afterwards.
+static mut DIRECT_MAP_RANGES: [MemoryArea<PhysicalAddress>; MAX_DIRECT_MAP_RANGES] =
+    [MemoryArea::new(PhysicalAddress::from_const(0), 0); MAX_DIRECT_MAP_RANGES];
+
+/// The number of valid entries in `DIRECT_MAP_RANGES`.
+static mut DIRECT_MAP_RANGE_COUNT: usize = 0;
 
 /// Initializes the paging.
 pub fn init(initramfs_area: MemoryArea<PhysicalAddress>) {
@@ -25,8 +47,14 @@ pub fn init(initramfs_area: MemoryArea<PhysicalAddress>) {
     debug!("Remapping the kernel...");
     unsafe { remap_kernel() };
 
+    debug!("Unmapping the low identity mapping...");
+    unmap_low_identity();
+
     debug!("Mapping the initramfs...");
     unsafe { map_initramfs(initramfs_area) };
+
+    debug!("Enforcing W^X for the kernel...");
+    enforce_kernel_wx();
 }
 
 /// Converts the general `PageFlags` to x86_64-specific flags.
@@ -54,43 +82,188 @@ pub fn convert_flags(flags: PageFlags) -> PageTableEntryFlags {
 
 /// Returns the flags for the given page, if the page is mapped.
 pub fn get_page_flags(page_address: VirtualAddress) -> PageFlags {
-    let mut flags = PageFlags::empty();
     let mut table = CURRENT_PAGE_TABLE.lock();
+    let page_address = Page::from_address(page_address).get_address();
 
-    if let Some(entry) = table.get_entry(Page::from_address(page_address).get_address()) {
-        let entry_flags = entry.flags();
+    let huge_entry_flags = table.get_huge_entry(page_address).map(|entry| entry.flags());
+    let entry_flags = match huge_entry_flags {
+        Some(entry_flags) => Some(entry_flags),
+        None => table.get_entry(page_address).map(|entry| entry.flags())
+    };
 
-        if entry_flags.contains(PageTableEntryFlags::PRESENT) {
-            flags |= PageFlags::PRESENT;
-        }
+    entry_flags
+        .map(convert_entry_flags)
+        .unwrap_or(PageFlags::empty())
+}
 
-        if entry_flags.contains(PageTableEntryFlags::WRITABLE) {
-            flags |= PageFlags::WRITABLE;
-        }
+/// Converts x86_64-specific flags back to the general `PageFlags`.
+pub fn convert_entry_flags(entry_flags: PageTableEntryFlags) -> PageFlags {
+    let mut flags = PageFlags::empty();
 
-        if !entry_flags.contains(PageTableEntryFlags::NO_EXECUTE) {
-            flags |= PageFlags::EXECUTABLE;
-        }
+    if entry_flags.contains(PageTableEntryFlags::PRESENT) {
+        flags |= PageFlags::PRESENT;
+    }
 
-        if entry_flags.contains(PageTableEntryFlags::DISABLE_CACHE) {
-            flags |= PageFlags::NO_CACHE;
-        }
+    if entry_flags.contains(PageTableEntryFlags::WRITABLE) {
+        flags |= PageFlags::WRITABLE;
+    }
 
-        if entry_flags.contains(PageTableEntryFlags::USER_ACCESSIBLE) {
-            flags |= PageFlags::USER_ACCESSIBLE;
-        }
+    if !entry_flags.contains(PageTableEntryFlags::NO_EXECUTE) {
+        flags |= PageFlags::EXECUTABLE;
+    }
+
+    if entry_flags.contains(PageTableEntryFlags::DISABLE_CACHE) {
+        flags |= PageFlags::NO_CACHE;
+    }
+
+    if entry_flags.contains(PageTableEntryFlags::USER_ACCESSIBLE) {
+        flags |= PageFlags::USER_ACCESSIBLE;
     }
 
     flags
 }
 
+/// Checks whether the given address is mapped in the current address space.
+pub fn is_mapped(address: VirtualAddress) -> bool {
+    CURRENT_PAGE_TABLE.lock().is_mapped(address)
+}
+
+/// Returns the address of the given physical address in the direct map.
+///
+/// This returns `None` before the direct map is set up or if the address lies
+/// outside of it. Only usable memory is direct mapped, so device memory and
+/// holes in the memory map are always outside of it.
+pub fn direct_map_address(address: PhysicalAddress) -> Option<VirtualAddress> {
+    if DIRECT_MAP_ACTIVE.load(Ordering::Acquire) && is_direct_mapped(address) {
+        Some(to_direct_map(address))
+    } else {
+        None
+    }
+}
+
+/// Checks whether `map_direct_map` mapped the page of the given address.
+///
+/// This only looks at the recorded ranges, because reading the boot memory
+/// map may itself need to convert physical addresses.
+fn is_direct_mapped(address: PhysicalAddress) -> bool {
+    let ranges = unsafe { &DIRECT_MAP_RANGES[..DIRECT_MAP_RANGE_COUNT] };
+
+    ranges
+        .iter()
+        .any(|area| area.start_address() <= address && address < area.end_address())
+}
+
+/// Returns the physical address corresponding to the given direct map address.
+///
+/// This returns `None` if the address doesn't lie within the direct map.
+pub fn direct_map_physical_address(address: VirtualAddress) -> Option<PhysicalAddress> {
+    if DIRECT_MAP_ACTIVE.load(Ordering::Acquire) {
+        from_direct_map(address)
+    } else {
+        None
+    }
+}
+
+/// Converts the physical address to its location in the direct map.
+fn to_direct_map(address: PhysicalAddress) -> VirtualAddress {
+    DIRECT_MAP_START + address.as_usize()
+}
+
+/// Converts an address in the direct map back to the physical address.
+fn from_direct_map(address: VirtualAddress) -> Option<PhysicalAddress> {
+    if address >= DIRECT_MAP_START && address - DIRECT_MAP_START < DIRECT_MAP_MAX_SIZE {
+        Some(PhysicalAddress::from_usize(address - DIRECT_MAP_START))
+    } else {
+        None
+    }
+}
+
+/// Returns the physical address the given address is mapped to in the current
+/// address space.
+pub fn translate_address(address: VirtualAddress) -> Option<PhysicalAddress> {
+    CURRENT_PAGE_TABLE.lock().translate_address(address)
+}
+
+/// Performs the given action with a virtual address at which the given frame
+/// is accessible.
+///
+/// This uses the direct map if it is set up already and falls back to a
+/// temporary mapping during the bootstrap phase.
+pub fn with_frame_access<F, T>(frame: PageFrame, action: F) -> T
+where
+    F: Fn(VirtualAddress) -> T
+{
+    match direct_map_address(frame.get_address()) {
+        Some(address) => action(address),
+        None => CURRENT_PAGE_TABLE
+            .lock()
+            .with_temporary_page(frame, |page| action(page.get_address()))
+    }
+}
+
 /// Returns the size of unused physical memory.
 pub fn get_free_memory_size() -> usize {
     FRAME_ALLOCATOR.get_free_frame_num() * PAGE_SIZE
 }
 
+/// Allocates a page frame and returns its address in the direct map.
+///
+/// Returns `None` if the frame lies outside of the direct map. The frame
+/// allocator hands out the lowest frames first, so this only happens once
+/// all frames in the direct map are in use.
+pub fn allocate_direct_mapped_frame() -> Option<VirtualAddress> {
+    let frame = FRAME_ALLOCATOR.allocate();
+    let address = direct_map_address(frame.get_address());
+
+    if address.is_none() {
+        unsafe { FRAME_ALLOCATOR.deallocate(frame) };
+    }
+
+    address
+}
+
+/// Frees a frame from `allocate_direct_mapped_frame`.
+///
+/// Returns false without freeing anything if the address doesn't lie within
+/// the direct map.
+///
+/// # Safety
+/// - Nothing may use the frame afterwards.
+pub unsafe fn free_direct_mapped_frame(address: VirtualAddress) -> bool {
+    match direct_map_physical_address(address) {
+        Some(physical_address) => {
+            FRAME_ALLOCATOR.deallocate(PageFrame::from_address(physical_address));
+            true
+        },
+        None => false
+    }
+}
+
 /// Maps the given page to the given frame using the given flags.
+///
+/// In debug builds this panics if the frame may not be mapped (see
+/// `may_map_frame`).
 pub fn map_page_at(page_address: VirtualAddress, frame_address: PhysicalAddress, flags: PageFlags) {
+    debug_assert!(
+        may_map_frame(frame_address),
+        "Trying to map {:?}, which is neither reserved nor allocated.",
+        frame_address
+    );
+
+    unsafe { map_page_at_unchecked(page_address, frame_address, flags) }
+}
+
+/// Maps the given page to the given frame using the given flags, without
+/// checking the frame.
+///
+/// # Safety
+/// - The frame must not be owned by the frame allocator or anything else
+/// that doesn't expect the mapping.
+pub unsafe fn map_page_at_unchecked(
+    page_address: VirtualAddress,
+    frame_address: PhysicalAddress,
+    flags: PageFlags
+) {
     CURRENT_PAGE_TABLE.lock().map_page_at(
         Page::from_address(page_address),
         PageFrame::from_address(frame_address),
@@ -98,6 +271,27 @@ pub fn map_page_at(page_address: VirtualAddress, frame_address: PhysicalAddress,
     );
 }
 
+/// Checks whether the frame at the given address may be mapped explicitly.
+///
+/// This is the case for reserved memory, which belongs to the firmware or a
+/// device, and for memory that isn't free, because it was allocated or holds
+/// the kernel or the initramfs. Mapping a free frame would alias memory the
+/// frame allocator still hands out and an address that is neither memory nor
+/// a known device is most likely a bug.
+///
+/// Neither the page table nor the free list may be locked by the caller.
+pub fn may_map_frame(frame_address: PhysicalAddress) -> bool {
+    let frame_area = MemoryArea::new(frame_address.page_align_down(), PAGE_SIZE);
+
+    if reserved::find_overlapping(frame_area).is_some() {
+        return true;
+    }
+
+    let is_memory = boot::get_physical_memory_map().any(|area| frame_area.is_contained_in(area));
+
+    is_memory && !free_list::is_free(frame_area)
+}
+
 /// Maps the given page using the given flags.
 pub fn map_page(page_address: VirtualAddress, flags: PageFlags) {
     CURRENT_PAGE_TABLE
@@ -115,6 +309,16 @@ pub unsafe fn unmap_page(start_address: VirtualAddress) {
         .unmap_page(Page::from_address(start_address));
 }
 
+/// Unmaps the given page of device memory, keeping the frame.
+///
+/// # Safety
+/// - Make sure this page isn't referenced anymore when unmapping it.
+pub unsafe fn unmap_device_page(start_address: VirtualAddress) {
+    CURRENT_PAGE_TABLE
+        .lock()
+        .unmap_shared_page(Page::from_address(start_address));
+}
+
 /// Maps the initramfs into the kernel.
 ///
 /// # Safety
@@ -143,13 +347,14 @@ unsafe fn remap_kernel() {
 
     let mut new_page_table = inactive_page_table::InactivePageTable::new();
 
-    {
+    // Prefer the permissions the boot loader reports for each section.
+    if !map_kernel_sections(&mut new_page_table) {
         // Map a section.
         let mut map_section = |size: usize, start: PhysicalAddress, flags: PageTableEntryFlags| {
             for i in 0..size / PAGE_SIZE {
                 let address = start + i * PAGE_SIZE;
                 new_page_table.map_page_at(
-                    Page::from_address(address.to_virtual()),
+                    Page::from_address(kernel_virtual_address(address)),
                     PageFrame::from_address(address),
                     flags
                 );
@@ -192,13 +397,16 @@ unsafe fn remap_kernel() {
     // Map the VGA buffer.
     // TODO: Allow for a different address to be used here.
     new_page_table.map_page_at(
-        Page::from_address(VirtualAddress::from_usize(to_virtual!(0xb8000))),
+        Page::from_address(kernel_virtual_address(PhysicalAddress::from_usize(0xb8000))),
         PageFrame::from_address(PhysicalAddress::from_usize(0xb8000)),
         PageTableEntryFlags::WRITABLE
             | PageTableEntryFlags::GLOBAL
             | PageTableEntryFlags::NO_EXECUTE
     );
 
+    // Map all physical memory.
+    map_direct_map(&mut new_page_table);
+
     // Map the stack pages.
     let stack_size = STACK_TOP - STACK_BOTTOM;
     for i in 0..stack_size / PAGE_SIZE {
@@ -214,6 +422,7 @@ unsafe fn remap_kernel() {
     }
 
     CURRENT_PAGE_TABLE.lock().switch(new_page_table).unmap();
+    DIRECT_MAP_ACTIVE.store(true, Ordering::Release);
 
     // Deallocate the inital, now unused, page tables.
     FRAME_ALLOCATOR.deallocate(PageFrame::from_address(L4_TABLE));
@@ -223,6 +432,254 @@ unsafe fn remap_kernel() {
     FRAME_ALLOCATOR.deallocate(PageFrame::from_address(STACK_L1_TABLE));
 }
 
+/// Maps the kernel image using the ELF sections provided by the boot loader.
+///
+/// Pages shared by multiple sections get the permissions of all of them.
+/// Returns false without mapping anything, if the sections aren't available.
+///
+/// # Safety
+/// - This should only be called while remapping the kernel.
+unsafe fn map_kernel_sections<T: PageTableManager>(page_table: &mut T) -> bool {
+    let sections = match boot::get_kernel_sections() {
+        Some(sections) => sections,
+        None => return false
+    };
+
+    // Only the part of the image that the linker symbols describe is mapped,
+    // the initial page tables and stack at the end of .bss are reused.
+    let image_area = MemoryArea::from_start_and_end(
+        kernel_virtual_address(TEXT_START),
+        kernel_virtual_address(BSS_END)
+    );
+
+    for section in sections {
+        let start = max(section.area.start_address(), image_area.start_address());
+        let end = min(section.area.end_address(), image_area.end_address());
+        let mut page_address = start.page_align_down();
+
+        while page_address < end {
+            let page = Page::from_address(page_address);
+
+            if !page_table.is_mapped(page_address) {
+                let page_area = MemoryArea::new(page_address, PAGE_SIZE);
+                let flags = boot::get_kernel_sections()
+                    .unwrap()
+                    .filter(|other| other.area.overlaps_with(page_area))
+                    .fold(PageFlags::empty(), |flags, other| flags | other.flags);
+
+                page_table.map_page_at(
+                    page,
+                    PageFrame::from_address(kernel_physical_address(page_address)),
+                    convert_flags(flags) | PageTableEntryFlags::GLOBAL
+                );
+            }
+
+            page_address += PAGE_SIZE;
+        }
+    }
+
+    true
+}
+
+/// Removes the low identity mapping that was needed to enable paging.
+///
+/// The remapped kernel page table shouldn't contain it anymore, so this makes
+/// sure nothing in the lower half survived. The page tables of the lower half
+/// are freed, except for the boot page tables. Afterwards null pointer
+/// dereferences and other stray accesses to low addresses fault.
+fn unmap_low_identity() {
+    {
+        let mut table = CURRENT_PAGE_TABLE.lock();
+        let l4 = table.get_l4();
+
+        // The lower half is covered by the first half of the level 4 table.
+        for index in 0..ENTRY_NUMBER / 2 {
+            let l3_start = VirtualAddress::from_usize(index << 39);
+
+            if let Some(l3) = l4.get_next_level_mut(l3_start) {
+                free_identity_tables(l3, l3_start);
+            }
+
+            if l4[index].flags().contains(PageTableEntryFlags::PRESENT) {
+                remove_identity_table(&mut l4[index]);
+            }
+        }
+
+        tlb::flush_all();
+    }
+
+    assert!(
+        !is_mapped(VirtualAddress::from_usize(0)),
+        "The null page is still mapped."
+    );
+}
+
+/// Frees the level 2 and level 1 tables below the given level 3 table of the
+/// identity map, which starts at the given address.
+///
+/// The mapped frames themselves aren't freed, as the identity map only
+/// borrowed them. Neither are the boot page tables in the kernel image.
+fn free_identity_tables(l3: &mut PageTable<Level3>, l3_start: VirtualAddress) {
+    for l3_index in 0..ENTRY_NUMBER {
+        let l2_start = l3_start + (l3_index << 30);
+
+        if let Some(l2) = l3.get_next_level_mut(l2_start) {
+            for l2_index in 0..ENTRY_NUMBER {
+                // Entries without a next level are huge pages or not present.
+                if l2.get_next_level(l2_start + (l2_index << 21)).is_some() {
+                    remove_identity_table(&mut l2[l2_index]);
+                } else {
+                    l2[l2_index].clear();
+                }
+            }
+        }
+
+        let flags = l3[l3_index].flags();
+        if flags.contains(PageTableEntryFlags::PRESENT)
+            && !flags.contains(PageTableEntryFlags::HUGE_PAGE)
+        {
+            remove_identity_table(&mut l3[l3_index]);
+        } else {
+            l3[l3_index].clear();
+        }
+    }
+}
+
+/// Removes an entry that points to a page table of the identity map.
+///
+/// The table is freed, unless it is one of the boot page tables. Those are
+/// part of the kernel image and never came from the frame allocator.
+fn remove_identity_table(entry: &mut PageTableEntry) {
+    let table = entry.points_to().expect("Trying to remove an unmapped page table.");
+
+    if MemoryArea::new(table, PAGE_SIZE).is_contained_in(get_kernel_area()) {
+        entry.clear();
+    } else {
+        entry.unmap();
+    }
+}
+
+/// Makes sure that no page of the kernel image or the boot stack is both
+/// writable and executable.
+///
+/// Pages of the text section lose write access, all others lose execute
+/// access. Kernel stacks of threads are mapped without execute access anyway.
+fn enforce_kernel_wx() {
+    let (text_area, image_area, stack_area) = kernel_wx_areas();
+
+    for area in &[image_area, stack_area] {
+        let mut page_address = area.start_address().page_align_down();
+
+        while page_address < area.end_address() {
+            let is_code = text_area.overlaps_with(MemoryArea::new(page_address, PAGE_SIZE));
+
+            let tightened = match CURRENT_PAGE_TABLE.lock().get_entry(page_address) {
+                Some(ref mut entry) if entry.flags().contains(PageTableEntryFlags::PRESENT) => {
+                    let flags = entry.flags();
+                    let wx_flags = wx_flags(flags, is_code);
+
+                    if flags != wx_flags {
+                        entry.set_flags(wx_flags);
+                        tlb::flush(::x86_64::VirtualAddress(page_address.as_usize()));
+                    }
+
+                    flags != wx_flags
+                },
+                _ => false
+            };
+
+            if tightened {
+                warn!("The kernel page {:?} was writable and executable.", page_address);
+            }
+
+            page_address += PAGE_SIZE;
+        }
+    }
+}
+
+/// Returns the text section, the whole kernel image and the boot stack.
+fn kernel_wx_areas() -> (
+    MemoryArea<VirtualAddress>,
+    MemoryArea<VirtualAddress>,
+    MemoryArea<VirtualAddress>
+) {
+    unsafe {
+        let stack_size = STACK_TOP - STACK_BOTTOM;
+
+        (
+            MemoryArea::from_start_and_end(
+                kernel_virtual_address(TEXT_START),
+                kernel_virtual_address(RODATA_START)
+            ),
+            MemoryArea::from_start_and_end(
+                kernel_virtual_address(TEXT_START),
+                kernel_virtual_address(BSS_END)
+            ),
+            MemoryArea::new(FINAL_STACK_TOP - stack_size, stack_size)
+        )
+    }
+}
+
+/// Returns the flags a page should have, so that it isn't writable and
+/// executable at the same time.
+///
+/// Code pages stay executable, all other pages stay writable.
+fn wx_flags(flags: PageTableEntryFlags, is_code: bool) -> PageTableEntryFlags {
+    let writable = flags.contains(PageTableEntryFlags::WRITABLE);
+    let executable = !flags.contains(PageTableEntryFlags::NO_EXECUTE);
+
+    if !(writable && executable) {
+        flags
+    } else if is_code {
+        flags - PageTableEntryFlags::WRITABLE
+    } else {
+        flags | PageTableEntryFlags::NO_EXECUTE
+    }
+}
+
+/// Maps all usable physical memory into the direct map of the given page
+/// table.
+///
+/// Only whole pages of usable memory are mapped. The mapped ranges are
+/// recorded for `is_direct_mapped`.
+///
+/// # Safety
+/// - This should only be called once, before the direct map is activated.
+unsafe fn map_direct_map<T: PageTableManager>(page_table: &mut T) {
+    for area in boot::get_physical_memory_map() {
+        let start = area.start_address().page_align_up();
+        let end = area.end_address().page_align_down();
+
+        let end = if end.as_usize() > DIRECT_MAP_MAX_SIZE {
+            warn!(
+                "Physical memory above {:x} is not in the direct map.",
+                DIRECT_MAP_MAX_SIZE
+            );
+            PhysicalAddress::from_usize(DIRECT_MAP_MAX_SIZE)
+        } else {
+            end
+        };
+
+        if start < end {
+            if DIRECT_MAP_RANGE_COUNT == MAX_DIRECT_MAP_RANGES {
+                warn!("Too many memory areas, {:?} is not in the direct map.", area);
+                continue;
+            }
+
+            DIRECT_MAP_RANGES[DIRECT_MAP_RANGE_COUNT] = MemoryArea::from_start_and_end(start, end);
+            DIRECT_MAP_RANGE_COUNT += 1;
+
+            page_table.map_range(
+                MemoryArea::new(DIRECT_MAP_START + start.as_usize(), end - start),
+                start,
+                PageTableEntryFlags::WRITABLE
+                    | PageTableEntryFlags::GLOBAL
+                    | PageTableEntryFlags::NO_EXECUTE
+            );
+        }
+    }
+}
+
 /// Represents a page.
 #[derive(Clone, Copy)]
 pub struct Page(VirtualAddress);
@@ -230,9 +687,7 @@ pub struct Page(VirtualAddress);
 impl Page {
     /// Returns the page that contains the given virtual address.
     pub fn from_address(address: VirtualAddress) -> Page {
-        Page(VirtualAddress::from_usize(
-            address.as_usize() & !(PAGE_SIZE - 1)
-        ))
+        Page(address.page_align_down())
     }
 
     /// Returns the virtual address of this page.
@@ -254,9 +709,7 @@ pub struct PageFrame(PhysicalAddress);
 impl PageFrame {
     /// Returns the page frame that contains the given physical address.
     pub fn from_address(address: PhysicalAddress) -> PageFrame {
-        PageFrame(PhysicalAddress::from_usize(
-            address.as_usize() & !(PAGE_SIZE - 1)
-        ))
+        PageFrame(address.page_align_down())
     }
 
     /// Returns the physical address of this page frame.
@@ -278,3 +731,104 @@ impl fmt::Debug for PageFrame {
         write!(f, "PageFrame: {:?}", self.0)
     }
 }
+
+/// Self-tests for the kernel page tables.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+
+    /// Checks that the text section is read-only and that no page of the
+    /// kernel image or the boot stack is writable and executable.
+    fn test_kernel_wx() -> Result<(), &'static str> {
+        let (text_area, image_area, stack_area) = kernel_wx_areas();
+        let code_page = VirtualAddress::from_usize(test_kernel_wx as usize).page_align_down();
+
+        if get_page_flags(code_page).contains(PageFlags::WRITABLE) {
+            return Err("The page of a kernel function is writable.");
+        }
+
+        for area in &[image_area, stack_area] {
+            let mut page_address = area.start_address().page_align_down();
+
+            while page_address < area.end_address() {
+                let flags = get_page_flags(page_address);
+                let is_code = text_area.overlaps_with(MemoryArea::new(page_address, PAGE_SIZE));
+
+                if flags.contains(PageFlags::WRITABLE | PageFlags::EXECUTABLE) {
+                    return Err("A kernel page is writable and executable.");
+                }
+                if is_code && flags.contains(PageFlags::WRITABLE) {
+                    return Err("A page of the text section is writable.");
+                }
+
+                page_address += PAGE_SIZE;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that nothing is mapped in the lower half after the identity
+    /// map was removed.
+    fn test_low_identity_unmapped() -> Result<(), &'static str> {
+        if translate_address(VirtualAddress::from_usize(0)).is_some() {
+            return Err("The null page is mapped.");
+        }
+
+        let mut table = CURRENT_PAGE_TABLE.lock();
+        let l4 = table.get_l4();
+
+        if (0..ENTRY_NUMBER / 2).any(|index| l4[index].points_to().is_some()) {
+            return Err("The lower half still has page tables.");
+        }
+
+        Ok(())
+    }
+
+    register_selftest!(KERNEL_WX, test_kernel_wx);
+    register_selftest!(LOW_IDENTITY_UNMAPPED, test_low_identity_unmapped);
+}
+
+/// Tests for the direct map address conversions.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that addresses survive a round-trip through the direct map.
+    #[test]
+    fn test_direct_map_round_trip() {
+        for &address in &[0, 0x1000, 0xb8000, 0x20_1234, 0xfee0_0000, DIRECT_MAP_MAX_SIZE - 1] {
+            let physical_address = PhysicalAddress::from_usize(address);
+            let virtual_address = to_direct_map(physical_address);
+
+            assert_eq!(virtual_address.offset_in_page(), physical_address.offset_in_page());
+            assert_eq!(from_direct_map(virtual_address), Some(physical_address));
+        }
+    }
+
+    /// Tests that pages never stay writable and executable.
+    #[test]
+    fn test_wx_flags() {
+        let present = PageTableEntryFlags::PRESENT | PageTableEntryFlags::GLOBAL;
+        let writable = present | PageTableEntryFlags::WRITABLE;
+        let no_execute = PageTableEntryFlags::NO_EXECUTE;
+
+        // Pages that already follow W^X are left alone.
+        assert_eq!(wx_flags(present, true), present);
+        assert_eq!(wx_flags(present | no_execute, false), present | no_execute);
+        assert_eq!(wx_flags(writable | no_execute, false), writable | no_execute);
+
+        // Code stays executable, data stays writable.
+        assert_eq!(wx_flags(writable, true), present);
+        assert_eq!(wx_flags(writable, false), writable | no_execute);
+    }
+
+    /// Tests that addresses outside of the direct map aren't converted.
+    #[test]
+    fn test_outside_direct_map() {
+        assert_eq!(from_direct_map(DIRECT_MAP_START - 1), None);
+        assert_eq!(from_direct_map(DIRECT_MAP_START + DIRECT_MAP_MAX_SIZE), None);
+        assert_eq!(from_direct_map(HEAP_START), None);
+        assert_eq!(from_direct_map(KERNEL_STACK_AREA_BASE), None);
+    }
+}
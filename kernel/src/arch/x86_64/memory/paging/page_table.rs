@@ -72,11 +72,14 @@ pub struct PageTable<T> {
 
 impl<T: ReducablePageTableLevel> PageTable<T> {
     /// Returns the address of the next page table if there is one.
+    ///
+    /// Entries mapping huge pages don't point to a page table.
     fn get_next_level_address(&self, index: usize) -> Option<VirtualAddress> {
         assert!(index < ENTRY_NUMBER);
         let flags = self[index].flags();
-        debug_assert!(!flags.contains(PageTableEntryFlags::HUGE_PAGE));
-        if flags.contains(PageTableEntryFlags::PRESENT) {
+        if flags.contains(PageTableEntryFlags::PRESENT)
+            && !flags.contains(PageTableEntryFlags::HUGE_PAGE)
+        {
             Some(VirtualAddress::from_usize(
                 (self as *const _ as usize | index << 3) << 9
             ))
@@ -104,6 +104,14 @@ impl PageTableEntry {
         self.0 = 0;
     }
 
+    /// Unmaps the entry without deallocating the frame it points to.
+    ///
+    /// This is used for huge pages, which are never handed out by the frame
+    /// allocator.
+    pub fn clear(&mut self) {
+        self.0 &= PageTableEntryFlags::ENTRY_LOCK.bits();
+    }
+
     /// Locks the pages this entry points to.
     ///
     /// They can't be accessed by other processors/threads after being locked.
@@ -240,4 +248,35 @@ mod tests {
             0xdead_b000 | (1 << 0) | (1 << 6) | (1 << 2) | (1 << 1) | (1 << 63)
         );
     }
+
+    /// Tests that a huge page entry points to the start of its frame.
+    #[test]
+    fn test_huge_page_points_to() {
+        let mut entry = PageTableEntry::new();
+        entry
+            .set_address(PhysicalAddress::from_usize(0x4020_0000))
+            .set_flags(PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE);
+        assert!(entry.flags().contains(PageTableEntryFlags::HUGE_PAGE));
+        assert_eq!(
+            entry.points_to(),
+            Some(PhysicalAddress::from_usize(0x4020_0000))
+        );
+    }
+
+    /// Tests that clearing an entry unmaps it, but keeps the lock.
+    #[test]
+    fn test_clear() {
+        let mut entry = PageTableEntry::new();
+        entry
+            .set_address(PhysicalAddress::from_usize(0x4020_0000))
+            .set_flags(
+                PageTableEntryFlags::PRESENT
+                    | PageTableEntryFlags::HUGE_PAGE
+                    | PageTableEntryFlags::ENTRY_LOCK
+            );
+        entry.clear();
+        assert_eq!(entry.points_to(), None);
+        assert!(entry.is_locked());
+        assert!(!entry.flags().contains(PageTableEntryFlags::HUGE_PAGE));
+    }
 }
@@ -1,11 +1,11 @@
 //! Uses a trait that has general page table managing functions.
 
 use super::frame_allocator::FRAME_ALLOCATOR;
-use super::page_table::{Level1, Level2, Level4, PageTable};
+use super::page_table::{Level1, Level2, Level4, PageTable, ENTRY_NUMBER};
 use super::page_table_entry::{PageTableEntry, PageTableEntryFlags};
-use super::{Page, PageFrame};
+use super::{Page, PageFrame, HUGE_PAGE_SIZE, PAGE_SIZE};
 use core::ops::{Deref, DerefMut};
-use crate::memory::{Address, PhysicalAddress, VirtualAddress};
+use crate::memory::{Address, MemoryArea, PhysicalAddress, VirtualAddress};
 use crate::sync::PreemptionState;
 use x86_64::instructions::tlb;
 
@@ -69,8 +69,22 @@ pub trait PageTableManager {
     /// Returns a mutable reference to the level 4 page table.
     fn get_l4(&mut self) -> &mut PageTable<Level4>;
 
+    /// Fills the given frame with zeros.
+    ///
+    /// The frame doesn't need to be mapped. Frames that get mapped into user
+    /// space must be zeroed, so that they don't reveal what they held before.
+    fn zero_frame(&mut self, frame: &PageFrame);
+
     /// Returns the corresponding physical address to a virtual address.
     fn translate_address(&mut self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let huge_frame = self
+            .get_huge_entry(address)
+            .and_then(|entry| entry.points_to());
+
+        if let Some(frame_address) = huge_frame {
+            return Some(frame_address + huge_page_offset(address));
+        }
+
         self.get_l1(address)
             .and_then(|l1| l1[PageTable::<Level1>::table_index(address)].points_to())
             .map(|page_address| page_address + (address.as_usize() & 0xfff))
@@ -92,7 +106,9 @@ pub trait PageTableManager {
             match l2 {
                 Some(table) => {
                     let l2_entry = &mut table[table_index];
-                    if l2_entry.points_to().is_some() {
+                    if l2_entry.points_to().is_some()
+                        && !l2_entry.flags().contains(PageTableEntryFlags::HUGE_PAGE)
+                    {
                         Some(l2_entry.lock())
                     } else {
                         None
@@ -116,6 +132,90 @@ pub trait PageTableManager {
         }
     }
 
+    /// Returns the level 2 entry corresponding to the given address, if it
+    /// maps a huge page.
+    fn get_huge_entry(&mut self, address: VirtualAddress) -> Option<&mut PageTableEntry> {
+        assert!(valid_address!(address));
+
+        let table_index = PageTable::<Level2>::table_index(address);
+        self.get_l4()
+            .get_next_level_mut(address)
+            .and_then(|l3| l3.get_next_level_mut(address))
+            .map(|l2| &mut l2[table_index])
+            .filter(|entry| {
+                entry
+                    .flags()
+                    .contains(PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE)
+            })
+    }
+
+    /// Checks whether the given address is mapped, either by a regular or a
+    /// huge page.
+    fn is_mapped(&mut self, address: VirtualAddress) -> bool {
+        if self.get_huge_entry(address).is_some() {
+            return true;
+        }
+
+        match self.get_entry(address) {
+            Some(entry) => entry.flags().contains(PageTableEntryFlags::PRESENT),
+            None => false
+        }
+    }
+
+    /// Calls the visitor for every present page in the lower half of the
+    /// address space, in ascending order.
+    ///
+    /// The visitor is passed the address and the size of the page and the
+    /// flags of its entry. Huge pages are visited once.
+    fn for_each_lower_half_page(
+        &mut self,
+        visitor: &mut FnMut(VirtualAddress, usize, PageTableEntryFlags)
+    ) {
+        let l4 = self.get_l4();
+
+        // The upper half of the level 4 table belongs to the kernel.
+        for l4_index in 0..ENTRY_NUMBER / 2 {
+            let l3_address = VirtualAddress::from_usize(l4_index << 39);
+            let l3 = match l4.get_next_level(l3_address) {
+                Some(table) => table,
+                None => continue
+            };
+
+            for l3_index in 0..ENTRY_NUMBER {
+                let l2_address = l3_address + (l3_index << 30);
+                let l2 = match l3.get_next_level(l2_address) {
+                    Some(table) => table,
+                    None => continue
+                };
+
+                for l2_index in 0..ENTRY_NUMBER {
+                    let l1_address = l2_address + (l2_index << 21);
+                    let l2_flags = l2[l2_index].flags();
+
+                    if l2_flags
+                        .contains(PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE)
+                    {
+                        visitor(l1_address, HUGE_PAGE_SIZE, l2_flags);
+                        continue;
+                    }
+
+                    let l1 = match l2.get_next_level(l1_address) {
+                        Some(table) => table,
+                        None => continue
+                    };
+
+                    for l1_index in 0..ENTRY_NUMBER {
+                        let flags = l1[l1_index].flags();
+
+                        if flags.contains(PageTableEntryFlags::PRESENT) {
+                            visitor(l1_address + l1_index * PAGE_SIZE, PAGE_SIZE, flags);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     /// Returns a reference to the level 1 table at the given address, possibly
     /// creating it.
     ///
@@ -172,7 +272,15 @@ pub trait PageTableManager {
     }
 
     /// Maps the given page to the given frame with the given flags.
+    ///
+    /// The null page is never mapped, so null pointer dereferences always
+    /// fault.
     fn map_page_at(&mut self, page: Page, frame: PageFrame, flags: PageTableEntryFlags) {
+        assert!(
+            page.get_address().as_usize() >= PAGE_SIZE,
+            "Trying to map the null page."
+        );
+
         if let Some(entry) = self.get_entry(page.get_address()) {
             debug_assert!(
                 !entry.flags().contains(PageTableEntryFlags::PRESENT),
@@ -189,7 +297,74 @@ pub trait PageTableManager {
             .set_flags(flags | PageTableEntryFlags::PRESENT);
     }
 
+    /// Maps the huge page at the given address to the given frame with the
+    /// given flags.
+    ///
+    /// Both addresses must be aligned to the huge page size.
+    fn map_huge_page_at(
+        &mut self,
+        address: VirtualAddress,
+        frame_address: PhysicalAddress,
+        flags: PageTableEntryFlags
+    ) {
+        assert!(valid_address!(address));
+        assert_eq!(address.as_usize() % HUGE_PAGE_SIZE, 0);
+        assert!(address.as_usize() != 0, "Trying to map the null page.");
+        assert_eq!(frame_address.as_usize() % HUGE_PAGE_SIZE, 0);
+
+        let table_index = PageTable::<Level2>::table_index(address);
+        let l2 = self
+            .get_l4()
+            .next_level_and_map(address)
+            .next_level_and_map(address);
+        let entry = &mut l2[table_index];
+
+        let preemption_state = entry.lock();
+        debug_assert!(
+            !entry.flags().contains(PageTableEntryFlags::PRESENT),
+            "Trying to double map huge page {:?}",
+            address
+        );
+        entry
+            .set_address(frame_address)
+            .set_flags(flags | PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE);
+        entry.unlock(&preemption_state);
+    }
+
+    /// Maps the given virtual area to the physical memory starting at the
+    /// given address.
+    ///
+    /// Huge pages are used wherever the alignment and the remaining size allow
+    /// it.
+    fn map_range(
+        &mut self,
+        area: MemoryArea<VirtualAddress>,
+        physical_start: PhysicalAddress,
+        flags: PageTableEntryFlags
+    ) {
+        let mut offset = 0;
+        while offset < area.length() {
+            let virtual_address = area.start_address() + offset;
+            let physical_address = physical_start + offset;
+            let size = mapping_size(virtual_address, physical_address, area.length() - offset);
+
+            if size == HUGE_PAGE_SIZE {
+                self.map_huge_page_at(virtual_address, physical_address, flags);
+            } else {
+                self.map_page_at(
+                    Page::from_address(virtual_address),
+                    PageFrame::from_address(physical_address),
+                    flags
+                );
+            }
+
+            offset += size;
+        }
+    }
+
     /// Maps the given page to an allocated frame with the given flags.
+    ///
+    /// The frame is zeroed, as the page may be accessible to user space.
     fn map_page(&mut self, page: Page, flags: PageTableEntryFlags) {
         if let Some(entry) = self.get_entry(page.get_address()) {
             debug_assert!(
@@ -200,6 +375,7 @@ pub trait PageTableManager {
         }
 
         let frame = FRAME_ALLOCATOR.allocate();
+        self.zero_frame(&frame);
 
         self.map_page_at(page, frame, flags);
     }
@@ -223,11 +399,42 @@ pub trait PageTableManager {
         }
     }
 
+    /// Unmaps the given huge page.
+    ///
+    /// The frame isn't deallocated, as huge frames aren't managed by the frame
+    /// allocator.
+    ///
+    /// # Safety
+    /// - Make sure the huge page isn't referenced anywhere anymore.
+    unsafe fn unmap_huge_page(&mut self, address: VirtualAddress) {
+        // TODO: Consider multiple CPUs.
+        {
+            let entry = self
+                .get_huge_entry(address)
+                .expect("Trying to unmap a huge page that isn't mapped.");
+            let preemption_state = entry.lock();
+            entry.clear();
+            entry.unlock(&preemption_state);
+        }
+
+        let start_address = address.align_down(HUGE_PAGE_SIZE);
+        for i in 0..HUGE_PAGE_SIZE / PAGE_SIZE {
+            tlb::flush(::x86_64::VirtualAddress((start_address + i * PAGE_SIZE).as_usize()));
+        }
+    }
+
     /// Unmaps the given page.
     ///
+    /// If the page is part of a huge page, the whole huge page is unmapped.
+    ///
     /// # Safety
     /// - Make sure the page isn't referenced anywhere anymore.
     unsafe fn unmap_page(&mut self, page: Page) {
+        if self.get_huge_entry(page.get_address()).is_some() {
+            self.unmap_huge_page(page.get_address());
+            return;
+        }
+
         // TODO: Consider multiple CPUs.
         // TODO: Consider that the page may still be in use elsewhere (don't free the
         // frame then).
@@ -256,4 +463,84 @@ pub trait PageTableManager {
             tlb::flush(::x86_64::VirtualAddress(page.get_address().as_usize()));
         }
     }
+
+    /// Unmaps the given page without deallocating the frame it points to.
+    ///
+    /// This is used for frames that are still mapped elsewhere.
+    ///
+    /// # Safety
+    /// - Make sure the page isn't referenced anywhere anymore.
+    unsafe fn unmap_shared_page(&mut self, page: Page) {
+        // TODO: Consider multiple CPUs.
+        self.get_entry(page.get_address())
+            .expect("Trying to unmap a page that isn't mapped.")
+            .clear();
+        tlb::flush(::x86_64::VirtualAddress(page.get_address().as_usize()));
+    }
+}
+
+/// Returns the offset of the address within its huge page.
+fn huge_page_offset(address: VirtualAddress) -> usize {
+    address.offset_in(HUGE_PAGE_SIZE)
+}
+
+/// Returns the size of the page that should be used to map the given
+/// addresses, if `remaining` bytes are left to map.
+fn mapping_size(
+    virtual_address: VirtualAddress,
+    physical_address: PhysicalAddress,
+    remaining: usize
+) -> usize {
+    if virtual_address.is_aligned(HUGE_PAGE_SIZE)
+        && physical_address.is_aligned(HUGE_PAGE_SIZE)
+        && remaining >= HUGE_PAGE_SIZE
+    {
+        HUGE_PAGE_SIZE
+    } else {
+        PAGE_SIZE
+    }
+}
+
+/// Tests for the huge page helpers.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that huge pages are only chosen for aligned, large enough areas.
+    #[test]
+    fn test_mapping_size() {
+        let virtual_address = VirtualAddress::from_usize(0xffff_c000_0020_0000);
+        let physical_address = PhysicalAddress::from_usize(0x20_0000);
+
+        assert_eq!(
+            mapping_size(virtual_address, physical_address, HUGE_PAGE_SIZE),
+            HUGE_PAGE_SIZE
+        );
+        assert_eq!(
+            mapping_size(virtual_address, physical_address, HUGE_PAGE_SIZE - 1),
+            PAGE_SIZE
+        );
+        assert_eq!(
+            mapping_size(virtual_address + PAGE_SIZE, physical_address, HUGE_PAGE_SIZE),
+            PAGE_SIZE
+        );
+        assert_eq!(
+            mapping_size(virtual_address, physical_address + PAGE_SIZE, HUGE_PAGE_SIZE),
+            PAGE_SIZE
+        );
+    }
+
+    /// Tests that a huge page covers the full 2MiB.
+    #[test]
+    fn test_huge_page_offset() {
+        let start = VirtualAddress::from_usize(0xffff_c000_0020_0000);
+
+        assert_eq!(huge_page_offset(start), 0);
+        assert_eq!(huge_page_offset(start + 0x1234), 0x1234);
+        assert_eq!(
+            huge_page_offset(start + HUGE_PAGE_SIZE - 1),
+            HUGE_PAGE_SIZE - 1
+        );
+        assert_eq!(huge_page_offset(start + HUGE_PAGE_SIZE), 0);
+    }
 }
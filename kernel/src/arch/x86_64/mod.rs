@@ -2,10 +2,17 @@
 //!
 //! This module does all the architecture specific things for x86_64.
 
+mod acpi;
+mod backtrace;
 pub mod context;
+pub mod cpu_local;
+mod fb_console;
 mod gdt;
 mod interrupts;
 pub mod memory;
+mod pci;
+#[cfg(feature = "selftest")]
+pub mod qemu;
 pub mod sync;
 mod syscalls;
 pub mod vga_buffer;
@@ -13,17 +20,21 @@ pub mod vga_buffer;
 mod serial;
 
 pub use self::context::Context;
-use self::gdt::{GDT, TSS};
-use self::interrupts::issue_self_interrupt;
-use self::interrupts::SCHEDULE_INTERRUPT_NUM;
+use self::gdt::GDT;
+use self::interrupts::{issue_self_interrupt, send_ipi};
+use self::interrupts::{RESCHEDULE_INTERRUPT_NUM, SCHEDULE_INTERRUPT_NUM};
 use self::serial::SerialPort;
 use super::Architecture;
+use alloc::Vec;
 use core::fmt;
 use core::fmt::Write;
 use core::time::Duration;
 use log::{set_logger, Level, Log, Metadata, Record};
+use crate::interrupts::InterruptCount;
+use crate::io::console::{print_nonblocking, serial_print_nonblocking};
+use crate::memory::allocator;
 use crate::memory::{Address, MemoryArea, PageFlags, PhysicalAddress, VirtualAddress};
-use crate::multitasking::{StackType, CURRENT_THREAD};
+use crate::multitasking::{current_tcb_unchecked, StackType};
 use raw_cpuid::CpuId;
 use crate::sync::mutex::Mutex;
 use crate::sync::time::Timestamp;
@@ -49,6 +60,10 @@ impl Architecture for X86_64 {
 
         if let Some(features) = cpuid.get_feature_info() {
             supported &= features.has_apic();
+
+            if features.has_x2apic() {
+                interrupts::lapic::use_x2apic();
+            }
         } else {
             supported = false;
         }
@@ -60,6 +75,12 @@ impl Architecture for X86_64 {
             supported = false;
         }
 
+        if let Some(features) = cpuid.get_extended_feature_info() {
+            if features.has_fsgsbase() {
+                context::enable_fsgsbase();
+            }
+        }
+
         if !supported {
             panic!("Your hardware unfortunately does not supported VeOS.");
         }
@@ -92,11 +113,17 @@ impl Architecture for X86_64 {
             GDT.load();
         }
 
+        debug!("Initializing the per-CPU block...");
+        cpu_local::init();
+
         debug!("Initializing the syscall interface...");
         syscalls::init();
 
         debug!("Initializing interrupts...");
         interrupts::init();
+
+        // The framebuffer can only be mapped once paging is set up.
+        fb_console::init();
     }
 
     fn init_io() {
@@ -125,16 +152,36 @@ impl Architecture for X86_64 {
             .initial_local_apic_id() as usize
     }
 
+    fn get_cached_cpu_id() -> usize {
+        cpu_local::cpu_id()
+    }
+
     fn invoke_scheduler() {
         issue_self_interrupt(SCHEDULE_INTERRUPT_NUM);
     }
 
+    fn invoke_scheduler_on(cpu_id: usize) {
+        if cpu_id == Self::get_cpu_id() {
+            Self::invoke_scheduler();
+        } else {
+            send_ipi(cpu_id, RESCHEDULE_INTERRUPT_NUM);
+        }
+    }
+
+    fn dump_cpu_state(cpu_id: usize) {
+        interrupts::request_state_dump(cpu_id);
+    }
+
+    fn interrupt_counts() -> Vec<InterruptCount> {
+        interrupts::interrupt_counts()
+    }
+
     unsafe fn enter_first_thread() -> ! {
-        let stack_pointer = CURRENT_THREAD
-            .without_locking()
-            .context
-            .kernel_stack_pointer;
-        TSS.as_mut().privilege_stack_table[0] = ::x86_64::VirtualAddress(stack_pointer.as_usize());
+        let current_thread = current_tcb_unchecked();
+        let stack_pointer = current_thread.context.kernel_stack_pointer;
+        context::set_kernel_stack(stack_pointer);
+        context::load_segment_bases(&current_thread.context);
+        cpu_local::set_current_thread(current_thread);
         asm!("mov rsp, $0
             ret"
             : : "r"(stack_pointer) : : "intel", "volatile");
@@ -156,6 +203,19 @@ impl Architecture for X86_64 {
         sync::interrupts_enabled()
     }
 
+    #[inline(always)]
+    fn get_stack_pointer() -> VirtualAddress {
+        context::get_stack_pointer()
+    }
+
+    fn get_tls_base() -> VirtualAddress {
+        context::read_fs_base()
+    }
+
+    unsafe fn set_tls_base(base: VirtualAddress) {
+        context::write_fs_base(base)
+    }
+
     #[inline(always)]
     unsafe fn disable_interrupts() {
         sync::disable_interrupts()
@@ -179,7 +239,7 @@ impl Architecture for X86_64 {
         // FIXME: This doesn't work, as long as the clock source is relying on
         // interrupts.
 
-        interrupts::lapic::set_timer(sleep_duration);
+        interrupts::set_timer(sleep_duration);
     }
 
     #[inline(always)]
@@ -191,6 +251,17 @@ impl Architecture for X86_64 {
         memory::get_free_memory_size()
     }
 
+    fn allocate_kernel_frame() -> VirtualAddress {
+        // Once the direct map is used up, pages of the heap are used instead.
+        memory::allocate_direct_mapped_frame().unwrap_or_else(allocator::allocate_page)
+    }
+
+    unsafe fn free_kernel_frame(address: VirtualAddress) {
+        if !memory::free_direct_mapped_frame(address) {
+            allocator::free_page(address);
+        }
+    }
+
     fn map_page(page_address: VirtualAddress, flags: PageFlags) {
         memory::map_page(page_address, flags)
     }
@@ -199,6 +270,14 @@ impl Architecture for X86_64 {
         memory::unmap_page(page_address)
     }
 
+    fn map_device_memory(address: PhysicalAddress, length: usize) -> VirtualAddress {
+        memory::map_device_memory(address, length)
+    }
+
+    unsafe fn unmap_device_memory(address: PhysicalAddress, length: usize) {
+        memory::unmap_device_memory(address, length)
+    }
+
     fn get_kernel_area() -> MemoryArea<PhysicalAddress> {
         memory::get_kernel_area()
     }
@@ -215,13 +294,80 @@ impl Architecture for X86_64 {
         memory::is_userspace_address(address)
     }
 
+    fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
+        memory::physical_to_virtual(address)
+    }
+
+    fn virtual_to_physical(address: VirtualAddress) -> Option<PhysicalAddress> {
+        memory::virtual_to_physical(address)
+    }
+
     const PAGE_SIZE: usize = memory::PAGE_SIZE;
 
     const HEAP_AREA: MemoryArea<VirtualAddress> =
         MemoryArea::new(memory::HEAP_START, memory::HEAP_MAX_SIZE);
 
+    const USER_MMAP_AREA: MemoryArea<VirtualAddress> =
+        MemoryArea::new(memory::USER_MMAP_AREA_BASE, memory::USER_MMAP_AREA_MAX_SIZE);
+
+    const PIE_LOAD_BASE: VirtualAddress = memory::USER_PIE_LOAD_BASE;
+
     fn write_fmt(args: fmt::Arguments) {
-        vga_buffer::WRITER.lock().write_fmt(args).unwrap();
+        let mut fb_writer = fb_console::WRITER.lock();
+
+        match *fb_writer {
+            Some(ref mut writer) => writer.write_fmt(args).unwrap(),
+            None => vga_buffer::WRITER.lock().write_fmt(args).unwrap()
+        }
+    }
+
+    fn try_write_fmt(args: fmt::Arguments) -> bool {
+        let mut fb_writer = match fb_console::WRITER.try_lock() {
+            Some(fb_writer) => fb_writer,
+            None => return false
+        };
+
+        match *fb_writer {
+            Some(ref mut writer) => writer.write_fmt(args).unwrap(),
+            None => match vga_buffer::WRITER.try_lock() {
+                Some(mut writer) => writer.write_fmt(args).unwrap(),
+                None => return false
+            }
+        }
+
+        true
+    }
+
+    unsafe fn force_unlock_output() {
+        fb_console::WRITER.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+        COM1.force_unlock();
+    }
+
+    unsafe fn print_backtrace() {
+        backtrace::print();
+    }
+
+    fn write_serial_fmt(args: fmt::Arguments) {
+        COM1.lock().write_fmt(args).unwrap();
+    }
+
+    fn try_write_serial_fmt(args: fmt::Arguments) -> bool {
+        match COM1.try_lock() {
+            Some(mut serial) => {
+                serial.write_fmt(args).unwrap();
+                true
+            },
+            None => false
+        }
+    }
+
+    fn read_pci_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        pci::read_config(bus, device, function, offset)
+    }
+
+    fn write_pci_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+        pci::write_config(bus, device, function, offset, value)
     }
 }
 
@@ -249,15 +395,17 @@ impl Log for KernelLogger {
         let time = Timestamp::get_current();
         match record.metadata().level() {
             Level::Error => {
-                println!("{}: {}", record.level(), record.args());
-                serial_println!(
-                    "{} {}{}{}: {}",
+                // Errors are logged from exception and panic handlers, which
+                // may have interrupted code holding the screen or serial lock.
+                print_nonblocking(format_args!("{}: {}\n", record.level(), record.args()));
+                serial_print_nonblocking(format_args!(
+                    "{} {}{}{}: {}\n",
                     time,
                     red,
                     record.level(),
                     reset,
                     record.args()
-                );
+                ));
             },
             Level::Warn => {
                 println!("{}: {}", record.level(), record.args());
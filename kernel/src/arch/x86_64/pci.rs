@@ -0,0 +1,45 @@
+//! Accesses the PCI configuration space through the legacy I/O ports.
+
+use crate::sync::Mutex;
+use x86_64::instructions::port::{inl, outl};
+
+/// The port that selects the configuration register to access.
+const CONFIG_ADDRESS: u16 = 0xcf8;
+
+/// The port that holds the data of the selected configuration register.
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Enables the configuration space access of an address.
+const ENABLE_BIT: u32 = 1 << 31;
+
+/// Makes sure that selecting and accessing a register happens at once.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Returns the value that selects the given register.
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    ENABLE_BIT
+        | (bus as u32) << 16
+        | (device as u32 & 0x1f) << 11
+        | (function as u32 & 0x7) << 8
+        | (offset as u32 & 0xfc)
+}
+
+/// Reads the configuration register at the given offset of a PCI function.
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let _lock = CONFIG_LOCK.lock();
+
+    unsafe {
+        outl(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+        inl(CONFIG_DATA)
+    }
+}
+
+/// Writes the configuration register at the given offset of a PCI function.
+pub fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let _lock = CONFIG_LOCK.lock();
+
+    unsafe {
+        outl(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+        outl(CONFIG_DATA, value);
+    }
+}
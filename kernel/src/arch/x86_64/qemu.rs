@@ -0,0 +1,65 @@
+//! Provides ways to leave QEMU from within the kernel.
+//!
+//! This is only compiled with the `selftest` feature, so a test harness can
+//! learn the result of the self-tests from the exit status of QEMU. This
+//! requires QEMU to be started with
+//! `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+//!
+//! Writing a code to that device makes QEMU exit with the status
+//! `(code << 1) | 1`, so
+//! - `SUCCESS` results in the exit status 33 and
+//! - `FAILURE` results in the exit status 35.
+//!
+//! A status of 0 or 1 therefore never comes from the kernel.
+
+use crate::sync::{cpu_halt, disable_preemption};
+use x86_64::instructions::port::{outl, outw};
+
+/// The exit code that reports success.
+pub const SUCCESS: u32 = 0x10;
+
+/// The exit code that reports a failure.
+pub const FAILURE: u32 = 0x11;
+
+/// The IO port of the isa-debug-exit device.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// The ACPI power management control ports of the chipsets QEMU emulates.
+///
+/// Newer versions of QEMU use `0x604`, older ones `0xb004`.
+const ACPI_SHUTDOWN_PORTS: [u16; 2] = [0x604, 0xb004];
+
+/// Requests the ACPI sleep state S5 on the QEMU chipsets.
+const ACPI_SHUTDOWN_COMMAND: u16 = 0x2000;
+
+/// Exits QEMU with the given code.
+///
+/// Without the isa-debug-exit device the virtual machine is shut down
+/// instead and the code is lost.
+pub fn exit(code: u32) -> ! {
+    unsafe {
+        disable_preemption();
+        outl(DEBUG_EXIT_PORT, code);
+    }
+
+    shutdown()
+}
+
+/// Shuts down the virtual machine.
+///
+/// On real hardware or other virtual machines the CPU halts instead.
+pub fn shutdown() -> ! {
+    unsafe {
+        disable_preemption();
+
+        for &port in ACPI_SHUTDOWN_PORTS.iter() {
+            outw(port, ACPI_SHUTDOWN_COMMAND);
+        }
+    }
+
+    loop {
+        unsafe {
+            cpu_halt();
+        }
+    }
+}
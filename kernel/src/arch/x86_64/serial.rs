@@ -27,6 +27,7 @@ impl SerialPort {
             outb(self.port + 3, 0x03); // 8 bits, no parity, one stop bit
             outb(self.port + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
             outb(self.port + 4, 0x0B); // IRQs enabled, RTS/DSR set
+            outb(self.port + 1, 0x01); // Enable the data available interrupt
         }
     }
 
@@ -35,6 +36,20 @@ impl SerialPort {
         unsafe { inb(self.port + 5) & 0x20 != 1 }
     }
 
+    /// Checks if received data is available.
+    fn data_available(&self) -> bool {
+        unsafe { inb(self.port + 5) & 0x01 != 0 }
+    }
+
+    /// Returns the next received byte, if there is one.
+    pub fn receive(&mut self) -> Option<u8> {
+        if self.data_available() {
+            Some(unsafe { inb(self.port) })
+        } else {
+            None
+        }
+    }
+
     /// Transmits a character on the serial port.
     pub fn transmit(&mut self, data: u8) {
         while !self.transmission_ready() {}
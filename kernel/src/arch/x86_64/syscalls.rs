@@ -1,29 +1,60 @@
 //! Serves to accept syscalls.
 
-use super::gdt::{USER_32BIT_CODE_SEGMENT, KERNEL_CODE_SEGMENT, TSS};
+use super::gdt::{
+    KERNEL_CODE_SEGMENT, KERNEL_DATA_SEGMENT, USER_32BIT_CODE_SEGMENT, USER_CODE_SEGMENT,
+    USER_DATA_SEGMENT
+};
 use crate::syscalls::syscall_handler;
 use x86_64::registers::flags::Flags;
-use x86_64::registers::msr::{wrmsr, IA32_FMASK, IA32_KERNEL_GS_BASE, IA32_LSTAR, IA32_STAR};
+use x86_64::registers::msr::{wrmsr, IA32_FMASK, IA32_LSTAR, IA32_STAR};
 
 /// Initializes the system to be able to accept syscalls.
+///
+/// `syscall` loads CS from `STAR[47:32]` and SS from the selector after it,
+/// which are the kernel code and data segments. `sysretq` loads CS from
+/// `STAR[63:48] + 16` and SS from `STAR[63:48] + 8`, which is why the user
+/// data segment directly follows the unused 32-bit code segment in the GDT
+/// and the user code segment follows the user data segment.
 pub fn init() {
     let sysret_cs = USER_32BIT_CODE_SEGMENT.0 as u64;
     let syscall_cs = KERNEL_CODE_SEGMENT.0 as u64;
 
+    debug_assert_eq!(sysret_cs + 8, USER_DATA_SEGMENT.0 as u64);
+    debug_assert_eq!(sysret_cs + 16, USER_CODE_SEGMENT.0 as u64);
+    debug_assert_eq!(syscall_cs + 8, KERNEL_DATA_SEGMENT.0 as u64);
+
     let star_value = sysret_cs << 48 | syscall_cs << 32;
     let lstar_value = syscall_entry as u64;
-    let fmask_value = Flags::IF.bits() as u64;
-    let gs_base_value = unsafe { &TSS.privilege_stack_table[0] as *const _ as u64 };
+    // Interrupts stay off until the stack is switched, the direction flag must
+    // be clear for the kernel code and single stepping must not trap into it.
+    let fmask_value = (Flags::IF | Flags::DF | Flags::TF).bits() as u64;
 
     unsafe {
         wrmsr(IA32_LSTAR, lstar_value);
         wrmsr(IA32_STAR, star_value);
         wrmsr(IA32_FMASK, fmask_value);
-        wrmsr(IA32_KERNEL_GS_BASE, gs_base_value);
     }
 }
 
 /// The entry point for all syscalls.
+///
+/// # Register contract
+/// - `rax` holds the syscall number and receives the return value.
+/// - `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9` hold the arguments in order.
+/// - `rcx` and `r11` are clobbered by the `syscall` instruction itself, as it
+/// saves the return address and the flags in them.
+/// - The argument registers are clobbered by the handler.
+/// - `rbx`, `rbp`, `rsp` and `r12` to `r15` are preserved.
+///
+/// The kernel stack is found through the per-CPU block (see `cpu_local`),
+/// which becomes reachable after `swapgs`. The stub never trusts the user GS
+/// base or stack pointer: it parks the user stack pointer in the scratch slot
+/// of the block, loads the kernel stack and moves the user stack pointer onto
+/// it. Until the stack is switched interrupts are masked by `IA32_FMASK`, so
+/// only NMIs and machine checks can arrive in between. Their handlers must
+/// use `KernelGsGuard::enter_paranoid`, because the code segment doesn't tell
+/// them which GS base is active. The same holds for the return path, which
+/// swaps the user GS base back in with interrupts disabled.
 #[naked]
 extern "C" fn syscall_entry() {
     extern "C" fn syscall_inner() -> isize {
@@ -50,37 +81,40 @@ extern "C" fn syscall_entry() {
     }
 
     unsafe {
-        asm!("// Load the gs base to point to the stack pointer.
+        asm!("// Load the gs base to point to the per-CPU block.
               swapgs
 
               // Save the old stack pointer.
-              mov r12, rsp
+              mov gs:[16], rsp
               // Load the new stack pointer.
-              mov rsp, gs:[0]
-
-              // Restore the gs base.
-              swapgs
+              mov rsp, gs:[8]
+              // Keep the old stack pointer on the kernel stack.
+              push qword ptr gs:[16]
 
               // Now that the stack pointer is a kernel stack pointer, enable interrupts.
               sti
 
               // Save some context.
-              push r12 //The old stack pointer
               push r11 //The flags register
               push rcx //The program counter
+              push rbp //Keeps the stack 16 byte aligned for the call
 
               // Call the actual handler.
               call $0
 
               // Restore the context.
+              pop rbp
               pop rcx
               pop r11
-              pop r12
 
-              // Restore the old stack pointer.
+              // No interrupt may arrive while the user gs base or the user
+              // stack is active in ring 0.
               cli
-              mov rsp, r12
-              sysret"
+              swapgs
+
+              // Restore the old stack pointer.
+              pop rsp
+              sysretq"
               : : "i"(syscall_inner as extern "C" fn() -> isize) : : "intel", "volatile");
     }
 }
@@ -3,13 +3,28 @@
 //! This module is used to handle IO with the basic VGA interface usually
 //! located at 0xb8000;
 
+use super::memory::{is_mapped, PAGE_SIZE};
 use crate::boot;
+use core::cmp::min;
 use core::fmt;
+use core::mem::size_of;
 use core::ptr::Unique;
-use crate::memory::VirtualAddress;
+use crate::memory::{Address, PhysicalAddress, VirtualAddress};
 use crate::sync::Mutex;
 use volatile::Volatile;
 
+/// The physical address of the default VGA text buffer.
+const DEFAULT_ADDRESS: usize = 0xb8000;
+
+/// The width of the default VGA text mode.
+const DEFAULT_WIDTH: usize = 80;
+
+/// The height of the default VGA text mode.
+const DEFAULT_HEIGHT: usize = 25;
+
+/// The largest width or height that is considered plausible for a text buffer.
+const MAX_DIMENSION: usize = 512;
+
 /// Represents a color in the buffer.
 #[allow(dead_code)]
 #[repr(u8)]
@@ -74,12 +89,22 @@ impl Buffer {
         }
     }
 
+    /// Returns the offset of the given position from the start of the buffer.
+    ///
+    /// Positions outside of the buffer are clamped to its edges.
+    fn offset(&self, row_position: usize, column_position: usize) -> isize {
+        let row_position = min(row_position, self.height - 1);
+        let column_position = min(column_position, self.width - 1);
+
+        (row_position * self.width + column_position) as isize
+    }
+
     /// Writes a character to this buffer.
     fn write_char(&mut self, row_position: usize, column_position: usize, character: ScreenChar) {
         let start = self.address.as_ptr();
-        // TODO better safety check here
+        let offset = self.offset(row_position, column_position);
         unsafe {
-            let position_ptr = start.offset((row_position * self.width + column_position) as isize);
+            let position_ptr = start.offset(offset);
             (&mut *position_ptr).write(character);
         }
     }
@@ -87,8 +112,9 @@ impl Buffer {
     /// Reads a character from this buffer.
     fn read_char(&self, row_position: usize, column_position: usize) -> ScreenChar {
         let start = unsafe { self.address.as_ref() as *const Volatile<ScreenChar> };
+        let offset = self.offset(row_position, column_position);
         unsafe {
-            let position_ptr = start.offset((row_position * self.width + column_position) as isize);
+            let position_ptr = start.offset(offset);
             (&*position_ptr).read()
         }
     }
@@ -111,6 +137,12 @@ impl Writer {
     pub fn write_char(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            0x08 => {
+                // Backspace only moves the cursor back within the line.
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                }
+            },
             byte => {
                 if self.column_position >= self.buffer.width {
                     self.new_line();
@@ -195,7 +227,8 @@ impl Writer {
 
         self.buffer.height = info.height;
         self.buffer.width = info.width;
-        self.buffer.address = unsafe { Unique::new_unchecked(info.address.as_mut_ptr()) };
+        self.buffer.address =
+            unsafe { Unique::new_unchecked(info.address.to_virtual().as_mut_ptr()) };
     }
 }
 
@@ -212,22 +245,100 @@ pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     row_position: 0,
     color_code: ColorCode::new(Color::LightGray, Color::Black),
-    buffer: Buffer::new(to_virtual!(0xb8000), 25, 80)
+    buffer: Buffer::new(to_virtual!(DEFAULT_ADDRESS), DEFAULT_WIDTH, DEFAULT_HEIGHT)
 });
 
+/// The kinds of video modes the boot loader can set up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoMode {
+    /// A text mode, where the buffer contains characters.
+    Text,
+    /// A graphics mode, where the buffer contains pixels.
+    Graphics
+}
+
 /// Contains basic buffer information.
 ///
 /// This is what is used to convey information about the buffer from the
 /// outside to this module.
 pub struct Info {
+    /// The height in characters or pixels.
     pub height: usize,
+    /// The width in characters or pixels.
     pub width: usize,
-    pub address: VirtualAddress
+    /// The physical address of the buffer.
+    pub address: PhysicalAddress,
+    /// The number of bytes per line.
+    pub pitch: usize,
+    /// The number of bits per character or pixel.
+    pub bpp: u8,
+    /// The kind of the video mode.
+    pub mode: VideoMode
+}
+
+impl Info {
+    /// Returns the information for the default VGA text mode.
+    fn default_text_mode() -> Info {
+        Info {
+            height: DEFAULT_HEIGHT,
+            width: DEFAULT_WIDTH,
+            address: PhysicalAddress::from_usize(DEFAULT_ADDRESS),
+            pitch: DEFAULT_WIDTH * size_of::<ScreenChar>(),
+            bpp: 16,
+            mode: VideoMode::Text
+        }
+    }
+
+    /// Checks if the buffer described is plausible and fully mapped.
+    fn is_valid(&self) -> bool {
+        // At least two lines are needed for scrolling.
+        let dimensions_valid = self.width > 0
+            && self.width <= MAX_DIMENSION
+            && self.height > 1
+            && self.height <= MAX_DIMENSION;
+
+        if !dimensions_valid || self.address.as_usize() % size_of::<ScreenChar>() != 0 {
+            return false;
+        }
+
+        is_area_mapped(
+            self.address.to_virtual(),
+            self.width * self.height * size_of::<ScreenChar>()
+        )
+    }
+}
+
+/// Checks if all the pages in the given area are mapped.
+fn is_area_mapped(start: VirtualAddress, size: usize) -> bool {
+    if size == 0 || start.as_usize().checked_add(size).is_none() {
+        return false;
+    }
+
+    let start_page = start.as_usize() / PAGE_SIZE;
+    let end_page = (start.as_usize() + size - 1) / PAGE_SIZE;
+
+    (start_page..end_page + 1).all(|page| is_mapped(VirtualAddress::from_usize(page * PAGE_SIZE)))
 }
 
 /// Initializes the buffer for use.
+///
+/// If the boot loader reported an unusable buffer, the default text mode is used.
 pub fn init() {
-    let info = boot::get_vga_info();
+    let mut info = boot::get_vga_info();
+
+    // Graphics modes are handled by the framebuffer console once paging is set up.
+    if info.mode == VideoMode::Graphics {
+        return;
+    }
+
+    if !info.is_valid() {
+        warn!(
+            "Invalid VGA buffer ({}x{} at {:?}), falling back to {}x{} text mode.",
+            info.width, info.height, info.address, DEFAULT_WIDTH, DEFAULT_HEIGHT
+        );
+        info = Info::default_text_mode();
+    }
+
     WRITER.lock().init(info);
     clear_screen();
 }
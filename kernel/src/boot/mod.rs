@@ -5,7 +5,7 @@ mod multiboot2;
 use crate::arch::{self, vga_buffer, Architecture};
 use core;
 use either::{Either, Left, Right};
-use crate::memory::{Address, MemoryArea, PhysicalAddress, PAGE_SIZE};
+use crate::memory::{Address, MemoryArea, PageFlags, PhysicalAddress, VirtualAddress};
 /// Lists possiblities for boot sources.
 #[derive(PartialEq)]
 pub enum BootMethod {
@@ -17,20 +17,54 @@ pub enum BootMethod {
     Multiboot2,
 }
 
+/// A section of the kernel image in memory.
+pub struct KernelSection {
+    /// The virtual memory area the section occupies.
+    pub area: MemoryArea<VirtualAddress>,
+    /// The permissions the section needs.
+    pub flags: PageFlags,
+}
+
 /// The memory area containing the initramfs.
 fn initramfs() -> MemoryArea<PhysicalAddress> {
     let area = get_initramfs_area();
     // Align to the previous page.
     let initramfs_start = area.start_address().page_align_down();
 
-    // Round up the the next page boundary.
-    let initramfs_length = area.length();
-    let initramfs_length = if initramfs_length > 0 {
-        (initramfs_length - 1) / PAGE_SIZE * PAGE_SIZE + PAGE_SIZE
-    } else {
-        0
-    };
-    MemoryArea::new(initramfs_start, initramfs_length)
+    if area.is_empty() {
+        return MemoryArea::new(initramfs_start, 0);
+    }
+
+    // The initramfs may start in the middle of a page, so its end is rounded
+    // up instead of its length.
+    MemoryArea::from_start_and_end(initramfs_start, area.end_address().page_align_up())
+}
+
+/// Returns the usable area of a memory map entry.
+///
+/// The firmware isn't trusted, so entries reaching beyond the address space
+/// are ignored.
+fn usable_area(start: PhysicalAddress, length: usize) -> Option<MemoryArea<PhysicalAddress>> {
+    let area = MemoryArea::try_new(start, length);
+
+    if area.is_none() {
+        warn!(
+            "Ignoring usable memory at {:?} with the invalid length {:#x}.",
+            start, length
+        );
+    }
+
+    area
+}
+
+/// Returns the reserved area of a memory map entry.
+///
+/// The firmware isn't trusted, so entries reaching beyond the address space
+/// are cut off at its end.
+fn reserved_area(start: PhysicalAddress, length: usize) -> MemoryArea<PhysicalAddress> {
+    MemoryArea::try_new(start, length).unwrap_or_else(|| {
+        MemoryArea::new(start, usize::max_value() - start.as_usize())
+    })
 }
 
 /// Provides an iterator for a memory map.
@@ -38,7 +72,6 @@ pub struct MemoryMapIterator<I: Iterator<Item = MemoryArea<PhysicalAddress>>> {
     // multiboot2_iterator: Option<multiboot2::MemoryMapIterator>,
     to_exclude: [MemoryArea<PhysicalAddress>; 2],
     current_entry: Option<MemoryArea<PhysicalAddress>>,
-    exclude_index: usize,
     multiboot_iterator: I,
 }
 
@@ -47,7 +80,7 @@ where
     I: Iterator<Item = MemoryArea<PhysicalAddress>>,
 {
     /// Creates a new memory map iterator.
-    fn new(mut iter: I) -> MemoryMapIterator<I> {
+    fn new(iter: I) -> MemoryMapIterator<I> {
         let kernel_area = arch::Current::get_kernel_area();
         let initramfs_area = initramfs();
 
@@ -57,10 +90,19 @@ where
             [initramfs_area, kernel_area]
         };
 
+        MemoryMapIterator::with_excludes(iter, to_exclude)
+    }
+
+    /// Creates a new memory map iterator that excludes the given areas.
+    ///
+    /// The areas to exclude must be ordered by their start addresses.
+    fn with_excludes(
+        mut iter: I,
+        to_exclude: [MemoryArea<PhysicalAddress>; 2],
+    ) -> MemoryMapIterator<I> {
         MemoryMapIterator {
             to_exclude: to_exclude,
             current_entry: iter.next(),
-            exclude_index: 0,
             multiboot_iterator: iter,
         }
     }
@@ -73,60 +115,45 @@ where
     type Item = MemoryArea<PhysicalAddress>;
 
     fn next(&mut self) -> Option<MemoryArea<PhysicalAddress>> {
-        // NOTE: This assumes function makes a few assumptions to work properly:
-        // - The to_exclude list must be ordered by the start addresses.
-        // - The to_exclude entries must not overlap.
-        // - The memory areas must not overlap.
-        // - A to_exclude entry must lie completely within a memory area.
+        // NOTE: This assumes that the to_exclude list is ordered by the start
+        // addresses. An exclude area may span several memory areas and the
+        // gaps between them, so each memory area is checked against all of
+        // them.
+        //
+        // Empty memory areas are never returned, even if the memory map
+        // contains them or an exclude area touches the boundary of one.
 
         loop {
-            return if let Some(current_entry) = self.current_entry {
-                if self.exclude_index >= self.to_exclude.len() {
-                    // If all the exclude areas were handled.
-
-                    self.current_entry = self.multiboot_iterator.next();
-
-                    Some(current_entry)
-                } else if self.to_exclude[self.exclude_index].is_contained_in(current_entry) {
-                    // Handle the exclude areas.
-
-                    // The area to exclude is contained in the current free entry.
-                    let (entry_before, entry_after) = {
-                        let exclude_area = &self.to_exclude[self.exclude_index];
-
-                        (
-                            MemoryArea::new(
-                                current_entry.start_address(),
-                                exclude_area.start_address() - current_entry.start_address(),
-                            ),
-                            MemoryArea::new(
-                                exclude_area.end_address(),
-                                current_entry.end_address() - exclude_area.end_address(),
-                            ),
-                        )
-                    };
-
-                    self.exclude_index += 1;
-
-                    if entry_after.end_address() == entry_after.start_address() {
-                        self.current_entry = self.multiboot_iterator.next();
-                    } else {
-                        self.current_entry = Some(entry_after);
-                    }
-
-                    if entry_before.end_address() == entry_before.start_address() {
-                        continue;
-                    } else {
-                        Some(entry_before)
-                    }
-                } else {
-                    self.current_entry = self.multiboot_iterator.next();
-
-                    Some(current_entry)
-                }
-            } else {
-                None
+            let current_entry = self.current_entry?;
+
+            if current_entry.is_empty() {
+                self.current_entry = self.multiboot_iterator.next();
+                continue;
+            }
+
+            // Because the exclude areas are ordered, nothing before the first
+            // overlapping one needs to be excluded. An empty exclude area
+            // (e.g. a missing initramfs) excludes nothing.
+            let exclude_area = self
+                .to_exclude
+                .iter()
+                .cloned()
+                .find(|area| !area.is_empty() && area.overlaps_with(current_entry));
+
+            let (entry_before, entry_after) = match exclude_area {
+                Some(exclude_area) => current_entry.subtract(exclude_area),
+                None => (Some(current_entry), None),
+            };
+
+            // The rest of the entry may still overlap later exclude areas.
+            self.current_entry = match entry_after {
+                Some(entry_after) => Some(entry_after),
+                None => self.multiboot_iterator.next(),
             };
+
+            if entry_before.is_some() {
+                return entry_before;
+            }
         }
     }
 }
@@ -184,6 +211,51 @@ pub fn get_bootloader_name() -> &'static str {
     }
 }
 
+/// Returns the command line the kernel was booted with.
+pub fn get_command_line() -> &'static str {
+    match *get_boot_method() {
+        BootMethod::Multiboot2 => multiboot2::get_command_line(),
+        BootMethod::Multiboot => multiboot::get_command_line(),
+        _ => "",
+    }
+}
+
+/// Returns the value of the given option on the kernel command line.
+///
+/// Options are separated by whitespace and have the form `name=value`.
+pub fn get_command_line_option(name: &str) -> Option<&'static str> {
+    find_option(get_command_line(), name)
+}
+
+/// Returns true if the given flag is on the kernel command line.
+///
+/// Flags are options without a value.
+pub fn has_command_line_flag(name: &str) -> bool {
+    has_flag(get_command_line(), name)
+}
+
+/// Returns true if the given flag is in the command line.
+fn has_flag(command_line: &str, name: &str) -> bool {
+    command_line.split_whitespace().any(|option| option == name)
+}
+
+/// Returns the value of the given option in the command line.
+///
+/// If the option is given multiple times, the last value is used.
+fn find_option<'a>(command_line: &'a str, name: &str) -> Option<&'a str> {
+    command_line
+        .split_whitespace()
+        .filter_map(|option| {
+            let mut parts = option.splitn(2, '=');
+
+            match (parts.next(), parts.next()) {
+                (Some(option_name), Some(value)) if option_name == name => Some(value),
+                _ => None,
+            }
+        })
+        .last()
+}
+
 /// Returns the memory area of the initramfs.
 pub fn get_initramfs_area() -> MemoryArea<PhysicalAddress> {
     match *get_boot_method() {
@@ -205,6 +277,40 @@ pub fn get_memory_map() -> Either<
     }
 }
 
+/// Returns an iterator over the memory that the memory map marks as not
+/// usable, together with what it is reserved for.
+pub fn get_reserved_memory_map(
+) -> Either<multiboot::ReservedMemoryIterator, multiboot2::ReservedMemoryIterator> {
+    match *get_boot_method() {
+        BootMethod::Multiboot => Left(multiboot::get_reserved_memory_map()),
+        BootMethod::Multiboot2 => Right(multiboot2::get_reserved_memory_map()),
+        _ => unimplemented!(),
+    }
+}
+
+/// Returns the loaded sections of the kernel, if the boot loader provided them.
+///
+/// Only multiboot2 boot loaders provide the sections at the moment.
+pub fn get_kernel_sections() -> Option<multiboot2::KernelSectionIterator> {
+    match *get_boot_method() {
+        BootMethod::Multiboot2 => multiboot2::elf_sections(),
+        _ => None,
+    }
+}
+
+/// Returns an iterator over all usable physical memory.
+///
+/// Unlike `get_memory_map`, this doesn't exclude the memory used by the kernel
+/// or the initramfs.
+pub fn get_physical_memory_map(
+) -> Either<multiboot::MemoryMapIterator, multiboot2::MemoryMapIterator> {
+    match *get_boot_method() {
+        BootMethod::Multiboot => Left(multiboot::get_memory_map()),
+        BootMethod::Multiboot2 => Right(multiboot2::get_memory_map()),
+        _ => unimplemented!(),
+    }
+}
+
 #[repr(C, align(4))]
 pub struct Multiboot1 {
     magic: u32,
@@ -221,7 +327,7 @@ pub struct Multiboot1 {
     depth: u32,
 }
 
-#[repr(C, align(4))]
+#[repr(C, align(8))]
 struct Multiboot2 {
     pub magic: u32,
     pub arch: u32,
@@ -233,12 +339,20 @@ struct Multiboot2 {
     pub fb_tag_width: u32,
     pub fb_tag_height: u32,
     pub fb_tag_depth: u32,
+    /// Pads the framebuffer tag, because tags must be 8-byte aligned.
+    pub fb_tag_padding: u32,
     pub end_tag_type: u16,
     pub end_tag_flags: u16,
     pub end_tag_size: u32,
 }
 
-#[repr(C)]
+/// The header that identifies the kernel to multiboot and multiboot2 boot
+/// loaders.
+///
+/// The multiboot header must be 4-byte aligned and the multiboot2 header (as
+/// well as each of its tags) must be 8-byte aligned, so the whole header is
+/// aligned to 8 bytes.
+#[repr(C, align(8))]
 pub struct MultibootHeader {
     mb1: Multiboot1,
     mb2: Multiboot2,
@@ -247,16 +361,24 @@ pub struct MultibootHeader {
 impl MultibootHeader {
     const MB_MAGIC: u32 = 0x1BAD_B002;
     const MB_FLAGS: u32 = 0b0000_0000_0000_0000_0000_0000_0000_0000;
+    /// The two's complement negation of the magic number and the flags.
+    const MB_CHECKSUM: u32 = (!(Self::MB_MAGIC.wrapping_add(Self::MB_FLAGS))).wrapping_add(1);
 
     const MB2_MAGIC: u32 = 0xE852_50D6;
+    const MB2_ARCH: u32 = 0;
     const MB2_SIZE: u32 = core::mem::size_of::<Multiboot2>() as u32;
+    /// The two's complement negation of the magic number, the architecture and
+    /// the header length.
+    const MB2_CHECKSUM: u32 =
+        (!(Self::MB2_MAGIC.wrapping_add(Self::MB2_ARCH).wrapping_add(Self::MB2_SIZE)))
+            .wrapping_add(1);
 
     pub const fn new() -> Self {
         MultibootHeader {
             mb1: Multiboot1 {
                 magic: Self::MB_MAGIC,
                 flags: Self::MB_FLAGS,
-                checksum: u32::max_value() - Self::MB_MAGIC - Self::MB_FLAGS + 1,
+                checksum: Self::MB_CHECKSUM,
                 header_addr: 0,
                 load_addr: 0,
                 load_end_addr: 0,
@@ -269,15 +391,16 @@ impl MultibootHeader {
             },
             mb2: Multiboot2 {
                 magic: Self::MB2_MAGIC,
-                arch: 0,
+                arch: Self::MB2_ARCH,
                 header_length: Self::MB2_SIZE,
-                checksum: u32::max_value() - Self::MB2_MAGIC - Self::MB2_SIZE + 1,
+                checksum: Self::MB2_CHECKSUM,
                 fb_tag_type: 5,
                 fb_tag_flags: 1,
                 fb_tag_size: 20,
                 fb_tag_width: 1024,
                 fb_tag_height: 768,
                 fb_tag_depth: 32,
+                fb_tag_padding: 0,
                 end_tag_type: 0,
                 end_tag_flags: 0,
                 end_tag_size: 8,
@@ -285,3 +408,248 @@ impl MultibootHeader {
         }
     }
 }
+
+// Fails to compile, if the multiboot checksum doesn't cancel out the other fields.
+#[allow(dead_code)]
+const MB_CHECKSUM_VALID: [(); 0] = [(); MultibootHeader::MB_MAGIC
+    .wrapping_add(MultibootHeader::MB_FLAGS)
+    .wrapping_add(MultibootHeader::MB_CHECKSUM) as usize];
+
+// Fails to compile, if the multiboot2 checksum doesn't cancel out the other fields.
+#[allow(dead_code)]
+const MB2_CHECKSUM_VALID: [(); 0] = [(); MultibootHeader::MB2_MAGIC
+    .wrapping_add(MultibootHeader::MB2_ARCH)
+    .wrapping_add(MultibootHeader::MB2_SIZE)
+    .wrapping_add(MultibootHeader::MB2_CHECKSUM) as usize];
+
+/// Tests for the multiboot header.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::Vec;
+    use core::mem::{align_of, size_of};
+    use core::slice;
+
+    /// The offset of the multiboot2 header within the combined header.
+    const MB2_OFFSET: usize = 48;
+
+    /// Returns the bytes of the given header, as a boot loader would see them.
+    fn header_bytes(header: &MultibootHeader) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                header as *const MultibootHeader as *const u8,
+                size_of::<MultibootHeader>(),
+            )
+        }
+    }
+
+    /// Reads a little endian `u32` at the given offset.
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        bytes[offset] as u32
+            | (bytes[offset + 1] as u32) << 8
+            | (bytes[offset + 2] as u32) << 16
+            | (bytes[offset + 3] as u32) << 24
+    }
+
+    /// Reads a little endian `u16` at the given offset.
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+    }
+
+    /// Creates a physical memory area from its start and end.
+    fn area(start: usize, end: usize) -> MemoryArea<PhysicalAddress> {
+        MemoryArea::from_start_and_end(
+            PhysicalAddress::from_usize(start),
+            PhysicalAddress::from_usize(end),
+        )
+    }
+
+    /// Returns the areas the memory map iterator emits for the given free
+    /// areas and exclude areas.
+    fn usable_areas(
+        free_areas: &[MemoryArea<PhysicalAddress>],
+        to_exclude: [MemoryArea<PhysicalAddress>; 2],
+    ) -> Vec<MemoryArea<PhysicalAddress>> {
+        let areas: Vec<_> =
+            MemoryMapIterator::with_excludes(free_areas.iter().cloned(), to_exclude).collect();
+
+        assert!(areas.iter().all(|area| !area.is_empty()));
+
+        areas
+    }
+
+    /// Tests excluding an area at the start of a free area.
+    #[test]
+    fn test_exclude_at_start() {
+        let areas = usable_areas(
+            &[area(0x1000, 0x5000)],
+            [MemoryArea::default(), area(0x1000, 0x2000)],
+        );
+
+        assert_eq!(&areas[..], &[area(0x2000, 0x5000)]);
+    }
+
+    /// Tests excluding an area at the end of a free area.
+    #[test]
+    fn test_exclude_at_end() {
+        let areas = usable_areas(
+            &[area(0x1000, 0x5000)],
+            [MemoryArea::default(), area(0x4000, 0x5000)],
+        );
+
+        assert_eq!(&areas[..], &[area(0x1000, 0x4000)]);
+    }
+
+    /// Tests excluding a whole free area.
+    #[test]
+    fn test_exclude_whole_area() {
+        let areas = usable_areas(
+            &[area(0x1000, 0x5000), area(0x8000, 0x9000)],
+            [area(0x1000, 0x5000), area(0x8000, 0x9000)],
+        );
+
+        assert!(areas.is_empty());
+    }
+
+    /// Tests that two excludes in one free area leave the pieces around them.
+    #[test]
+    fn test_two_excludes_in_one_area() {
+        let areas = usable_areas(
+            &[area(0x1000, 0x9000), area(0, 0)],
+            [area(0x1000, 0x2000), area(0x2000, 0x4000)],
+        );
+
+        assert_eq!(&areas[..], &[area(0x4000, 0x9000)]);
+    }
+
+    /// Tests excluding an area that spans two free areas and the hole
+    /// between them.
+    #[test]
+    fn test_exclude_spanning_areas() {
+        let areas = usable_areas(
+            &[area(0x1000, 0x3000), area(0x4000, 0x6000), area(0x8000, 0x9000)],
+            [area(0x2000, 0x5000), area(0x8000, 0x8800)],
+        );
+
+        assert_eq!(
+            &areas[..],
+            &[area(0x1000, 0x2000), area(0x5000, 0x6000), area(0x8800, 0x9000)]
+        );
+    }
+
+    /// Tests that excludes are applied to free areas in any order.
+    #[test]
+    fn test_unordered_free_areas() {
+        let areas = usable_areas(
+            &[area(0x8000, 0x9000), area(0x1000, 0x3000)],
+            [area(0x2000, 0x3000), area(0x8000, 0x8800)],
+        );
+
+        assert_eq!(&areas[..], &[area(0x8800, 0x9000), area(0x1000, 0x2000)]);
+    }
+
+    /// Tests finding options on the command line.
+    #[test]
+    fn test_find_option() {
+        let command_line = "/boot/kernel.bin  initramfs_crc=0x1234abcd quiet level=";
+
+        assert_eq!(find_option(command_line, "initramfs_crc"), Some("0x1234abcd"));
+        assert_eq!(find_option(command_line, "level"), Some(""));
+        assert_eq!(find_option(command_line, "quiet"), None);
+        assert_eq!(find_option(command_line, "initramfs"), None);
+        assert_eq!(find_option("", "initramfs_crc"), None);
+        assert_eq!(find_option("a=1 a=2", "a"), Some("2"));
+    }
+
+    /// Tests finding flags on the command line.
+    #[test]
+    fn test_has_flag() {
+        let command_line = "/boot/kernel.bin  selftest level=quiet";
+
+        assert!(has_flag(command_line, "selftest"));
+        assert!(!has_flag(command_line, "quiet"));
+        assert!(!has_flag(command_line, "level"));
+        assert!(!has_flag(command_line, "self"));
+        assert!(!has_flag("", "selftest"));
+    }
+
+    /// Tests that the multiboot header is laid out as the specification
+    /// requires.
+    #[test]
+    fn test_multiboot_layout() {
+        let header = MultibootHeader::new();
+        let bytes = header_bytes(&header);
+
+        assert_eq!(align_of::<MultibootHeader>() % 8, 0);
+        assert_eq!(read_u32(bytes, 0), 0x1BAD_B002);
+        assert_eq!(read_u32(bytes, 4), 0);
+        assert_eq!(
+            read_u32(bytes, 0)
+                .wrapping_add(read_u32(bytes, 4))
+                .wrapping_add(read_u32(bytes, 8)),
+            0
+        );
+    }
+
+    /// Tests that the multiboot2 header is laid out as the specification
+    /// requires.
+    #[test]
+    fn test_multiboot2_layout() {
+        let header = MultibootHeader::new();
+        let bytes = header_bytes(&header);
+        let header_length = read_u32(bytes, MB2_OFFSET + 8);
+
+        assert_eq!(MB2_OFFSET % 8, 0);
+        assert_eq!(read_u32(bytes, MB2_OFFSET), 0xE852_50D6);
+        assert_eq!(read_u32(bytes, MB2_OFFSET + 4), 0);
+        assert_eq!(header_length as usize, size_of::<Multiboot2>());
+        assert_eq!(MB2_OFFSET + header_length as usize, bytes.len());
+        assert_eq!(
+            read_u32(bytes, MB2_OFFSET)
+                .wrapping_add(read_u32(bytes, MB2_OFFSET + 4))
+                .wrapping_add(header_length)
+                .wrapping_add(read_u32(bytes, MB2_OFFSET + 12)),
+            0
+        );
+
+        // The framebuffer tag.
+        let tag_offset = MB2_OFFSET + 16;
+        assert_eq!(read_u16(bytes, tag_offset), 5);
+        assert_eq!(read_u32(bytes, tag_offset + 4), 20);
+
+        // The end tag must follow at the next 8-byte boundary.
+        let tag_offset = tag_offset + 24;
+        assert_eq!(tag_offset % 8, 0);
+        assert_eq!(read_u16(bytes, tag_offset), 0);
+        assert_eq!(read_u16(bytes, tag_offset + 2), 0);
+        assert_eq!(read_u32(bytes, tag_offset + 4), 8);
+        assert_eq!(tag_offset + 8, bytes.len());
+    }
+
+    /// Tests that the multiboot header fields sum to zero.
+    #[test]
+    fn test_multiboot_checksum() {
+        let header = MultibootHeader::new();
+        let mb1 = &header.mb1;
+
+        assert_eq!(
+            mb1.magic.wrapping_add(mb1.flags).wrapping_add(mb1.checksum),
+            0
+        );
+    }
+
+    /// Tests that the multiboot2 header fields sum to zero.
+    #[test]
+    fn test_multiboot2_checksum() {
+        let header = MultibootHeader::new();
+        let mb2 = &header.mb2;
+
+        assert_eq!(
+            mb2.magic
+                .wrapping_add(mb2.arch)
+                .wrapping_add(mb2.header_length)
+                .wrapping_add(mb2.checksum),
+            0
+        );
+    }
+}
@@ -1,8 +1,10 @@
 //! Handles the multiboot information structure.
 
+use super::{reserved_area, usable_area};
 use crate::arch::vga_buffer;
 use core::mem::size_of;
-use crate::memory::{Address, MemoryArea, PhysicalAddress, VirtualAddress};
+use crate::memory::reserved::ReservedKind;
+use crate::memory::{Address, MemoryArea, PhysicalAddress};
 
 /// Represents the multiboot information structure.
 #[repr(C)]
@@ -110,8 +112,9 @@ pub fn init(information_structure_address: usize) {
     assert_has_not_been_called!("The multiboot module should only be initialized once.");
 
     unsafe {
-        STRUCT_BASE_ADDRESS =
-            to_virtual!(information_structure_address) as *const MultibootInformation
+        STRUCT_BASE_ADDRESS = PhysicalAddress::from_usize(information_structure_address)
+            .to_virtual()
+            .as_ptr()
     };
 
     assert!(!get_flags().contains(MultibootFlags::A_OUT | MultibootFlags::ELF));
@@ -125,14 +128,24 @@ pub fn get_vga_info() -> vga_buffer::Info {
         vga_buffer::Info {
             height: info.framebuffer_height as usize,
             width: info.framebuffer_width as usize,
-            address: VirtualAddress::from_usize(to_virtual!(info.framebuffer_addr)), /* bpp: 16                                                               * pitch: 160 */
+            address: PhysicalAddress::from_usize(info.framebuffer_addr as usize),
+            pitch: info.framebuffer_pitch as usize,
+            bpp: info.framebuffer_bpp,
+            // Type 2 is EGA text, types 0 and 1 are indexed and direct color graphics.
+            mode: if info.framebuffer_type == 2 {
+                vga_buffer::VideoMode::Text
+            } else {
+                vga_buffer::VideoMode::Graphics
+            },
         }
     } else {
         vga_buffer::Info {
             height: 25,
             width: 80,
-            address: VirtualAddress::from_usize(to_virtual!(0xb8000)), /* bpp: 16,
-                                                                        * pitch: 160 */
+            address: PhysicalAddress::from_usize(0xb8000),
+            pitch: 160,
+            bpp: 16,
+            mode: vga_buffer::VideoMode::Text,
         }
     }
 }
@@ -141,22 +154,28 @@ pub fn get_vga_info() -> vga_buffer::Info {
 pub fn get_initramfs_area() -> MemoryArea<PhysicalAddress> {
     let module_entry = get_initramfs_module_entry();
 
-    MemoryArea::from_start_and_end(
+    MemoryArea::try_from_start_and_end(
         PhysicalAddress::from_usize(module_entry.mod_start as usize),
         PhysicalAddress::from_usize(module_entry.mod_end as usize),
-    )
+    ).expect("The boot loader placed the end of the initramfs before its start.")
 }
 
 /// Returns the module entry for the initramfs.
 fn get_initramfs_module_entry() -> &'static ModuleEntry {
     let info = get_info();
     let mod_count = info.mods_count as usize;
-    let mod_addr = to_virtual!(info.mods_addr) as usize;
+    let mod_addr = PhysicalAddress::from_usize(info.mods_addr as usize)
+        .to_virtual()
+        .as_usize();
 
     for i in 0..mod_count {
         let mod_entry =
             unsafe { &*((mod_addr + i * size_of::<ModuleEntry>()) as *const ModuleEntry) };
-        let mod_string = from_c_str!(to_virtual!(mod_entry.string as usize)).unwrap();
+        let mod_string = from_c_str!(
+            PhysicalAddress::from_usize(mod_entry.string as usize)
+                .to_virtual()
+                .as_usize()
+        ).unwrap();
         if mod_string == "initramfs" {
             return mod_entry;
         }
@@ -168,13 +187,32 @@ fn get_initramfs_module_entry() -> &'static ModuleEntry {
 /// Returns the name of the boot loader.
 pub fn get_bootloader_name() -> &'static str {
     if get_flags().contains(MultibootFlags::BOOT_LOADER_NAME) {
-        from_c_str!(to_virtual!(get_info().boot_loader_name)).unwrap()
+        from_c_str!(
+            PhysicalAddress::from_usize(get_info().boot_loader_name as usize)
+                .to_virtual()
+                .as_usize()
+        ).unwrap()
     } else {
         // When no specific name was given by the boot loader.
         "a multiboot compliant bootloader"
     }
 }
 
+/// Returns the kernel command line.
+///
+/// If the boot loader didn't pass one, the command line is empty.
+pub fn get_command_line() -> &'static str {
+    if get_flags().contains(MultibootFlags::CMDLINE) {
+        from_c_str!(
+            PhysicalAddress::from_usize(get_info().cmdline as usize)
+                .to_virtual()
+                .as_usize()
+        ).unwrap_or("")
+    } else {
+        ""
+    }
+}
+
 /// Returns the flags of the multiboot structure.
 fn get_flags() -> MultibootFlags {
     MultibootFlags::from_bits_truncate(get_info().flags)
@@ -200,8 +238,13 @@ impl MemoryMapIterator {
             && *super::get_boot_method() == super::BootMethod::Multiboot
         {
             MemoryMapIterator {
-                address: to_virtual!(get_info().mmap_addr),
-                max_address: to_virtual!(get_info().mmap_addr + get_info().mmap_length),
+                address: PhysicalAddress::from_usize(get_info().mmap_addr as usize)
+                    .to_virtual()
+                    .as_usize(),
+                max_address: PhysicalAddress::from_usize(
+                    (get_info().mmap_addr + get_info().mmap_length) as usize,
+                ).to_virtual()
+                    .as_usize(),
             }
         } else {
             MemoryMapIterator {
@@ -212,28 +255,70 @@ impl MemoryMapIterator {
     }
 }
 
-impl Iterator for MemoryMapIterator {
-    type Item = MemoryArea<PhysicalAddress>;
-
-    fn next(&mut self) -> Option<MemoryArea<PhysicalAddress>> {
-        while self.address < self.max_address {
+impl MemoryMapIterator {
+    /// Returns the next entry of the memory map, regardless of its type.
+    fn next_entry(&mut self) -> Option<&'static MmapEntry> {
+        if self.address < self.max_address {
             let current_entry = unsafe { &*(self.address as *const MmapEntry) };
 
             self.address += size_of::<u32>() + current_entry.size as usize;
 
+            Some(current_entry)
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for MemoryMapIterator {
+    type Item = MemoryArea<PhysicalAddress>;
+
+    fn next(&mut self) -> Option<MemoryArea<PhysicalAddress>> {
+        while let Some(current_entry) = self.next_entry() {
             if current_entry.mem_type == 1 {
                 // only a type of 1 is usable memory
-                return Some(MemoryArea::new(
-                    current_entry.base_addr,
-                    current_entry.length,
-                ));
+                let area = usable_area(current_entry.base_addr, current_entry.length);
+
+                if area.is_some() {
+                    return area;
+                }
             }
         }
         None
     }
 }
 
+/// Provides an iterator for the reserved areas of the memory map.
+pub struct ReservedMemoryIterator(MemoryMapIterator);
+
+impl Iterator for ReservedMemoryIterator {
+    type Item = (MemoryArea<PhysicalAddress>, ReservedKind);
+
+    fn next(&mut self) -> Option<(MemoryArea<PhysicalAddress>, ReservedKind)> {
+        while let Some(current_entry) = self.0.next_entry() {
+            let kind = match current_entry.mem_type {
+                1 => continue,
+                // Type 3 is reclaimable ACPI memory, type 4 is ACPI NVS memory.
+                3 | 4 => ReservedKind::Acpi,
+                _ => ReservedKind::Firmware,
+            };
+
+            return Some((
+                reserved_area(current_entry.base_addr, current_entry.length),
+                kind,
+            ));
+        }
+        None
+    }
+}
+
 /// Returns the memory map given by the boot loader.
 pub fn get_memory_map() -> MemoryMapIterator {
     MemoryMapIterator::new()
 }
+
+/// Returns the areas the memory map given by the boot loader marks as not
+/// usable.
+pub fn get_reserved_memory_map() -> ReservedMemoryIterator {
+    ReservedMemoryIterator(MemoryMapIterator::new())
+}
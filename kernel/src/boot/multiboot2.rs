@@ -1,16 +1,44 @@
 //! Handles the multiboot2 information structure.
 
+use super::{reserved_area, usable_area, KernelSection};
 use crate::arch::vga_buffer;
-use crate::memory::{Address, MemoryArea, PhysicalAddress, VirtualAddress};
+use crate::memory::reserved::ReservedKind;
+use crate::memory::{Address, MemoryArea, PageFlags, PhysicalAddress, VirtualAddress};
 use multiboot2;
+use multiboot2::ElfSectionFlags;
 use spin::Once;
 
 static BOOT_INFO: Once<&multiboot2::BootInformation> = Once::new();
 
+/// The physical address of the information structure.
+// This is only valid after init was called.
+static mut INFORMATION_STRUCTURE_ADDRESS: usize = 0;
+
+/// The type of the tag that holds the command line.
+const COMMAND_LINE_TAG_TYPE: u32 = 1;
+
+/// The type of the tag that describes the framebuffer.
+const FRAMEBUFFER_TAG_TYPE: u32 = 8;
+
+/// The type of the tag that ends the information structure.
+const END_TAG_TYPE: u32 = 0;
+
+/// The offset of the framebuffer type within the framebuffer tag.
+const FRAMEBUFFER_TYPE_OFFSET: usize = 29;
+
+/// The framebuffer type for EGA text.
+///
+/// Types 0 and 1 are indexed and direct color graphics.
+const EGA_TEXT_FRAMEBUFFER_TYPE: u8 = 2;
+
 /// Initializes the multiboot module.
 pub fn init(information_structure_address: usize) {
     assert_has_not_been_called!("The multiboot2 module should only be initialized once.");
     BOOT_INFO.call_once(|| unsafe { multiboot2::load(information_structure_address) });
+
+    unsafe {
+        INFORMATION_STRUCTURE_ADDRESS = information_structure_address;
+    }
 }
 
 /// Returns the VGA buffer information requested.
@@ -20,15 +48,22 @@ pub fn get_vga_info() -> vga_buffer::Info {
         Some(framebuffer_tag) => vga_buffer::Info {
             height: framebuffer_tag.height as usize,
             width: framebuffer_tag.width as usize,
-            address: VirtualAddress::from_usize(to_virtual!(framebuffer_tag.addr)), /* bpp: framebuffer_tag.
-                                                                                     * bpp,
-                                                                                     * pitch: framebuffer_tag.pitch as usize */
+            address: PhysicalAddress::from_usize(framebuffer_tag.addr as usize),
+            pitch: framebuffer_tag.pitch as usize,
+            bpp: framebuffer_tag.bpp,
+            mode: if get_framebuffer_type() == Some(EGA_TEXT_FRAMEBUFFER_TYPE) {
+                vga_buffer::VideoMode::Text
+            } else {
+                vga_buffer::VideoMode::Graphics
+            },
         },
         None => vga_buffer::Info {
             height: 25,
             width: 80,
-            address: VirtualAddress::from_usize(to_virtual!(0xb8000)), /* bpp: 16,
-                                                                        * pitch: 160 */
+            address: PhysicalAddress::from_usize(0xb8000),
+            pitch: 160,
+            bpp: 16,
+            mode: vga_buffer::VideoMode::Text,
         },
     }
 }
@@ -41,6 +76,51 @@ pub fn get_bootloader_name() -> &'static str {
     }
 }
 
+/// Returns the type of the framebuffer the boot loader set up.
+///
+/// The multiboot2 crate doesn't expose this field, so it is read from the
+/// tag directly.
+fn get_framebuffer_type() -> Option<u8> {
+    find_tag(FRAMEBUFFER_TAG_TYPE)
+        .map(|tag_address| unsafe { *((tag_address + FRAMEBUFFER_TYPE_OFFSET) as *const u8) })
+}
+
+/// Returns the kernel command line.
+///
+/// If the boot loader didn't pass one, the command line is empty.
+pub fn get_command_line() -> &'static str {
+    find_tag(COMMAND_LINE_TAG_TYPE)
+        .and_then(|tag_address| from_c_str!(tag_address + 8).ok())
+        .unwrap_or("")
+}
+
+/// Returns the virtual address of the first tag with the given type.
+fn find_tag(wanted_type: u32) -> Option<usize> {
+    let base = PhysicalAddress::from_usize(unsafe { INFORMATION_STRUCTURE_ADDRESS })
+        .to_virtual()
+        .as_usize();
+    let total_size = unsafe { *(base as *const u32) } as usize;
+
+    // The tags start after the total size and a reserved field.
+    let mut offset = 8;
+
+    while offset + 8 <= total_size {
+        let tag_type = unsafe { *((base + offset) as *const u32) };
+        let tag_size = unsafe { *((base + offset + 4) as *const u32) } as usize;
+
+        if tag_type == END_TAG_TYPE {
+            break;
+        } else if tag_type == wanted_type {
+            return Some(base + offset);
+        }
+
+        // Tags are 8-byte aligned.
+        offset += (tag_size + 7) & !7;
+    }
+
+    None
+}
+
 /// Returns the module entry for the initramfs.
 fn get_initramfs_module_entry() -> &'static multiboot2::ModuleTag {
     for module in BOOT_INFO.try().unwrap().module_tags() {
@@ -56,10 +136,10 @@ fn get_initramfs_module_entry() -> &'static multiboot2::ModuleTag {
 pub fn get_initramfs_area() -> MemoryArea<PhysicalAddress> {
     let module_entry = get_initramfs_module_entry();
 
-    MemoryArea::from_start_and_end(
+    MemoryArea::try_from_start_and_end(
         PhysicalAddress::from_usize(module_entry.start_address() as usize),
         PhysicalAddress::from_usize(module_entry.end_address() as usize),
-    )
+    ).expect("The boot loader placed the end of the initramfs before its start.")
 }
 
 /// Provides an iterator for the memory map.
@@ -88,9 +168,38 @@ impl Iterator for MemoryMapIterator {
     fn next(&mut self) -> Option<MemoryArea<PhysicalAddress>> {
         while let Some(next_area) = self.memory.next() {
             if next_area.area_type() == multiboot2::MemoryAreaType::Usable {
-                return Some(MemoryArea::new(
+                let area = usable_area(
                     PhysicalAddress::from_usize(next_area.start_address()),
                     next_area.size(),
+                );
+
+                if area.is_some() {
+                    return area;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Provides an iterator for the reserved areas of the memory map.
+pub struct ReservedMemoryIterator {
+    /// Iterator for current memory.
+    memory: multiboot2::MemoryAreaIter,
+}
+
+impl Iterator for ReservedMemoryIterator {
+    type Item = (MemoryArea<PhysicalAddress>, ReservedKind);
+
+    fn next(&mut self) -> Option<(MemoryArea<PhysicalAddress>, ReservedKind)> {
+        while let Some(next_area) = self.memory.next() {
+            if next_area.area_type() != multiboot2::MemoryAreaType::Usable {
+                return Some((
+                    reserved_area(
+                        PhysicalAddress::from_usize(next_area.start_address()),
+                        next_area.size(),
+                    ),
+                    ReservedKind::Firmware,
                 ));
             }
         }
@@ -102,3 +211,61 @@ impl Iterator for MemoryMapIterator {
 pub fn get_memory_map() -> MemoryMapIterator {
     MemoryMapIterator::new()
 }
+
+/// Returns the areas the memory map given by the boot loader marks as not
+/// usable.
+pub fn get_reserved_memory_map() -> ReservedMemoryIterator {
+    ReservedMemoryIterator {
+        memory: MemoryMapIterator::new().memory,
+    }
+}
+
+/// Provides an iterator over the sections of the kernel that are loaded into
+/// memory.
+pub struct KernelSectionIterator {
+    /// Iterator for the ELF sections.
+    sections: multiboot2::ElfSectionIter,
+}
+
+impl Iterator for KernelSectionIterator {
+    type Item = KernelSection;
+
+    fn next(&mut self) -> Option<KernelSection> {
+        while let Some(section) = self.sections.next() {
+            if !section.is_allocated() || section.start_address() == section.end_address() {
+                continue;
+            }
+
+            let mut flags = PageFlags::READABLE;
+
+            if section.flags().contains(ElfSectionFlags::WRITABLE) {
+                flags |= PageFlags::WRITABLE;
+            }
+
+            if section.flags().contains(ElfSectionFlags::EXECUTABLE) {
+                flags |= PageFlags::EXECUTABLE;
+            }
+
+            return Some(KernelSection {
+                area: MemoryArea::try_from_start_and_end(
+                    VirtualAddress::from_usize(section.start_address() as usize),
+                    VirtualAddress::from_usize(section.end_address() as usize),
+                ).expect("The boot loader reported a kernel section ending before its start."),
+                flags,
+            });
+        }
+        None
+    }
+}
+
+/// Returns the loaded sections of the kernel, if the boot loader provided
+/// them.
+pub fn elf_sections() -> Option<KernelSectionIterator> {
+    BOOT_INFO
+        .try()
+        .unwrap()
+        .elf_sections_tag()
+        .map(|tag| KernelSectionIterator {
+            sections: tag.sections(),
+        })
+}
@@ -0,0 +1,5 @@
+//! Provides collections that are shared throughout the kernel.
+
+pub mod ring_buffer;
+
+pub use self::ring_buffer::{RingBuffer, SyncRingBuffer};
@@ -0,0 +1,169 @@
+//! Provides bounded first-in first-out buffers.
+
+use alloc::Vec;
+use crate::sync::Mutex;
+
+/// A first-in first-out buffer with a fixed capacity.
+pub struct RingBuffer<T> {
+    /// The slots holding the elements.
+    slots: Vec<Option<T>>,
+    /// The index of the oldest element.
+    head: usize,
+    /// The number of elements in the buffer.
+    length: usize
+}
+
+impl<T> RingBuffer<T> {
+    /// Creates a new empty ring buffer that can hold `capacity` elements.
+    pub fn with_capacity(capacity: usize) -> RingBuffer<T> {
+        assert!(capacity > 0, "A ring buffer needs a capacity.");
+
+        let mut slots = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            slots.push(None);
+        }
+
+        RingBuffer {
+            slots,
+            head: 0,
+            length: 0
+        }
+    }
+
+    /// Adds an element to the end of the buffer.
+    ///
+    /// If the buffer is full, the element is handed back.
+    pub fn push(&mut self, element: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(element);
+        }
+
+        let index = (self.head + self.length) % self.capacity();
+        self.slots[index] = Some(element);
+        self.length += 1;
+
+        Ok(())
+    }
+
+    /// Removes the oldest element from the buffer.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let element = self.slots[self.head].take();
+        self.head = (self.head + 1) % self.capacity();
+        self.length -= 1;
+
+        element
+    }
+
+    /// Returns a reference to the oldest element in the buffer.
+    pub fn peek(&self) -> Option<&T> {
+        self.slots[self.head].as_ref()
+    }
+
+    /// Returns the number of elements in the buffer.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the maximum number of elements in the buffer.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Checks if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Checks if the buffer is full.
+    pub fn is_full(&self) -> bool {
+        self.length == self.capacity()
+    }
+}
+
+/// A ring buffer that can be shared with interrupt handlers.
+pub struct SyncRingBuffer<T> {
+    /// The buffer that is protected.
+    buffer: Mutex<RingBuffer<T>>
+}
+
+impl<T> SyncRingBuffer<T> {
+    /// Creates a new empty ring buffer that can hold `capacity` elements.
+    pub fn with_capacity(capacity: usize) -> SyncRingBuffer<T> {
+        SyncRingBuffer {
+            buffer: Mutex::new(RingBuffer::with_capacity(capacity))
+        }
+    }
+
+    /// Adds an element to the end of the buffer.
+    ///
+    /// If the buffer is full, the element is handed back.
+    pub fn push(&self, element: T) -> Result<(), T> {
+        self.buffer.lock().push(element)
+    }
+
+    /// Removes the oldest element from the buffer.
+    pub fn pop(&self) -> Option<T> {
+        self.buffer.lock().pop()
+    }
+
+    /// Checks if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.lock().is_empty()
+    }
+
+    /// Checks if the buffer is full.
+    pub fn is_full(&self) -> bool {
+        self.buffer.lock().is_full()
+    }
+}
+
+/// Tests for the ring buffer.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a new buffer is empty.
+    #[test]
+    fn test_empty() {
+        let mut buffer: RingBuffer<u8> = RingBuffer::with_capacity(4);
+
+        assert!(buffer.is_empty());
+        assert!(!buffer.is_full());
+        assert_eq!(buffer.peek(), None);
+        assert_eq!(buffer.pop(), None);
+    }
+
+    /// Tests that pushing to a full buffer fails.
+    #[test]
+    fn test_full() {
+        let mut buffer = RingBuffer::with_capacity(2);
+
+        assert_eq!(buffer.push(1), Ok(()));
+        assert_eq!(buffer.push(2), Ok(()));
+        assert!(buffer.is_full());
+        assert_eq!(buffer.push(3), Err(3));
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop(), Some(1));
+        assert!(!buffer.is_full());
+    }
+
+    /// Tests that elements keep their order when the buffer wraps around.
+    #[test]
+    fn test_wraparound() {
+        let mut buffer = RingBuffer::with_capacity(3);
+
+        for round in 0..5 {
+            assert_eq!(buffer.push(round * 2), Ok(()));
+            assert_eq!(buffer.push(round * 2 + 1), Ok(()));
+            assert_eq!(buffer.peek(), Some(&(round * 2)));
+            assert_eq!(buffer.pop(), Some(round * 2));
+            assert_eq!(buffer.pop(), Some(round * 2 + 1));
+        }
+
+        assert!(buffer.is_empty());
+    }
+}
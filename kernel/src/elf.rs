@@ -1,15 +1,21 @@
 //! Handles ELF files.
 
 use alloc::boxed::Box;
+use alloc::Vec;
 use core::fmt;
 use core::mem;
 use core::mem::size_of;
+use core::slice;
+use crate::arch::{self, Architecture};
 use crate::file_handle::FileHandle;
 use crate::initramfs;
 use crate::memory::address_space;
 use crate::memory::address_space::{AddressSpace, Segment};
-use crate::memory::{Address, MemoryArea, PageFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE};
-use crate::multitasking::{create_process, ProcessID};
+use crate::memory::{round_up_to_page_size, Address, MemoryArea, PageFlags, PhysicalAddress};
+use crate::memory::{VirtualAddress, PAGE_SIZE};
+use crate::multitasking::fd_table::FdTable;
+use crate::multitasking::stack::{AT_ENTRY, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::multitasking::{create_process, AuxiliaryEntry, ProcessID};
 
 /// Represents an ELF file.
 struct ElfFile {
@@ -22,44 +28,47 @@ struct ElfFile {
 impl ElfFile {
     /// Reads an ELF file from the initramfs.
     fn from_initramfs(name: &str) -> Result<ElfFile, ElfError> {
-        if let Ok(mut file_handle) = initramfs::open(name) {
-            Header::from_file_handle(&mut *file_handle).and_then(|header| {
-                let file_size = file_handle.len();
-
-                // Check if the program header is fully contained in the file.
-                if file_size
-                    < (header.program_header_offset as u64).saturating_add(
-                        (header.program_header_entry_num as u64)
-                            .saturating_mul(header.program_header_entry_size as u64)
-                    ) {
-                    return Err(ElfError::InvalidFile);
-                }
+        match initramfs::open(name) {
+            Ok(file_handle) => ElfFile::from_file_handle(file_handle),
+            Err(_) => Err(ElfError::FileNotExistant)
+        }
+    }
 
-                // Check that all the program header segments are fully contained in the file.
-                {
-                    let program_header_iterator = ProgramHeaderIterator {
-                        current_header_index: 0,
-                        header_num: header.program_header_entry_num as usize,
-                        header_size: header.program_header_entry_size as usize,
-                        header_offset: header.program_header_offset as u64,
-                        file_handle: &mut *file_handle
-                    };
-
-                    for program_header in program_header_iterator {
-                        if !program_header.is_fully_contained(file_size) {
-                            return Err(ElfError::InvalidFile);
-                        }
-                    }
-                }
+    /// Reads an ELF file from the given file handle.
+    fn from_file_handle(mut file_handle: Box<FileHandle>) -> Result<ElfFile, ElfError> {
+        let header = Header::from_file_handle(&mut *file_handle)?;
+        let file_size = file_handle.len();
 
-                Ok(ElfFile {
-                    file_handle,
-                    header
-                })
-            })
-        } else {
-            Err(ElfError::FileNotExistant)
+        // Check if the program header is fully contained in the file.
+        if file_size
+            < (header.program_header_offset as u64).saturating_add(
+                (header.program_header_entry_num as u64)
+                    .saturating_mul(header.program_header_entry_size as u64)
+            ) {
+            return Err(ElfError::InvalidFile);
         }
+
+        // Check that all the program header segments are fully contained in the file.
+        {
+            let program_header_iterator = ProgramHeaderIterator {
+                current_header_index: 0,
+                header_num: header.program_header_entry_num as usize,
+                header_size: header.program_header_entry_size as usize,
+                header_offset: header.program_header_offset as u64,
+                file_handle: &mut *file_handle
+            };
+
+            for program_header in program_header_iterator {
+                if !program_header.is_fully_contained(file_size) {
+                    return Err(ElfError::InvalidFile);
+                }
+            }
+        }
+
+        Ok(ElfFile {
+            file_handle,
+            header
+        })
     }
 
     /// Returns an iterator for the program header table.
@@ -94,7 +103,20 @@ pub enum ElfError {
     /// The file is not a valid ELF file.
     InvalidFile,
     /// The segments within the ELF file overlapped.
-    OverlappingSegments
+    ///
+    /// Segments must not even share a page, as each page can only be mapped
+    /// with the permissions of a single segment.
+    OverlappingSegments,
+    /// A segment covered the null page.
+    ///
+    /// The null page is never mapped, so that null pointer dereferences fault.
+    NullPageSegment,
+    /// A segment was both writable and executable.
+    WritableAndExecutable,
+    /// The file needs relocations that can't be applied by the kernel.
+    UnsupportedRelocation,
+    /// The process doesn't fit into its memory limit.
+    ExceedsMemoryLimit
 }
 
 /// Differentiates the endianness (byte order).
@@ -265,6 +287,13 @@ impl fmt::Debug for Header {
 }
 
 impl Header {
+    /// Returns true if the file is a position independent executable.
+    ///
+    /// These are linked at address zero and have to be relocated.
+    fn is_position_independent(&self) -> bool {
+        { self.elf_type } == ElfType::Shared
+    }
+
     /// Creates a ELF header from the file handle.
     fn from_file_handle(file_handle: &mut FileHandle) -> Result<Header, ElfError> {
         let file_size = file_handle.len();
@@ -307,7 +336,7 @@ impl Header {
             && { self.instruction_set }.is_native()
             && self.abi == 0
             && self.abi_version == 0
-            && { self.elf_type } == ElfType::Executable
+            && ({ self.elf_type } == ElfType::Executable || self.is_position_independent())
             && self.program_header_offset != 0
             && self.elf_class.is_native()
     }
@@ -371,6 +400,66 @@ impl ProgramHeader {
         file_size >= (self.offset as u64).saturating_add(self.size_in_file as u64)
             || self.size_in_file == 0
     }
+
+    /// Returns the flags the pages of the segment are mapped with.
+    ///
+    /// Segments that are both writable and executable are refused, so that
+    /// no user page ever allows both. Pages of segments that aren't marked
+    /// executable are never executable.
+    fn page_flags(&self) -> Result<PageFlags, ElfError> {
+        let header_flags = self.flags;
+
+        if header_flags.contains(SegmentFlags::WRITABLE | SegmentFlags::EXECUTABLE) {
+            return Err(ElfError::WritableAndExecutable);
+        }
+
+        let mut flags = PageFlags::USER_ACCESSIBLE;
+
+        if header_flags.contains(SegmentFlags::READABLE) {
+            flags |= PageFlags::READABLE;
+        }
+
+        if header_flags.contains(SegmentFlags::WRITABLE) {
+            flags |= PageFlags::WRITABLE;
+        }
+
+        if header_flags.contains(SegmentFlags::EXECUTABLE) {
+            flags |= PageFlags::EXECUTABLE;
+        }
+
+        Ok(flags)
+    }
+
+    /// Returns the offset in the file of the given area in memory, if the
+    /// whole area is loaded from the file.
+    fn file_offset_of(&self, area: MemoryArea<VirtualAddress>) -> Option<u64> {
+        let start = self.virtual_address;
+
+        if area.start_address() >= start && area.end_address() <= start + self.size_in_file {
+            Some((self.offset + (area.start_address() - start)) as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the segment and the page it ends in lie below the end
+    /// of the address space.
+    fn fits_in_address_space(&self) -> bool {
+        self.virtual_address
+            .as_usize()
+            .checked_add(self.size_in_memory)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .is_some()
+    }
+
+    /// Returns the area of all the pages the segment occupies in memory.
+    ///
+    /// The segment has to fit in the address space.
+    fn page_area(&self) -> MemoryArea<VirtualAddress> {
+        let start = self.virtual_address.page_align_down();
+        let end = self.virtual_address + self.size_in_memory;
+        MemoryArea::from_start_and_end(start, end.page_align_up())
+    }
 }
 
 /// Provides an iterator for the program headers.
@@ -416,59 +505,262 @@ impl<'a> Iterator for ProgramHeaderIterator<'a> {
     }
 }
 
+/// The tag that ends the dynamic section.
+const DT_NULL: u64 = 0;
+/// The tag of the size of the PLT relocations.
+const DT_PLTRELSZ: u64 = 2;
+/// The tag of the address of the relocations with addends.
+const DT_RELA: u64 = 7;
+/// The tag of the size of the relocations with addends.
+const DT_RELASZ: u64 = 8;
+/// The tag of the size of a relocation with addend.
+const DT_RELAENT: u64 = 9;
+/// The tag of the address of the relocations without addends.
+const DT_REL: u64 = 17;
+
+/// A relocation that does nothing.
+const R_X86_64_NONE: u32 = 0;
+/// A relocation that adds the load base to the addend.
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Represents an entry of the dynamic section.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct DynamicEntry {
+    /// The type of the entry.
+    tag: u64,
+    /// The value or address of the entry.
+    value: u64
+}
+
+/// Represents a relocation with an addend.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Relocation {
+    /// The address to apply the relocation at, relative to the load base.
+    offset: usize,
+    /// The symbol index and the type of the relocation.
+    info: u64,
+    /// The value to add.
+    addend: i64
+}
+
+impl Relocation {
+    /// Returns the type of the relocation.
+    fn relocation_type(&self) -> u32 {
+        self.info as u32
+    }
+
+    /// Returns the address to write to and the value to write when the file
+    /// is loaded at the given base.
+    ///
+    /// Returns `None` for relocations that don't need to be applied.
+    fn apply_at(&self, load_base: usize) -> Result<Option<(VirtualAddress, usize)>, ElfError> {
+        match self.relocation_type() {
+            R_X86_64_NONE => Ok(None),
+            R_X86_64_RELATIVE => {
+                let address = VirtualAddress::from_usize(load_base.wrapping_add(self.offset));
+                let value = load_base.wrapping_add(self.addend as usize);
+
+                Ok(Some((address, value)))
+            },
+            _ => Err(ElfError::UnsupportedRelocation)
+        }
+    }
+}
+
+/// Describes where the relocations of a file are.
+#[derive(Debug, PartialEq)]
+struct RelocationTable {
+    /// The address of the table, relative to the load base.
+    address: usize,
+    /// The size of the table in bytes.
+    size: usize,
+    /// The size of a single relocation.
+    entry_size: usize
+}
+
+impl RelocationTable {
+    /// Finds the relocation table in the given dynamic entries.
+    ///
+    /// Returns `None` if there are no relocations. Only relocations with
+    /// addends outside of the PLT are supported.
+    fn from_dynamic_entries<I>(entries: I) -> Result<Option<RelocationTable>, ElfError>
+    where
+        I: Iterator<Item = DynamicEntry>
+    {
+        let mut address = None;
+        let mut size = 0;
+        let mut entry_size = size_of::<Relocation>();
+
+        for entry in entries {
+            match entry.tag {
+                DT_NULL => break,
+                DT_RELA => address = Some(entry.value as usize),
+                DT_RELASZ => size = entry.value as usize,
+                DT_RELAENT => entry_size = entry.value as usize,
+                DT_PLTRELSZ if entry.value != 0 => return Err(ElfError::UnsupportedRelocation),
+                DT_REL => return Err(ElfError::UnsupportedRelocation),
+                _ => ()
+            }
+        }
+
+        if entry_size < size_of::<Relocation>() {
+            return Err(ElfError::InvalidFile);
+        }
+
+        Ok(address.map(|address| RelocationTable {
+            address,
+            size,
+            entry_size
+        }))
+    }
+
+    /// Returns the number of relocations in the table.
+    fn len(&self) -> usize {
+        self.size / self.entry_size
+    }
+}
+
+/// Reads a value of the given type at the given position in the file.
+///
+/// # Safety
+/// - Every bit pattern must be a valid value of the type.
+unsafe fn read_value<T>(file_handle: &mut FileHandle, position: u64) -> Result<T, ElfError> {
+    let mut value: T = mem::uninitialized();
+
+    {
+        let buffer = slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, size_of::<T>());
+
+        if file_handle.read_at(buffer, position).is_err() {
+            return Err(ElfError::InvalidFile);
+        }
+    }
+
+    Ok(value)
+}
+
 /// Creates a new process from the given file on the initramfs.
-pub fn process_from_initramfs_file(name: &str) -> Result<ProcessID, ElfError> {
-    ElfFile::from_initramfs(name).and_then(|file| process_from_elf_file(file))
+///
+/// The process gets its name as the first argument, followed by the given
+/// arguments. Its user memory is limited to `memory_limit` bytes. Without a
+/// file descriptor table, it shares the open files of the current process.
+pub fn process_from_initramfs_file(
+    name: &str,
+    arguments: &[&str],
+    memory_limit: usize,
+    fd_table: Option<FdTable>
+) -> Result<ProcessID, ElfError> {
+    ElfFile::from_initramfs(name)
+        .and_then(|file| process_from_elf_file(file, name, arguments, memory_limit, fd_table))
 }
 
 /// Creates a new process from the given ELF file handle.
-fn process_from_elf_file(mut file: ElfFile) -> Result<ProcessID, ElfError> {
+fn process_from_elf_file(
+    file: ElfFile,
+    name: &str,
+    arguments: &[&str],
+    memory_limit: usize,
+    fd_table: Option<FdTable>
+) -> Result<ProcessID, ElfError> {
+    let LoadedProgram {
+        address_space,
+        entry,
+        auxiliary_vector
+    } = load_elf_file(file, memory_limit)?;
+
+    create_process(address_space, entry, name, arguments, &auxiliary_vector, fd_table)
+        .ok_or(ElfError::ExceedsMemoryLimit)
+}
+
+/// A program that was loaded into a new address space.
+struct LoadedProgram {
+    /// The address space that holds the segments of the program.
+    address_space: AddressSpace,
+    /// The address the program starts executing at.
+    entry: VirtualAddress,
+    /// The auxiliary vector that is passed to the program.
+    auxiliary_vector: Vec<AuxiliaryEntry>
+}
+
+/// Loads the segments of the given ELF file into a new address space with
+/// the given memory limit.
+///
+/// Position independent executables are loaded at `PIE_LOAD_BASE` and their
+/// relative relocations are applied before the process starts.
+fn load_elf_file(mut file: ElfFile, memory_limit: usize) -> Result<LoadedProgram, ElfError> {
     let mut address_space = AddressSpace::new();
+    address_space.set_memory_limit(memory_limit);
+    let mut loaded_segments: Vec<ProgramHeader> = Vec::new();
+    let mut dynamic_segment = None;
+    let mut program_headers_address = None;
+    let program_header_offset = file.header.program_header_offset;
+
+    let load_base = if file.header.is_position_independent() {
+        arch::Current::PIE_LOAD_BASE.as_usize()
+    } else {
+        0
+    };
 
     {
         let mut iterator = file.program_headers();
 
         // For each segment.
-        while let Some(program_header) = iterator.next() {
-            if { program_header.segment_type } != SegmentType::Load {
-                continue;
+        while let Some(mut program_header) = iterator.next() {
+            match { program_header.segment_type } {
+                SegmentType::Load => (),
+                SegmentType::Dynamic => {
+                    dynamic_segment = Some(program_header);
+                    continue;
+                },
+                // There is no dynamic linker to run.
+                SegmentType::Interpreter => return Err(ElfError::WrongType),
+                _ => continue
             }
 
-            // Convert the flags to page flags.
-            let mut flags = PageFlags::USER_ACCESSIBLE;
-            let header_flags = program_header.flags;
+            program_header.virtual_address =
+                relocate({ program_header.virtual_address }, load_base)?;
 
-            if header_flags.contains(SegmentFlags::READABLE) {
-                flags |= PageFlags::READABLE;
+            // The file data must fit into the segment and its end must be addressable.
+            if !program_header.fits_in_address_space()
+                || program_header.size_in_file > program_header.size_in_memory
+            {
+                return Err(ElfError::InvalidFile);
             }
 
-            if header_flags.contains(SegmentFlags::WRITABLE) {
-                flags |= PageFlags::WRITABLE;
-            }
+            let flags = program_header.page_flags()?;
+
+            // A shared page would get the permissions of both segments.
+            let page_area = program_header.page_area();
 
-            if header_flags.contains(SegmentFlags::EXECUTABLE) {
-                flags |= PageFlags::EXECUTABLE;
+            if loaded_segments
+                .iter()
+                .any(|segment| segment.page_area().overlaps_with(page_area))
+            {
+                return Err(ElfError::OverlappingSegments);
             }
 
-            let segment = Segment::new(
-                MemoryArea::new(
-                    program_header.virtual_address,
-                    program_header.size_in_memory
-                ),
-                flags,
-                address_space::SegmentType::FromFile
+            let segment_area = MemoryArea::new(
+                program_header.virtual_address,
+                program_header.size_in_memory
             );
 
+            if !address_space.can_reserve(segment_area) {
+                return Err(ElfError::ExceedsMemoryLimit);
+            }
+
+            let segment = Segment::new(segment_area, flags, address_space::SegmentType::FromFile);
+
+            if segment.overlaps_null_page() {
+                return Err(ElfError::NullPageSegment);
+            }
+
             if !address_space.add_segment(segment) {
                 return Err(ElfError::OverlappingSegments);
             }
 
             // Map all the segments (page by page).
-            let pages_in_file = if program_header.size_in_file != 0 {
-                (program_header.size_in_file - 1) / PAGE_SIZE + 1
-            } else {
-                0
-            };
+            let pages_in_file = round_up_to_page_size(program_header.size_in_file) / PAGE_SIZE;
             for i in 0..pages_in_file {
                 let mut segment_data_buffer: [u8; crate::memory::PAGE_SIZE] =
                     unsafe { mem::uninitialized() };
@@ -488,15 +780,14 @@ fn process_from_elf_file(mut file: ElfFile) -> Result<ProcessID, ElfError> {
                 }
 
                 address_space
-                    .write_to(segment_data, program_header.virtual_address + i * PAGE_SIZE);
+                    .write_to(segment_data, program_header.virtual_address + i * PAGE_SIZE)
+                    .map_err(|_| ElfError::InvalidFile)?;
             }
 
-            let last_mapped_page = (program_header.virtual_address + program_header.size_in_file
-                - 1)
-                .as_usize() / PAGE_SIZE + 1;
-            let last_page_to_map = (program_header.virtual_address + program_header.size_in_memory
-                - 1)
-                .as_usize() / PAGE_SIZE + 1;
+            let last_mapped_page =
+                (program_header.virtual_address + program_header.size_in_file - 1).page_num() + 1;
+            let last_page_to_map =
+                (program_header.virtual_address + program_header.size_in_memory - 1).page_num() + 1;
             let page_aligned_start_address = program_header.virtual_address.page_align_down();
 
             for i in 0..last_page_to_map - last_mapped_page {
@@ -508,10 +799,452 @@ fn process_from_elf_file(mut file: ElfFile) -> Result<ProcessID, ElfError> {
                     program_header.virtual_address + program_header.size_in_file,
                     program_header.size_in_memory - program_header.size_in_file
                 );
-                address_space.zero_mapped_area(area_to_zero);
+                address_space
+                    .zero_mapped_area(area_to_zero)
+                    .map_err(|_| ElfError::InvalidFile)?;
+            }
+
+            // The runtime of the program may want to read its program headers.
+            let offset_in_segment = program_header_offset.wrapping_sub(program_header.offset);
+
+            if offset_in_segment < program_header.size_in_file {
+                program_headers_address = Some(program_header.virtual_address + offset_in_segment);
+            }
+
+            loaded_segments.push(program_header);
+        }
+    }
+
+    if file.header.is_position_independent() {
+        if let Some(dynamic_segment) = dynamic_segment {
+            apply_relocations(
+                &mut file,
+                &mut address_space,
+                &loaded_segments,
+                &dynamic_segment,
+                load_base
+            )?;
+        }
+    }
+
+    let entry = relocate({ file.header.program_entry }, load_base)?;
+
+    let mut auxiliary_vector = Vec::new();
+
+    if let Some(address) = program_headers_address {
+        auxiliary_vector.push(AuxiliaryEntry {
+            key: AT_PHDR,
+            value: address.as_usize()
+        });
+    }
+
+    auxiliary_vector.push(AuxiliaryEntry {
+        key: AT_PHENT,
+        value: file.header.program_header_entry_size as usize
+    });
+    auxiliary_vector.push(AuxiliaryEntry {
+        key: AT_PHNUM,
+        value: file.header.program_header_entry_num as usize
+    });
+    auxiliary_vector.push(AuxiliaryEntry {
+        key: AT_PAGESZ,
+        value: PAGE_SIZE
+    });
+    auxiliary_vector.push(AuxiliaryEntry {
+        key: AT_ENTRY,
+        value: entry.as_usize()
+    });
+
+    Ok(LoadedProgram {
+        address_space,
+        entry,
+        auxiliary_vector
+    })
+}
+
+/// Moves the given address of the file by the load base.
+///
+/// Fails if the address would wrap around, which only a broken file causes.
+fn relocate(address: VirtualAddress, load_base: usize) -> Result<VirtualAddress, ElfError> {
+    address
+        .as_usize()
+        .checked_add(load_base)
+        .map(VirtualAddress::from_usize)
+        .ok_or(ElfError::InvalidFile)
+}
+
+/// Applies the relocations listed in the dynamic segment.
+fn apply_relocations(
+    file: &mut ElfFile,
+    address_space: &mut AddressSpace,
+    loaded_segments: &[ProgramHeader],
+    dynamic_segment: &ProgramHeader,
+    load_base: usize
+) -> Result<(), ElfError> {
+    let entry_num = dynamic_segment.size_in_file / size_of::<DynamicEntry>();
+    let mut entries = Vec::with_capacity(entry_num);
+
+    for i in 0..entry_num {
+        let position = (dynamic_segment.offset + i * size_of::<DynamicEntry>()) as u64;
+
+        entries.push(unsafe { read_value::<DynamicEntry>(&mut *file.file_handle, position)? });
+    }
+
+    let table = match RelocationTable::from_dynamic_entries(entries.into_iter())? {
+        Some(table) => table,
+        None => return Ok(())
+    };
+
+    // The table itself is part of a loaded segment.
+    let table_start = relocate(VirtualAddress::from_usize(table.address), load_base)?;
+    let table_area = MemoryArea::new(table_start, table.size);
+    let table_offset = loaded_segments
+        .iter()
+        .filter_map(|segment| segment.file_offset_of(table_area))
+        .next()
+        .ok_or(ElfError::InvalidFile)?;
+
+    for i in 0..table.len() {
+        let position = table_offset + (i * table.entry_size) as u64;
+        let relocation = unsafe { read_value::<Relocation>(&mut *file.file_handle, position)? };
+
+        if let Some((address, value)) = relocation.apply_at(load_base)? {
+            if !address_space.contains_area(MemoryArea::new(address, size_of::<usize>())) {
+                return Err(ElfError::InvalidFile);
             }
+
+            unsafe {
+                address_space
+                    .write_val(value, address)
+                    .map_err(|_| ElfError::InvalidFile)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Tests for the ELF loader.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a loadable program header with the given flags.
+    fn program_header(flags: SegmentFlags, address: usize, size: usize) -> ProgramHeader {
+        ProgramHeader {
+            segment_type: SegmentType::Load,
+            flags,
+            offset: 0,
+            virtual_address: VirtualAddress::from_usize(address),
+            physical_address: PhysicalAddress::from_usize(0),
+            size_in_file: size,
+            size_in_memory: size,
+            align: PAGE_SIZE
+        }
+    }
+
+    /// Tests that writable and executable segments are refused.
+    #[test]
+    fn test_writable_and_executable() {
+        let header = program_header(
+            SegmentFlags::READABLE | SegmentFlags::WRITABLE | SegmentFlags::EXECUTABLE,
+            0x40_0000,
+            0x1000
+        );
+
+        match header.page_flags() {
+            Err(ElfError::WritableAndExecutable) => (),
+            other => panic!("A W+X segment was accepted: {:?}", other)
+        }
+    }
+
+    /// Tests that the pages get exactly the requested permissions.
+    #[test]
+    fn test_page_flags() {
+        let text = program_header(SegmentFlags::READABLE | SegmentFlags::EXECUTABLE, 0, 0);
+        let data = program_header(SegmentFlags::READABLE | SegmentFlags::WRITABLE, 0, 0);
+        let rodata = program_header(SegmentFlags::READABLE, 0, 0);
+
+        assert_eq!(
+            text.page_flags().unwrap(),
+            PageFlags::USER_ACCESSIBLE | PageFlags::READABLE | PageFlags::EXECUTABLE
+        );
+        assert_eq!(
+            data.page_flags().unwrap(),
+            PageFlags::USER_ACCESSIBLE | PageFlags::READABLE | PageFlags::WRITABLE
+        );
+        assert_eq!(
+            rodata.page_flags().unwrap(),
+            PageFlags::USER_ACCESSIBLE | PageFlags::READABLE
+        );
+    }
+
+    /// Tests that segments sharing a page are detected.
+    #[test]
+    fn test_page_area() {
+        let text = program_header(SegmentFlags::READABLE, 0x40_0000, 0x1800);
+        let data = program_header(SegmentFlags::READABLE, 0x40_1800, 0x100);
+        let bss = program_header(SegmentFlags::READABLE, 0x40_2000, 0x10);
+
+        assert_eq!(
+            text.page_area(),
+            MemoryArea::new(VirtualAddress::from_usize(0x40_0000), 0x2000)
+        );
+        assert!(text.page_area().overlaps_with(data.page_area()));
+        assert!(!text.page_area().overlaps_with(bss.page_area()));
+    }
+
+    /// Tests that segments reaching past the end of the address space are
+    /// detected.
+    #[test]
+    fn test_fits_in_address_space() {
+        let fitting = program_header(SegmentFlags::READABLE, 0x40_0000, 0x1800);
+        let below_last_page = program_header(SegmentFlags::READABLE, !0 - 0x1fff, 0x1000);
+        let in_last_page = program_header(SegmentFlags::READABLE, !0 - 0xfff, 0x800);
+        let wrapping = program_header(SegmentFlags::READABLE, !0 - 0xfff, 0x1000);
+
+        assert!(fitting.fits_in_address_space());
+        assert!(below_last_page.fits_in_address_space());
+        assert!(!in_last_page.fits_in_address_space());
+        assert!(!wrapping.fits_in_address_space());
+        assert!(relocate(VirtualAddress::from_usize(!0 - 0xfff), 0x2000).is_err());
+    }
+
+    /// Tests finding the relocations in the dynamic section.
+    #[test]
+    fn test_relocation_table() {
+        let entries = [
+            DynamicEntry { tag: 0x6fff_fffb, value: 0x800_0000 },
+            DynamicEntry { tag: DT_RELA, value: 0x1f8 },
+            DynamicEntry { tag: DT_RELASZ, value: 0x48 },
+            DynamicEntry { tag: DT_RELAENT, value: 0x18 },
+            DynamicEntry { tag: DT_NULL, value: 0 },
+            DynamicEntry { tag: DT_REL, value: 0x300 }
+        ];
+
+        assert_eq!(
+            RelocationTable::from_dynamic_entries(entries.iter().cloned()).unwrap(),
+            Some(RelocationTable {
+                address: 0x1f8,
+                size: 0x48,
+                entry_size: 0x18
+            })
+        );
+        assert_eq!(
+            RelocationTable::from_dynamic_entries(entries[..1].iter().cloned()).unwrap(),
+            None
+        );
+        assert!(RelocationTable::from_dynamic_entries(entries[1..].iter().cloned()).is_ok());
+        assert!(RelocationTable::from_dynamic_entries(entries[5..].iter().cloned()).is_err());
+    }
+
+    /// Tests that a relocated pointer points into the loaded file.
+    #[test]
+    fn test_relative_relocation() {
+        let load_base = 0x5555_5555_4000;
+        let relocation = Relocation {
+            offset: 0x2010,
+            info: R_X86_64_RELATIVE as u64,
+            addend: 0x1139
+        };
+
+        assert_eq!(
+            relocation.apply_at(load_base).unwrap(),
+            Some((VirtualAddress::from_usize(0x5555_5555_6010), 0x5555_5555_5139))
+        );
+
+        let none = Relocation {
+            info: R_X86_64_NONE as u64,
+            ..relocation
+        };
+        let absolute = Relocation {
+            info: 1 | 5 << 32,
+            ..relocation
+        };
+
+        assert_eq!(none.apply_at(load_base).unwrap(), None);
+        assert!(absolute.apply_at(load_base).is_err());
+    }
+
+    /// Tests finding the file data of an area in memory.
+    #[test]
+    fn test_file_offset_of() {
+        let mut data = program_header(SegmentFlags::READABLE, 0x40_2e10, 0x200);
+        data.offset = 0x1e10;
+        data.size_in_memory = 0x400;
+
+        let area = |address, length| MemoryArea::new(VirtualAddress::from_usize(address), length);
+
+        assert_eq!(data.file_offset_of(area(0x40_2e10, 0x18)), Some(0x1e10));
+        assert_eq!(data.file_offset_of(area(0x40_2f00, 0x110)), Some(0x1f00));
+        assert_eq!(data.file_offset_of(area(0x40_2f00, 0x111)), None);
+        assert_eq!(data.file_offset_of(area(0x40_2e00, 0x18)), None);
+    }
+}
+
+/// Self-tests that load synthetic ELF files.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+    use core::mem::size_of_val;
+    use core::ptr;
+    use crate::initramfs::FileDescriptor;
+
+    /// The size of the synthetic ELF files.
+    const IMAGE_SIZE: usize = 0x200;
+
+    /// The offset of the segment data in the synthetic ELF files.
+    const DATA_OFFSET: usize = 0x100;
+
+    /// Copies the value into the image at the given offset.
+    fn put<T>(image: &mut [u8], offset: usize, value: &T) {
+        assert!(offset + size_of::<T>() <= image.len());
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                value as *const T as *const u8,
+                image[offset..].as_mut_ptr(),
+                size_of::<T>()
+            );
+        }
+    }
+
+    /// Creates a loadable program header for the segment data of the image.
+    fn load_header(address: usize, size: usize) -> ProgramHeader {
+        ProgramHeader {
+            segment_type: SegmentType::Load,
+            flags: SegmentFlags::READABLE | SegmentFlags::WRITABLE,
+            offset: DATA_OFFSET,
+            virtual_address: VirtualAddress::from_usize(address),
+            physical_address: PhysicalAddress::from_usize(0),
+            size_in_file: size,
+            size_in_memory: size,
+            align: PAGE_SIZE
+        }
+    }
+
+    /// Creates an ELF image with the given program headers.
+    fn elf_image(elf_type: ElfType, program_headers: &[ProgramHeader]) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.resize(IMAGE_SIZE, 0);
+
+        let header = Header {
+            magic: [0x7f, 'E' as u8, 'L' as u8, 'F' as u8],
+            elf_class: ELFClass::Bit64,
+            endianness: Endianness::Little,
+            version: 1,
+            abi: 0,
+            abi_version: 0,
+            padding: [0; 7],
+            elf_type,
+            instruction_set: InstructionSet::x86_64,
+            elf_version: 1,
+            program_entry: VirtualAddress::from_usize(0),
+            program_header_offset: size_of::<Header>(),
+            section_header_offset: 0,
+            flags: 0,
+            header_size: size_of::<Header>() as u16,
+            program_header_entry_size: size_of::<ProgramHeader>() as u16,
+            program_header_entry_num: program_headers.len() as u16,
+            section_header_entry_size: 0,
+            section_header_entry_num: 0,
+            name_string_table_index: 0
+        };
+
+        put(&mut image, 0, &header);
+
+        for (index, program_header) in program_headers.iter().enumerate() {
+            let offset = size_of::<Header>() + index * size_of::<ProgramHeader>();
+
+            put(&mut image, offset, program_header);
         }
+
+        image
+    }
+
+    /// Loads the ELF file in the given image.
+    fn load(image: &[u8]) -> Result<LoadedProgram, ElfError> {
+        let start = VirtualAddress::from_usize(image.as_ptr() as usize);
+        let area = MemoryArea::new(start, image.len());
+
+        ElfFile::from_file_handle(Box::new(FileDescriptor::new(area)))
+            .and_then(|file| load_elf_file(file, address_space::DEFAULT_MEMORY_LIMIT))
+    }
+
+    /// Checks that the loader leaves the null page unmapped and refuses a
+    /// segment covering it.
+    fn test_null_page_segment() -> Result<(), &'static str> {
+        let address = VirtualAddress::from_usize(0x40_0000);
+        let mut image = elf_image(ElfType::Executable, &[load_header(address.as_usize(), 8)]);
+        put(&mut image, DATA_OFFSET, &0x1234_5678usize);
+
+        let mut program = load(&image).map_err(|_| "Loading a valid file failed.")?;
+
+        if unsafe { program.address_space.read_val::<usize>(address) } != Some(0x1234_5678) {
+            return Err("The segment data wasn't loaded.");
+        }
+
+        let null = VirtualAddress::from_usize(0);
+        if unsafe { program.address_space.read_val::<u8>(null) }.is_some() {
+            return Err("The null page is mapped.");
+        }
+
+        let image = elf_image(ElfType::Executable, &[load_header(0, 8)]);
+
+        match load(&image) {
+            Err(ElfError::NullPageSegment) => Ok(()),
+            Err(_) => Err("Loading the file failed for another reason."),
+            Ok(_) => Err("A segment covering the null page was loaded.")
+        }
+    }
+
+    /// Checks that a position independent executable is moved to the load
+    /// base and that its relocations are applied.
+    fn test_position_independent() -> Result<(), &'static str> {
+        let load_base = arch::Current::PIE_LOAD_BASE;
+        let dynamic_offset = 0x20;
+        let dynamic_entries = [
+            DynamicEntry { tag: DT_RELA, value: 0x8 },
+            DynamicEntry { tag: DT_RELASZ, value: size_of::<Relocation>() as u64 },
+            DynamicEntry { tag: DT_RELAENT, value: size_of::<Relocation>() as u64 },
+            DynamicEntry { tag: DT_NULL, value: 0 }
+        ];
+        let dynamic_header = ProgramHeader {
+            segment_type: SegmentType::Dynamic,
+            offset: DATA_OFFSET + dynamic_offset,
+            virtual_address: VirtualAddress::from_usize(dynamic_offset),
+            size_in_file: size_of_val(&dynamic_entries),
+            size_in_memory: size_of_val(&dynamic_entries),
+            ..load_header(0, 0)
+        };
+        let relocation = Relocation {
+            offset: 0,
+            info: R_X86_64_RELATIVE as u64,
+            addend: 0x1234
+        };
+
+        let mut image = elf_image(
+            ElfType::Shared,
+            &[load_header(0, IMAGE_SIZE - DATA_OFFSET), dynamic_header]
+        );
+        put(&mut image, DATA_OFFSET + 0x8, &relocation);
+        put(&mut image, DATA_OFFSET + dynamic_offset, &dynamic_entries);
+
+        let mut program = load(&image).map_err(|_| "Loading a PIE failed.")?;
+
+        if program.entry != load_base {
+            return Err("The entry point wasn't moved to the load base.");
+        }
+
+        let value = unsafe { program.address_space.read_val::<usize>(load_base) };
+
+        if value != Some(load_base.as_usize() + 0x1234) {
+            return Err("The relative relocation wasn't applied.");
+        }
+
+        Ok(())
     }
 
-    Ok(create_process(address_space, file.header.program_entry))
+    register_selftest!(NULL_PAGE_SEGMENT, test_null_page_segment);
+    register_selftest!(POSITION_INDEPENDENT, test_position_independent);
 }
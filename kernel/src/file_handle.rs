@@ -1,7 +1,7 @@
 //! This modules aims to offer an abstraction for accessing files.
 
 /// Abstracts the different kinds of errors that can occur with file operations.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum FileError {
     /// A seek before byte 0 was attempted.
     SeekBeforeStart,
@@ -10,7 +10,15 @@ pub enum FileError {
     /// The file was not found.
     FileNotFound,
     /// The filesystem is invalid.
-    InvalidFilesystem
+    InvalidFilesystem,
+    /// The path is not absolute.
+    InvalidPath,
+    /// The path refers to a file where a directory is needed.
+    NotADirectory,
+    /// The file or filesystem can't be modified.
+    ReadOnly,
+    /// A filesystem is already mounted at the path.
+    AlreadyMounted
 }
 
 /// A result of a file operation.
@@ -28,13 +36,18 @@ pub enum SeekFrom {
 }
 
 /// Everything that abstracts a file should implement this.
-pub trait FileHandle {
+pub trait FileHandle: Send {
     /// Sets the current seek position. Returns the offset from the beginning.
     fn seek(&mut self, position: SeekFrom) -> Result<u64>;
 
     /// Reads `length` bytes into `buffer`.
     fn read(&mut self, buffer: &mut [u8]) -> Result<()>;
 
+    /// Writes `buffer` at the current seek position.
+    fn write(&mut self, _buffer: &[u8]) -> Result<()> {
+        Err(FileError::ReadOnly)
+    }
+
     /// Reads `length` bytes into `buffer` at offset `position` from the
     /// beginning.
     fn read_at(&mut self, buffer: &mut [u8], position: u64) -> Result<()> {
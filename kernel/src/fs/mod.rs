@@ -0,0 +1,13 @@
+//! Provides the filesystems of the kernel.
+
+pub mod vfs;
+
+use alloc::arc::Arc;
+use crate::initramfs::Initramfs;
+
+/// Mounts the initial filesystems.
+pub fn init() {
+    assert_has_not_been_called!("The filesystems should only be initialized once.");
+
+    vfs::mount("/", Arc::new(Initramfs)).expect("The initramfs could not be mounted.");
+}
@@ -0,0 +1,332 @@
+//! Presents all mounted filesystems in a single namespace.
+//!
+//! Each filesystem is mounted at a directory. Paths are resolved using the
+//! mount point with the longest matching prefix and the rest of the path is
+//! passed on to the filesystem mounted there.
+
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use alloc::{String, Vec};
+use crate::file_handle::{FileError, FileHandle, Result};
+use crate::sync::Mutex;
+
+lazy_static! {
+    /// The mounted filesystems.
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+/// The maximum length of a name in a `DirectoryEntryInfo` in bytes.
+///
+/// Longer names are truncated.
+pub const MAX_FILE_NAME_LENGTH: usize = 64;
+
+/// The kinds of entries in a directory.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    /// A regular file.
+    File = 0,
+    /// A directory.
+    Directory = 1
+}
+
+/// An entry in a directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectoryEntry {
+    /// The name of the entry within the directory.
+    pub name: String,
+    /// The kind of the entry.
+    pub kind: FileKind
+}
+
+impl DirectoryEntry {
+    /// Returns the entry in the layout that is passed to user space.
+    pub fn info(&self) -> DirectoryEntryInfo {
+        let name = truncate_name(&self.name);
+        let mut name_bytes = [0; MAX_FILE_NAME_LENGTH];
+        name_bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+        DirectoryEntryInfo {
+            kind: self.kind,
+            name: name_bytes,
+            name_length: name.len()
+        }
+    }
+}
+
+/// An entry in a directory, as it is passed to user space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DirectoryEntryInfo {
+    /// The kind of the entry.
+    pub kind: FileKind,
+    /// The name of the entry, padded with zeros.
+    pub name: [u8; MAX_FILE_NAME_LENGTH],
+    /// The length of the name of the entry.
+    pub name_length: usize
+}
+
+/// Everything that provides files should implement this.
+///
+/// All paths are absolute and normalized, relative to the root of the filesystem.
+pub trait Filesystem: Send + Sync {
+    /// Opens the file at the given path.
+    fn open(&self, path: &str) -> Result<Box<FileHandle>>;
+
+    /// Lists the entries of the directory at the given path.
+    fn readdir(&self, path: &str) -> Result<Vec<DirectoryEntry>>;
+
+    /// Creates an empty file at the given path and opens it.
+    fn create(&self, _path: &str) -> Result<Box<FileHandle>> {
+        Err(FileError::ReadOnly)
+    }
+
+    /// Removes the file at the given path.
+    fn remove(&self, _path: &str) -> Result<()> {
+        Err(FileError::ReadOnly)
+    }
+}
+
+/// A filesystem mounted at a directory.
+struct Mount {
+    /// The normalized path of the directory.
+    path: String,
+    /// The filesystem itself.
+    filesystem: Arc<Filesystem>
+}
+
+/// Mounts the filesystem at the given directory.
+pub fn mount(path: &str, filesystem: Arc<Filesystem>) -> Result<()> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FileError::AlreadyMounted);
+    }
+
+    mounts.push(Mount { path, filesystem });
+
+    Ok(())
+}
+
+/// Opens the file at the given path.
+pub fn open(path: &str) -> Result<Box<FileHandle>> {
+    let (filesystem, path) = resolve(path)?;
+
+    filesystem.open(&path)
+}
+
+/// Lists the entries of the directory at the given path.
+pub fn readdir(path: &str) -> Result<Vec<DirectoryEntry>> {
+    let (filesystem, path) = resolve(path)?;
+
+    filesystem.readdir(&path)
+}
+
+/// Checks that the given path refers to a directory.
+pub fn check_directory(path: &str) -> Result<()> {
+    readdir(path).map(|_| ())
+}
+
+/// Reads `buffer.len()` bytes at the given offset of the file at the given path.
+pub fn read(path: &str, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    open(path)?.read_at(buffer, offset)
+}
+
+/// Finds the filesystem responsible for the path and the path within it.
+fn resolve(path: &str) -> Result<(Arc<Filesystem>, String)> {
+    let path = normalize(path)?;
+    let mounts = MOUNTS.lock();
+
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            relative_path(&mount.path, &path).map(|relative| (mount, relative))
+        })
+        .max_by_key(|&(mount, _)| mount.path.len())
+        .map(|(mount, relative)| (mount.filesystem.clone(), String::from(relative)))
+        .ok_or(FileError::FileNotFound)
+}
+
+/// Returns the path relative to the mount point, if it lies below it.
+///
+/// Both paths must be normalized. The result is absolute again.
+fn relative_path<'a>(mount_point: &str, path: &'a str) -> Option<&'a str> {
+    if mount_point == "/" {
+        return Some(path);
+    }
+
+    if !path.starts_with(mount_point) {
+        return None;
+    }
+
+    match &path[mount_point.len()..] {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        // Only a prefix of the last component matched.
+        _ => None
+    }
+}
+
+/// Returns the normalized absolute path of the given path.
+///
+/// Relative paths are resolved against the given working directory, which
+/// must be absolute.
+pub fn absolute_path(working_directory: &str, path: &str) -> Result<String> {
+    if path.is_empty() {
+        Err(FileError::InvalidPath)
+    } else if path.starts_with('/') {
+        normalize(path)
+    } else {
+        let mut joined = String::from(working_directory);
+        joined.push('/');
+        joined.push_str(path);
+
+        normalize(&joined)
+    }
+}
+
+/// Truncates the name to at most `MAX_FILE_NAME_LENGTH` bytes.
+///
+/// The name is only cut at character boundaries.
+fn truncate_name(name: &str) -> &str {
+    let mut length = name.len().min(MAX_FILE_NAME_LENGTH);
+
+    while !name.is_char_boundary(length) {
+        length -= 1;
+    }
+
+    &name[..length]
+}
+
+/// Normalizes the given absolute path.
+///
+/// Empty components and `.` are removed and `..` removes the previous
+/// component. The result has no trailing slash, except for the root.
+pub fn normalize(path: &str) -> Result<String> {
+    if !path.starts_with('/') {
+        return Err(FileError::InvalidPath);
+    }
+
+    let mut components: Vec<&str> = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                // The parent of the root is the root itself.
+                components.pop();
+            },
+            component => components.push(component)
+        }
+    }
+
+    if components.is_empty() {
+        return Ok(String::from("/"));
+    }
+
+    let mut normalized = String::new();
+
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+
+    Ok(normalized)
+}
+
+/// Tests for the path handling.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that paths are normalized.
+    #[test]
+    fn test_normalize() {
+        let table = [
+            ("/", "/"),
+            ("//", "/"),
+            ("/bin/", "/bin"),
+            ("/bin//init", "/bin/init"),
+            ("/./bin/./init/.", "/bin/init"),
+            ("/bin/../etc", "/etc"),
+            ("/bin/init/..", "/bin"),
+            ("/..", "/"),
+            ("/../../bin", "/bin"),
+            ("/bin/...", "/bin/...")
+        ];
+
+        for &(path, expected) in table.iter() {
+            assert_eq!(normalize(path).unwrap(), expected, "{}", path);
+        }
+    }
+
+    /// Tests that relative paths are rejected.
+    #[test]
+    fn test_normalize_relative() {
+        assert_eq!(normalize("bin/init"), Err(FileError::InvalidPath));
+        assert_eq!(normalize(""), Err(FileError::InvalidPath));
+    }
+
+    /// Tests that relative paths are resolved against the working directory.
+    #[test]
+    fn test_absolute_path() {
+        let table = [
+            ("/", "bin", "/bin"),
+            ("/bin", "init", "/bin/init"),
+            ("/bin", "./init", "/bin/init"),
+            ("/bin", "..", "/"),
+            ("/bin", "../../..", "/"),
+            ("/bin", "../etc/", "/etc"),
+            ("/bin", "/etc", "/etc"),
+            ("/bin", "/..", "/")
+        ];
+
+        for &(working_directory, path, expected) in table.iter() {
+            assert_eq!(
+                absolute_path(working_directory, path).unwrap(),
+                expected,
+                "{} in {}",
+                path,
+                working_directory
+            );
+        }
+
+        assert_eq!(absolute_path("/bin", ""), Err(FileError::InvalidPath));
+    }
+
+    /// Tests that entries are converted to the user space layout.
+    #[test]
+    fn test_directory_entry_info() {
+        let entry = DirectoryEntry {
+            name: String::from("init"),
+            kind: FileKind::File
+        };
+        let info = entry.info();
+        assert_eq!(info.kind, FileKind::File);
+        assert_eq!(&info.name[..info.name_length], b"init");
+        assert!(info.name[info.name_length..].iter().all(|&byte| byte == 0));
+
+        let mut long_name = String::from("a");
+        for _ in 0..MAX_FILE_NAME_LENGTH {
+            long_name.push('ä');
+        }
+        let long_entry = DirectoryEntry {
+            name: long_name.clone(),
+            kind: FileKind::Directory
+        };
+        let long_info = long_entry.info();
+        assert_eq!(long_info.name_length, MAX_FILE_NAME_LENGTH - 1);
+        assert!(long_name.as_bytes().starts_with(&long_info.name[..long_info.name_length]));
+    }
+
+    /// Tests that paths are only matched to mount points at component borders.
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path("/", "/bin/init"), Some("/bin/init"));
+        assert_eq!(relative_path("/mnt", "/mnt"), Some("/"));
+        assert_eq!(relative_path("/mnt", "/mnt/disk"), Some("/disk"));
+        assert_eq!(relative_path("/mnt", "/mnt2/disk"), None);
+        assert_eq!(relative_path("/mnt", "/bin"), None);
+    }
+}
@@ -1,10 +1,13 @@
 //! This modules is responsible for reading the initramfs.
 
 use alloc::boxed::Box;
+use alloc::{String, Vec};
 use crate::arch::{self, Architecture};
+use crate::boot;
 use core::mem::size_of;
 use core::{ptr, slice, str};
 use crate::file_handle::{FileError, FileHandle, Result, SeekFrom};
+use crate::fs::vfs::{DirectoryEntry, FileKind, Filesystem};
 use crate::memory::{MemoryArea, VirtualAddress};
 
 /// The magic number that identifies a VeOS initramfs.
@@ -12,6 +15,9 @@ const MAGIC: [u8; 8] = [
     'V' as u8, 'e' as u8, 'O' as u8, 'S' as u8, 'i' as u8, 'r' as u8, 'f' as u8, 's' as u8,
 ];
 
+/// The command line option that holds the expected CRC32 of the initramfs.
+const CHECKSUM_OPTION: &'static str = "initramfs_crc";
+
 /// The size of a single metadata object within the initramfs.
 const FILE_METADATA_SIZE: usize = size_of::<u64>() * 4;
 
@@ -23,6 +29,16 @@ pub struct FileDescriptor {
     current_offset: u64
 }
 
+impl FileDescriptor {
+    /// Creates a handle for the file contents in the given memory area.
+    pub fn new(memory_area: MemoryArea<VirtualAddress>) -> FileDescriptor {
+        FileDescriptor {
+            memory_area,
+            current_offset: 0
+        }
+    }
+}
+
 impl FileHandle for FileDescriptor {
     fn seek(&mut self, position: SeekFrom) -> Result<u64> {
         match position {
@@ -114,6 +130,8 @@ struct FileMetadata {
 
 /// An iterator through the file metadata.
 struct FileIterator {
+    /// The area of the whole initramfs.
+    initramfs: MemoryArea<VirtualAddress>,
     /// The address of the file metadata that is returned next.
     current_file_metadata_address: VirtualAddress,
     /// The address of the highest file number that can be returned.
@@ -124,9 +142,8 @@ impl Iterator for FileIterator {
     type Item = FileMetadata;
 
     fn next(&mut self) -> Option<FileMetadata> {
-        let area = arch::Current::get_initramfs_area();
-        let start = area.start_address();
-        let length = area.length();
+        let start = self.initramfs.start_address();
+        let length = self.initramfs.length();
 
         loop {
             if self.current_file_metadata_address < self.max_address {
@@ -178,17 +195,21 @@ impl Iterator for FileIterator {
     }
 }
 
-/// Returns an iterator through the file metadata.
-fn get_file_iterator() -> Result<FileIterator> {
-    if !initramfs_valid() {
+/// Returns an iterator through the file metadata of the given initramfs.
+///
+/// The initramfs must stay mapped while the iterator and the returned
+/// metadata are used.
+fn get_file_iterator(initramfs: MemoryArea<VirtualAddress>) -> Result<FileIterator> {
+    if !initramfs_valid(initramfs) {
         Err(FileError::InvalidFilesystem)
     } else {
-        let start = arch::Current::get_initramfs_area().start_address();
+        let start = initramfs.start_address();
 
         let first_metadata = start + size_of::<[u8; 8]>() + size_of::<u64>();
         let amount_of_files = unsafe { read_u64_big_endian(start + size_of::<[u8; 8]>()) } as usize;
 
         Ok(FileIterator {
+            initramfs,
             current_file_metadata_address: first_metadata,
             max_address: first_metadata + FILE_METADATA_SIZE * amount_of_files
         })
@@ -210,11 +231,10 @@ unsafe fn read_u64_big_endian(address: VirtualAddress) -> u64 {
     result
 }
 
-/// Checks whether the initramfs is valid.
-fn initramfs_valid() -> bool {
-    let area = arch::Current::get_initramfs_area();
-    let start = area.start_address();
-    let length = area.length();
+/// Checks whether the given initramfs is valid.
+fn initramfs_valid(initramfs: MemoryArea<VirtualAddress>) -> bool {
+    let start = initramfs.start_address();
+    let length = initramfs.length();
 
     if length < size_of::<[u8; 8]>() + size_of::<u64>() {
         false
@@ -231,16 +251,290 @@ fn initramfs_valid() -> bool {
     }
 }
 
+/// Verifies the initramfs against the checksum given on the command line.
+///
+/// Without a checksum the verification is skipped. A mismatch means that
+/// the initramfs was loaded incorrectly, so nothing from it can be trusted.
+///
+/// # Panics
+/// - If the checksum doesn't match or can't be parsed.
+pub fn verify_checksum() {
+    let option = match boot::get_command_line_option(CHECKSUM_OPTION) {
+        Some(option) => option,
+        None => {
+            debug!("No initramfs checksum given, skipping the verification.");
+            return;
+        }
+    };
+
+    let expected = parse_checksum(option).unwrap_or_else(|| {
+        panic!(
+            "The initramfs checksum \"{}\" is not a hexadecimal number.",
+            option
+        )
+    });
+
+    let area = arch::Current::get_initramfs_area();
+    let bytes = unsafe { slice::from_raw_parts(area.start_address().as_ptr(), area.length()) };
+    let checksum = crc32(bytes);
+
+    if checksum != expected {
+        panic!(
+            "The initramfs is corrupt (its CRC32 is {:#010x}, but {:#010x} was expected).",
+            checksum, expected
+        );
+    }
+
+    debug!("The initramfs checksum {:#010x} is correct.", checksum);
+}
+
+/// Parses a checksum given as a hexadecimal number.
+///
+/// The number may be prefixed with `0x`.
+fn parse_checksum(value: &str) -> Option<u32> {
+    let digits = if value.starts_with("0x") {
+        &value[2..]
+    } else {
+        value
+    };
+
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Computes the CRC32 (as used by zlib and Ethernet) of the given bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
 /// Returns the file descriptor for the file with the given name.
 pub fn open(name: &str) -> Result<Box<FileHandle>> {
-    for file in get_file_iterator()? {
+    Ok(Box::new(FileDescriptor::new(file_area(name)?)))
+}
+
+/// Returns the memory area that holds the content of the file with the
+/// given name.
+///
+/// The initramfs stays mapped for the whole uptime, so the area can be
+/// shared with user processes.
+pub fn file_area(name: &str) -> Result<MemoryArea<VirtualAddress>> {
+    file_area_in(arch::Current::get_initramfs_area(), name)
+}
+
+/// Returns the memory area of the file with the given name in the given
+/// initramfs.
+fn file_area_in(
+    initramfs: MemoryArea<VirtualAddress>,
+    name: &str
+) -> Result<MemoryArea<VirtualAddress>> {
+    for file in get_file_iterator(initramfs)? {
         if file.name == name {
-            return Ok(Box::new(FileDescriptor {
-                memory_area: MemoryArea::new(file.start, file.length),
-                current_offset: 0
-            }));
+            return Ok(MemoryArea::new(file.start, file.length));
         }
     }
 
     Err(FileError::FileNotFound)
 }
+
+/// The initramfs as a filesystem.
+///
+/// The initramfs only stores files with their full paths, so directories are
+/// derived from the paths.
+pub struct Initramfs;
+
+impl Filesystem for Initramfs {
+    fn open(&self, path: &str) -> Result<Box<FileHandle>> {
+        open(path)
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<DirectoryEntry>> {
+        readdir_in(arch::Current::get_initramfs_area(), path)
+    }
+}
+
+/// Returns the entries of the directory at the given path in the given
+/// initramfs.
+fn readdir_in(initramfs: MemoryArea<VirtualAddress>, path: &str) -> Result<Vec<DirectoryEntry>> {
+    let prefix_length = if path == "/" { 1 } else { path.len() + 1 };
+    let mut entries: Vec<DirectoryEntry> = Vec::new();
+    let mut directory_exists = path == "/";
+
+    for file in get_file_iterator(initramfs)? {
+        if file.name == path {
+            return Err(FileError::NotADirectory);
+        }
+
+        let in_directory = file.name.starts_with(path)
+            && file.name.len() > prefix_length
+            && file.name.as_bytes()[prefix_length - 1] == b'/';
+
+        if !in_directory {
+            continue;
+        }
+
+        directory_exists = true;
+
+        let rest = &file.name[prefix_length..];
+        let entry = match rest.find('/') {
+            Some(end) => DirectoryEntry {
+                name: String::from(&rest[..end]),
+                kind: FileKind::Directory
+            },
+            None => DirectoryEntry {
+                name: String::from(rest),
+                kind: FileKind::File
+            }
+        };
+
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+
+    if directory_exists {
+        Ok(entries)
+    } else {
+        Err(FileError::FileNotFound)
+    }
+}
+
+/// Tests for the initramfs.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Address;
+
+    /// Returns the big endian bytes of the given value.
+    fn big_endian(value: u64) -> [u8; 8] {
+        let mut bytes = [0; 8];
+
+        for i in 0..8 {
+            bytes[i] = (value >> ((7 - i) * 8)) as u8;
+        }
+
+        bytes
+    }
+
+    /// Builds an initramfs containing the given files.
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut metadata = Vec::new();
+        let mut data = Vec::new();
+        let data_start = MAGIC.len() + size_of::<u64>() + FILE_METADATA_SIZE * files.len();
+
+        for &(name, content) in files {
+            metadata.extend_from_slice(&big_endian((data_start + data.len()) as u64));
+            metadata.extend_from_slice(&big_endian(name.len() as u64));
+            data.extend_from_slice(name.as_bytes());
+
+            metadata.extend_from_slice(&big_endian((data_start + data.len()) as u64));
+            metadata.extend_from_slice(&big_endian(content.len() as u64));
+            data.extend_from_slice(content);
+        }
+
+        let mut archive = Vec::new();
+        archive.extend_from_slice(&MAGIC);
+        archive.extend_from_slice(&big_endian(files.len() as u64));
+        archive.extend_from_slice(&metadata);
+        archive.extend_from_slice(&data);
+
+        archive
+    }
+
+    /// Returns the memory area of the given archive.
+    fn area_of(archive: &[u8]) -> MemoryArea<VirtualAddress> {
+        MemoryArea::new(
+            VirtualAddress::from_usize(archive.as_ptr() as usize),
+            archive.len()
+        )
+    }
+
+    /// Tests listing directories of a synthetic initramfs.
+    #[test]
+    fn test_readdir() {
+        let archive = archive(&[
+            ("/bin/init", &b"init"[..]),
+            ("/bin/shell", &b"shell"[..]),
+            ("/etc/motd", &b"Welcome to VeOS"[..]),
+            ("/readme", &b""[..])
+        ]);
+        let area = area_of(&archive);
+
+        let entry = |name: &str, kind| DirectoryEntry {
+            name: String::from(name),
+            kind
+        };
+
+        let root = readdir_in(area, "/").unwrap();
+        assert_eq!(
+            &root[..],
+            &[
+                entry("bin", FileKind::Directory),
+                entry("etc", FileKind::Directory),
+                entry("readme", FileKind::File)
+            ][..]
+        );
+
+        let bin = readdir_in(area, "/bin").unwrap();
+        assert_eq!(
+            &bin[..],
+            &[entry("init", FileKind::File), entry("shell", FileKind::File)][..]
+        );
+
+        assert_eq!(readdir_in(area, "/bin/init"), Err(FileError::NotADirectory));
+        assert_eq!(readdir_in(area, "/nope"), Err(FileError::FileNotFound));
+        assert_eq!(readdir_in(area, "/bi"), Err(FileError::FileNotFound));
+
+        let mut motd = FileDescriptor::new(file_area_in(area, "/etc/motd").unwrap());
+        let mut buffer = [0; 15];
+        motd.read(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"Welcome to VeOS");
+        assert_eq!(motd.read(&mut [0; 16]), Err(FileError::SeekPastEnd));
+    }
+
+    /// Tests that an archive without the magic number is rejected.
+    #[test]
+    fn test_invalid_archive() {
+        let mut archive = archive(&[("/readme", &b""[..])]);
+        archive[0] = b'v';
+
+        assert_eq!(
+            readdir_in(area_of(&archive), "/"),
+            Err(FileError::InvalidFilesystem)
+        );
+        assert_eq!(
+            readdir_in(area_of(&archive[..12]), "/"),
+            Err(FileError::InvalidFilesystem)
+        );
+    }
+
+    /// Tests the CRC32 against known values.
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    /// Tests parsing checksums from the command line.
+    #[test]
+    fn test_parse_checksum() {
+        assert_eq!(parse_checksum("0xcbf43926"), Some(0xcbf4_3926));
+        assert_eq!(parse_checksum("CBF43926"), Some(0xcbf4_3926));
+        assert_eq!(parse_checksum("0x"), None);
+        assert_eq!(parse_checksum("crc"), None);
+        assert_eq!(parse_checksum("0x123456789"), None);
+    }
+}
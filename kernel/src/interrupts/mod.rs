@@ -4,32 +4,97 @@
 //! They should instead
 //! be called by the architecture specific interrupt handlers.
 
-use crate::arch::{self, schedule, Architecture};
+use crate::arch::{self, Architecture};
+use crate::io::{keyboard, serial};
 use crate::memory::VirtualAddress;
-use crate::multitasking::CURRENT_THREAD;
+use crate::multitasking::{self, get_current_process, scheduler, CURRENT_THREAD, SIGSEGV};
+
+/// How often an interrupt vector fired on a CPU.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptCount {
+    /// The CPU the interrupts arrived on.
+    pub cpu_id: u32,
+    /// The interrupt vector.
+    pub vector: u32,
+    /// The number of interrupts.
+    pub count: u64
+}
 
 /// The timer interrupt handler for the system.
 pub fn timer_interrupt() {
-    schedule();
+    scheduler::timer_tick();
+}
+
+/// The handler for reschedule requests from other CPUs.
+///
+/// The reschedule happens once the handler returns.
+pub fn reschedule_interrupt() {
+    scheduler::set_need_resched(arch::Current::get_cpu_id());
+}
+
+/// Performs the work that is deferred until an interrupt handler returns.
+///
+/// This should be called at the end of every interrupt handler that may
+/// request a reschedule, while another interrupt would still nest.
+pub fn before_interrupt_return() {
+    scheduler::reschedule_if_needed();
 }
 
 /// The keyboard interrupt handler.
 pub fn keyboard_interrupt(scancode: u8) {
-    if scancode == 1 {
-        unsafe { crate::sync::disable_preemption() };
-        loop {}
-    }
-    info!("Key: <{}>", scancode);
+    keyboard::handle_scancode(scancode);
+}
+
+/// The serial port interrupt handler.
+pub fn serial_interrupt(byte: u8) {
+    serial::handle_byte(byte);
 }
 
 /// The page fault handler.
-pub fn page_fault_handler(address: VirtualAddress, program_counter: VirtualAddress) {
+///
+/// Faults of user mode on pages of shared file mappings that weren't accessed
+/// before map the page. Other faults of user mode end the process as if it
+/// received `SIGSEGV`, while faults of the kernel are fatal.
+pub fn page_fault_handler(
+    address: VirtualAddress,
+    program_counter: VirtualAddress,
+    user_mode: bool,
+    page_present: bool
+) {
+    // The kernel copies shared file mappings through the address space, so
+    // it never faults on them. Kernel faults might happen with the process
+    // locked, so they don't touch it.
+    if user_mode
+        && !page_present
+        && arch::Current::is_userspace_address(address)
+        && get_current_process().address_space.map_file_backed_page(address)
+    {
+        return;
+    }
+
+    if user_mode {
+        let (pid, id) = {
+            let current_thread = CURRENT_THREAD.lock();
+            (current_thread.pid, current_thread.id)
+        };
+
+        warn!(
+            "Segmentation fault in {:?} {:?} at address {:?} (PC: {:?}).",
+            pid, id, address, program_counter
+        );
+
+        // Exiting switches away, which needs interrupts like a syscall does.
+        unsafe { arch::Current::enable_interrupts() };
+        multitasking::exit_current_process(128 + SIGSEGV as i32);
+    }
+
     unsafe { crate::sync::disable_preemption() };
     let current_thread = CURRENT_THREAD.lock();
 
     error!(
-        "Page fault in {:?} {:?} at address {:?} (PC: {:?})",
-        current_thread.pid, current_thread.id, address, program_counter
+        "Page fault in {:?} {:?} at address {:?} (PC: {:?}, user mode: {}, page present: {})",
+        current_thread.pid, current_thread.id, address, program_counter, user_mode, page_present
     );
 
     error!("Page flags: {:?}", arch::Current::get_page_flags(address));
@@ -0,0 +1,552 @@
+//! A driver for SATA disks attached to an AHCI controller.
+//!
+//! Only the first disk found is used. Commands are issued one at a time on
+//! the first command slot and completion is polled, so no interrupts are
+//! needed.
+
+use super::block::{BlockDevice, BlockError, Result};
+use super::pci::{self, Bar, PciDevice};
+use crate::arch::{self, Architecture};
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use crate::memory::{self, Address, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use crate::sync::Mutex;
+
+/// The size of a sector in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The number of sectors transferred by a single command.
+const SECTORS_PER_COMMAND: usize = PAGE_SIZE / SECTOR_SIZE;
+
+/// The PCI class of mass storage controllers.
+const MASS_STORAGE_CLASS: u8 = 0x01;
+
+/// The PCI subclass of SATA controllers.
+const SATA_SUBCLASS: u8 = 0x06;
+
+/// The PCI programming interface of AHCI controllers.
+const AHCI_PROG_IF: u8 = 0x01;
+
+/// The index of the BAR that holds the AHCI registers.
+const ABAR_INDEX: usize = 5;
+
+/// The size of the memory mapped AHCI registers.
+const ABAR_SIZE: usize = 0x1100;
+
+/// The number of iterations to wait for the device before giving up.
+const TIMEOUT_ITERATIONS: usize = 10_000_000;
+
+/// The global host control register.
+const GHC: usize = 0x04;
+
+/// The ports implemented register.
+const PI: usize = 0x0c;
+
+/// Enables the AHCI mode in the global host control register.
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+/// The offset of the registers of the first port.
+const PORT_REGISTERS_OFFSET: usize = 0x100;
+
+/// The size of the registers of a port.
+const PORT_REGISTERS_SIZE: usize = 0x80;
+
+/// The number of ports an AHCI controller can have.
+const PORT_COUNT: usize = 32;
+
+/// The command list base address register of a port.
+const PORT_CLB: usize = 0x00;
+
+/// The upper half of the command list base address register of a port.
+const PORT_CLBU: usize = 0x04;
+
+/// The received FIS base address register of a port.
+const PORT_FB: usize = 0x08;
+
+/// The upper half of the received FIS base address register of a port.
+const PORT_FBU: usize = 0x0c;
+
+/// The interrupt status register of a port.
+const PORT_IS: usize = 0x10;
+
+/// The command and status register of a port.
+const PORT_CMD: usize = 0x18;
+
+/// The task file data register of a port.
+const PORT_TFD: usize = 0x20;
+
+/// The signature register of a port.
+const PORT_SIG: usize = 0x24;
+
+/// The SATA status register of a port.
+const PORT_SSTS: usize = 0x28;
+
+/// The SATA error register of a port.
+const PORT_SERR: usize = 0x30;
+
+/// The command issue register of a port.
+const PORT_CI: usize = 0x38;
+
+/// Starts processing the command list.
+const CMD_START: u32 = 1 << 0;
+
+/// Enables receiving FISes.
+const CMD_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+
+/// Set while FISes are being received.
+const CMD_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+
+/// Set while the command list is being processed.
+const CMD_COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+/// The task file error bit in the interrupt status.
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+
+/// The error bit of the task file status.
+const TFD_ERROR: u32 = 1 << 0;
+
+/// Set in the task file status while the device requests a data transfer.
+const TFD_DRQ: u32 = 1 << 3;
+
+/// Set in the task file status while the device is busy.
+const TFD_BUSY: u32 = 1 << 7;
+
+/// The device detection value of a present device with established communication.
+const SSTS_DEVICE_PRESENT: u32 = 0x3;
+
+/// The signature of a SATA disk.
+const SATA_DISK_SIGNATURE: u32 = 0x0000_0101;
+
+/// The offset of the received FIS area within the command page.
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+
+/// The offset of the command table within the command page.
+const COMMAND_TABLE_OFFSET: usize = 0x500;
+
+/// The offset of the physical region descriptor table within the command table.
+const PRDT_OFFSET: usize = 0x80;
+
+/// The size of the command table with a single physical region descriptor.
+const COMMAND_TABLE_SIZE: usize = PRDT_OFFSET + 0x10;
+
+/// The length of a host to device register FIS in double words.
+const H2D_FIS_LENGTH: u32 = 5;
+
+/// The FIS type of a host to device register FIS.
+const FIS_TYPE_H2D: u8 = 0x27;
+
+/// Marks a host to device register FIS as a command.
+const FIS_COMMAND_BIT: u8 = 1 << 7;
+
+/// Selects LBA addressing in the device register.
+const DEVICE_LBA_MODE: u8 = 1 << 6;
+
+/// The ATA command that reads sectors using DMA and 48-bit LBAs.
+const ATA_READ_DMA_EXT: u8 = 0x25;
+
+/// The ATA command that writes sectors using DMA and 48-bit LBAs.
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+
+/// The ATA command that returns the identification data of a device.
+const ATA_IDENTIFY: u8 = 0xec;
+
+/// The disk found during initialization.
+pub static DISK: Mutex<Option<AhciDisk>> = Mutex::new(None);
+
+/// A SATA disk attached to a port of an AHCI controller.
+pub struct AhciDisk {
+    /// The registers of the port.
+    port: VirtualAddress,
+    /// The page holding the command list, received FISes and the command table.
+    command_page: VirtualAddress,
+    /// The page that data is transferred through.
+    transfer_buffer: VirtualAddress,
+    /// The number of sectors on the disk.
+    sector_count: u64
+}
+
+// The pages are only accessed through the disk, so this is okay.
+unsafe impl Send for AhciDisk {}
+
+impl AhciDisk {
+    /// Sets up the given port for issuing commands and identifies the disk on it.
+    fn new(port: VirtualAddress) -> Option<AhciDisk> {
+        let mut disk = AhciDisk {
+            port,
+            command_page: allocate_dma_page(),
+            transfer_buffer: allocate_dma_page(),
+            sector_count: 0
+        };
+
+        if !disk.stop() {
+            return None;
+        }
+
+        let command_list = physical_address(disk.command_page).as_usize() as u64;
+        let received_fis = command_list + RECEIVED_FIS_OFFSET as u64;
+
+        disk.write_register(PORT_CLB, command_list as u32);
+        disk.write_register(PORT_CLBU, (command_list >> 32) as u32);
+        disk.write_register(PORT_FB, received_fis as u32);
+        disk.write_register(PORT_FBU, (received_fis >> 32) as u32);
+
+        // Writing ones clears the bits.
+        disk.write_register(PORT_SERR, u32::max_value());
+        disk.write_register(PORT_IS, u32::max_value());
+
+        disk.start();
+
+        if disk.issue_command(ATA_IDENTIFY, 0, 0, 1, false).is_err() {
+            return None;
+        }
+
+        disk.sector_count = disk.identified_sector_count();
+
+        Some(disk)
+    }
+
+    /// Reads `count` sectors starting at `lba` into `buffer`.
+    ///
+    /// The buffer can be located anywhere, as the data is transferred through a
+    /// separate, suitably aligned buffer.
+    pub fn read_sectors(&mut self, lba: u64, count: usize, buffer: &mut [u8]) -> Result<()> {
+        self.check_request(lba, count, buffer.len())?;
+
+        for (index, chunk) in buffer.chunks_mut(SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let chunk_lba = lba + (index * SECTORS_PER_COMMAND) as u64;
+            let chunk_sectors = chunk.len() / SECTOR_SIZE;
+
+            self.issue_command(ATA_READ_DMA_EXT, chunk_lba, chunk_sectors, chunk_sectors, false)?;
+
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.transfer_buffer.as_ptr(),
+                    chunk.as_mut_ptr(),
+                    chunk.len()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes `count` sectors from `buffer` starting at `lba`.
+    ///
+    /// The buffer can be located anywhere, as the data is transferred through a
+    /// separate, suitably aligned buffer.
+    pub fn write_sectors(&mut self, lba: u64, count: usize, buffer: &[u8]) -> Result<()> {
+        self.check_request(lba, count, buffer.len())?;
+
+        for (index, chunk) in buffer.chunks(SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let chunk_lba = lba + (index * SECTORS_PER_COMMAND) as u64;
+            let chunk_sectors = chunk.len() / SECTOR_SIZE;
+
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    chunk.as_ptr(),
+                    self.transfer_buffer.as_mut_ptr(),
+                    chunk.len()
+                );
+            }
+
+            self.issue_command(ATA_WRITE_DMA_EXT, chunk_lba, chunk_sectors, chunk_sectors, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of sectors on the disk.
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    /// Checks that the sectors exist and the buffer matches their size.
+    fn check_request(&self, lba: u64, count: usize, buffer_length: usize) -> Result<()> {
+        if count.checked_mul(SECTOR_SIZE) != Some(buffer_length) {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+
+    /// Issues an ATA command transferring `sectors` sectors through the transfer buffer.
+    ///
+    /// `count` is the sector count passed to the device.
+    fn issue_command(
+        &mut self,
+        command: u8,
+        lba: u64,
+        count: usize,
+        sectors: usize,
+        write: bool
+    ) -> Result<()> {
+        debug_assert!(sectors > 0 && sectors <= SECTORS_PER_COMMAND);
+
+        if !self.wait_while(PORT_TFD, TFD_BUSY | TFD_DRQ) {
+            return Err(BlockError::DeviceError);
+        }
+
+        let command_table = self.command_page + COMMAND_TABLE_OFFSET;
+        let command_table_address = physical_address(command_table).as_usize() as u64;
+        let buffer_address = physical_address(self.transfer_buffer).as_usize() as u64;
+
+        // The data base address must be word aligned.
+        debug_assert_eq!(buffer_address % 2, 0);
+
+        unsafe {
+            ptr::write_bytes(command_table.as_mut_ptr::<u8>(), 0, COMMAND_TABLE_SIZE);
+
+            // The command header of the first slot.
+            let header = self.command_page.as_mut_ptr::<u32>();
+            let write_bit = if write { 1 << 6 } else { 0 };
+            *header = H2D_FIS_LENGTH | write_bit | 1 << 16;
+            *header.offset(1) = 0;
+            *header.offset(2) = command_table_address as u32;
+            *header.offset(3) = (command_table_address >> 32) as u32;
+
+            // The command FIS.
+            let fis = command_table.as_mut_ptr::<u8>();
+            *fis = FIS_TYPE_H2D;
+            *fis.offset(1) = FIS_COMMAND_BIT;
+            *fis.offset(2) = command;
+            *fis.offset(4) = lba as u8;
+            *fis.offset(5) = (lba >> 8) as u8;
+            *fis.offset(6) = (lba >> 16) as u8;
+            *fis.offset(7) = if command == ATA_IDENTIFY { 0 } else { DEVICE_LBA_MODE };
+            *fis.offset(8) = (lba >> 24) as u8;
+            *fis.offset(9) = (lba >> 32) as u8;
+            *fis.offset(10) = (lba >> 40) as u8;
+            *fis.offset(12) = count as u8;
+            *fis.offset(13) = (count >> 8) as u8;
+
+            // The only physical region descriptor.
+            let prd = (command_table + PRDT_OFFSET).as_mut_ptr::<u32>();
+            *prd = buffer_address as u32;
+            *prd.offset(1) = (buffer_address >> 32) as u32;
+            *prd.offset(3) = (sectors * SECTOR_SIZE - 1) as u32;
+        }
+
+        // The command has to be in memory before the device is told about it.
+        fence(Ordering::SeqCst);
+
+        self.write_register(PORT_IS, u32::max_value());
+        self.write_register(PORT_CI, 1);
+
+        let mut iterations = 0;
+        while self.read_register(PORT_CI) & 1 != 0 {
+            if self.read_register(PORT_IS) & IS_TASK_FILE_ERROR != 0 {
+                return Err(BlockError::DeviceError);
+            }
+
+            iterations += 1;
+            if iterations >= TIMEOUT_ITERATIONS {
+                return Err(BlockError::DeviceError);
+            }
+
+            arch::Current::cpu_relax();
+        }
+
+        // The data has to be read only after the device finished writing it.
+        fence(Ordering::SeqCst);
+
+        if self.read_register(PORT_TFD) & TFD_ERROR != 0 {
+            Err(BlockError::DeviceError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the number of sectors reported by the identification data.
+    fn identified_sector_count(&self) -> u64 {
+        let words = self.transfer_buffer.as_ptr::<u16>();
+        let word = |index: isize| unsafe { *words.offset(index) as u64 };
+
+        let lba48_sectors = word(100) | word(101) << 16 | word(102) << 32 | word(103) << 48;
+
+        if lba48_sectors != 0 {
+            lba48_sectors
+        } else {
+            word(60) | word(61) << 16
+        }
+    }
+
+    /// Stops the processing of commands.
+    ///
+    /// Returns `false` if the port didn't stop in time.
+    fn stop(&mut self) -> bool {
+        let command = self.read_register(PORT_CMD);
+        self.write_register(PORT_CMD, command & !(CMD_START | CMD_FIS_RECEIVE_ENABLE));
+
+        self.wait_while(PORT_CMD, CMD_COMMAND_LIST_RUNNING | CMD_FIS_RECEIVE_RUNNING)
+    }
+
+    /// Checks whether the port was given the command list of this disk.
+    fn uses_command_page(&self) -> bool {
+        let command_list = physical_address(self.command_page).as_usize() as u64;
+        let low = u64::from(self.read_register(PORT_CLB));
+        let high = u64::from(self.read_register(PORT_CLBU));
+
+        (high << 32 | low) == command_list
+    }
+
+    /// Starts the processing of commands.
+    fn start(&mut self) {
+        self.wait_while(PORT_CMD, CMD_COMMAND_LIST_RUNNING);
+
+        let command = self.read_register(PORT_CMD);
+        self.write_register(PORT_CMD, command | CMD_FIS_RECEIVE_ENABLE);
+        self.write_register(PORT_CMD, command | CMD_FIS_RECEIVE_ENABLE | CMD_START);
+    }
+
+    /// Waits until none of the given bits are set in the register.
+    ///
+    /// Returns `false` if waiting timed out.
+    fn wait_while(&self, register: usize, bits: u32) -> bool {
+        for _ in 0..TIMEOUT_ITERATIONS {
+            if self.read_register(register) & bits == 0 {
+                return true;
+            }
+
+            arch::Current::cpu_relax();
+        }
+
+        false
+    }
+
+    /// Reads a register of the port.
+    fn read_register(&self, register: usize) -> u32 {
+        read_mmio(self.port + register)
+    }
+
+    /// Writes a register of the port.
+    fn write_register(&mut self, register: usize, value: u32) {
+        write_mmio(self.port + register, value);
+    }
+}
+
+impl Drop for AhciDisk {
+    fn drop(&mut self) {
+        // The pages can only be freed once the controller no longer accesses them.
+        if self.uses_command_page() && !self.stop() {
+            warn!("Leaking the DMA pages of an AHCI port that didn't stop.");
+            return;
+        }
+
+        unsafe {
+            arch::Current::free_kernel_frame(self.command_page);
+            arch::Current::free_kernel_frame(self.transfer_buffer);
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_block(&mut self, index: u64, buffer: &mut [u8]) -> Result<()> {
+        self.read_sectors(index, 1, buffer)
+    }
+
+    fn write_block(&mut self, index: u64, buffer: &[u8]) -> Result<()> {
+        self.write_sectors(index, 1, buffer)
+    }
+}
+
+/// Reads the memory mapped register at the given address.
+fn read_mmio(address: VirtualAddress) -> u32 {
+    unsafe { ptr::read_volatile(address.as_ptr()) }
+}
+
+/// Writes the memory mapped register at the given address.
+fn write_mmio(address: VirtualAddress, value: u32) {
+    unsafe { ptr::write_volatile(address.as_mut_ptr(), value) }
+}
+
+/// Allocates a zeroed page that devices can access.
+fn allocate_dma_page() -> VirtualAddress {
+    let page = arch::Current::allocate_kernel_frame();
+
+    unsafe {
+        ptr::write_bytes(page.as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+    }
+
+    page
+}
+
+/// Returns the physical address of the given kernel address.
+fn physical_address(address: VirtualAddress) -> PhysicalAddress {
+    arch::Current::virtual_to_physical(address).expect("DMA memory must be mapped.")
+}
+
+/// Sets up the first SATA disk of the given controller.
+fn init_controller(controller: &PciDevice) -> Option<AhciDisk> {
+    let abar = match controller.bars[ABAR_INDEX] {
+        Bar::Memory { address, .. } => PhysicalAddress::from_usize(address as usize),
+        _ => return None
+    };
+
+    controller.enable_bus_mastering();
+
+    let registers = match memory::map_mmio(abar, ABAR_SIZE) {
+        Ok(registers) => registers,
+        Err(error) => {
+            warn!("Couldn't map the AHCI registers: {:?}", error);
+            return None;
+        }
+    };
+    write_mmio(registers + GHC, read_mmio(registers + GHC) | GHC_AHCI_ENABLE);
+
+    let ports_implemented = read_mmio(registers + PI);
+
+    for port_number in 0..PORT_COUNT {
+        if ports_implemented & 1 << port_number == 0 {
+            continue;
+        }
+
+        let port = registers + PORT_REGISTERS_OFFSET + port_number * PORT_REGISTERS_SIZE;
+
+        if read_mmio(port + PORT_SSTS) & 0xf != SSTS_DEVICE_PRESENT
+            || read_mmio(port + PORT_SIG) != SATA_DISK_SIGNATURE
+        {
+            continue;
+        }
+
+        match AhciDisk::new(port) {
+            Some(disk) => {
+                info!(
+                    "Found an AHCI disk on port {} with {} sectors.",
+                    port_number,
+                    disk.sector_count()
+                );
+                return Some(disk);
+            },
+            None => warn!("The AHCI disk on port {} didn't respond.", port_number)
+        }
+    }
+
+    None
+}
+
+/// Searches the PCI buses for an AHCI controller and sets up its first disk.
+pub fn init() {
+    assert_has_not_been_called!("The AHCI driver should only be initialized once.");
+
+    let disk = pci::enumerate()
+        .filter(|device| {
+            device.class == MASS_STORAGE_CLASS
+                && device.subclass == SATA_SUBCLASS
+                && device.prog_if == AHCI_PROG_IF
+        })
+        .filter_map(|controller| init_controller(&controller))
+        .next();
+
+    *DISK.lock() = disk;
+}
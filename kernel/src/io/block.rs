@@ -0,0 +1,36 @@
+//! Abstracts devices that store data in fixed size blocks.
+
+/// The errors that can occur when accessing a block device.
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    /// The block lies past the end of the device.
+    OutOfRange,
+    /// The buffer doesn't have the size of a block.
+    InvalidBuffer,
+    /// The device doesn't support writing.
+    ReadOnly,
+    /// The device reported an error or didn't respond.
+    DeviceError
+}
+
+/// A result of a block device operation.
+pub type Result<T> = ::core::result::Result<T, BlockError>;
+
+/// Everything that stores data in blocks should implement this.
+pub trait BlockDevice: Send {
+    /// Returns the size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Reads the block with the given index into `buffer`.
+    ///
+    /// The buffer must be exactly one block large.
+    fn read_block(&mut self, index: u64, buffer: &mut [u8]) -> Result<()>;
+
+    /// Writes `buffer` to the block with the given index.
+    ///
+    /// The buffer must be exactly one block large.
+    fn write_block(&mut self, index: u64, buffer: &[u8]) -> Result<()>;
+}
@@ -0,0 +1,269 @@
+//! Provides the consoles that the printing macros write to.
+//!
+//! `print!` and `println!` write to the default sink, which can be changed
+//! with `set_default_sink`. `print_to!` and `println_to!` write to an
+//! explicitly given console instead.
+
+use crate::arch::{self, Architecture};
+use core::fmt;
+use crate::sync::{cpu_relax, Mutex};
+
+/// How often to retry printing to a contended screen before giving up.
+const CONTENDED_RETRIES: usize = 1000;
+
+/// The marker that precedes messages that couldn't be printed to the screen.
+const CONTENDED_MARKER: &'static str = "[screen busy]";
+
+/// An output device that formatted text can be written to.
+pub trait Console: Sync {
+    /// Writes the formatted arguments to this console.
+    fn write_fmt(&self, args: fmt::Arguments);
+}
+
+/// The screen of the computer.
+pub struct Screen;
+
+impl Console for Screen {
+    fn write_fmt(&self, args: fmt::Arguments) {
+        arch::Current::write_fmt(args);
+    }
+}
+
+/// The serial port.
+pub struct Serial;
+
+impl Console for Serial {
+    fn write_fmt(&self, args: fmt::Arguments) {
+        arch::Current::write_serial_fmt(args);
+    }
+}
+
+/// Writes everything to both of the contained consoles.
+pub struct Tee<A: Console, B: Console>(pub A, pub B);
+
+impl<A: Console, B: Console> Console for Tee<A, B> {
+    fn write_fmt(&self, args: fmt::Arguments) {
+        self.0.write_fmt(args);
+        self.1.write_fmt(args);
+    }
+}
+
+/// The screen console.
+pub static SCREEN: Screen = Screen;
+
+/// The serial console.
+pub static SERIAL: Serial = Serial;
+
+/// The console that writes to both the screen and the serial port.
+pub static SCREEN_AND_SERIAL: Tee<Screen, Serial> = Tee(Screen, Serial);
+
+/// The console that `print!` and `println!` write to.
+static DEFAULT_SINK: Mutex<&'static Console> = Mutex::new(&SCREEN);
+
+/// Sets the console that `print!` and `println!` write to.
+pub fn set_default_sink(sink: &'static Console) {
+    *DEFAULT_SINK.lock() = sink;
+}
+
+/// Writes the formatted arguments to the default sink.
+pub fn print(args: fmt::Arguments) {
+    // The lock isn't held while printing, so the sink can be changed at any time.
+    let sink = *DEFAULT_SINK.lock();
+
+    sink.write_fmt(args);
+}
+
+/// Writes the formatted arguments to the screen without risking a deadlock.
+///
+/// This is meant for interrupt and panic context, where the screen lock may
+/// be held by the interrupted code. If the screen stays in use, the message is
+/// written to the serial port with a marker instead. If that is in use too,
+/// the message is dropped.
+pub fn print_nonblocking(args: fmt::Arguments) {
+    write_nonblocking(
+        arch::Current::try_write_fmt,
+        arch::Current::try_write_serial_fmt,
+        args
+    );
+}
+
+/// Writes the formatted arguments to the serial port without risking a
+/// deadlock.
+///
+/// The message is dropped if the serial port stays in use.
+pub fn serial_print_nonblocking(args: fmt::Arguments) {
+    try_repeatedly(arch::Current::try_write_serial_fmt, args);
+}
+
+/// Writes the formatted arguments with `try_write`, falling back to
+/// `try_fallback` if that keeps failing.
+///
+/// The message is dropped if neither succeeds.
+fn write_nonblocking<F, G>(try_write: F, try_fallback: G, args: fmt::Arguments)
+where
+    F: Fn(fmt::Arguments) -> bool,
+    G: Fn(fmt::Arguments) -> bool
+{
+    if !try_repeatedly(try_write, args) {
+        try_repeatedly(try_fallback, format_args!("{} {}", CONTENDED_MARKER, args));
+    }
+}
+
+/// Calls `try_write` until it succeeds, at most `CONTENDED_RETRIES` times.
+///
+/// Returns false if it never succeeded.
+fn try_repeatedly<F>(try_write: F, args: fmt::Arguments) -> bool
+where
+    F: Fn(fmt::Arguments) -> bool
+{
+    for _ in 0..CONTENDED_RETRIES {
+        if try_write(args) {
+            return true;
+        }
+
+        cpu_relax();
+    }
+
+    false
+}
+
+/// Tests for the consoles.
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::sync::Arc;
+    use self::std::thread;
+    use super::*;
+    use alloc::{String, Vec};
+    use core::fmt::Write;
+    use spin;
+
+    /// The number of lines each thread of the concurrency test prints.
+    const LINES_PER_THREAD: usize = 200;
+
+    /// A console that captures everything written to it.
+    struct Capture(spin::Mutex<String>);
+
+    impl Console for Capture {
+        fn write_fmt(&self, args: fmt::Arguments) {
+            self.0.lock().write_fmt(args).unwrap();
+        }
+    }
+
+    impl Capture {
+        /// Writes to the capture, unless it is in use.
+        fn try_write_fmt(&self, args: fmt::Arguments) -> bool {
+            match self.0.try_lock() {
+                Some(mut output) => {
+                    output.write_fmt(args).unwrap();
+                    true
+                },
+                None => false
+            }
+        }
+    }
+
+    /// Tests that a contended console makes the message go to the fallback.
+    #[test]
+    fn test_nonblocking_contended() {
+        let screen = Capture(spin::Mutex::new(String::new()));
+        let serial = Capture(spin::Mutex::new(String::new()));
+
+        {
+            let _held = screen.0.lock();
+            write_nonblocking(
+                |args| screen.try_write_fmt(args),
+                |args| serial.try_write_fmt(args),
+                format_args!("x")
+            );
+        }
+
+        assert_eq!(*screen.0.lock(), "");
+        assert_eq!(*serial.0.lock(), "[screen busy] x");
+    }
+
+    /// Tests that the message is dropped if both consoles are contended.
+    #[test]
+    fn test_nonblocking_both_contended() {
+        let screen = Capture(spin::Mutex::new(String::new()));
+        let serial = Capture(spin::Mutex::new(String::new()));
+
+        {
+            let _held_screen = screen.0.lock();
+            let _held_serial = serial.0.lock();
+            write_nonblocking(
+                |args| screen.try_write_fmt(args),
+                |args| serial.try_write_fmt(args),
+                format_args!("x")
+            );
+        }
+
+        assert_eq!(*screen.0.lock(), "");
+        assert_eq!(*serial.0.lock(), "");
+    }
+
+    /// Tests that two CPUs printing at the same time don't interleave
+    /// characters within a line.
+    ///
+    /// The kernel only runs on the boot CPU so far, so two host threads take
+    /// the place of the CPUs.
+    #[test]
+    fn test_nonblocking_concurrent() {
+        let screen = Arc::new(Capture(spin::Mutex::new(String::new())));
+        let serial = Arc::new(Capture(spin::Mutex::new(String::new())));
+
+        let printers: Vec<_> = ["aaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbb"]
+            .iter()
+            .map(|&text| {
+                let screen = screen.clone();
+                let serial = serial.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..LINES_PER_THREAD {
+                        write_nonblocking(
+                            |args| screen.try_write_fmt(args),
+                            |args| serial.try_write_fmt(args),
+                            format_args!("{}\n", text)
+                        );
+                    }
+                })
+            })
+            .collect();
+
+        for printer in printers {
+            printer.join().unwrap();
+        }
+
+        let screen_output = screen.0.lock();
+        let serial_output = serial.0.lock();
+        let lines: Vec<&str> = screen_output
+            .lines()
+            .chain(serial_output.lines().map(|line| line.trim_left_matches("[screen busy] ")))
+            .collect();
+
+        // Lines are only dropped if both consoles stayed contended.
+        assert!(lines.len() <= 2 * LINES_PER_THREAD);
+        for line in lines {
+            assert!(
+                line == "aaaaaaaaaaaaaaaa" || line == "bbbbbbbbbbbbbbbb",
+                "Interleaved line: {:?}",
+                line
+            );
+        }
+    }
+
+    /// Tests that a tee writes to both of its consoles.
+    #[test]
+    fn test_tee() {
+        let tee = Tee(
+            Capture(spin::Mutex::new(String::new())),
+            Capture(spin::Mutex::new(String::new()))
+        );
+
+        println_to!(tee, "{} + {} = {}", 1, 1, 2);
+
+        assert_eq!(*(tee.0).0.lock(), "1 + 1 = 2\n");
+        assert_eq!(*(tee.1).0.lock(), "1 + 1 = 2\n");
+    }
+}
@@ -0,0 +1,516 @@
+//! Decodes keyboard scancodes into key events.
+//!
+//! The decoder understands scancode set 1, including the 0xE0 extended prefix.
+
+use crate::collections::SyncRingBuffer;
+use crate::sync::Mutex;
+
+/// The maximum number of key events that are buffered.
+const KEY_EVENT_BUFFER_SIZE: usize = 128;
+
+/// The prefix of extended scancodes.
+const EXTENDED_PREFIX: u8 = 0xe0;
+
+/// The bit that is set in the scancode when a key is released.
+const RELEASE_BIT: u8 = 0x80;
+
+lazy_static! {
+    /// The decoder for the scancodes received from the keyboard.
+    static ref DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+    /// The key events that weren't read yet.
+    static ref KEY_EVENTS: SyncRingBuffer<KeyEvent> =
+        SyncRingBuffer::with_capacity(KEY_EVENT_BUFFER_SIZE);
+}
+
+/// Represents a key on the keyboard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    /// A key that produces a character.
+    Char(char),
+    /// The escape key.
+    Escape,
+    /// The backspace key.
+    Backspace,
+    /// The tab key.
+    Tab,
+    /// The enter key.
+    Enter,
+    /// The left shift key.
+    LeftShift,
+    /// The right shift key.
+    RightShift,
+    /// The left control key.
+    LeftControl,
+    /// The right control key.
+    RightControl,
+    /// The left alt key.
+    LeftAlt,
+    /// The right alt key.
+    RightAlt,
+    /// The caps lock key.
+    CapsLock,
+    /// The num lock key.
+    NumLock,
+    /// The scroll lock key.
+    ScrollLock,
+    /// A function key with the given number.
+    Function(u8),
+    /// The up arrow key.
+    Up,
+    /// The down arrow key.
+    Down,
+    /// The left arrow key.
+    Left,
+    /// The right arrow key.
+    Right,
+    /// The home key.
+    Home,
+    /// The end key.
+    End,
+    /// The page up key.
+    PageUp,
+    /// The page down key.
+    PageDown,
+    /// The insert key.
+    Insert,
+    /// The delete key.
+    Delete,
+    /// A key that isn't known, with its scancode.
+    Unknown(u8)
+}
+
+bitflags! {
+    /// The modifier keys that are active.
+    pub struct Modifiers: u8 {
+        /// The left shift key is held.
+        const LEFT_SHIFT = 1 << 0;
+        /// The right shift key is held.
+        const RIGHT_SHIFT = 1 << 1;
+        /// The left control key is held.
+        const LEFT_CONTROL = 1 << 2;
+        /// The right control key is held.
+        const RIGHT_CONTROL = 1 << 3;
+        /// The left alt key is held.
+        const LEFT_ALT = 1 << 4;
+        /// The right alt key is held.
+        const RIGHT_ALT = 1 << 5;
+        /// Caps lock is active.
+        const CAPS_LOCK = 1 << 6;
+    }
+}
+
+impl Modifiers {
+    /// Checks if either shift key is held.
+    pub fn shift(&self) -> bool {
+        self.intersects(Modifiers::LEFT_SHIFT | Modifiers::RIGHT_SHIFT)
+    }
+
+    /// Checks if either control key is held.
+    pub fn control(&self) -> bool {
+        self.intersects(Modifiers::LEFT_CONTROL | Modifiers::RIGHT_CONTROL)
+    }
+
+    /// Checks if either alt key is held.
+    pub fn alt(&self) -> bool {
+        self.intersects(Modifiers::LEFT_ALT | Modifiers::RIGHT_ALT)
+    }
+}
+
+/// A key being pressed or released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The key that the event is about.
+    pub key: Key,
+    /// Whether the key was pressed or released.
+    pub pressed: bool,
+    /// The modifiers that were active when the event happened.
+    pub modifiers: Modifiers
+}
+
+impl KeyEvent {
+    /// Returns the character this event produces, if any.
+    ///
+    /// Only key presses produce characters.
+    pub fn character(&self) -> Option<char> {
+        if !self.pressed {
+            return None;
+        }
+
+        match self.key {
+            Key::Char(character) => {
+                if self.modifiers.control() && character.is_ascii_alphabetic() {
+                    // Control characters, such as ^C.
+                    Some((character as u8 & 0x1f) as char)
+                } else if character.is_ascii_alphabetic() {
+                    let upper_case =
+                        self.modifiers.shift() != self.modifiers.contains(Modifiers::CAPS_LOCK);
+                    if upper_case {
+                        Some(character.to_ascii_uppercase())
+                    } else {
+                        Some(character)
+                    }
+                } else if self.modifiers.shift() {
+                    Some(shifted_character(character))
+                } else {
+                    Some(character)
+                }
+            },
+            Key::Enter => Some('\n'),
+            Key::Tab => Some('\t'),
+            Key::Backspace => Some('\x08'),
+            Key::Escape => Some('\x1b'),
+            _ => None
+        }
+    }
+}
+
+/// The keys for the scancodes without the extended prefix.
+const SCANCODE_TABLE: [Key; 0x59] = [
+    Key::Unknown(0x00),
+    Key::Escape,
+    Key::Char('1'),
+    Key::Char('2'),
+    Key::Char('3'),
+    Key::Char('4'),
+    Key::Char('5'),
+    Key::Char('6'),
+    Key::Char('7'),
+    Key::Char('8'),
+    Key::Char('9'),
+    Key::Char('0'),
+    Key::Char('-'),
+    Key::Char('='),
+    Key::Backspace,
+    Key::Tab,
+    Key::Char('q'),
+    Key::Char('w'),
+    Key::Char('e'),
+    Key::Char('r'),
+    Key::Char('t'),
+    Key::Char('y'),
+    Key::Char('u'),
+    Key::Char('i'),
+    Key::Char('o'),
+    Key::Char('p'),
+    Key::Char('['),
+    Key::Char(']'),
+    Key::Enter,
+    Key::LeftControl,
+    Key::Char('a'),
+    Key::Char('s'),
+    Key::Char('d'),
+    Key::Char('f'),
+    Key::Char('g'),
+    Key::Char('h'),
+    Key::Char('j'),
+    Key::Char('k'),
+    Key::Char('l'),
+    Key::Char(';'),
+    Key::Char('\''),
+    Key::Char('`'),
+    Key::LeftShift,
+    Key::Char('\\'),
+    Key::Char('z'),
+    Key::Char('x'),
+    Key::Char('c'),
+    Key::Char('v'),
+    Key::Char('b'),
+    Key::Char('n'),
+    Key::Char('m'),
+    Key::Char(','),
+    Key::Char('.'),
+    Key::Char('/'),
+    Key::RightShift,
+    Key::Char('*'),
+    Key::LeftAlt,
+    Key::Char(' '),
+    Key::CapsLock,
+    Key::Function(1),
+    Key::Function(2),
+    Key::Function(3),
+    Key::Function(4),
+    Key::Function(5),
+    Key::Function(6),
+    Key::Function(7),
+    Key::Function(8),
+    Key::Function(9),
+    Key::Function(10),
+    Key::NumLock,
+    Key::ScrollLock,
+    Key::Char('7'),
+    Key::Char('8'),
+    Key::Char('9'),
+    Key::Char('-'),
+    Key::Char('4'),
+    Key::Char('5'),
+    Key::Char('6'),
+    Key::Char('+'),
+    Key::Char('1'),
+    Key::Char('2'),
+    Key::Char('3'),
+    Key::Char('0'),
+    Key::Char('.'),
+    Key::Unknown(0x54),
+    Key::Unknown(0x55),
+    Key::Unknown(0x56),
+    Key::Function(11),
+    Key::Function(12)
+];
+
+/// Returns the key for a scancode without the extended prefix.
+fn regular_key(scancode: u8) -> Key {
+    SCANCODE_TABLE
+        .get(scancode as usize)
+        .cloned()
+        .unwrap_or(Key::Unknown(scancode))
+}
+
+/// Returns the key for a scancode with the extended prefix.
+fn extended_key(scancode: u8) -> Option<Key> {
+    match scancode {
+        // Fake shifts sent around some extended keys.
+        0x2a | 0x36 => None,
+        0x1c => Some(Key::Enter),
+        0x1d => Some(Key::RightControl),
+        0x35 => Some(Key::Char('/')),
+        0x38 => Some(Key::RightAlt),
+        0x47 => Some(Key::Home),
+        0x48 => Some(Key::Up),
+        0x49 => Some(Key::PageUp),
+        0x4b => Some(Key::Left),
+        0x4d => Some(Key::Right),
+        0x4f => Some(Key::End),
+        0x50 => Some(Key::Down),
+        0x51 => Some(Key::PageDown),
+        0x52 => Some(Key::Insert),
+        0x53 => Some(Key::Delete),
+        _ => Some(Key::Unknown(scancode))
+    }
+}
+
+/// Returns the character produced by the given key when shift is held.
+fn shifted_character(character: char) -> char {
+    match character {
+        '1' => '!',
+        '2' => '@',
+        '3' => '#',
+        '4' => '$',
+        '5' => '%',
+        '6' => '^',
+        '7' => '&',
+        '8' => '*',
+        '9' => '(',
+        '0' => ')',
+        '-' => '_',
+        '=' => '+',
+        '[' => '{',
+        ']' => '}',
+        ';' => ':',
+        '\'' => '"',
+        '`' => '~',
+        '\\' => '|',
+        ',' => '<',
+        '.' => '>',
+        '/' => '?',
+        other => other.to_ascii_uppercase()
+    }
+}
+
+/// Translates a stream of scancodes into key events.
+pub struct Decoder {
+    /// Whether the last scancode was the extended prefix.
+    extended: bool,
+    /// The modifiers that are currently active.
+    modifiers: Modifiers
+}
+
+impl Decoder {
+    /// Creates a new decoder with no active modifiers.
+    pub fn new() -> Decoder {
+        Decoder {
+            extended: false,
+            modifiers: Modifiers::empty()
+        }
+    }
+
+    /// Decodes the next scancode.
+    ///
+    /// Returns `None` if the scancode doesn't complete a key event.
+    pub fn decode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if scancode == EXTENDED_PREFIX {
+            self.extended = true;
+            return None;
+        }
+
+        let pressed = scancode & RELEASE_BIT == 0;
+        let code = scancode & !RELEASE_BIT;
+
+        let key = if self.extended {
+            self.extended = false;
+            extended_key(code)?
+        } else {
+            regular_key(code)
+        };
+
+        self.update_modifiers(key, pressed);
+
+        Some(KeyEvent {
+            key,
+            pressed,
+            modifiers: self.modifiers
+        })
+    }
+
+    /// Updates the modifier state for the given key.
+    fn update_modifiers(&mut self, key: Key, pressed: bool) {
+        let modifier = match key {
+            Key::LeftShift => Modifiers::LEFT_SHIFT,
+            Key::RightShift => Modifiers::RIGHT_SHIFT,
+            Key::LeftControl => Modifiers::LEFT_CONTROL,
+            Key::RightControl => Modifiers::RIGHT_CONTROL,
+            Key::LeftAlt => Modifiers::LEFT_ALT,
+            Key::RightAlt => Modifiers::RIGHT_ALT,
+            Key::CapsLock => {
+                if pressed {
+                    self.modifiers.toggle(Modifiers::CAPS_LOCK);
+                }
+                return;
+            },
+            _ => return
+        };
+
+        self.modifiers.set(modifier, pressed);
+    }
+}
+
+/// Handles a scancode received from the keyboard.
+pub fn handle_scancode(scancode: u8) {
+    let event = DECODER.lock().decode(scancode);
+
+    if let Some(event) = event {
+        // Drop new events if nobody reads them.
+        let _ = KEY_EVENTS.push(event);
+    }
+
+    super::line_discipline::process_input();
+}
+
+/// Returns the oldest key event that wasn't read yet.
+pub fn read_event() -> Option<KeyEvent> {
+    KEY_EVENTS.pop()
+}
+
+/// Returns the next character typed on the keyboard, if there is one.
+///
+/// Events that don't produce characters are skipped.
+pub fn read_char() -> Option<char> {
+    while let Some(event) = read_event() {
+        if let Some(character) = event.character() {
+            return Some(character);
+        }
+    }
+
+    None
+}
+
+/// Tests for the scancode decoder.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the scancodes and returns the last produced event.
+    fn decode_all(decoder: &mut Decoder, scancodes: &[u8]) -> Option<KeyEvent> {
+        let mut last_event = None;
+        for &scancode in scancodes {
+            if let Some(event) = decoder.decode(scancode) {
+                last_event = Some(event);
+            }
+        }
+        last_event
+    }
+
+    /// Tests known scancode sequences against the expected keys.
+    #[test]
+    fn test_decode_table() {
+        let cases: [(&[u8], Key, bool); 8] = [
+            (&[0x1e], Key::Char('a'), true),
+            (&[0x9e], Key::Char('a'), false),
+            (&[0x1c], Key::Enter, true),
+            (&[0x3b], Key::Function(1), true),
+            (&[0xe0, 0x48], Key::Up, true),
+            (&[0xe0, 0xc8], Key::Up, false),
+            (&[0xe0, 0x1d], Key::RightControl, true),
+            (&[0xe0, 0x53], Key::Delete, true)
+        ];
+
+        for &(scancodes, key, pressed) in cases.iter() {
+            let event = decode_all(&mut Decoder::new(), scancodes).unwrap();
+            assert_eq!(event.key, key);
+            assert_eq!(event.pressed, pressed);
+        }
+    }
+
+    /// Tests that the extended prefix alone doesn't produce an event.
+    #[test]
+    fn test_extended_prefix() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(0xe0), None);
+        assert_eq!(decoder.decode(0x48).unwrap().key, Key::Up);
+        // The prefix only applies to a single scancode.
+        assert_eq!(decoder.decode(0x48).unwrap().key, Key::Char('8'));
+    }
+
+    /// Tests that shift changes the produced characters.
+    #[test]
+    fn test_shift() {
+        let mut decoder = Decoder::new();
+        let event = decode_all(&mut decoder, &[0x2a, 0x1e]).unwrap();
+        assert!(event.modifiers.shift());
+        assert_eq!(event.character(), Some('A'));
+
+        let event = decode_all(&mut decoder, &[0x02]).unwrap();
+        assert_eq!(event.character(), Some('!'));
+
+        let event = decode_all(&mut decoder, &[0xaa, 0x1e]).unwrap();
+        assert!(!event.modifiers.shift());
+        assert_eq!(event.character(), Some('a'));
+    }
+
+    /// Tests that caps lock toggles on key presses.
+    #[test]
+    fn test_caps_lock() {
+        let mut decoder = Decoder::new();
+        let event = decode_all(&mut decoder, &[0x3a, 0xba, 0x1e]).unwrap();
+        assert_eq!(event.character(), Some('A'));
+
+        let event = decode_all(&mut decoder, &[0x36, 0x1e]).unwrap();
+        assert_eq!(event.character(), Some('a'));
+
+        let event = decode_all(&mut decoder, &[0xb6, 0x3a, 0xba, 0x1e]).unwrap();
+        assert_eq!(event.character(), Some('a'));
+    }
+
+    /// Tests that control and alt are tracked.
+    #[test]
+    fn test_control_and_alt() {
+        let mut decoder = Decoder::new();
+        let event = decode_all(&mut decoder, &[0x1d, 0x2e]).unwrap();
+        assert!(event.modifiers.control());
+        assert_eq!(event.character(), Some('\x03'));
+
+        let event = decode_all(&mut decoder, &[0x9d, 0xe0, 0x38, 0x2e]).unwrap();
+        assert!(!event.modifiers.control());
+        assert!(event.modifiers.alt());
+
+        let event = decode_all(&mut decoder, &[0xe0, 0xb8]).unwrap();
+        assert!(!event.modifiers.alt());
+    }
+
+    /// Tests that released keys don't produce characters.
+    #[test]
+    fn test_release_has_no_character() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(0x9e).unwrap().character(), None);
+    }
+}
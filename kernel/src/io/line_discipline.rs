@@ -0,0 +1,203 @@
+//! Provides line buffered console input.
+//!
+//! Typed characters are collected into a line, which is only passed on once
+//! enter is pressed. In raw mode the characters are passed on unprocessed.
+
+use super::{keyboard, serial};
+use alloc::Vec;
+use core::mem;
+use crate::collections::RingBuffer;
+use crate::sync::time::Timestamp;
+use crate::sync::{Interrupted, Mutex, WaitQueue};
+
+/// The maximum length of a line, including the line break.
+const LINE_BUFFER_SIZE: usize = 256;
+
+/// The maximum amount of input that can wait to be read.
+const INPUT_BUFFER_SIZE: usize = 1024;
+
+/// The ASCII backspace character.
+const BACKSPACE: u8 = 0x08;
+
+/// The ASCII delete character.
+const DELETE: u8 = 0x7f;
+
+lazy_static! {
+    /// The line discipline of the console.
+    static ref LINE_DISCIPLINE: Mutex<LineDiscipline> = Mutex::new(LineDiscipline::new());
+
+    /// The threads waiting for console input.
+    static ref INPUT_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+}
+
+/// Turns typed characters into input for programs.
+struct LineDiscipline {
+    /// The line that is currently being edited.
+    line: Vec<u8>,
+    /// The input that is ready to be read.
+    input: RingBuffer<u8>,
+    /// Whether characters are passed on without processing.
+    raw: bool
+}
+
+impl LineDiscipline {
+    /// Creates a new line discipline in cooked mode.
+    fn new() -> LineDiscipline {
+        LineDiscipline {
+            line: Vec::with_capacity(LINE_BUFFER_SIZE),
+            input: RingBuffer::with_capacity(INPUT_BUFFER_SIZE),
+            raw: false
+        }
+    }
+
+    /// Handles a typed character.
+    ///
+    /// Returns true if new input is ready to be read.
+    fn handle_char(&mut self, character: u8) -> bool {
+        if self.raw {
+            return self.push_input(character);
+        }
+
+        match character {
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+                false
+            },
+            b'\n' => {
+                print!("\n");
+                self.line.push(b'\n');
+
+                let line = mem::replace(&mut self.line, Vec::with_capacity(LINE_BUFFER_SIZE));
+                let mut accepted = false;
+                for byte in line {
+                    accepted |= self.push_input(byte);
+                }
+                accepted
+            },
+            byte => {
+                // Keep space for the line break. Characters that don't fit are dropped.
+                if self.line.len() < LINE_BUFFER_SIZE - 1 {
+                    self.line.push(byte);
+                    print!("{}", byte as char);
+                }
+                false
+            }
+        }
+    }
+
+    /// Adds the byte to the input that is ready to be read.
+    ///
+    /// The byte is dropped if the input buffer is full.
+    fn push_input(&mut self, byte: u8) -> bool {
+        self.input.push(byte).is_ok()
+    }
+
+    /// Checks if there is input to read.
+    fn has_input(&self) -> bool {
+        !self.input.is_empty()
+    }
+
+    /// Reads the available input into the buffer.
+    ///
+    /// In cooked mode at most one line is read at a time.
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let mut count = 0;
+
+        while count < buffer.len() {
+            match self.input.pop() {
+                Some(byte) => {
+                    buffer[count] = byte;
+                    count += 1;
+
+                    if byte == b'\n' && !self.raw {
+                        break;
+                    }
+                },
+                None => break
+            }
+        }
+
+        count
+    }
+}
+
+/// Processes the characters typed since the last call.
+///
+/// Input is taken from both the keyboard and the serial port.
+pub fn process_input() {
+    let input_available = {
+        let mut line_discipline = LINE_DISCIPLINE.lock();
+        let mut input_available = false;
+
+        while let Some(character) = keyboard::read_char() {
+            if character.is_ascii() {
+                input_available |= line_discipline.handle_char(character as u8);
+            }
+        }
+
+        while let Some(byte) = serial::read_byte() {
+            // Serial terminals send a carriage return for enter.
+            let byte = if byte == b'\r' { b'\n' } else { byte };
+            input_available |= line_discipline.handle_char(byte);
+        }
+
+        input_available
+    };
+
+    if input_available {
+        INPUT_WAIT_QUEUE.notify_all();
+    }
+}
+
+/// Reads console input into the buffer, blocking until input is available.
+///
+/// Returns the number of bytes read. Reading fails if the process of the
+/// thread is killed meanwhile.
+pub fn read(buffer: &mut [u8]) -> Result<usize, Interrupted> {
+    read_with_deadline(buffer, None)
+}
+
+/// Reads console input into the buffer, like `read`, but gives up once the
+/// optional deadline passed.
+pub fn read_with_deadline(
+    buffer: &mut [u8],
+    deadline: Option<Timestamp>
+) -> Result<usize, Interrupted> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+
+    loop {
+        let has_input = || LINE_DISCIPLINE.lock().has_input();
+
+        match deadline {
+            Some(deadline) => INPUT_WAIT_QUEUE.wait_until_timeout(has_input, deadline)?,
+            None => INPUT_WAIT_QUEUE.wait_until(has_input)?
+        }
+
+        // Another reader might have been faster.
+        let count = LINE_DISCIPLINE.lock().read(buffer);
+        if count > 0 {
+            return Ok(count);
+        }
+    }
+}
+
+/// Checks whether console input is available.
+pub fn has_input() -> bool {
+    LINE_DISCIPLINE.lock().has_input()
+}
+
+/// Returns the queue that is notified when console input becomes available.
+pub fn input_wait_queue() -> &'static WaitQueue {
+    &INPUT_WAIT_QUEUE
+}
+
+/// Enables or disables raw mode.
+///
+/// In raw mode characters aren't echoed and are readable immediately.
+pub fn set_raw_mode(raw: bool) {
+    LINE_DISCIPLINE.lock().raw = raw;
+}
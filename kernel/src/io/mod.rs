@@ -0,0 +1,67 @@
+//! This module deals with all in-kernel IO.
+//!
+//! It handles all the IO that kernel code needs to perform.
+//!
+//! Printing and logging never allocate, so they can be used before the heap
+//! is initialized. Only formatting values that allocate themselves, like
+//! strings built with `format!`, requires the heap.
+
+pub mod ahci;
+pub mod block;
+pub mod console;
+pub mod keyboard;
+pub mod line_discipline;
+pub mod pci;
+pub mod pipe;
+pub mod poll;
+pub mod ram_disk;
+pub mod serial;
+
+use crate::arch::{self, Architecture};
+
+/// Initializes all IO devices.
+pub fn init() {
+    assert_has_not_been_called!("IO components should only be initialized once");
+    arch::Current::init_io();
+}
+
+/// Prints the given line to the screen.
+///
+/// It uses the arguments passed to it and prints the string with the
+/// formatting arguments.
+/// Then a new line is started.
+#[macro_export]
+macro_rules! println {
+    ($fmt:expr) => (print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Prints the given string to the screen.
+///
+/// It uses the arguments passed to it and prints the string with the
+/// formatting arguments.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ({
+        $crate::io::console::print(format_args!($($arg)*));
+    });
+}
+
+/// Prints the given line to the given console.
+///
+/// This works like `println!`, but ignores the default sink.
+#[macro_export]
+macro_rules! println_to {
+    ($sink:expr, $fmt:expr) => (print_to!($sink, concat!($fmt, "\n")));
+    ($sink:expr, $fmt:expr, $($arg:tt)*) => (print_to!($sink, concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Prints the given string to the given console.
+///
+/// This works like `print!`, but ignores the default sink.
+#[macro_export]
+macro_rules! print_to {
+    ($sink:expr, $($arg:tt)*) => ({
+        $crate::io::console::Console::write_fmt(&$sink, format_args!($($arg)*));
+    });
+}
@@ -0,0 +1,384 @@
+//! Enumerates the devices on the PCI buses.
+//!
+//! The configuration space is accessed through the architecture, which on
+//! x86_64 uses the legacy configuration ports.
+
+use alloc::Vec;
+use crate::arch::{self, Architecture};
+use crate::memory::reserved::{self, ReservedKind};
+use crate::memory::{Address, MemoryArea, PhysicalAddress};
+
+/// The number of PCI buses.
+const BUS_COUNT: u16 = 256;
+
+/// The number of devices on a PCI bus.
+const DEVICES_PER_BUS: u8 = 32;
+
+/// The number of functions of a PCI device.
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+/// The vendor ID that is read for functions that don't exist.
+const INVALID_VENDOR_ID: u16 = 0xffff;
+
+/// The bit of the header type that marks multi-function devices.
+const MULTI_FUNCTION_BIT: u8 = 0x80;
+
+/// The header type of general devices, which have six BARs.
+const GENERAL_DEVICE_HEADER: u8 = 0x00;
+
+/// The header type of PCI-to-PCI bridges, which have two BARs.
+const PCI_BRIDGE_HEADER: u8 = 0x01;
+
+/// The offset of the first BAR in the configuration space.
+const BAR_OFFSET: u8 = 0x10;
+
+/// The offset of the command register in the configuration space.
+const COMMAND_OFFSET: u8 = 0x04;
+
+/// The bit of the command register that lets the device access memory.
+const MEMORY_SPACE_BIT: u32 = 1 << 1;
+
+/// The bit of the command register that lets the device initiate DMA.
+const BUS_MASTER_BIT: u32 = 1 << 2;
+
+/// A base address register (BAR) of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bar {
+    /// The BAR is unused or the upper half of a 64-bit BAR.
+    None,
+    /// The BAR describes memory mapped registers.
+    Memory {
+        /// The physical base address.
+        address: u64,
+        /// The size of the decoded memory in bytes.
+        ///
+        /// This is probed once when the device is discovered and is zero
+        /// before.
+        size: u64,
+        /// Whether reading the memory has no side effects.
+        prefetchable: bool
+    },
+    /// The BAR describes a range of I/O ports.
+    Io(u32)
+}
+
+/// A function of a device on a PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PciDevice {
+    /// The bus the device is on.
+    pub bus: u8,
+    /// The number of the device on its bus.
+    pub device: u8,
+    /// The number of the function within the device.
+    pub function: u8,
+    /// The ID of the vendor.
+    pub vendor_id: u16,
+    /// The ID of the device, assigned by the vendor.
+    pub device_id: u16,
+    /// The class of the device.
+    pub class: u8,
+    /// The subclass of the device.
+    pub subclass: u8,
+    /// The programming interface of the device.
+    pub prog_if: u8,
+    /// The layout of the rest of the configuration space.
+    pub header_type: u8,
+    /// The base address registers.
+    pub bars: [Bar; 6]
+}
+
+impl PciDevice {
+    /// Reads the function with the given address using the given register reader.
+    ///
+    /// Returns `None` if the function doesn't exist.
+    fn read<F: Fn(u8) -> u32>(
+        bus: u8,
+        device: u8,
+        function: u8,
+        read_config: F
+    ) -> Option<PciDevice> {
+        let id = read_config(0x00);
+        let vendor_id = id as u16;
+
+        if vendor_id == INVALID_VENDOR_ID {
+            return None;
+        }
+
+        let class = read_config(0x08);
+        let header_type = (read_config(0x0c) >> 16) as u8 & !MULTI_FUNCTION_BIT;
+
+        let bar_count = match header_type {
+            GENERAL_DEVICE_HEADER => 6,
+            PCI_BRIDGE_HEADER => 2,
+            _ => 0
+        };
+
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            header_type,
+            bars: read_bars(bar_count, &read_config)
+        })
+    }
+
+    /// Probes the sizes of the memory BARs.
+    fn probe_bar_sizes(&mut self) {
+        for index in 0..self.bars.len() {
+            if let Bar::Memory { ref mut size, .. } = self.bars[index] {
+                *size = probe_memory_bar_size(self.bus, self.device, self.function, index);
+            }
+        }
+    }
+
+    /// Records the memory of the memory BARs as reserved.
+    fn reserve_bars(&self) {
+        for bar in &self.bars {
+            if let Bar::Memory { address, size, .. } = *bar {
+                reserved::reserve(
+                    MemoryArea::new(PhysicalAddress::from_usize(address as usize), size as usize),
+                    ReservedKind::Mmio
+                );
+            }
+        }
+    }
+
+    /// Allows the device to respond to memory accesses and to perform DMA.
+    pub fn enable_bus_mastering(&self) {
+        let command =
+            arch::Current::read_pci_config(self.bus, self.device, self.function, COMMAND_OFFSET);
+
+        // The upper half holds the status, where writing ones clears bits.
+        let command = command & 0xffff | MEMORY_SPACE_BIT | BUS_MASTER_BIT;
+
+        arch::Current::write_pci_config(
+            self.bus,
+            self.device,
+            self.function,
+            COMMAND_OFFSET,
+            command
+        );
+    }
+}
+
+/// Returns the size of the memory the BAR with the given index of the given
+/// function decodes.
+///
+/// The size is probed by writing ones to the BAR, so memory decoding is
+/// disabled meanwhile.
+fn probe_memory_bar_size(bus: u8, device: u8, function: u8, index: usize) -> u64 {
+    let read_config = |offset| arch::Current::read_pci_config(bus, device, function, offset);
+    let write_config = |offset, value| {
+        arch::Current::write_pci_config(bus, device, function, offset, value)
+    };
+    let probe = |offset| {
+        let original = read_config(offset);
+        write_config(offset, 0xffff_ffff);
+        let value = read_config(offset);
+        write_config(offset, original);
+        value
+    };
+
+    let command = read_config(COMMAND_OFFSET);
+    write_config(COMMAND_OFFSET, command & 0xffff & !MEMORY_SPACE_BIT);
+
+    let offset = BAR_OFFSET + index as u8 * 4;
+    let low = probe(offset);
+    let high = if is_64_bit_bar(read_config(offset)) {
+        Some(probe(offset + 4))
+    } else {
+        None
+    };
+
+    write_config(COMMAND_OFFSET, command & 0xffff);
+
+    bar_size(low, high)
+}
+
+/// Decodes the given number of BARs.
+fn read_bars<F: Fn(u8) -> u32>(count: usize, read_config: &F) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    let mut index = 0;
+
+    while index < count {
+        let bar_index = index;
+        let value = read_config(BAR_OFFSET + index as u8 * 4);
+
+        if value & 0x1 == 0x1 {
+            bars[bar_index] = Bar::Io(value & !0x3);
+        } else if value != 0 {
+            let mut address = (value & !0xf) as u64;
+
+            // 64-bit BARs use the next BAR for the upper half of the address.
+            if is_64_bit_bar(value) && index + 1 < count {
+                index += 1;
+                address |= (read_config(BAR_OFFSET + index as u8 * 4) as u64) << 32;
+            }
+
+            bars[bar_index] = Bar::Memory {
+                address,
+                size: 0,
+                prefetchable: value & 0x8 != 0
+            };
+        }
+
+        index += 1;
+    }
+
+    bars
+}
+
+/// Returns true if the given value of a memory BAR describes a 64-bit BAR.
+fn is_64_bit_bar(value: u32) -> bool {
+    (value >> 1) & 0x3 == 0x2
+}
+
+/// Returns the size of a memory BAR from the values read after writing ones
+/// to it.
+///
+/// The upper half is only given for 64-bit BARs.
+fn bar_size(low: u32, high: Option<u32>) -> u64 {
+    let low_mask = (low & !0xf) as u64;
+
+    match high {
+        Some(high) => (!((high as u64) << 32 | low_mask)).wrapping_add(1),
+        None => (!(low_mask as u32)).wrapping_add(1) as u64
+    }
+}
+
+/// Reads the function with the given address from the configuration space.
+fn read_function(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+    PciDevice::read(bus, device, function, |offset| {
+        arch::Current::read_pci_config(bus, device, function, offset)
+    })
+}
+
+lazy_static! {
+    /// The functions of all devices on the PCI buses.
+    static ref DEVICES: Vec<PciDevice> = discover();
+}
+
+/// Returns all the functions of all devices on the PCI buses.
+///
+/// The buses are only scanned on the first call.
+pub fn enumerate() -> impl Iterator<Item = PciDevice> {
+    DEVICES.iter().cloned()
+}
+
+/// Scans the PCI buses for functions.
+///
+/// The sizes of the memory BARs are probed and their memory is reserved.
+fn discover() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+
+    for bus in 0..BUS_COUNT {
+        for device in 0..DEVICES_PER_BUS {
+            let first_function = match read_function(bus as u8, device, 0) {
+                Some(first_function) => first_function,
+                None => continue
+            };
+            let header = arch::Current::read_pci_config(bus as u8, device, 0, 0x0c);
+            let header_type = (header >> 16) as u8;
+            let multi_function = header_type & MULTI_FUNCTION_BIT != 0;
+
+            devices.push(first_function);
+
+            if multi_function {
+                for function in 1..FUNCTIONS_PER_DEVICE {
+                    if let Some(function) = read_function(bus as u8, device, function) {
+                        devices.push(function);
+                    }
+                }
+            }
+        }
+    }
+
+    for device in &mut devices {
+        device.probe_bar_sizes();
+        device.reserve_bars();
+    }
+
+    devices
+}
+
+/// Tests for the PCI device decoding.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a reader for the configuration space of the e1000 network card of QEMU.
+    fn e1000_config(offset: u8) -> u32 {
+        match offset {
+            0x00 => 0x100e_8086,
+            0x08 => 0x0200_0003,
+            0x0c => 0x0000_0000,
+            0x10 => 0xfebc_0000,
+            0x14 => 0x0000_c001,
+            _ => 0
+        }
+    }
+
+    /// Tests that the IDs, class and BARs of a device are decoded.
+    #[test]
+    fn test_read_device() {
+        let device = PciDevice::read(0, 3, 0, e1000_config).unwrap();
+
+        assert_eq!(device.vendor_id, 0x8086);
+        assert_eq!(device.device_id, 0x100e);
+        assert_eq!((device.class, device.subclass, device.prog_if), (0x02, 0x00, 0x00));
+        assert_eq!(
+            device.bars[0],
+            Bar::Memory {
+                address: 0xfebc_0000,
+                size: 0,
+                prefetchable: false
+            }
+        );
+        assert_eq!(device.bars[1], Bar::Io(0xc000));
+        assert_eq!(device.bars[2], Bar::None);
+    }
+
+    /// Tests that 64-bit BARs are combined from two registers.
+    #[test]
+    fn test_64_bit_bar() {
+        let read_config = |offset| match offset {
+            0x00 => 0x1af4_1234,
+            0x10 => 0xe000_000c,
+            0x14 => 0x0000_0001,
+            _ => 0
+        };
+        let device = PciDevice::read(0, 4, 0, read_config).unwrap();
+
+        assert_eq!(
+            device.bars[0],
+            Bar::Memory {
+                address: 0x1_e000_0000,
+                size: 0,
+                prefetchable: true
+            }
+        );
+        assert_eq!(device.bars[1], Bar::None);
+    }
+
+    /// Tests decoding the sizes of probed BARs.
+    #[test]
+    fn test_bar_size() {
+        assert_eq!(bar_size(0xfffe_0000, None), 0x2_0000);
+        assert_eq!(bar_size(0xffff_f008, None), 0x1000);
+        assert_eq!(bar_size(0xf000_000c, Some(0xffff_ffff)), 0x1000_0000);
+        assert_eq!(bar_size(0x0000_000c, Some(0xffff_fffc)), 0x4_0000_0000);
+        // Unimplemented BARs read back as zero.
+        assert_eq!(bar_size(0, None), 0);
+    }
+
+    /// Tests that missing functions aren't reported.
+    #[test]
+    fn test_missing_function() {
+        assert_eq!(PciDevice::read(0, 31, 0, |_| 0xffff_ffff), None);
+    }
+}
@@ -0,0 +1,282 @@
+//! Provides anonymous pipes for passing bytes between processes.
+//!
+//! A pipe has a read end and a write end. Reading blocks until data is
+//! available or all write ends are closed, writing blocks while the buffer is
+//! full.
+
+use alloc::arc::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::collections::RingBuffer;
+use crate::io::poll::PollEvents;
+use crate::sync::time::Timestamp;
+use crate::sync::{Interrupted, Mutex, WaitQueue};
+
+/// The number of bytes a pipe can buffer.
+const PIPE_CAPACITY: usize = 4096;
+
+/// The errors that can occur when using a pipe.
+#[derive(Debug, PartialEq)]
+pub enum PipeError {
+    /// All read ends of the pipe are closed.
+    BrokenPipe
+}
+
+/// The state shared by both ends of a pipe.
+pub struct Pipe {
+    /// The bytes that were written, but not read yet.
+    buffer: Mutex<RingBuffer<u8>>,
+    /// The number of open read ends.
+    readers: AtomicUsize,
+    /// The number of open write ends.
+    writers: AtomicUsize,
+    /// The threads waiting for data to read.
+    readable: WaitQueue,
+    /// The threads waiting for space to write.
+    writable: WaitQueue
+}
+
+impl Pipe {
+    /// Reads available bytes into the buffer and returns their number.
+    ///
+    /// This blocks until at least one byte is available. Zero is returned
+    /// once all write ends are closed and the pipe is empty. Reading fails if
+    /// the process of the thread is killed meanwhile.
+    ///
+    /// # Note
+    /// No locks may be held while reading.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Interrupted> {
+        self.read_with_deadline(buffer, None)
+    }
+
+    /// Reads available bytes into the buffer, like `read`, but gives up once
+    /// the optional deadline passed.
+    ///
+    /// # Note
+    /// No locks may be held while reading.
+    pub fn read_with_deadline(
+        &self,
+        buffer: &mut [u8],
+        deadline: Option<Timestamp>
+    ) -> Result<usize, Interrupted> {
+        let mut count = 0;
+
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        {
+            let condition = || {
+                let mut data = self.buffer.lock();
+
+                while count < buffer.len() {
+                    match data.pop() {
+                        Some(byte) => buffer[count] = byte,
+                        None => break
+                    }
+                    count += 1;
+                }
+
+                count > 0 || self.writers.load(Ordering::SeqCst) == 0
+            };
+
+            match deadline {
+                Some(deadline) => self.readable.wait_until_timeout(condition, deadline)?,
+                None => self.readable.wait_until(condition)?
+            }
+        }
+
+        self.writable.notify_all();
+
+        Ok(count)
+    }
+
+    /// Writes all bytes of the buffer and returns their number.
+    ///
+    /// This blocks while the pipe is full. If all read ends are closed, the
+    /// number of bytes written until then is returned, or an error if there
+    /// were none.
+    ///
+    /// # Note
+    /// No locks may be held while writing.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, PipeError> {
+        let mut written = 0;
+        let mut broken = false;
+
+        while written < buffer.len() && !broken {
+            let result = self.writable.wait_until(|| {
+                if self.readers.load(Ordering::SeqCst) == 0 {
+                    broken = true;
+                    return true;
+                }
+
+                let mut data = self.buffer.lock();
+                let written_before = written;
+
+                while written < buffer.len() && data.push(buffer[written]).is_ok() {
+                    written += 1;
+                }
+
+                written > written_before
+            });
+
+            self.readable.notify_all();
+
+            // The thread exits once it returns from the syscall, so the result doesn't matter.
+            if result == Err(Interrupted::Killed) {
+                break;
+            }
+        }
+
+        if written == 0 && broken {
+            Err(PipeError::BrokenPipe)
+        } else {
+            Ok(written)
+        }
+    }
+
+    /// Returns the events that are ready on the read end.
+    ///
+    /// The read end is readable while data is buffered and hung up once all
+    /// write ends are closed.
+    pub fn reader_events(&self) -> PollEvents {
+        let mut events = PollEvents::empty();
+
+        if !self.buffer.lock().is_empty() {
+            events |= PollEvents::READABLE;
+        }
+
+        if self.writers.load(Ordering::SeqCst) == 0 {
+            events |= PollEvents::HANG_UP;
+        }
+
+        events
+    }
+
+    /// Returns the events that are ready on the write end.
+    ///
+    /// The write end is writable while the buffer has space and has an error
+    /// once all read ends are closed.
+    pub fn writer_events(&self) -> PollEvents {
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            PollEvents::ERROR
+        } else if !self.buffer.lock().is_full() {
+            PollEvents::WRITABLE
+        } else {
+            PollEvents::empty()
+        }
+    }
+
+    /// Returns the queue that is notified when the read end may have become
+    /// ready.
+    pub fn readable_queue(&self) -> &WaitQueue {
+        &self.readable
+    }
+
+    /// Returns the queue that is notified when the write end may have become
+    /// ready.
+    pub fn writable_queue(&self) -> &WaitQueue {
+        &self.writable
+    }
+}
+
+/// The read end of a pipe.
+pub struct PipeReader(Arc<Pipe>);
+
+impl PipeReader {
+    /// Returns the pipe this end belongs to.
+    pub fn pipe(&self) -> Arc<Pipe> {
+        self.0.clone()
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.readers.fetch_sub(1, Ordering::SeqCst);
+
+        // Blocked writers have to notice that nobody reads anymore.
+        self.0.writable.notify_all();
+    }
+}
+
+/// The write end of a pipe.
+pub struct PipeWriter(Arc<Pipe>);
+
+impl PipeWriter {
+    /// Returns the pipe this end belongs to.
+    pub fn pipe(&self) -> Arc<Pipe> {
+        self.0.clone()
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.writers.fetch_sub(1, Ordering::SeqCst);
+
+        // Blocked readers have to notice the end of the data.
+        self.0.readable.notify_all();
+    }
+}
+
+/// Creates a new pipe and returns its read and write end.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(RingBuffer::with_capacity(PIPE_CAPACITY)),
+        readers: AtomicUsize::new(1),
+        writers: AtomicUsize::new(1),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new()
+    });
+
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+/// Self-tests for pipes.
+///
+/// These can't be unit tests, because pipes use the scheduler's wait queues.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+
+    /// Checks that closing the write end ends the data after the buffered
+    /// bytes.
+    fn test_closed_writer() -> Result<(), &'static str> {
+        let (reader, writer) = pipe();
+        let mut buffer = [0u8; 4];
+
+        if writer.pipe().write(b"ab") != Ok(2) {
+            return Err("Writing to an open pipe failed.");
+        }
+        drop(writer);
+
+        if reader.pipe().reader_events() != PollEvents::READABLE | PollEvents::HANG_UP {
+            return Err("The read end wasn't readable and hung up.");
+        }
+        if reader.pipe().read(&mut buffer) != Ok(2) || &buffer[..2] != b"ab" {
+            return Err("The buffered bytes weren't read after the write end was closed.");
+        }
+        if reader.pipe().read(&mut buffer) != Ok(0) {
+            return Err("Reading after the end of the data didn't return zero.");
+        }
+
+        Ok(())
+    }
+
+    /// Checks that writing fails once the read end is closed.
+    fn test_closed_reader() -> Result<(), &'static str> {
+        let (reader, writer) = pipe();
+
+        drop(reader);
+
+        if writer.pipe().writer_events() != PollEvents::ERROR {
+            return Err("The write end didn't report an error.");
+        }
+        if writer.pipe().write(b"x") != Err(PipeError::BrokenPipe) {
+            return Err("Writing without a read end didn't fail.");
+        }
+
+        Ok(())
+    }
+
+    register_selftest!(PIPE_CLOSED_WRITER, test_closed_writer);
+    register_selftest!(PIPE_CLOSED_READER, test_closed_reader);
+}
@@ -0,0 +1,212 @@
+//! Waits for any of several sources to become ready.
+//!
+//! The readiness of each kind of source is defined as follows:
+//! - The console is always writable and readable while input is available.
+//! - Files of a filesystem never block, so they are always readable and
+//! writable.
+//! - The read end of a pipe is readable while data is buffered. Once all
+//! write ends are closed, it is hung up.
+//! - The write end of a pipe is writable while the buffer has space. Once all
+//! read ends are closed, it has an error.
+//!
+//! Errors and hang ups are always reported, even if they weren't requested.
+
+use alloc::arc::Arc;
+use alloc::Vec;
+use crate::io::line_discipline;
+use crate::io::pipe::Pipe;
+use crate::sync::time::Timestamp;
+use crate::sync::wait_queue::wait_on_any;
+use crate::sync::WaitQueue;
+
+bitflags! {
+    /// The events that can be waited for.
+    pub struct PollEvents: u16 {
+        /// Set if reading won't block.
+        const READABLE = 1 << 0;
+        /// Set if writing won't block.
+        const WRITABLE = 1 << 2;
+        /// Set if the other end of a pipe was closed while writing.
+        const ERROR = 1 << 3;
+        /// Set if the other end of a pipe was closed while reading.
+        const HANG_UP = 1 << 4;
+        /// Set if the file descriptor isn't open.
+        const INVALID = 1 << 5;
+    }
+}
+
+/// Something whose readiness can be waited for.
+pub enum PollSource {
+    /// The console.
+    Console,
+    /// A file of a filesystem.
+    File,
+    /// The read end of a pipe.
+    PipeReader(Arc<Pipe>),
+    /// The write end of a pipe.
+    PipeWriter(Arc<Pipe>),
+    /// A file descriptor that isn't open.
+    Invalid
+}
+
+impl PollSource {
+    /// Returns the events that are currently ready.
+    pub fn events(&self) -> PollEvents {
+        match *self {
+            PollSource::Console => {
+                if line_discipline::has_input() {
+                    PollEvents::READABLE | PollEvents::WRITABLE
+                } else {
+                    PollEvents::WRITABLE
+                }
+            },
+            PollSource::File => PollEvents::READABLE | PollEvents::WRITABLE,
+            PollSource::PipeReader(ref pipe) => pipe.reader_events(),
+            PollSource::PipeWriter(ref pipe) => pipe.writer_events(),
+            PollSource::Invalid => PollEvents::INVALID
+        }
+    }
+
+    /// Returns the queue that is notified when the events may have changed.
+    ///
+    /// Sources whose events never change have no queue.
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        match *self {
+            PollSource::Console => Some(line_discipline::input_wait_queue()),
+            PollSource::PipeReader(ref pipe) => Some(pipe.readable_queue()),
+            PollSource::PipeWriter(ref pipe) => Some(pipe.writable_queue()),
+            PollSource::File | PollSource::Invalid => None
+        }
+    }
+}
+
+/// Returns the requested events of the source that are ready.
+///
+/// Errors, hang ups and invalid sources are always included.
+fn ready_events(source: &PollSource, requested: PollEvents) -> PollEvents {
+    let always_reported = PollEvents::ERROR | PollEvents::HANG_UP | PollEvents::INVALID;
+
+    source.events() & (requested | always_reported)
+}
+
+/// Waits until any of the sources has one of its requested events ready.
+///
+/// Returns the ready events of each source. If the optional deadline passes
+/// first, no events are ready.
+///
+/// # Note
+/// No locks may be held while polling.
+pub fn poll(
+    sources: &[(PollSource, PollEvents)],
+    deadline: Option<Timestamp>
+) -> Vec<PollEvents> {
+    let mut queues: Vec<&WaitQueue> = Vec::new();
+
+    for &(ref source, _) in sources {
+        if let Some(queue) = source.wait_queue() {
+            // Two descriptors can refer to the same pipe.
+            if !queues
+                .iter()
+                .any(|&known| known as *const WaitQueue == queue as *const WaitQueue)
+            {
+                queues.push(queue);
+            }
+        }
+    }
+
+    let mut ready = Vec::with_capacity(sources.len());
+
+    {
+        let condition = || {
+            ready.clear();
+            ready.extend(
+                sources
+                    .iter()
+                    .map(|&(ref source, requested)| ready_events(source, requested))
+            );
+
+            ready.iter().any(|events| !events.is_empty())
+        };
+
+        // After a timeout or a kill the last check found no events ready.
+        let _ = wait_on_any(&queues, condition, deadline);
+    }
+
+    ready
+}
+
+/// Tests for polling.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that only requested events are reported, besides errors.
+    #[test]
+    fn test_ready_events() {
+        let both = PollEvents::READABLE | PollEvents::WRITABLE;
+
+        assert_eq!(
+            ready_events(&PollSource::File, PollEvents::READABLE),
+            PollEvents::READABLE
+        );
+        assert_eq!(ready_events(&PollSource::File, both), both);
+        assert_eq!(
+            ready_events(&PollSource::File, PollEvents::empty()),
+            PollEvents::empty()
+        );
+        assert_eq!(
+            ready_events(&PollSource::Invalid, PollEvents::empty()),
+            PollEvents::INVALID
+        );
+    }
+}
+
+/// Self-tests for polling.
+///
+/// These can't be unit tests, because pipes use the scheduler's wait queues.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+    use crate::io::pipe::pipe;
+
+    /// Checks the ready events of the ends of a pipe.
+    fn test_pipe_ready_events() -> Result<(), &'static str> {
+        let (reader, writer) = pipe();
+        let reader_source = PollSource::PipeReader(reader.pipe());
+        let writer_source = PollSource::PipeWriter(writer.pipe());
+
+        if ready_events(&reader_source, PollEvents::READABLE) != PollEvents::empty() {
+            return Err("An empty pipe was readable.");
+        }
+        if ready_events(&writer_source, PollEvents::WRITABLE) != PollEvents::WRITABLE {
+            return Err("An empty pipe wasn't writable.");
+        }
+        if ready_events(&writer_source, PollEvents::empty()) != PollEvents::empty() {
+            return Err("An unrequested event of the write end was reported.");
+        }
+
+        if writer.pipe().write(b"x") != Ok(1) {
+            return Err("Writing to an open pipe failed.");
+        }
+        if ready_events(&reader_source, PollEvents::READABLE) != PollEvents::READABLE {
+            return Err("A filled pipe wasn't readable.");
+        }
+
+        // The sources don't keep the ends open.
+        drop(writer);
+
+        if ready_events(&reader_source, PollEvents::empty()) != PollEvents::HANG_UP {
+            return Err("Closing the write end wasn't reported as a hang up.");
+        }
+
+        drop(reader);
+
+        if ready_events(&writer_source, PollEvents::WRITABLE) != PollEvents::ERROR {
+            return Err("Closing the read end wasn't reported as an error.");
+        }
+
+        Ok(())
+    }
+
+    register_selftest!(POLL_PIPE_READY_EVENTS, test_pipe_ready_events);
+}
@@ -0,0 +1,113 @@
+//! A block device that keeps its blocks in memory.
+
+use super::block::{BlockDevice, BlockError, Result};
+use alloc::Vec;
+
+/// A block device backed by a heap allocation.
+pub struct RamDisk {
+    /// The contents of all blocks.
+    data: Vec<u8>,
+    /// The size of a block in bytes.
+    block_size: usize
+}
+
+impl RamDisk {
+    /// Creates a zeroed RAM disk with the given geometry.
+    pub fn new(block_size: usize, block_count: usize) -> RamDisk {
+        assert!(block_size > 0, "The block size must not be zero.");
+
+        let size = block_size
+            .checked_mul(block_count)
+            .expect("The RAM disk is too large.");
+
+        let mut data = Vec::with_capacity(size);
+        data.resize(size, 0);
+
+        RamDisk { data, block_size }
+    }
+
+    /// Returns the bytes of the given block, if it lies on the disk.
+    fn block_range(&self, index: u64, buffer_length: usize) -> Result<(usize, usize)> {
+        if buffer_length != self.block_size {
+            return Err(BlockError::InvalidBuffer);
+        }
+
+        if index >= self.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let start = index as usize * self.block_size;
+
+        Ok((start, start + self.block_size))
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn read_block(&mut self, index: u64, buffer: &mut [u8]) -> Result<()> {
+        let (start, end) = self.block_range(index, buffer.len())?;
+
+        buffer.copy_from_slice(&self.data[start..end]);
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, index: u64, buffer: &[u8]) -> Result<()> {
+        let (start, end) = self.block_range(index, buffer.len())?;
+
+        self.data[start..end].copy_from_slice(buffer);
+
+        Ok(())
+    }
+}
+
+/// Tests for the RAM disk.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    /// Tests that written blocks can be read back without touching their neighbours.
+    #[test]
+    fn test_read_write() {
+        let mut disk: Box<BlockDevice> = Box::new(RamDisk::new(16, 4));
+        let mut buffer = [0u8; 16];
+
+        disk.write_block(2, &[0xab; 16]).unwrap();
+
+        disk.read_block(2, &mut buffer).unwrap();
+        assert_eq!(buffer, [0xab; 16]);
+
+        disk.read_block(1, &mut buffer).unwrap();
+        assert_eq!(buffer, [0; 16]);
+        disk.read_block(3, &mut buffer).unwrap();
+        assert_eq!(buffer, [0; 16]);
+    }
+
+    /// Tests that buffers that don't cover exactly one block are rejected.
+    #[test]
+    fn test_partial_block() {
+        let mut disk = RamDisk::new(16, 4);
+
+        assert_eq!(disk.read_block(0, &mut [0; 8]), Err(BlockError::InvalidBuffer));
+        assert_eq!(disk.write_block(0, &[0; 17]), Err(BlockError::InvalidBuffer));
+    }
+
+    /// Tests that blocks past the end of the disk are rejected.
+    #[test]
+    fn test_out_of_range() {
+        let mut disk = RamDisk::new(16, 4);
+        let mut buffer = [0u8; 16];
+
+        assert_eq!(disk.block_count(), 4);
+        assert_eq!(disk.read_block(4, &mut buffer), Err(BlockError::OutOfRange));
+        assert_eq!(disk.write_block(u64::max_value(), &buffer), Err(BlockError::OutOfRange));
+    }
+}
@@ -0,0 +1,25 @@
+//! Buffers input received on the serial port.
+
+use crate::collections::SyncRingBuffer;
+
+/// The maximum number of received bytes that are buffered.
+const SERIAL_INPUT_BUFFER_SIZE: usize = 256;
+
+lazy_static! {
+    /// The bytes received on the serial port that weren't read yet.
+    static ref SERIAL_INPUT: SyncRingBuffer<u8> =
+        SyncRingBuffer::with_capacity(SERIAL_INPUT_BUFFER_SIZE);
+}
+
+/// Handles a byte received on the serial port.
+pub fn handle_byte(byte: u8) {
+    // Drop new bytes if nobody reads them.
+    let _ = SERIAL_INPUT.push(byte);
+
+    super::line_discipline::process_input();
+}
+
+/// Returns the oldest byte received on the serial port, if there is one.
+pub fn read_byte() -> Option<u8> {
+    SERIAL_INPUT.pop()
+}
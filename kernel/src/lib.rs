@@ -48,12 +48,15 @@ mod macros;
 mod io;
 mod arch;
 mod boot;
+mod collections;
 mod elf;
 mod file_handle;
+mod fs;
 mod initramfs;
 mod interrupts;
 mod memory;
 mod multitasking;
+mod selftest;
 mod sync;
 mod syscalls;
 
@@ -103,7 +106,11 @@ pub extern "C" fn main(magic_number: u32, information_structure_address: usize)
         boot::get_bootloader_name()
     );
     memory::init();
+    initramfs::verify_checksum();
+    multitasking::scheduler::configure_quanta();
     arch::Current::init();
+    io::ahci::init();
+    fs::init();
 
     let extended_info = raw_cpuid::CpuId::new().get_extended_function_info();
     let unwrapped_info = extended_info.unwrap();
@@ -116,7 +123,13 @@ pub extern "C" fn main(magic_number: u32, information_structure_address: usize)
         arch::Current::get_free_memory_size() / 1024 / 1024
     );
 
-    elf::process_from_initramfs_file("/bin/init").expect("Initprocess could not be loaded");
+    if selftest::is_enabled() {
+        selftest::run();
+    }
+
+    let memory_limit = memory::address_space::DEFAULT_MEMORY_LIMIT;
+    elf::process_from_initramfs_file("/bin/init", &[], memory_limit, None)
+        .expect("Initprocess could not be loaded");
 
     unsafe {
         arch::Current::enter_first_thread();
@@ -134,10 +147,18 @@ pub extern "C" fn main(magic_number: u32, information_structure_address: usize)
 #[panic_implementation]
 #[no_mangle]
 pub extern "C" fn panic_fmt(info: &PanicInfo) -> ! {
-    error!("{}", info);
     unsafe {
         sync::disable_preemption();
+        // The panicking code might have held the output locks and won't release them.
+        arch::Current::force_unlock_output();
+    }
+    error!("{}", info);
+    unsafe {
+        arch::Current::print_backtrace();
+        sync::held_locks::print_held_locks();
     }
+    // Failing self-tests exit QEMU instead of halting.
+    selftest::handle_panic();
     loop {
         unsafe {
             sync::cpu_halt();
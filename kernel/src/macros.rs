@@ -35,22 +35,6 @@ macro_rules! from_c_str {
     }};
 }
 
-/// Creates a `&'static str` from a pointer to a raw string and it's length.
-#[macro_export]
-macro_rules! from_raw_str {
-    ($address:expr, $length:expr) => {{
-        use core::slice;
-        use core::str;
-        if $length > 0 {
-            let ptr: *const u8 = $address.as_ptr();
-            let bytes: &[u8] = unsafe { slice::from_raw_parts(ptr, $length as usize) };
-            str::from_utf8(bytes)
-        } else {
-            Ok("")
-        }
-    }};
-}
-
 /// Converts to a virtual address.
 ///
 /// Converts a given physical address within the kernel part of memory to its
@@ -79,6 +63,8 @@ macro_rules! valid_address {
 }
 
 /// Used to define statics that are local to each cpu core.
+///
+/// The value for each core is lazily created on its first access on that core.
 macro_rules! cpu_local {
     ($(#[$attr: meta])* static ref $name: ident : $type: ty = $val: expr;) => {
         __cpu_local_internal!($(#[$attr])*, CPULocal, $name, $type, $val);
@@ -99,18 +85,11 @@ macro_rules! __cpu_local_internal {
         lazy_static! {
             $(#[$attr])*
             pub static ref $name: crate::multitasking::$wrapper_type<$type> = {
-                use alloc::Vec;
                 use crate::multitasking::get_cpu_num;
 
-                let cpu_num = get_cpu_num();
-                let mut vec = Vec::with_capacity(cpu_num);
-
-                for i in 0..cpu_num {
-                    vec.push($val(i));
-                }
-
+                // The values themselves are only created on first access.
                 unsafe {
-                    crate::multitasking::$wrapper_type::new(vec)
+                    crate::multitasking::$wrapper_type::new(get_cpu_num(), $val)
                 }
             };
         }
@@ -119,20 +98,34 @@ macro_rules! __cpu_local_internal {
         lazy_static! {
             $(#[$attr])*
             static ref $name: crate::multitasking::$wrapper_type<$type> = {
-                use alloc::Vec;
                 use crate::multitasking::get_cpu_num;
 
-                let cpu_num = get_cpu_num();
-                let mut vec = Vec::with_capacity(cpu_num);
-
-                for i in 0..cpu_num {
-                    vec.push($val(i));
-                }
-
+                // The values themselves are only created on first access.
                 unsafe {
-                    crate::multitasking::$wrapper_type::new(vec)
+                    crate::multitasking::$wrapper_type::new(get_cpu_num(), $val)
                 }
             };
         }
     };
 }
+
+/// Registers a kernel self-test.
+///
+/// The first argument names the static holding the registration. The second
+/// one is the test function, which returns `Err` with a description of the
+/// problem if the test fails. The tests are run in self-test mode, see the
+/// `selftest` module.
+///
+/// This may only be used with the `selftest` feature enabled.
+#[macro_export]
+macro_rules! register_selftest {
+    ($registration:ident, $function:ident) => {
+        #[link_section = ".selftests"]
+        #[used]
+        #[allow(dead_code)]
+        static $registration: $crate::selftest::SelfTest = $crate::selftest::SelfTest {
+            name: concat!(module_path!(), "::", stringify!($function)),
+            function: $function
+        };
+    };
+}
@@ -1,27 +1,138 @@
 //! This module defines address spaces.
 
 use super::address_space_manager::AddressSpaceManager;
-use super::{PageFlags, PhysicalAddress, VirtualAddress};
+use super::{Address, PageFlags, PhysicalAddress, VirtualAddress};
 use alloc::Vec;
 use crate::arch::{self, Architecture};
-use core::mem::size_of_val;
+use core::fmt;
+use core::mem::{self, size_of, size_of_val};
 use core::slice;
-use crate::memory::{MemoryArea, PAGE_SIZE};
+use crate::memory::{round_up_to_page_size, MemoryArea, PAGE_SIZE};
 use crate::multitasking::{Stack, ThreadID};
 
+/// The memory the segments of a new user address space may reserve, in
+/// bytes.
+pub const DEFAULT_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// The memory used by an address space, counted in pages.
+///
+/// Pages that map frames shared with other mappings, like mapped initramfs
+/// files, are counted as shared instead of resident. This way the resident
+/// pages of all processes add up to the frames they own. This is also the
+/// layout that is passed to user space.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemoryUsage {
+    /// The pages that map frames owned by the address space.
+    pub resident_pages: usize,
+    /// The pages that map frames shared with other mappings.
+    pub shared_pages: usize,
+    /// The pages reserved by the segments of the address space, whether they
+    /// are mapped or not.
+    pub virtual_pages: usize,
+}
+
+impl MemoryUsage {
+    /// Counts a newly mapped page.
+    pub fn add_page(&mut self, shared: bool) {
+        if shared {
+            self.shared_pages += 1;
+        } else {
+            self.resident_pages += 1;
+        }
+    }
+
+    /// Counts an unmapped page.
+    pub fn remove_page(&mut self, shared: bool) {
+        if shared {
+            self.shared_pages = self.shared_pages.saturating_sub(1);
+        } else {
+            self.resident_pages = self.resident_pages.saturating_sub(1);
+        }
+    }
+}
+
+/// A contiguous range of mapped pages that share the same flags.
+///
+/// This is also the layout that is passed to user space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// The address of the first page.
+    pub start: VirtualAddress,
+    /// The length of the range in bytes.
+    pub length: usize,
+    /// The flags of all pages in the range.
+    pub flags: PageFlags,
+}
+
+impl Mapping {
+    /// Returns the first address after the range.
+    pub fn end_address(&self) -> VirtualAddress {
+        self.start + self.length
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag_char = |flag, character| {
+            if self.flags.contains(flag) {
+                character
+            } else {
+                '-'
+            }
+        };
+
+        write!(
+            f,
+            "{:#018x}-{:#018x} {}{}{}{}",
+            self.start.as_usize(),
+            self.end_address().as_usize(),
+            flag_char(PageFlags::READABLE, 'r'),
+            flag_char(PageFlags::WRITABLE, 'w'),
+            flag_char(PageFlags::EXECUTABLE, 'x'),
+            flag_char(PageFlags::USER_ACCESSIBLE, 'u')
+        )
+    }
+}
+
+/// Adds a mapped page to the sorted list of mappings.
+///
+/// If the page directly follows the last mapping and has the same flags,
+/// the last mapping is extended instead.
+pub fn add_mapped_page(
+    mappings: &mut Vec<Mapping>,
+    address: VirtualAddress,
+    size: usize,
+    flags: PageFlags,
+) {
+    if let Some(last) = mappings.last_mut() {
+        if last.end_address() == address && last.flags == flags {
+            last.length += size;
+            return;
+        }
+    }
+
+    mappings.push(Mapping {
+        start: address,
+        length: size,
+        flags,
+    });
+}
+
 /// Represents an address space
 pub struct AddressSpace {
     /// The segments that are part of the address space.
     segments: Vec<Segment>,
     /// The address space manager.
     manager: <arch::Current as Architecture>::AddressSpaceManager,
+    /// The memory the user accessible segments may reserve in total, in bytes.
+    memory_limit: usize,
 }
 
 impl Drop for AddressSpace {
     fn drop(&mut self) {
-        for segment in &mut self.segments {
-            segment.unmap(&mut self.manager);
-        }
+        self.clear();
     }
 }
 
@@ -32,6 +143,7 @@ impl AddressSpace {
             segments: Vec::new(),
             manager:
                 <<arch::Current as Architecture>::AddressSpaceManager as AddressSpaceManager>::new(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
         }
     }
 
@@ -42,13 +154,34 @@ impl AddressSpace {
             manager:
                 <<arch::Current as Architecture>::AddressSpaceManager as AddressSpaceManager>::idle(
                 ),
+            memory_limit: usize::max_value(),
         }
     }
 
+    /// Unmaps and removes all segments.
+    pub fn clear(&mut self) {
+        for segment in &mut self.segments {
+            segment.unmap(&mut self.manager);
+        }
+
+        self.segments.clear();
+    }
+
     /// Adds the segment to the address space.
     ///
-    /// Returns true if the segment was successfully added.
+    /// Returns true if the segment was successfully added. Segments containing
+    /// the null page are always refused, so null pointer dereferences fault.
+    /// User accessible segments that would exceed the memory limit are refused
+    /// as well.
     pub fn add_segment(&mut self, segment_to_add: Segment) -> bool {
+        if segment_to_add.overlaps_null_page() {
+            return false;
+        }
+
+        if segment_to_add.is_user_accessible() && !self.can_reserve(segment_to_add.memory_area) {
+            return false;
+        }
+
         for segment in &self.segments {
             if segment_to_add.overlaps(segment) {
                 return false;
@@ -66,36 +199,247 @@ impl AddressSpace {
         }
     }
 
+    /// Removes the segment with exactly the given area and unmaps its pages.
+    ///
+    /// Returns false if there is no such segment.
+    pub fn remove_segment(&mut self, area: MemoryArea<VirtualAddress>) -> bool {
+        let index = self
+            .segments
+            .iter()
+            .position(|segment| segment.memory_area == area);
+
+        match index {
+            Some(index) => {
+                let segment = self.segments.remove(index);
+                segment.unmap(&mut self.manager);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the memory the user accessible segments may reserve in total,
+    /// in bytes.
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    /// Sets the memory the user accessible segments may reserve in total, in
+    /// bytes.
+    ///
+    /// Segments that already exist are kept, even if they exceed the new
+    /// limit.
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.memory_limit = limit;
+    }
+
+    /// Returns true if a user accessible segment with the given area fits
+    /// into the memory limit.
+    ///
+    /// Only user accessible segments count against the limit, so the kernel
+    /// stacks of the threads don't use it up.
+    pub fn can_reserve(&self, area: MemoryArea<VirtualAddress>) -> bool {
+        let user_pages: usize = self
+            .segments
+            .iter()
+            .filter(|segment| segment.is_user_accessible())
+            .map(|segment| segment.page_count())
+            .sum();
+
+        fits_in_limit(user_pages, page_count(area), self.memory_limit)
+    }
+
+    /// Maps the given kernel memory area into the shared memory area of this
+    /// address space without copying it.
+    ///
+    /// The pages containing the area are mapped read-only when they are first
+    /// accessed and are never freed through this address space, so the kernel
+    /// memory must outlive it. Returns the address the start of the area is
+    /// mapped to, or `None` if there is no space left.
+    pub fn map_shared_area(&mut self, area: MemoryArea<VirtualAddress>) -> Option<VirtualAddress> {
+        let offset = area.start_address().offset_in_page();
+        let kernel_start = area.start_address().page_align_down();
+        let length = round_up_to_page_size(offset + area.length());
+
+        let mmap_area = arch::Current::USER_MMAP_AREA;
+        let start = self
+            .segments
+            .iter()
+            .filter(|segment| segment.memory_area.is_contained_in(mmap_area))
+            .map(|segment| segment.end_address())
+            .max()
+            .unwrap_or(mmap_area.start_address());
+
+        let segment_area = MemoryArea::new(start, length);
+
+        if !segment_area.is_contained_in(mmap_area) {
+            return None;
+        }
+
+        let flags = PageFlags::READABLE | PageFlags::USER_ACCESSIBLE;
+        let segment_type = SegmentType::FileBacked(kernel_start);
+
+        if !self.add_segment(Segment::new(segment_area, flags, segment_type)) {
+            return None;
+        }
+
+        Some(start + offset)
+    }
+
+    /// Maps the page containing the given address, if it belongs to a file
+    /// backed segment and isn't mapped yet.
+    ///
+    /// Returns true if the page was mapped.
+    pub fn map_file_backed_page(&mut self, address: VirtualAddress) -> bool {
+        let page_address = address.page_align_down();
+        let (kernel_page, flags) = match self.get_segment(MemoryArea::new(address, 1)) {
+            Some(&Segment {
+                segment_type: SegmentType::FileBacked(kernel_start),
+                memory_area,
+                flags
+            }) => (kernel_start + (page_address - memory_area.start_address()), flags),
+            _ => return false,
+        };
+
+        if self.manager.is_mapped(page_address) {
+            return false;
+        }
+
+        let frame_address = arch::Current::virtual_to_physical(kernel_page)
+            .expect("Trying to share unmapped kernel memory.");
+
+        self.manager.map_page_at(page_address, frame_address, flags);
+
+        true
+    }
+
+    /// Maps the pages of file backed segments within the given area that
+    /// aren't mapped yet.
+    fn map_file_backed_pages(&mut self, area: MemoryArea<VirtualAddress>) {
+        let first_page = area.start_address().page_align_down();
+
+        for page_num in 0..page_count(area) {
+            self.map_file_backed_page(first_page + page_num * PAGE_SIZE);
+        }
+    }
+
+    /// Returns the memory used by this address space.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            virtual_pages: self.segments.iter().map(|segment| segment.page_count()).sum(),
+            ..self.manager.memory_usage()
+        }
+    }
+
+    /// Returns the mapped ranges of the lower half of this address space.
+    pub fn mappings(&mut self) -> Vec<Mapping> {
+        self.manager.mappings()
+    }
+
+    /// Prints the mapped ranges of the lower half of this address space.
+    pub fn dump(&mut self) {
+        for mapping in self.mappings() {
+            println!("{}", mapping);
+        }
+    }
+
+    /// Returns true if the given memory area is contained within a single
+    /// writable segment.
+    pub fn is_writable_area(&self, area: MemoryArea<VirtualAddress>) -> bool {
+        self.get_segment(area)
+            .map(|segment| segment.flags.contains(PageFlags::WRITABLE))
+            .unwrap_or(false)
+    }
+
     /// Writes to the given address in the address space.
-    pub fn write_to(&mut self, buffer: &[u8], address: VirtualAddress) {
+    ///
+    /// Shared file mappings can't be written to. Returns the address of the
+    /// area then.
+    pub fn write_to(
+        &mut self,
+        buffer: &[u8],
+        address: VirtualAddress
+    ) -> Result<(), VirtualAddress> {
         let area = MemoryArea::new(address, buffer.len());
-        let segment_flags = { self.get_segment(area).map(|segment| segment.flags) };
+        let segment_flags = { self.get_segment(area).map(|segment| segment.writable_flags()) };
 
-        if let Some(segment_flags) = segment_flags {
-            self.manager.write_to(buffer, address, segment_flags);
+        match segment_flags {
+            Some(Some(segment_flags)) => {
+                self.manager.write_to(buffer, address, segment_flags);
+                Ok(())
+            }
+            Some(None) => Err(address),
+            None => self.handle_out_of_segment(area),
+        }
+    }
+
+    /// Reads from the given address in the address space.
+    ///
+    /// The address may come from user space, so accesses outside of the
+    /// segments or to unmapped pages fail instead of panicking. Returns the
+    /// first address that couldn't be read then.
+    pub fn read_from(
+        &mut self,
+        buffer: &mut [u8],
+        address: VirtualAddress
+    ) -> Result<(), VirtualAddress> {
+        let area = MemoryArea::new(address, buffer.len());
+
+        if self.contains_area(area) {
+            self.map_file_backed_pages(area);
+            self.manager.read_from(buffer, address)
         } else {
-            self.handle_out_of_segment(area);
+            Err(address)
         }
     }
 
     /// Zeros an already mapped area.
-    pub fn zero_mapped_area(&mut self, area: MemoryArea<VirtualAddress>) {
-        let segment_flags = { self.get_segment(area).map(|segment| segment.flags) };
+    ///
+    /// Shared file mappings can't be written to. Returns the address of the
+    /// area then.
+    pub fn zero_mapped_area(
+        &mut self,
+        area: MemoryArea<VirtualAddress>
+    ) -> Result<(), VirtualAddress> {
+        let segment_flags = { self.get_segment(area).map(|segment| segment.writable_flags()) };
 
-        if let Some(segment_flags) = segment_flags {
-            self.manager.zero(area, segment_flags);
-        } else {
-            self.handle_out_of_segment(area);
+        match segment_flags {
+            Some(Some(segment_flags)) => {
+                self.manager.zero(area, segment_flags);
+                Ok(())
+            }
+            Some(None) => Err(area.start_address()),
+            None => self.handle_out_of_segment(area),
         }
     }
 
     /// Writes the given value to the given address in this address space.
-    pub unsafe fn write_val<T>(&mut self, value: T, address: VirtualAddress) {
+    ///
+    /// Shared file mappings can't be written to. Returns the address then.
+    pub unsafe fn write_val<T>(
+        &mut self,
+        value: T,
+        address: VirtualAddress
+    ) -> Result<(), VirtualAddress> {
         let value_ptr = &value as *const T;
         let buffer = slice::from_raw_parts(value_ptr as *const u8, size_of_val(&value));
         self.write_to(buffer, address)
     }
 
+    /// Reads a value from the given address in this address space.
+    ///
+    /// Returns `None` if the value isn't completely mapped.
+    pub unsafe fn read_val<T: Copy>(&mut self, address: VirtualAddress) -> Option<T> {
+        let mut value: T = mem::uninitialized();
+        let result = {
+            let value_ptr = &mut value as *mut T;
+            let buffer = slice::from_raw_parts_mut(value_ptr as *mut u8, size_of::<T>());
+            self.read_from(buffer, address)
+        };
+        result.ok().map(|_| value)
+    }
+
     /// Returns the segment that contains the address with length bytes space
     /// after, if it exists.
     fn get_segment(&self, area: MemoryArea<VirtualAddress>) -> Option<&Segment> {
@@ -108,7 +452,7 @@ impl AddressSpace {
     }
 
     /// Handles the case of accesses outside of a segment.
-    fn handle_out_of_segment(&self, area: MemoryArea<VirtualAddress>) {
+    fn handle_out_of_segment(&self, area: MemoryArea<VirtualAddress>) -> ! {
         panic!("Out of segment access (area: {:?})", area);
     }
 
@@ -158,7 +502,9 @@ impl AddressSpace {
     }
 
     /// Creates a new user stack.
-    pub fn create_user_stack(&mut self, id: ThreadID) -> Stack {
+    ///
+    /// Returns `None` if it doesn't fit into the memory limit.
+    pub fn create_user_stack(&mut self, id: ThreadID) -> Option<Stack> {
         <<arch::Current as Architecture>::AddressSpaceManager as AddressSpaceManager>::create_user_stack(id, self)
     }
 }
@@ -170,6 +516,14 @@ pub enum SegmentType {
     FromFile,
     /// The content of the segment is only in memory.
     MemoryOnly,
+    /// The segment maps the frames of a file directly.
+    ///
+    /// The frames are those of the kernel pages starting at the given
+    /// address. They are shared with every other mapping of the file, so the
+    /// segment is read-only and never frees them. Writes would need
+    /// copy-on-write, which isn't supported. The pages are only mapped once
+    /// they are accessed.
+    FileBacked(VirtualAddress),
 }
 
 /// Represents a segment of memory in the address space.
@@ -202,6 +556,12 @@ impl Segment {
         self.memory_area.overlaps_with(other.memory_area)
     }
 
+    /// Returns true if the segment contains any part of the first page.
+    pub fn overlaps_null_page(&self) -> bool {
+        self.memory_area
+            .overlaps_with(MemoryArea::new(VirtualAddress::from_usize(0), PAGE_SIZE))
+    }
+
     /// Checks whether this segment contains the given memory area.
     fn contains_area(&self, area: MemoryArea<VirtualAddress>) -> bool {
         area.is_contained_in(self.memory_area)
@@ -217,9 +577,29 @@ impl Segment {
         self.memory_area.end_address()
     }
 
+    /// Returns true if user mode code may access the segment.
+    fn is_user_accessible(&self) -> bool {
+        self.flags.contains(PageFlags::USER_ACCESSIBLE)
+    }
+
+    /// Returns the number of pages the segment touches.
+    fn page_count(&self) -> usize {
+        page_count(self.memory_area)
+    }
+
+    /// Returns the flags of this segment, making sure it can be written to.
+    ///
+    /// Returns `None` if the frames of the segment are shared.
+    fn writable_flags(&self) -> Option<PageFlags> {
+        match self.segment_type {
+            SegmentType::FileBacked(_) => None,
+            _ => Some(self.flags),
+        }
+    }
+
     /// Unmaps this segment.
     fn unmap(&self, manager: &mut <arch::Current as Architecture>::AddressSpaceManager) {
-        let pages_in_segment = (self.memory_area.length() - 1) / PAGE_SIZE + 1;
+        let pages_in_segment = round_up_to_page_size(self.memory_area.length()) / PAGE_SIZE;
         for page_num in 0..pages_in_segment {
             unsafe {
                 match self.segment_type {
@@ -229,8 +609,123 @@ impl Segment {
                     SegmentType::MemoryOnly => {
                         manager.unmap_page_unchecked(self.start_address() + page_num * PAGE_SIZE)
                     }
+                    SegmentType::FileBacked(_) => {
+                        manager.unmap_shared_page(self.start_address() + page_num * PAGE_SIZE)
+                    }
                 }
             }
         }
     }
 }
+
+/// Returns the number of pages the area touches.
+fn page_count(area: MemoryArea<VirtualAddress>) -> usize {
+    if area.is_empty() {
+        return 0;
+    }
+
+    let first_page = area.start_address().page_num();
+    let last_page = (area.end_address() - 1).page_num();
+
+    last_page - first_page + 1
+}
+
+/// Returns true if reserving more pages stays within the limit in bytes.
+fn fits_in_limit(reserved_pages: usize, new_pages: usize, limit: usize) -> bool {
+    reserved_pages
+        .saturating_add(new_pages)
+        .saturating_mul(PAGE_SIZE)
+        <= limit
+}
+
+/// Tests for address spaces.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a user segment at the given address.
+    fn segment(start: usize, length: usize) -> Segment {
+        Segment::new(
+            MemoryArea::new(VirtualAddress::from_usize(start), length),
+            PageFlags::READABLE | PageFlags::USER_ACCESSIBLE,
+            SegmentType::MemoryOnly,
+        )
+    }
+
+    /// Tests that segments covering the null page are detected.
+    #[test]
+    fn test_overlaps_null_page() {
+        assert!(segment(0, PAGE_SIZE).overlaps_null_page());
+        assert!(segment(0, 1).overlaps_null_page());
+        assert!(segment(PAGE_SIZE - 1, 2).overlaps_null_page());
+        assert!(segment(0, 0x10_0000).overlaps_null_page());
+        assert!(!segment(PAGE_SIZE, PAGE_SIZE).overlaps_null_page());
+        assert!(!segment(0x40_0000, 0x1000).overlaps_null_page());
+    }
+
+    /// Tests counting the pages of segments.
+    #[test]
+    fn test_page_count() {
+        assert_eq!(segment(0x40_0000, 0).page_count(), 0);
+        assert_eq!(segment(0x40_0000, 1).page_count(), 1);
+        assert_eq!(segment(0x40_0000, PAGE_SIZE).page_count(), 1);
+        assert_eq!(segment(0x40_0000, PAGE_SIZE + 1).page_count(), 2);
+        assert_eq!(segment(0x40_0fff, 2).page_count(), 2);
+    }
+
+    /// Tests checking reservations against the memory limit.
+    #[test]
+    fn test_fits_in_limit() {
+        assert!(fits_in_limit(0, 0, 0));
+        assert!(fits_in_limit(2, 2, 4 * PAGE_SIZE));
+        assert!(!fits_in_limit(2, 3, 4 * PAGE_SIZE));
+        assert!(!fits_in_limit(0, 1, PAGE_SIZE - 1));
+        assert!(fits_in_limit(usize::max_value(), 1, usize::max_value()));
+        assert!(!fits_in_limit(1, usize::max_value(), DEFAULT_MEMORY_LIMIT));
+    }
+
+    /// Tests that adjacent pages with the same flags are merged.
+    #[test]
+    fn test_add_mapped_page() {
+        let mut mappings = Vec::new();
+        let read_only = PageFlags::READABLE | PageFlags::USER_ACCESSIBLE;
+        let writable = read_only | PageFlags::WRITABLE;
+        let address = |offset| VirtualAddress::from_usize(0x40_0000 + offset);
+
+        add_mapped_page(&mut mappings, address(0), PAGE_SIZE, read_only);
+        add_mapped_page(&mut mappings, address(PAGE_SIZE), PAGE_SIZE, read_only);
+        add_mapped_page(&mut mappings, address(2 * PAGE_SIZE), PAGE_SIZE, writable);
+        add_mapped_page(&mut mappings, address(4 * PAGE_SIZE), PAGE_SIZE, writable);
+        add_mapped_page(&mut mappings, address(5 * PAGE_SIZE), 0x20_0000, writable);
+
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[0].start, address(0));
+        assert_eq!(mappings[0].length, 2 * PAGE_SIZE);
+        assert_eq!(mappings[1].length, PAGE_SIZE);
+        assert_eq!(mappings[1].flags, writable);
+        assert_eq!(mappings[2].start, address(4 * PAGE_SIZE));
+        assert_eq!(mappings[2].end_address(), address(5 * PAGE_SIZE + 0x20_0000));
+    }
+
+    /// Tests that the usage follows a sequence of mappings.
+    #[test]
+    fn test_memory_usage() {
+        let mut usage = MemoryUsage::default();
+
+        usage.add_page(false);
+        usage.add_page(false);
+        usage.add_page(true);
+        usage.remove_page(false);
+        usage.add_page(true);
+        usage.remove_page(true);
+
+        assert_eq!(usage.resident_pages, 1);
+        assert_eq!(usage.shared_pages, 1);
+
+        usage.remove_page(false);
+        usage.remove_page(false);
+
+        assert_eq!(usage.resident_pages, 0);
+        assert_eq!(usage.shared_pages, 1);
+    }
+}
@@ -2,6 +2,8 @@
 
 use super::{MemoryArea, PageFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE};
 use crate::multitasking::{Stack, ThreadID};
+use alloc::Vec;
+use crate::memory::address_space::{Mapping, MemoryUsage};
 use crate::memory::AddressSpace;
 
 /// This trait should be implemented by any architecture specific address space
@@ -17,15 +19,39 @@ pub trait AddressSpaceManager: Send {
     /// space setting the given flags.
     fn write_to(&mut self, buffer: &[u8], address: VirtualAddress, flags: PageFlags);
 
+    /// Reads from the `address` in the target address space into `buffer`.
+    ///
+    /// Returns the first address that isn't mapped, if any. The buffer may
+    /// be partially filled then.
+    fn read_from(
+        &mut self,
+        buffer: &mut [u8],
+        address: VirtualAddress
+    ) -> Result<(), VirtualAddress>;
+
     /// Returns the address of the page table.
     ///
     /// # Safety
     /// - Should only be used by architecture specific code.
     unsafe fn get_page_table_address(&self) -> PhysicalAddress; // TODO: Find something better than exposing this publicly.
 
+    /// Returns true if the given page is mapped in the managed address space.
+    fn is_mapped(&mut self, page_address: VirtualAddress) -> bool;
+
     /// Maps the given page in the managed address space.
     fn map_page(&mut self, page_address: VirtualAddress, flags: PageFlags);
 
+    /// Maps the given page to the given frame in the managed address space.
+    ///
+    /// The frame isn't owned by the mapping and is never deallocated through
+    /// it.
+    fn map_page_at(
+        &mut self,
+        page_address: VirtualAddress,
+        frame_address: PhysicalAddress,
+        flags: PageFlags
+    );
+
     /// Unmaps the given page in the managed address space.
     ///
     /// # Safety
@@ -39,15 +65,38 @@ pub trait AddressSpaceManager: Send {
     /// - Nothing should reference the unmapped pages.
     unsafe fn unmap_page_unchecked(&mut self, start_address: VirtualAddress); // TODO: Check if this is necessary.
 
+    /// Unmaps the given page in the managed address space without
+    /// deallocating the frame it was mapped to.
+    ///
+    /// Does nothing if the page isn't mapped.
+    ///
+    /// # Safety
+    /// - Nothing should reference the unmapped pages.
+    unsafe fn unmap_shared_page(&mut self, start_address: VirtualAddress);
+
+    /// Returns the number of resident and shared pages.
+    ///
+    /// The number of virtual pages is left at zero, as the segments are not
+    /// known to the manager.
+    fn memory_usage(&self) -> MemoryUsage;
+
+    /// Returns the mapped ranges of the lower half of the managed address
+    /// space, sorted by address.
+    ///
+    /// Adjacent pages with the same flags are merged into one range.
+    fn mappings(&mut self) -> Vec<Mapping>;
+
     /// Creates a new kernel stack.
     ///
-    /// This assumes that the given thread id is unused.
+    /// This assumes that the given thread id is unused. Kernel stacks don't
+    /// count against the memory limit of the address space.
     fn create_kernel_stack(id: ThreadID, address_space: &mut AddressSpace) -> Stack;
 
     /// Creates a new user mode stack.
     ///
-    /// This assumes that the given thread id is unused.
-    fn create_user_stack(id: ThreadID, address_space: &mut AddressSpace) -> Stack;
+    /// This assumes that the given thread id is unused. Returns `None` if the
+    /// stack doesn't fit into the memory limit of the address space.
+    fn create_user_stack(id: ThreadID, address_space: &mut AddressSpace) -> Option<Stack>;
 
     /// Creates a new idle process stack.
     fn create_idle_stack(cpu_id: usize) -> Stack;
@@ -0,0 +1,109 @@
+//! Surrounds heap allocations with guard bytes to detect buffer overruns.
+//!
+//! The guards are checked when the allocation is freed. This is only used in
+//! debug builds.
+
+use core::cmp::max;
+use core::ptr;
+
+/// The number of guard bytes behind each allocation.
+const GUARD_SIZE: usize = 16;
+
+/// The value that the guard bytes are filled with.
+const GUARD_BYTE: u8 = 0xfd;
+
+/// Returns the offset of the allocation within its guarded block.
+///
+/// The front guard is enlarged to keep the alignment of the allocation.
+fn front_offset(alignment: usize) -> usize {
+    debug_assert!(alignment.is_power_of_two());
+
+    max(GUARD_SIZE, alignment)
+}
+
+/// Returns the size of the guarded block for the given allocation.
+pub fn guarded_size(size: usize, alignment: usize) -> usize {
+    front_offset(alignment) + size + GUARD_SIZE
+}
+
+/// Writes the guards into the given block and returns the start of the allocation.
+///
+/// # Safety
+/// - The block must be at least `guarded_size(size, alignment)` bytes large.
+pub unsafe fn add_guards(block: *mut u8, size: usize, alignment: usize) -> *mut u8 {
+    let offset = front_offset(alignment);
+    let allocation = block.offset(offset as isize);
+
+    ptr::write_bytes(block, GUARD_BYTE, offset);
+    ptr::write_bytes(allocation.offset(size as isize), GUARD_BYTE, GUARD_SIZE);
+
+    allocation
+}
+
+/// Checks the guards of the given allocation and returns the start of its block.
+///
+/// Panics if any of the guards were overwritten.
+///
+/// # Safety
+/// - The allocation must have been guarded with `add_guards` using the same
+/// size and alignment.
+pub unsafe fn remove_guards(allocation: *mut u8, size: usize, alignment: usize) -> *mut u8 {
+    let offset = front_offset(alignment);
+    let block = allocation.offset(-(offset as isize));
+
+    if !is_intact(block, offset) {
+        panic!(
+            "The guard in front of the allocation of {} bytes at {:?} was overwritten.",
+            size, allocation
+        );
+    }
+
+    if !is_intact(allocation.offset(size as isize), GUARD_SIZE) {
+        panic!(
+            "The guard behind the allocation of {} bytes at {:?} was overwritten.",
+            size, allocation
+        );
+    }
+
+    block
+}
+
+/// Checks that the given guard wasn't overwritten.
+unsafe fn is_intact(guard: *const u8, length: usize) -> bool {
+    (0..length).all(|index| *guard.offset(index as isize) == GUARD_BYTE)
+}
+
+/// Tests for the allocation guards.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that writes within the allocation aren't reported.
+    #[test]
+    fn test_intact_guards() {
+        let mut block = [0u64; 8];
+        let block_ptr = block.as_mut_ptr() as *mut u8;
+        assert!(guarded_size(8, 8) <= 64);
+
+        unsafe {
+            let allocation = add_guards(block_ptr, 8, 8);
+            ptr::write_bytes(allocation, 0, 8);
+
+            assert_eq!(remove_guards(allocation, 8, 8), block_ptr);
+        }
+    }
+
+    /// Tests that an overrun is detected.
+    #[test]
+    #[should_panic]
+    fn test_overrun() {
+        let mut block = [0u64; 8];
+
+        unsafe {
+            let allocation = add_guards(block.as_mut_ptr() as *mut u8, 8, 8);
+            ptr::write_bytes(allocation, 0, 9);
+
+            remove_guards(allocation, 8, 8);
+        }
+    }
+}
@@ -1,25 +1,57 @@
 //! Provides the heap allocator for the kernel.
 
+mod guard;
 mod linked_list_allocator;
 
 use self::linked_list_allocator::LinkedListAllocator;
 use alloc::allocator::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use crate::arch::{self, Architecture};
-use crate::memory::{Address, VirtualAddress};
+use crate::memory::{oom, Address, VirtualAddress, PAGE_SIZE};
 use crate::sync::mutex::Mutex;
 
 pub struct Allocator;
 
+/// Whether the heap is ready to be used.
+///
+/// Everything used before this is set, like early printing, must not allocate.
+static HEAP_INITIALIZED: AtomicBool = ATOMIC_BOOL_INIT;
+
 unsafe impl GlobalAlloc for Allocator {
     // TODO: Read more on this trait and possibly make it more efficient.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        ALLOCATOR
-            .lock()
-            .allocate_first_fit(layout.size(), layout.align())
+        debug_assert!(
+            HEAP_INITIALIZED.load(Ordering::Relaxed),
+            "Allocation of {} bytes before the heap was initialized.",
+            layout.size()
+        );
+
+        if cfg!(debug_assertions) {
+            let size = guard::guarded_size(layout.size(), layout.align());
+            let block = ALLOCATOR.lock().allocate_first_fit(size, layout.align());
+
+            if block.is_null() {
+                block
+            } else {
+                guard::add_guards(block, layout.size(), layout.align())
+            }
+        } else {
+            ALLOCATOR
+                .lock()
+                .allocate_first_fit(layout.size(), layout.align())
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        ALLOCATOR.lock().free(ptr, layout.size(), layout.align());
+        if cfg!(debug_assertions) {
+            // The guards are checked before locking, so a detected overrun can be reported.
+            let block = guard::remove_guards(ptr, layout.size(), layout.align());
+            let size = guard::guarded_size(layout.size(), layout.align());
+
+            ALLOCATOR.lock().free(block, size, layout.align());
+        } else {
+            ALLOCATOR.lock().free(ptr, layout.size(), layout.align());
+        }
     }
 }
 
@@ -29,6 +61,37 @@ lazy_static! {
         Mutex::new(LinkedListAllocator::new(arch::Current::HEAP_AREA));
 }
 
+/// Marks the heap as ready to be used.
+///
+/// This must be called once the heap area can be mapped on demand.
+pub fn init() {
+    HEAP_INITIALIZED.store(true, Ordering::Relaxed);
+}
+
+/// Allocates a page aligned page from the heap.
+pub fn allocate_page() -> VirtualAddress {
+    let block = unsafe { Allocator.alloc(page_layout()) };
+
+    if block.is_null() {
+        oom();
+    }
+
+    VirtualAddress::from_usize(block as usize)
+}
+
+/// Frees a page from `allocate_page`.
+///
+/// # Safety
+/// - Nothing may use the page afterwards.
+pub unsafe fn free_page(page: VirtualAddress) {
+    Allocator.dealloc(page.as_mut_ptr(), page_layout());
+}
+
+/// Returns the layout of a page aligned page.
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
 /// Aligns the given address to the given alignment.
 ///
 /// The alignment must be a power of two.
@@ -42,3 +105,58 @@ fn align(address: VirtualAddress, alignment: usize) -> VirtualAddress {
         VirtualAddress::from_usize((address.as_usize() & alignment_bitmask) + alignment)
     }
 }
+
+/// Self-tests for the heap.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+    use alloc::Vec;
+    use core::{ptr, slice};
+
+    /// Checks that allocations are aligned and keep their contents.
+    fn test_heap_allocation() -> Result<(), &'static str> {
+        let mut blocks = Vec::new();
+
+        for shift in 0..12 {
+            let layout = Layout::from_size_align(24 + shift * 40, 1 << shift).unwrap();
+            let block = unsafe { Allocator.alloc(layout.clone()) };
+
+            if block.is_null() {
+                return Err("An allocation failed.");
+            }
+
+            if block as usize % layout.align() != 0 {
+                return Err("An allocation is misaligned.");
+            }
+
+            unsafe {
+                ptr::write_bytes(block, shift as u8, layout.size());
+            }
+
+            blocks.push((block, layout));
+        }
+
+        let intact = blocks
+            .iter()
+            .enumerate()
+            .all(|(index, &(block, ref layout))| {
+                let contents = unsafe { slice::from_raw_parts(block, layout.size()) };
+
+                contents.iter().all(|&byte| byte == index as u8)
+            });
+
+        for (block, layout) in blocks {
+            unsafe {
+                Allocator.dealloc(block, layout);
+            }
+        }
+
+        if intact {
+            Ok(())
+        } else {
+            Err("An allocation was overwritten.")
+        }
+    }
+
+    register_selftest!(HEAP_ALLOCATION, test_heap_allocation);
+}
@@ -3,18 +3,28 @@
 pub mod address_space;
 pub mod address_space_manager;
 pub mod allocator;
+pub mod reserved;
+pub mod slab;
 
 pub use self::address_space::AddressSpace;
 pub use self::address_space_manager::AddressSpaceManager;
+pub use self::reserved::MmioError;
 
 use crate::arch::{self, Architecture};
+use core::cmp::{max, min};
 use core::fmt;
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
 /// Represents the current page size.
+///
+/// All page arithmetic should go through the helpers of the `Address` trait
+/// and `round_up_to_page_size`, instead of assuming a particular page size.
 pub const PAGE_SIZE: usize = arch::Current::PAGE_SIZE;
 
 /// Represents something that can act like an address.
+///
+/// The alignment helpers work with any power of two, so the same arithmetic
+/// can be used for pages of any size.
 pub trait Address: PartialOrd + Ord + Add<usize, Output = Self> + Sized + Clone + Copy {
     /// Returns the value of the address as a `usize`.
     #[inline(always)]
@@ -24,17 +34,69 @@ pub trait Address: PartialOrd + Ord + Add<usize, Output = Self> + Sized + Clone
     #[inline(always)]
     fn from_usize(_: usize) -> Self;
 
+    /// Aligns the address to the given power of two, rounded down.
+    fn align_down(self, alignment: usize) -> Self {
+        debug_assert!(alignment.is_power_of_two());
+
+        Self::from_usize(self.as_usize() & !(alignment - 1))
+    }
+
+    /// Aligns the address to the given power of two, rounded up.
+    fn align_up(self, alignment: usize) -> Self {
+        debug_assert!(alignment.is_power_of_two());
+
+        Self::from_usize((self.as_usize() + alignment - 1) & !(alignment - 1))
+    }
+
+    /// Returns the offset of the address from the previous multiple of the
+    /// given power of two.
+    fn offset_in(self, alignment: usize) -> usize {
+        debug_assert!(alignment.is_power_of_two());
+
+        self.as_usize() & (alignment - 1)
+    }
+
+    /// Checks if the address is a multiple of the given power of two.
+    fn is_aligned(self, alignment: usize) -> bool {
+        self.offset_in(alignment) == 0
+    }
+
     /// Aligns the address to the next page border, rounded down.
     fn page_align_down(self) -> Self {
-        Self::from_usize(self.as_usize() / PAGE_SIZE * PAGE_SIZE)
+        self.align_down(PAGE_SIZE)
+    }
+
+    /// Aligns the address to the next page border, rounded up.
+    fn page_align_up(self) -> Self {
+        self.align_up(PAGE_SIZE)
     }
 
     /// Returns the offset of the page from the previous page border.
     fn offset_in_page(self) -> usize {
-        self.as_usize() % PAGE_SIZE
+        self.offset_in(PAGE_SIZE)
+    }
+
+    /// Checks if the address lies on a page border.
+    fn is_page_aligned(self) -> bool {
+        self.is_aligned(PAGE_SIZE)
+    }
+
+    /// Returns the number of the page that the address lies in.
+    fn page_num(self) -> usize {
+        self.as_usize() / PAGE_SIZE
+    }
+
+    /// Returns the start address of the page with the given number.
+    fn from_page_num(page_num: usize) -> Self {
+        Self::from_usize(page_num * PAGE_SIZE)
     }
 }
 
+/// Rounds the length up to a multiple of the page size.
+pub fn round_up_to_page_size(length: usize) -> usize {
+    (length + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
 /// Represents a physical address.
 #[repr(transparent)]
 #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
@@ -46,9 +108,10 @@ impl PhysicalAddress {
         PhysicalAddress(addr)
     }
 
-    /// Creates a virtual address from the given physical one.
+    /// Returns the virtual address at which the kernel can access this
+    /// physical address.
     pub fn to_virtual(self) -> VirtualAddress {
-        VirtualAddress::from_usize(to_virtual!(self.as_usize()))
+        arch::Current::physical_to_virtual(self)
     }
 }
 
@@ -115,14 +178,11 @@ impl VirtualAddress {
         VirtualAddress(addr)
     }
 
-    /// Returns the start address of the page with the given number.
-    pub fn from_page_num(page_num: usize) -> VirtualAddress {
-        VirtualAddress::from_usize(page_num * PAGE_SIZE)
-    }
-
-    /// Returns the number of the page that the address lies in.
-    pub fn page_num(self) -> usize {
-        self.as_usize() / PAGE_SIZE
+    /// Returns the physical address this address is mapped to.
+    ///
+    /// This returns `None` if the address isn't mapped.
+    pub fn to_physical(self) -> Option<PhysicalAddress> {
+        arch::Current::virtual_to_physical(self)
     }
 
     /// Casts the address as a pointer.
@@ -189,7 +249,7 @@ impl SubAssign<usize> for VirtualAddress {
 }
 
 /// Represents a chunk of virtual memory.
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 pub struct MemoryArea<AddressType: Sized + Address> {
     /// The address at which the chunk starts.
     start_address: AddressType,
@@ -199,6 +259,9 @@ pub struct MemoryArea<AddressType: Sized + Address> {
 
 impl<AddressType: Address> MemoryArea<AddressType> {
     /// Creates a new MemoryArea.
+    ///
+    /// The area must end within the address space. Use `try_new` if the
+    /// length isn't trusted.
     pub const fn new(start_address: AddressType, length: usize) -> MemoryArea<AddressType> {
         MemoryArea {
             start_address,
@@ -206,21 +269,46 @@ impl<AddressType: Address> MemoryArea<AddressType> {
         }
     }
 
-    /// Creates a new MemoryArea.
+    /// Creates a new MemoryArea, unless it would end beyond the address
+    /// space.
+    pub fn try_new(start_address: AddressType, length: usize) -> Option<MemoryArea<AddressType>> {
+        start_address
+            .as_usize()
+            .checked_add(length)
+            .map(|_| MemoryArea::new(start_address, length))
+    }
+
+    /// Creates a new MemoryArea from its start and end address.
+    ///
+    /// The end must not lie before the start. Use `try_from_start_and_end` if
+    /// the addresses aren't trusted.
     pub fn from_start_and_end(
         start_address: AddressType,
         end_address: AddressType
     ) -> MemoryArea<AddressType> {
-        if start_address > end_address {
-            MemoryArea::new(
-                start_address,
-                start_address.as_usize() - end_address.as_usize()
-            )
+        debug_assert!(
+            start_address <= end_address,
+            "The memory area ends at {:#x}, before its start at {:#x}.",
+            end_address.as_usize(),
+            start_address.as_usize()
+        );
+
+        MemoryArea::new(
+            start_address,
+            end_address.as_usize().saturating_sub(start_address.as_usize())
+        )
+    }
+
+    /// Creates a new MemoryArea from its start and end address, unless the
+    /// end lies before the start.
+    pub fn try_from_start_and_end(
+        start_address: AddressType,
+        end_address: AddressType
+    ) -> Option<MemoryArea<AddressType>> {
+        if start_address <= end_address {
+            Some(MemoryArea::from_start_and_end(start_address, end_address))
         } else {
-            MemoryArea::new(
-                start_address,
-                end_address.as_usize() - start_address.as_usize()
-            )
+            None
         }
     }
 
@@ -233,6 +321,13 @@ impl<AddressType: Address> MemoryArea<AddressType> {
     ///
     /// The end address is the address of the first byte not contained in it.
     pub fn end_address(&self) -> AddressType {
+        debug_assert!(
+            self.start_address.as_usize().checked_add(self.length).is_some(),
+            "The memory area at {:#x} with length {:#x} ends beyond the address space.",
+            self.start_address.as_usize(),
+            self.length
+        );
+
         self.start_address + self.length
     }
 
@@ -241,6 +336,50 @@ impl<AddressType: Address> MemoryArea<AddressType> {
         self.length
     }
 
+    /// Returns true if the area doesn't contain any bytes.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Splits the area into the part before the given address and the part
+    /// starting at it.
+    ///
+    /// Addresses outside of the area are clamped to it, so one of the parts
+    /// may be empty.
+    pub fn split_at(
+        &self,
+        address: AddressType
+    ) -> (MemoryArea<AddressType>, MemoryArea<AddressType>) {
+        let split_address = min(max(address, self.start_address()), self.end_address());
+
+        (
+            MemoryArea::from_start_and_end(self.start_address(), split_address),
+            MemoryArea::from_start_and_end(split_address, self.end_address())
+        )
+    }
+
+    /// Returns the parts of this area that lie before and after the other
+    /// area.
+    ///
+    /// Parts that would be empty are returned as `None`.
+    pub fn subtract(
+        &self,
+        other: MemoryArea<AddressType>
+    ) -> (Option<MemoryArea<AddressType>>, Option<MemoryArea<AddressType>>) {
+        let (before, rest) = self.split_at(other.start_address());
+        let (_, after) = rest.split_at(other.end_address());
+
+        let non_empty = |area: MemoryArea<AddressType>| {
+            if area.is_empty() {
+                None
+            } else {
+                Some(area)
+            }
+        };
+
+        (non_empty(before), non_empty(after))
+    }
+
     /// Checks if the address is contained within the segment.
     fn contains(&self, address: AddressType) -> bool {
         self.start_address() <= address && address < self.end_address()
@@ -262,7 +401,7 @@ impl MemoryArea<PhysicalAddress> {
     /// Returns the same area except for the first frame.
     pub fn without_first_frame(&self) -> MemoryArea<PhysicalAddress> {
         // The start address should be page aligned.
-        assert!(self.start_address.as_usize() % PAGE_SIZE == 0);
+        assert!(self.start_address.is_page_aligned());
 
         MemoryArea {
             start_address: self.start_address() + PAGE_SIZE,
@@ -315,10 +454,252 @@ bitflags! {
 pub fn init() {
     assert_has_not_been_called!("Memory state should only be initialized once.");
 
+    reserved::init();
     arch::Current::memory_init();
+    allocator::init();
+}
+
+/// Maps the given device registers uncached and returns their address.
+///
+/// The registers are recorded as mapped, so mapping registers that overlap
+/// with already mapped ones fails.
+pub fn map_mmio(address: PhysicalAddress, length: usize) -> Result<VirtualAddress, MmioError> {
+    debug_assert!(length > 0, "Trying to map empty MMIO.");
+
+    reserved::reserve_mmio_mapping(MemoryArea::new(address, length))?;
+
+    Ok(arch::Current::map_device_memory(address, length))
+}
+
+/// Unmaps device registers that were mapped with `map_mmio`.
+///
+/// Pages that also hold other mapped registers stay mapped.
+///
+/// # Safety
+/// - The registers must not be accessed through the mapping anymore.
+pub unsafe fn unmap_mmio(address: PhysicalAddress, length: usize) {
+    let area = MemoryArea::new(address, length);
+
+    assert!(
+        reserved::release_mmio_mapping(area),
+        "Trying to unmap MMIO that isn't mapped."
+    );
+
+    let mut frame = address.page_align_down();
+
+    while frame < area.end_address() {
+        if reserved::find_mmio_mapping(MemoryArea::new(frame, PAGE_SIZE)).is_none() {
+            arch::Current::unmap_device_memory(frame, PAGE_SIZE);
+        }
+
+        frame += PAGE_SIZE;
+    }
 }
 
 /// This function gets called when the system is out of memory.
 pub fn oom() -> ! {
     panic!("Out of memory!");
 }
+
+/// Self-tests for address arithmetic.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    /// Checks that the address of a heap value is translated consistently.
+    fn test_address_translation() -> Result<(), &'static str> {
+        let value = Box::new(0x1234_5678_9abc_def0u64);
+        let address = VirtualAddress::from_usize(&*value as *const u64 as usize);
+        let page = address.page_align_down();
+        let physical = address
+            .to_physical()
+            .ok_or("A heap address isn't mapped.")?;
+
+        if physical.offset_in_page() != address.offset_in_page() {
+            return Err("The translation changed the offset in the page.");
+        }
+
+        if unsafe { *physical.to_virtual().as_ptr::<u64>() } != *value {
+            return Err("The physical address holds a different value.");
+        }
+
+        if !page.is_page_aligned()
+            || page.page_align_up() != page
+            || page + address.offset_in_page() != address
+            || VirtualAddress::from_page_num(address.page_num()) != page
+        {
+            return Err("The page of the address is inconsistent.");
+        }
+
+        Ok(())
+    }
+
+    register_selftest!(ADDRESS_TRANSLATION, test_address_translation);
+}
+
+/// Tests for memory areas.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a physical memory area from its start and end.
+    fn area(start: usize, end: usize) -> MemoryArea<PhysicalAddress> {
+        MemoryArea::from_start_and_end(
+            PhysicalAddress::from_usize(start),
+            PhysicalAddress::from_usize(end)
+        )
+    }
+
+    /// Tests splitting inside and outside of an area.
+    #[test]
+    fn test_split_at() {
+        let whole = area(0x1000, 0x5000);
+
+        assert_eq!(
+            whole.split_at(PhysicalAddress::from_usize(0x2000)),
+            (area(0x1000, 0x2000), area(0x2000, 0x5000))
+        );
+        assert_eq!(
+            whole.split_at(PhysicalAddress::from_usize(0x1000)),
+            (area(0x1000, 0x1000), whole)
+        );
+        assert_eq!(
+            whole.split_at(PhysicalAddress::from_usize(0x5000)),
+            (whole, area(0x5000, 0x5000))
+        );
+        assert_eq!(
+            whole.split_at(PhysicalAddress::from_usize(0)),
+            (area(0x1000, 0x1000), whole)
+        );
+        assert_eq!(
+            whole.split_at(PhysicalAddress::from_usize(0x9000)),
+            (whole, area(0x5000, 0x5000))
+        );
+    }
+
+    /// Tests subtracting areas in all overlap configurations.
+    #[test]
+    fn test_subtract() {
+        let whole = area(0x1000, 0x5000);
+        let cases = [
+            // Disjoint before and after.
+            (area(0, 0x800), (None, Some(whole))),
+            (area(0x6000, 0x7000), (Some(whole), None)),
+            // Touching the start and the end.
+            (area(0, 0x1000), (None, Some(whole))),
+            (area(0x5000, 0x6000), (Some(whole), None)),
+            // Overlapping the start and the end.
+            (area(0, 0x2000), (None, Some(area(0x2000, 0x5000)))),
+            (area(0x4000, 0x6000), (Some(area(0x1000, 0x4000)), None)),
+            // Strictly inside.
+            (
+                area(0x2000, 0x3000),
+                (Some(area(0x1000, 0x2000)), Some(area(0x3000, 0x5000)))
+            ),
+            // Inside, but flush with the start or the end.
+            (area(0x1000, 0x2000), (None, Some(area(0x2000, 0x5000)))),
+            (area(0x4000, 0x5000), (Some(area(0x1000, 0x4000)), None)),
+            // Equal and covering.
+            (whole, (None, None)),
+            (area(0, 0x6000), (None, None)),
+            // Zero-length areas remove nothing.
+            (
+                area(0x3000, 0x3000),
+                (Some(area(0x1000, 0x3000)), Some(area(0x3000, 0x5000)))
+            )
+        ];
+
+        for &(other, expected) in cases.iter() {
+            assert_eq!(whole.subtract(other), expected, "subtracting {:?}", other);
+        }
+
+        assert_eq!(area(0x1000, 0x1000).subtract(area(0, 0x6000)), (None, None));
+    }
+
+    /// Tests that the page helpers agree with the page size.
+    #[test]
+    fn test_page_alignment() {
+        let address = VirtualAddress::from_usize(5 * PAGE_SIZE + 3);
+
+        assert_eq!(address.page_align_down(), VirtualAddress::from_usize(5 * PAGE_SIZE));
+        assert_eq!(address.page_align_up(), VirtualAddress::from_usize(6 * PAGE_SIZE));
+        assert_eq!(address.offset_in_page(), 3);
+        assert_eq!(address.page_num(), 5);
+        assert_eq!(VirtualAddress::from_page_num(5), address.page_align_down());
+        assert!(!address.is_page_aligned());
+
+        let aligned = PhysicalAddress::from_usize(7 * PAGE_SIZE);
+
+        assert_eq!(aligned.page_align_down(), aligned);
+        assert_eq!(aligned.page_align_up(), aligned);
+        assert_eq!(aligned.offset_in_page(), 0);
+        assert!(aligned.is_page_aligned());
+
+        assert_eq!(round_up_to_page_size(0), 0);
+        assert_eq!(round_up_to_page_size(1), PAGE_SIZE);
+        assert_eq!(round_up_to_page_size(PAGE_SIZE), PAGE_SIZE);
+        assert_eq!(round_up_to_page_size(PAGE_SIZE + 1), 2 * PAGE_SIZE);
+    }
+
+    /// Tests aligning to sizes other than the page size.
+    #[test]
+    fn test_alignment() {
+        let huge_page_size = 512 * PAGE_SIZE;
+        let address = PhysicalAddress::from_usize(3 * huge_page_size + PAGE_SIZE);
+
+        assert_eq!(
+            address.align_down(huge_page_size),
+            PhysicalAddress::from_usize(3 * huge_page_size)
+        );
+        assert_eq!(
+            address.align_up(huge_page_size),
+            PhysicalAddress::from_usize(4 * huge_page_size)
+        );
+        assert_eq!(address.offset_in(huge_page_size), PAGE_SIZE);
+        assert!(address.is_aligned(PAGE_SIZE));
+        assert!(!address.is_aligned(huge_page_size));
+    }
+
+    /// Tests that areas reaching beyond the address space are rejected.
+    #[test]
+    fn test_try_new() {
+        let max = usize::max_value();
+        let try_new = |start, length| {
+            MemoryArea::try_new(PhysicalAddress::from_usize(start), length)
+        };
+
+        assert_eq!(try_new(0x1000, 0x1000), Some(area(0x1000, 0x2000)));
+        assert_eq!(try_new(0, max), Some(area(0, max)));
+        assert_eq!(try_new(max - 0xfff, 0xfff), Some(area(max - 0xfff, max)));
+        assert_eq!(try_new(max, 0), Some(area(max, max)));
+
+        // The end address would have to be one past the last address.
+        assert_eq!(try_new(max - 0xfff, 0x1000), None);
+        assert_eq!(try_new(max, 1), None);
+        assert_eq!(try_new(0x1000, max), None);
+    }
+
+    /// Tests that areas ending before their start are rejected.
+    #[test]
+    fn test_try_from_start_and_end() {
+        let try_area = |start, end| {
+            MemoryArea::try_from_start_and_end(
+                PhysicalAddress::from_usize(start),
+                PhysicalAddress::from_usize(end)
+            )
+        };
+
+        assert_eq!(
+            try_area(0x1000, 0x3000),
+            Some(MemoryArea::new(PhysicalAddress::from_usize(0x1000), 0x2000))
+        );
+
+        let empty = try_area(0x2000, 0x2000).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.start_address(), PhysicalAddress::from_usize(0x2000));
+
+        assert_eq!(try_area(0x3000, 0x1000), None);
+        assert_eq!(try_area(usize::max_value(), 0), None);
+    }
+}
@@ -0,0 +1,408 @@
+//! Keeps track of physical memory that is reserved for firmware and devices.
+//!
+//! The boot memory map only hands the usable memory to the frame allocator.
+//! The reserved areas are recorded here instead of being discarded, so that
+//! device memory can be told apart from RAM. The registry is filled before
+//! the heap exists, which is why it has a fixed capacity.
+//!
+//! Device registers that a driver maps are recorded as mapped MMIO. Unlike
+//! the other kinds, mapped MMIO areas may not overlap, so that no two drivers
+//! access the same registers.
+
+use crate::boot;
+use crate::memory::{Address, MemoryArea, PhysicalAddress};
+use crate::sync::Mutex;
+
+/// The maximum number of reserved areas that can be recorded.
+const MAX_RESERVED_AREAS: usize = 128;
+
+/// The registry of all reserved areas.
+///
+/// # Safety
+/// - The frame allocator is initialized while holding this lock, so it must
+/// never be acquired while holding a page table lock.
+static RESERVED_AREAS: Mutex<ReservedAreas> = Mutex::new(ReservedAreas::new());
+
+/// Describes what a reserved area is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedKind {
+    /// The firmware marked the area as reserved in the memory map.
+    Firmware,
+    /// The area holds ACPI tables or ACPI non-volatile storage.
+    Acpi,
+    /// The area holds memory mapped device registers.
+    Mmio,
+    /// The area holds the framebuffer.
+    Framebuffer,
+    /// The area holds device registers that a driver mapped.
+    MappedMmio
+}
+
+/// The reasons why device registers can't be mapped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MmioError {
+    /// The registers overlap with the given mapped area.
+    Overlapping(ReservedArea),
+    /// There is no space left to record the mapping.
+    RegistryFull
+}
+
+/// A reserved area of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReservedArea {
+    /// The reserved physical memory.
+    pub area: MemoryArea<PhysicalAddress>,
+    /// What the memory is used for.
+    pub kind: ReservedKind
+}
+
+/// A list of reserved areas with a fixed capacity.
+struct ReservedAreas {
+    /// The recorded areas, the first `count` of which are used.
+    areas: [Option<ReservedArea>; MAX_RESERVED_AREAS],
+    /// The number of recorded areas.
+    count: usize
+}
+
+impl ReservedAreas {
+    /// Creates an empty list.
+    const fn new() -> ReservedAreas {
+        ReservedAreas {
+            areas: [None; MAX_RESERVED_AREAS],
+            count: 0
+        }
+    }
+
+    /// Records the given area.
+    ///
+    /// Empty areas and areas that are already recorded are ignored. Returns
+    /// false if there is no space left.
+    fn insert(&mut self, reserved_area: ReservedArea) -> bool {
+        if reserved_area.area.is_empty() || self.iter().any(|area| *area == reserved_area) {
+            return true;
+        }
+
+        if self.count == MAX_RESERVED_AREAS {
+            return false;
+        }
+
+        self.areas[self.count] = Some(reserved_area);
+        self.count += 1;
+
+        true
+    }
+
+    /// Records the given area as mapped MMIO.
+    ///
+    /// Fails if the area overlaps with another mapped MMIO area.
+    fn insert_mmio_mapping(&mut self, area: MemoryArea<PhysicalAddress>) -> Result<(), MmioError> {
+        if let Some(mapped) = self.find_mmio_mapping(area) {
+            return Err(MmioError::Overlapping(mapped));
+        }
+
+        if self.insert(ReservedArea {
+            area,
+            kind: ReservedKind::MappedMmio
+        }) {
+            Ok(())
+        } else {
+            Err(MmioError::RegistryFull)
+        }
+    }
+
+    /// Removes the given recorded area.
+    ///
+    /// Returns false if it wasn't recorded.
+    fn remove(&mut self, reserved_area: ReservedArea) -> bool {
+        let position = self.areas[..self.count]
+            .iter()
+            .position(|area| *area == Some(reserved_area));
+
+        match position {
+            Some(index) => {
+                self.count -= 1;
+                self.areas[index] = self.areas[self.count];
+                self.areas[self.count] = None;
+
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns an iterator over the recorded areas.
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a ReservedArea> {
+        self.areas[..self.count].iter().filter_map(|area| area.as_ref())
+    }
+
+    /// Returns the first recorded area that shares memory with the given
+    /// area.
+    fn find_overlapping(&self, area: MemoryArea<PhysicalAddress>) -> Option<ReservedArea> {
+        if area.is_empty() {
+            return None;
+        }
+
+        self.iter()
+            .find(|reserved| reserved.area.overlaps_with(area))
+            .cloned()
+    }
+
+    /// Returns the first mapped MMIO area that shares memory with the given
+    /// area.
+    fn find_mmio_mapping(&self, area: MemoryArea<PhysicalAddress>) -> Option<ReservedArea> {
+        self.iter()
+            .find(|reserved| {
+                reserved.kind == ReservedKind::MappedMmio && reserved.area.overlaps_with(area)
+            })
+            .cloned()
+    }
+
+    /// Returns the recorded area that contains the whole given area.
+    fn find_containing(&self, area: MemoryArea<PhysicalAddress>) -> Option<ReservedArea> {
+        self.iter()
+            .find(|reserved| area.is_contained_in(reserved.area))
+            .cloned()
+    }
+
+    /// Calls the given function for each part of the area that isn't
+    /// reserved.
+    fn for_each_unreserved_part<F>(&self, area: MemoryArea<PhysicalAddress>, action: &mut F)
+    where
+        F: FnMut(MemoryArea<PhysicalAddress>)
+    {
+        match self.find_overlapping(area) {
+            Some(reserved) => {
+                let (before, after) = area.subtract(reserved.area);
+
+                if let Some(before) = before {
+                    self.for_each_unreserved_part(before, action);
+                }
+
+                if let Some(after) = after {
+                    self.for_each_unreserved_part(after, action);
+                }
+            },
+            None => action(area)
+        }
+    }
+}
+
+/// Records the reserved areas that are known at boot.
+///
+/// This must be called before the frame allocator is initialized.
+pub fn init() {
+    assert_has_not_been_called!("The reserved areas should only be initialized once.");
+
+    for (area, kind) in boot::get_reserved_memory_map() {
+        reserve(area, kind);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let vga_info = boot::get_vga_info();
+
+        reserve(
+            MemoryArea::new(vga_info.address, vga_info.pitch * vga_info.height),
+            ReservedKind::Framebuffer
+        );
+    }
+}
+
+/// Records the given area as reserved.
+///
+/// Areas that become known after the frame allocator was initialized must
+/// not lie in usable memory.
+pub fn reserve(area: MemoryArea<PhysicalAddress>, kind: ReservedKind) {
+    let reserved_area = ReservedArea { area, kind };
+
+    if !RESERVED_AREAS.lock().insert(reserved_area) {
+        warn!("Too many reserved areas, ignoring {:?}.", reserved_area);
+    }
+}
+
+/// Records the given area as mapped by a driver.
+///
+/// Fails if another driver mapped registers in the area.
+pub fn reserve_mmio_mapping(area: MemoryArea<PhysicalAddress>) -> Result<(), MmioError> {
+    RESERVED_AREAS.lock().insert_mmio_mapping(area)
+}
+
+/// Removes the record of a mapping made with `reserve_mmio_mapping`.
+///
+/// Returns false if the area wasn't recorded as mapped.
+pub fn release_mmio_mapping(area: MemoryArea<PhysicalAddress>) -> bool {
+    RESERVED_AREAS.lock().remove(ReservedArea {
+        area,
+        kind: ReservedKind::MappedMmio
+    })
+}
+
+/// Returns a mapped MMIO area that shares memory with the given area, if
+/// there is one.
+pub fn find_mmio_mapping(area: MemoryArea<PhysicalAddress>) -> Option<ReservedArea> {
+    RESERVED_AREAS.lock().find_mmio_mapping(area)
+}
+
+/// Returns a reserved area that shares memory with the given area, if there
+/// is one.
+pub fn find_overlapping(area: MemoryArea<PhysicalAddress>) -> Option<ReservedArea> {
+    RESERVED_AREAS.lock().find_overlapping(area)
+}
+
+/// Returns the reserved area that contains the whole given area, if there is
+/// one.
+pub fn find_containing(area: MemoryArea<PhysicalAddress>) -> Option<ReservedArea> {
+    RESERVED_AREAS.lock().find_containing(area)
+}
+
+/// Calls the given function for each part of the area that isn't reserved.
+///
+/// The registry is locked while the function runs.
+pub fn for_each_unreserved_part<F>(area: MemoryArea<PhysicalAddress>, mut action: F)
+where
+    F: FnMut(MemoryArea<PhysicalAddress>)
+{
+    RESERVED_AREAS
+        .lock()
+        .for_each_unreserved_part(area, &mut action)
+}
+
+/// Tests for the registry of reserved areas.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::Vec;
+
+    /// Creates a physical memory area from its start and end.
+    fn area(start: usize, end: usize) -> MemoryArea<PhysicalAddress> {
+        MemoryArea::from_start_and_end(
+            PhysicalAddress::from_usize(start),
+            PhysicalAddress::from_usize(end)
+        )
+    }
+
+    /// Creates a registry with the given MMIO areas.
+    fn registry(areas: &[MemoryArea<PhysicalAddress>]) -> ReservedAreas {
+        let mut registry = ReservedAreas::new();
+
+        for &area in areas {
+            assert!(registry.insert(ReservedArea {
+                area,
+                kind: ReservedKind::Mmio
+            }));
+        }
+
+        registry
+    }
+
+    /// Tests detecting overlaps with reserved areas.
+    #[test]
+    fn test_find_overlapping() {
+        let registry = registry(&[area(0x1000, 0x3000), area(0x8000, 0x9000)]);
+
+        assert_eq!(
+            registry.find_overlapping(area(0x2000, 0x5000)).map(|r| r.area),
+            Some(area(0x1000, 0x3000))
+        );
+        assert_eq!(
+            registry.find_overlapping(area(0x7000, 0xa000)).map(|r| r.area),
+            Some(area(0x8000, 0x9000))
+        );
+        assert_eq!(
+            registry.find_overlapping(area(0x8fff, 0x9000)).map(|r| r.area),
+            Some(area(0x8000, 0x9000))
+        );
+
+        // Touching areas share no memory.
+        assert_eq!(registry.find_overlapping(area(0x3000, 0x8000)), None);
+        assert_eq!(registry.find_overlapping(area(0, 0x1000)), None);
+        assert_eq!(registry.find_overlapping(area(0x2000, 0x2000)), None);
+    }
+
+    /// Tests that only areas within a single reserved area are contained.
+    #[test]
+    fn test_find_containing() {
+        let registry = registry(&[area(0x1000, 0x3000), area(0x3000, 0x4000)]);
+
+        assert!(registry.find_containing(area(0x1000, 0x3000)).is_some());
+        assert!(registry.find_containing(area(0x1800, 0x1900)).is_some());
+        assert!(registry.find_containing(area(0x2000, 0x3800)).is_none());
+        assert!(registry.find_containing(area(0x5000, 0x6000)).is_none());
+    }
+
+    /// Tests that duplicates and empty areas don't use up space.
+    #[test]
+    fn test_insert() {
+        let mut registry = registry(&[area(0x1000, 0x2000)]);
+
+        assert!(registry.insert(ReservedArea {
+            area: area(0x1000, 0x2000),
+            kind: ReservedKind::Mmio
+        }));
+        assert!(registry.insert(ReservedArea {
+            area: area(0x5000, 0x5000),
+            kind: ReservedKind::Acpi
+        }));
+        assert_eq!(registry.count, 1);
+
+        for i in 1..MAX_RESERVED_AREAS {
+            assert!(registry.insert(ReservedArea {
+                area: area(i * 0x10000, i * 0x10000 + 0x1000),
+                kind: ReservedKind::Firmware
+            }));
+        }
+
+        assert!(!registry.insert(ReservedArea {
+            area: area(0x1000_0000, 0x1000_1000),
+            kind: ReservedKind::Firmware
+        }));
+    }
+
+    /// Tests that mapped MMIO areas can't overlap and can be released.
+    #[test]
+    fn test_mmio_mappings() {
+        let mut registry = registry(&[area(0x1000, 0x3000)]);
+
+        // Registers described by a plain MMIO record can be mapped.
+        assert_eq!(registry.insert_mmio_mapping(area(0x1000, 0x2000)), Ok(()));
+        assert_eq!(registry.insert_mmio_mapping(area(0x2000, 0x2100)), Ok(()));
+
+        let mapped = ReservedArea {
+            area: area(0x1000, 0x2000),
+            kind: ReservedKind::MappedMmio
+        };
+
+        assert_eq!(
+            registry.insert_mmio_mapping(area(0x1800, 0x1900)),
+            Err(MmioError::Overlapping(mapped))
+        );
+        assert_eq!(
+            registry.insert_mmio_mapping(area(0x1000, 0x2000)),
+            Err(MmioError::Overlapping(mapped))
+        );
+
+        assert!(registry.remove(mapped));
+        assert!(!registry.remove(mapped));
+        assert_eq!(registry.count, 2);
+        assert_eq!(registry.find_mmio_mapping(area(0x1000, 0x2000)), None);
+        assert_eq!(registry.insert_mmio_mapping(area(0x1800, 0x1900)), Ok(()));
+    }
+
+    /// Tests that reserved areas are cut out of usable memory.
+    #[test]
+    fn test_unreserved_parts() {
+        let registry = registry(&[
+            area(0x2000, 0x3000),
+            area(0x6000, 0x8000),
+            area(0x9000, 0xa000)
+        ]);
+        let mut parts = Vec::new();
+
+        registry.for_each_unreserved_part(area(0x1000, 0x9800), &mut |part| parts.push(part));
+
+        assert_eq!(
+            &parts[..],
+            &[area(0x1000, 0x2000), area(0x3000, 0x6000), area(0x8000, 0x9000)]
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! Provides caches for frequently allocated kernel objects of a fixed size.
+//!
+//! Each cache carves whole page frames into slots for one type of object.
+//! Freed slots are kept in a list and reused first, so allocating and freeing
+//! takes constant time and doesn't fragment the general heap. Pages whose
+//! slots are all free can be returned with `shrink`.
+//!
+//! Page tables don't live in slabs. They need whole, page aligned frames that
+//! are accessible through the direct map and come from the frame allocator.
+
+use super::{Address, VirtualAddress, PAGE_SIZE};
+use alloc::btree_map::BTreeMap;
+use crate::arch::{self, Architecture};
+use core::cmp::{max, Ordering};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use crate::sync::Mutex;
+
+/// A free slot in a slab.
+struct FreeSlot {
+    /// The next free slot.
+    next: *mut FreeSlot
+}
+
+/// A cache of slots for objects of type `T`.
+pub struct SlabCache<T> {
+    /// The first free slot.
+    free_list: *mut FreeSlot,
+    /// The number of pages that were carved into slots.
+    slab_count: usize,
+    /// The number of slots that are currently in use.
+    allocated: usize,
+    /// The function that provides the pages for new slabs.
+    allocate_page: fn() -> VirtualAddress,
+    /// The function that takes back the pages of empty slabs.
+    free_page: unsafe fn(VirtualAddress),
+    /// The type of the objects.
+    object_type: PhantomData<T>
+}
+
+// The slots are only accessed through the cache, so this is okay.
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Creates a new cache that uses kernel page frames for its slabs.
+    pub fn new() -> SlabCache<T> {
+        SlabCache::with_page_source(
+            arch::Current::allocate_kernel_frame,
+            arch::Current::free_kernel_frame
+        )
+    }
+
+    /// Creates a new cache that gets the pages for its slabs from the given functions.
+    ///
+    /// The pages must be page aligned.
+    pub fn with_page_source(
+        allocate_page: fn() -> VirtualAddress,
+        free_page: unsafe fn(VirtualAddress)
+    ) -> SlabCache<T> {
+        assert!(
+            Self::slot_size() <= PAGE_SIZE,
+            "Objects larger than a page can't be allocated in a slab."
+        );
+
+        SlabCache {
+            free_list: ptr::null_mut(),
+            slab_count: 0,
+            allocated: 0,
+            allocate_page,
+            free_page,
+            object_type: PhantomData
+        }
+    }
+
+    /// Returns the size of a single slot.
+    fn slot_size() -> usize {
+        let alignment = max(align_of::<T>(), align_of::<FreeSlot>());
+        let size = max(size_of::<T>(), size_of::<FreeSlot>());
+
+        (size + alignment - 1) / alignment * alignment
+    }
+
+    /// Returns an uninitialized slot for an object.
+    pub fn alloc(&mut self) -> *mut T {
+        if self.free_list.is_null() {
+            self.grow();
+        }
+
+        let slot = self.free_list;
+        self.free_list = unsafe { (*slot).next };
+        self.allocated += 1;
+
+        slot as *mut T
+    }
+
+    /// Returns the slot of the given object to the cache.
+    ///
+    /// # Safety
+    /// - The object must have been allocated by this cache.
+    /// - The object must have been dropped already and mustn't be used afterwards.
+    pub unsafe fn free(&mut self, object: *mut T) {
+        debug_assert!(self.allocated > 0, "Freeing more objects than were allocated.");
+
+        let slot = object as *mut FreeSlot;
+        (*slot).next = self.free_list;
+        self.free_list = slot;
+        self.allocated -= 1;
+    }
+
+    /// Returns the number of pages used by this cache.
+    pub fn slab_count(&self) -> usize {
+        self.slab_count
+    }
+
+    /// Returns the number of objects that are currently allocated.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Returns the pages of all slabs without allocated objects.
+    ///
+    /// Returns the number of freed pages.
+    pub fn shrink(&mut self) -> usize {
+        let slots_per_slab = PAGE_SIZE / Self::slot_size();
+        let mut free_slots = BTreeMap::new();
+
+        let mut slot = self.free_list;
+        while !slot.is_null() {
+            *free_slots.entry(Self::slab_of(slot)).or_insert(0) += 1;
+            slot = unsafe { (*slot).next };
+        }
+
+        // Unlink the slots of the empty slabs.
+        let mut link: *mut *mut FreeSlot = &mut self.free_list;
+        unsafe {
+            while !(*link).is_null() {
+                let slot = *link;
+
+                if free_slots[&Self::slab_of(slot)] == slots_per_slab {
+                    *link = (*slot).next;
+                } else {
+                    link = &mut (*slot).next;
+                }
+            }
+        }
+
+        let mut freed = 0;
+        for (&slab, &count) in free_slots.iter() {
+            if count == slots_per_slab {
+                unsafe { (self.free_page)(slab) };
+                freed += 1;
+            }
+        }
+
+        self.slab_count -= freed;
+        freed
+    }
+
+    /// Returns the page of the slab that contains the given slot.
+    fn slab_of(slot: *mut FreeSlot) -> VirtualAddress {
+        VirtualAddress::from_usize(slot as usize).page_align_down()
+    }
+
+    /// Carves a new page into slots.
+    fn grow(&mut self) {
+        let page = (self.allocate_page)();
+        let slot_size = Self::slot_size();
+
+        debug_assert!(page.is_aligned(PAGE_SIZE), "Slab pages must be page aligned.");
+
+        // Push the slots in reverse, so they are handed out in address order.
+        for index in (0..PAGE_SIZE / slot_size).rev() {
+            let slot = (page + index * slot_size).as_mut_ptr::<FreeSlot>();
+
+            unsafe {
+                (*slot).next = self.free_list;
+            }
+            self.free_list = slot;
+        }
+
+        self.slab_count += 1;
+    }
+}
+
+/// An object that lives in a slab cache.
+///
+/// This behaves like a box, but frees the object back to its cache.
+pub struct SlabBox<T: 'static> {
+    /// The object itself.
+    object: *mut T,
+    /// The cache the object was allocated in.
+    cache: &'static Mutex<SlabCache<T>>
+}
+
+// The object is owned by the box, so this is okay.
+unsafe impl<T: Send + 'static> Send for SlabBox<T> {}
+unsafe impl<T: Sync + 'static> Sync for SlabBox<T> {}
+
+impl<T: 'static> SlabBox<T> {
+    /// Moves the value into a slot of the given cache.
+    pub fn new(value: T, cache: &'static Mutex<SlabCache<T>>) -> SlabBox<T> {
+        let object = cache.lock().alloc();
+
+        unsafe {
+            ptr::write(object, value);
+        }
+
+        SlabBox { object, cache }
+    }
+}
+
+impl<T: 'static> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // Drop the object first, as dropping might use the cache itself.
+            ptr::drop_in_place(self.object);
+            self.cache.lock().free(self.object);
+        }
+    }
+}
+
+impl<T: 'static> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.object }
+    }
+}
+
+impl<T: 'static> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.object }
+    }
+}
+
+impl<T: fmt::Debug + 'static> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: PartialEq + 'static> PartialEq for SlabBox<T> {
+    fn eq(&self, other: &SlabBox<T>) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq + 'static> Eq for SlabBox<T> {}
+
+impl<T: Ord + 'static> Ord for SlabBox<T> {
+    fn cmp(&self, other: &SlabBox<T>) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: PartialOrd + 'static> PartialOrd for SlabBox<T> {
+    fn partial_cmp(&self, other: &SlabBox<T>) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+/// Tests for the slab caches.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::Vec;
+
+    /// A page aligned buffer.
+    #[repr(align(4096))]
+    struct TestPage([u8; PAGE_SIZE]);
+
+    /// Provides a leaked test page.
+    fn test_page() -> VirtualAddress {
+        let page = Box::new(TestPage([0; PAGE_SIZE]));
+
+        VirtualAddress::from_usize(Box::into_raw(page) as usize)
+    }
+
+    /// Frees a page from `test_page`.
+    unsafe fn free_test_page(page: VirtualAddress) {
+        drop(Box::from_raw(page.as_mut_ptr::<TestPage>()));
+    }
+
+    /// Tests that freed slots are reused immediately.
+    #[test]
+    fn test_reuse() {
+        let mut cache: SlabCache<[u64; 4]> = SlabCache::with_page_source(test_page, free_test_page);
+
+        let first = cache.alloc();
+        let second = cache.alloc();
+        assert_ne!(first, second);
+
+        unsafe { cache.free(first) };
+        assert_eq!(cache.alloc(), first);
+        assert_eq!(cache.allocated(), 2);
+    }
+
+    /// Tests that repeatedly allocating and freeing doesn't grow the cache.
+    #[test]
+    fn test_no_fragmentation_growth() {
+        let mut cache: SlabCache<[u64; 5]> = SlabCache::with_page_source(test_page, free_test_page);
+        let mut objects = Vec::new();
+
+        for _ in 0..1000 {
+            objects.push(cache.alloc());
+        }
+        let slab_count = cache.slab_count();
+
+        for round in 0..100 {
+            // Free every other object and allocate them again.
+            for index in (0..objects.len()).filter(|index| index % 2 == round % 2) {
+                unsafe { cache.free(objects[index]) };
+            }
+            for index in (0..objects.len()).filter(|index| index % 2 == round % 2) {
+                objects[index] = cache.alloc();
+            }
+        }
+
+        assert_eq!(cache.slab_count(), slab_count);
+        assert_eq!(cache.allocated(), 1000);
+    }
+
+    /// Tests that shrinking only returns the pages of empty slabs.
+    #[test]
+    fn test_shrink() {
+        let mut cache: SlabCache<[u64; 8]> = SlabCache::with_page_source(test_page, free_test_page);
+        let slots_per_slab = PAGE_SIZE / SlabCache::<[u64; 8]>::slot_size();
+        let mut objects = Vec::new();
+
+        for _ in 0..3 * slots_per_slab {
+            objects.push(cache.alloc());
+        }
+        assert_eq!(cache.slab_count(), 3);
+
+        // Empty the first slab and keep one object in the last one.
+        for object in objects.drain(..slots_per_slab) {
+            unsafe { cache.free(object) };
+        }
+        for object in objects.drain(slots_per_slab..2 * slots_per_slab - 1) {
+            unsafe { cache.free(object) };
+        }
+
+        assert_eq!(cache.shrink(), 1);
+        assert_eq!(cache.slab_count(), 2);
+        assert_eq!(cache.shrink(), 0);
+
+        // The remaining free slots are still usable.
+        for _ in 0..slots_per_slab - 1 {
+            objects.push(cache.alloc());
+        }
+        assert_eq!(cache.slab_count(), 2);
+        assert_eq!(cache.allocated(), 2 * slots_per_slab);
+    }
+}
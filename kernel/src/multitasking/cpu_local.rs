@@ -1,60 +1,113 @@
 //! Provides the necessary types to handle CPU local values.
+//!
+//! The value of each CPU is only created on its first access, so CPUs that
+//! never come online don't allocate anything for their values.
+//!
+//! # Thread safety
+//! Every slot is initialized at most once. If two CPUs access the same
+//! uninitialized slot at the same time, one of them initializes it while the
+//! other one spins until the initialization is complete. Because of this, the
+//! initialization function must not access the value it initializes.
 
 use super::get_cpu_id;
 use alloc::Vec;
 use core::cell::UnsafeCell;
 use core::ops::Deref;
+use spin::Once;
 
 /// A helper type to wrap a CPU local value.
-pub struct CPULocal<T>(Vec<T>);
+pub struct CPULocal<T> {
+    /// The slots for the values of each CPU.
+    slots: Vec<Once<T>>,
+    /// The function creating the value for a CPU.
+    init: fn(usize) -> T
+}
 
 impl<T> Deref for CPULocal<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.0[get_cpu_id()]
+        self.get_specific(get_cpu_id())
     }
 }
 
+unsafe impl<T: Sync> Sync for CPULocal<T> {}
+
 impl<T> CPULocal<T> {
-    /// Creates a new `CPULocal` from the underlying vector.
+    /// Creates a new `CPULocal` with a slot for each CPU.
     ///
     /// # Safety
-    /// - Make sure that the vector has as many elements as the CPU number is.
+    /// - Make sure that `cpu_num` is the number of CPUs.
     /// - Should only be called by a macro and not directly.
-    pub unsafe fn new(vec: Vec<T>) -> CPULocal<T> {
-        CPULocal(vec)
+    pub unsafe fn new(cpu_num: usize, init: fn(usize) -> T) -> CPULocal<T> {
+        CPULocal {
+            slots: create_slots(cpu_num),
+            init
+        }
     }
 
     /// Gets the local value of the given cpu.
+    ///
+    /// # Panics
+    /// Panics if there is no CPU with the given id.
     pub fn get_specific(&self, cpu_id: usize) -> &T {
+        self.for_cpu(cpu_id)
+            .expect("Accessing a CPU local value of a non-existent CPU.")
+    }
+
+    /// Gets the local value of the given cpu, if the CPU exists.
+    ///
+    /// The value may be accessed concurrently by the CPU it belongs to, so
+    /// cross-CPU accesses must still go through the synchronization of the
+    /// value itself (e.g. the contained `Mutex`).
+    pub fn for_cpu(&self, cpu_id: usize) -> Option<&T> {
+        let init = self.init;
+
         // This is safe, because the values are immutable.
-        &self.0[cpu_id]
+        self.slots
+            .get(cpu_id)
+            .map(|slot| slot.call_once(|| init(cpu_id)))
+    }
+
+    /// Gets the local value of the given cpu, if it was already created.
+    ///
+    /// Unlike `for_cpu`, this never creates the value, so it can be used to
+    /// look at all CPUs without side effects.
+    pub fn try_get(&self, cpu_id: usize) -> Option<&T> {
+        self.slots.get(cpu_id).and_then(|slot| slot.try())
     }
 }
 
 /// A helper type to wrap a mutable CPU local value.
-pub struct CPULocalMut<T>(UnsafeCell<Vec<T>>);
+pub struct CPULocalMut<T> {
+    /// The slots for the values of each CPU.
+    slots: Vec<Once<UnsafeCell<T>>>,
+    /// The function creating the value for a CPU.
+    init: fn(usize) -> T
+}
 
 impl<T> Deref for CPULocalMut<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        unsafe { &(*self.0.get())[get_cpu_id()] }
+        unsafe { &*self.get_cell() }
     }
 }
 
 unsafe impl<T: Sync> Sync for CPULocalMut<T> {}
 
 impl<T> CPULocalMut<T> {
-    /// Creates a new `CPULocal` from the underlying vector.
+    /// Creates a new `CPULocalMut` with a slot for each CPU.
     ///
     /// # Safety
-    /// - Make sure that the vector has as many elements as the CPU number is.
+    /// - Make sure that `cpu_num` is the number of CPUs.
     /// - Should only be called by a macro and not directly.
     /// - There should be some kind of synchronization for the contained type.
-    pub unsafe fn new(vec: Vec<T>) -> CPULocalMut<T> {
-        CPULocalMut(UnsafeCell::new(vec))
+    pub unsafe fn new(cpu_num: usize, init: fn(usize) -> T) -> CPULocalMut<T> {
+        CPULocalMut {
+            slots: create_slots(cpu_num),
+            init
+        }
     }
 
     /// Sets the value to the specified type.
@@ -62,7 +115,7 @@ impl<T> CPULocalMut<T> {
     /// # Safety
     /// - Make sure there are no references relying on the value.
     pub unsafe fn set(&self, value: T) {
-        (*self.0.get())[get_cpu_id()] = value;
+        *self.get_cell() = value;
     }
 
     /// Returns a mutable reference to the contained type.
@@ -70,6 +123,27 @@ impl<T> CPULocalMut<T> {
     /// # Safety
     /// - Make sure there is only one mutable reference at a time.
     pub unsafe fn as_mut(&self) -> &mut T {
-        &mut (*self.0.get())[get_cpu_id()]
+        &mut *self.get_cell()
     }
+
+    /// Returns a pointer to the value of the current CPU.
+    fn get_cell(&self) -> *mut T {
+        let cpu_id = get_cpu_id();
+        let init = self.init;
+
+        self.slots[cpu_id]
+            .call_once(|| UnsafeCell::new(init(cpu_id)))
+            .get()
+    }
+}
+
+/// Creates `cpu_num` uninitialized slots.
+fn create_slots<T>(cpu_num: usize) -> Vec<Once<T>> {
+    let mut slots = Vec::with_capacity(cpu_num);
+
+    for _ in 0..cpu_num {
+        slots.push(Once::new());
+    }
+
+    slots
 }
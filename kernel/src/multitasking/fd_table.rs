@@ -0,0 +1,255 @@
+//! Maps the file descriptors of a process to its open files.
+//!
+//! Open files are reference counted, so multiple descriptors and processes
+//! can share the same open file, including its seek position.
+//!
+//! Processes created with `exec` inherit the table of their parent: every
+//! descriptor refers to the same open file as in the parent. The first
+//! processes inherit the console on descriptors 0, 1 and 2 from the idle
+//! process.
+//!
+//! Releasing the last reference to a pipe end wakes the threads waiting on
+//! the pipe, which needs the process list. So the methods that remove files
+//! hand them back, to be dropped once the process list is unlocked.
+
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use alloc::Vec;
+use core::mem;
+use crate::file_handle::FileHandle;
+use crate::io::pipe::{PipeReader, PipeWriter};
+use crate::io::poll::PollSource;
+use crate::sync::BlockingMutex;
+
+/// The maximum number of open file descriptors of a process.
+pub const MAX_FILE_DESCRIPTORS: usize = 64;
+
+/// The file descriptor of the standard input.
+pub const STDIN: usize = 0;
+
+/// The file descriptor of the standard output.
+pub const STDOUT: usize = 1;
+
+/// The file descriptor of the standard error output.
+pub const STDERR: usize = 2;
+
+/// The errors that can occur when using a file descriptor table.
+#[derive(Debug, PartialEq)]
+pub enum FdError {
+    /// The file descriptor isn't open.
+    BadDescriptor,
+    /// All file descriptors are in use.
+    TooManyFiles
+}
+
+/// A file that was opened by a process.
+pub enum OpenFile {
+    /// The console, reading from the keyboard and writing to the screen.
+    Console,
+    /// A file of a filesystem.
+    File(Box<FileHandle>),
+    /// The read end of a pipe.
+    PipeReader(PipeReader),
+    /// The write end of a pipe.
+    PipeWriter(PipeWriter)
+}
+
+impl OpenFile {
+    /// Returns the source through which the readiness of the file is polled.
+    pub fn poll_source(&self) -> PollSource {
+        match *self {
+            OpenFile::Console => PollSource::Console,
+            OpenFile::File(_) => PollSource::File,
+            OpenFile::PipeReader(ref reader) => PollSource::PipeReader(reader.pipe()),
+            OpenFile::PipeWriter(ref writer) => PollSource::PipeWriter(writer.pipe())
+        }
+    }
+}
+
+/// An open file that can be referred to by multiple file descriptors.
+///
+/// Writing to a file can take a while, so threads waiting for it block.
+pub type SharedFile = Arc<BlockingMutex<OpenFile>>;
+
+/// The file descriptor table of a process.
+#[derive(Clone)]
+pub struct FdTable {
+    /// The open files, indexed by their file descriptor.
+    entries: Vec<Option<SharedFile>>
+}
+
+impl FdTable {
+    /// Creates a table without any open files.
+    pub fn new() -> FdTable {
+        FdTable {
+            entries: Vec::new()
+        }
+    }
+
+    /// Creates a table with the console on the standard descriptors.
+    pub fn with_console() -> FdTable {
+        let mut table = FdTable::new();
+        let console = Arc::new(BlockingMutex::new(OpenFile::Console));
+
+        for _ in STDIN..STDERR + 1 {
+            table
+                .alloc_fd(console.clone())
+                .expect("The standard descriptors must be available.");
+        }
+
+        table
+    }
+
+    /// Installs the file at the lowest free file descriptor and returns it.
+    pub fn alloc_fd(&mut self, file: SharedFile) -> Result<usize, FdError> {
+        match self.entries.iter().position(|entry| entry.is_none()) {
+            Some(fd) => {
+                self.entries[fd] = Some(file);
+                Ok(fd)
+            },
+            None if self.entries.len() < MAX_FILE_DESCRIPTORS => {
+                self.entries.push(Some(file));
+                Ok(self.entries.len() - 1)
+            },
+            None => Err(FdError::TooManyFiles)
+        }
+    }
+
+    /// Returns the open file of the given file descriptor.
+    pub fn get(&self, fd: usize) -> Result<SharedFile, FdError> {
+        match self.entries.get(fd) {
+            Some(&Some(ref file)) => Ok(file.clone()),
+            _ => Err(FdError::BadDescriptor)
+        }
+    }
+
+    /// Installs the file at the given file descriptor.
+    ///
+    /// A file previously open at the descriptor is removed and returned.
+    pub fn replace(
+        &mut self,
+        fd: usize,
+        file: SharedFile
+    ) -> Result<Option<SharedFile>, FdError> {
+        if fd >= MAX_FILE_DESCRIPTORS {
+            return Err(FdError::BadDescriptor);
+        }
+
+        while self.entries.len() <= fd {
+            self.entries.push(None);
+        }
+
+        Ok(mem::replace(&mut self.entries[fd], Some(file)))
+    }
+
+    /// Closes the given file descriptor and returns its open file.
+    ///
+    /// The open file is released once no descriptor refers to it anymore.
+    pub fn close(&mut self, fd: usize) -> Result<SharedFile, FdError> {
+        let file = match self.entries.get_mut(fd) {
+            Some(entry) if entry.is_some() => entry.take().unwrap(),
+            _ => return Err(FdError::BadDescriptor)
+        };
+
+        // Shrink the table if the last descriptors are unused.
+        while let Some(&None) = self.entries.last() {
+            self.entries.pop();
+        }
+
+        Ok(file)
+    }
+
+    /// Closes all file descriptors and returns their open files.
+    pub fn close_all(&mut self) -> Vec<SharedFile> {
+        self.entries.drain(..).filter_map(|entry| entry).collect()
+    }
+}
+
+/// Tests for the file descriptor table.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a new console file.
+    fn console() -> SharedFile {
+        Arc::new(BlockingMutex::new(OpenFile::Console))
+    }
+
+    /// Tests that the lowest free descriptor is reused.
+    #[test]
+    fn test_lowest_free_descriptor() {
+        let mut table = FdTable::with_console();
+
+        assert_eq!(table.alloc_fd(console()), Ok(3));
+        assert_eq!(table.alloc_fd(console()), Ok(4));
+
+        table.close(1).unwrap();
+        table.close(3).unwrap();
+
+        assert_eq!(table.alloc_fd(console()), Ok(1));
+        assert_eq!(table.alloc_fd(console()), Ok(3));
+        assert_eq!(table.alloc_fd(console()), Ok(5));
+    }
+
+    /// Tests that closed and unknown descriptors are rejected.
+    #[test]
+    fn test_bad_descriptor() {
+        let mut table = FdTable::with_console();
+
+        assert!(table.get(2).is_ok());
+        table.close(2).unwrap();
+
+        assert_eq!(table.close(2).err(), Some(FdError::BadDescriptor));
+        assert!(table.get(2).is_err());
+        assert!(table.get(MAX_FILE_DESCRIPTORS).is_err());
+    }
+
+    /// Tests that replacing a descriptor past the end fills the gap.
+    #[test]
+    fn test_replace() {
+        let mut table = FdTable::with_console();
+
+        table.replace(6, console()).unwrap();
+        assert!(table.get(6).is_ok());
+        assert!(table.get(5).is_err());
+        assert_eq!(table.alloc_fd(console()), Ok(3));
+
+        assert_eq!(
+            table.replace(MAX_FILE_DESCRIPTORS, console()).err(),
+            Some(FdError::BadDescriptor)
+        );
+    }
+
+    /// Tests that removed files are handed back instead of being dropped.
+    #[test]
+    fn test_removed_files_are_returned() {
+        let mut table = FdTable::new();
+        let first = console();
+        let second = console();
+
+        table.alloc_fd(first.clone()).unwrap();
+        table.alloc_fd(second.clone()).unwrap();
+
+        let replaced = table.replace(0, console()).unwrap();
+        assert!(Arc::ptr_eq(&replaced.unwrap(), &first));
+        assert!(table.replace(2, console()).unwrap().is_none());
+
+        let closed = table.close(1).unwrap();
+        assert!(Arc::ptr_eq(&closed, &second));
+
+        assert_eq!(table.close_all().len(), 2);
+        assert!(table.get(0).is_err());
+    }
+
+    /// Tests that the table doesn't grow past its limit.
+    #[test]
+    fn test_too_many_files() {
+        let mut table = FdTable::new();
+
+        for fd in 0..MAX_FILE_DESCRIPTORS {
+            assert_eq!(table.alloc_fd(console()), Ok(fd));
+        }
+
+        assert_eq!(table.alloc_fd(console()), Err(FdError::TooManyFiles));
+    }
+}
@@ -0,0 +1,141 @@
+//! Provides an allocator for process and thread IDs.
+
+use alloc::vec_deque::VecDeque;
+use core::marker::PhantomData;
+
+/// Hands out unique IDs and recycles them once they are freed.
+///
+/// Freed IDs are reused in the order they were freed, so a recently freed
+/// ID is reused as late as possible.
+pub struct IdAllocator<T> {
+    /// The lowest ID that was never handed out.
+    next_id: usize,
+    /// The IDs that were freed and can be reused.
+    free_ids: VecDeque<usize>,
+    /// The type of the IDs.
+    id_type: PhantomData<T>
+}
+
+impl<T> IdAllocator<T>
+where
+    T: From<usize>,
+    usize: From<T>
+{
+    /// Creates a new allocator that starts handing out IDs at `first_id`.
+    pub fn new(first_id: usize) -> IdAllocator<T> {
+        IdAllocator {
+            next_id: first_id,
+            free_ids: VecDeque::new(),
+            id_type: PhantomData
+        }
+    }
+
+    /// Allocates an ID that is currently not in use.
+    ///
+    /// Returns `None` if all IDs are in use.
+    pub fn allocate(&mut self) -> Option<T> {
+        if let Some(id) = self.free_ids.pop_front() {
+            return Some(id.into());
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1)?;
+
+        Some(id.into())
+    }
+
+    /// Checks if the given ID is currently in use.
+    pub fn is_allocated(&self, id: T) -> bool {
+        let id: usize = id.into();
+
+        id < self.next_id && !self.free_ids.contains(&id)
+    }
+
+    /// Checks if the given ID was ever handed out.
+    pub fn was_allocated(&self, id: T) -> bool {
+        usize::from(id) < self.next_id
+    }
+
+    /// Frees the given ID, so it can be reused.
+    ///
+    /// This must only be called once nothing refers to the ID anymore.
+    pub fn free(&mut self, id: T) {
+        let id: usize = id.into();
+
+        debug_assert!(id < self.next_id, "Freeing an ID that was never allocated.");
+        debug_assert!(!self.free_ids.contains(&id), "Freeing an ID twice.");
+
+        self.free_ids.push_back(id);
+    }
+}
+
+/// Tests for the ID allocator.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::btree_set::BTreeSet;
+    use alloc::Vec;
+
+    /// Tests that IDs are handed out in order.
+    #[test]
+    fn test_sequential_allocation() {
+        let mut allocator: IdAllocator<usize> = IdAllocator::new(1);
+
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), Some(3));
+    }
+
+    /// Tests that freed IDs are reused in the order they were freed.
+    #[test]
+    fn test_reuse_order() {
+        let mut allocator: IdAllocator<usize> = IdAllocator::new(0);
+
+        for _ in 0..4 {
+            allocator.allocate();
+        }
+        allocator.free(2);
+        allocator.free(0);
+
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), Some(0));
+        assert_eq!(allocator.allocate(), Some(4));
+    }
+
+    /// Tests that freed IDs aren't considered allocated.
+    #[test]
+    fn test_is_allocated() {
+        let mut allocator: IdAllocator<usize> = IdAllocator::new(1);
+
+        let id = allocator.allocate().unwrap();
+        assert!(allocator.is_allocated(id));
+
+        allocator.free(id);
+        assert!(!allocator.is_allocated(id));
+        assert!(allocator.was_allocated(id));
+        assert!(!allocator.was_allocated(id + 1));
+    }
+
+    /// Tests that no ID is ever used twice at the same time.
+    #[test]
+    fn test_create_exit_cycles() {
+        let mut allocator: IdAllocator<usize> = IdAllocator::new(1);
+        let mut live = BTreeSet::new();
+        let mut order = Vec::new();
+
+        for round in 0..1000 {
+            let id = allocator.allocate().unwrap();
+            assert!(live.insert(id), "ID {} was handed out twice.", id);
+            order.push(id);
+
+            // Exit a pseudo-randomly chosen process every other round.
+            if round % 2 == 1 {
+                let id = order.remove((round * 7) % order.len());
+                live.remove(&id);
+                allocator.free(id);
+            }
+        }
+
+        assert_eq!(live.len(), order.len());
+    }
+}
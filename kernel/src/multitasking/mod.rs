@@ -1,22 +1,28 @@
 //! Manages multitasking in the operating system.
 
 mod cpu_local;
+pub mod fd_table;
+mod id_allocator;
 mod pcb;
 pub mod scheduler;
 pub mod stack;
 mod tcb;
 
 pub use self::cpu_local::{CPULocal, CPULocalMut};
-pub use self::pcb::{get_current_process, PCB};
+pub use self::pcb::{get_current_process, ProcessInfo, PCB};
 pub use self::scheduler::CURRENT_THREAD;
-pub use self::stack::{Stack, StackType};
+pub use self::stack::{AuxiliaryEntry, Stack, StackType};
 pub use self::tcb::{ThreadState, TCB};
+use self::fd_table::FdTable;
+use self::id_allocator::IdAllocator;
 use alloc::btree_map::BTreeMap;
+use alloc::{String, Vec};
 use crate::arch::{self, Architecture};
-use crate::memory::address_space::AddressSpace;
+use crate::memory::address_space::{AddressSpace, MemoryUsage};
+use crate::memory::slab::SlabBox;
 use crate::memory::VirtualAddress;
-use crate::sync::mutex::MutexGuard;
-use crate::sync::Mutex;
+use crate::sync::time::Timestamp;
+use crate::sync::{cpu_relax, Mutex, WaitQueue};
 
 /// The type of a process ID.
 #[repr(transparent)]
@@ -54,34 +60,183 @@ impl From<ThreadID> for usize {
 
 lazy_static! {
     /// The list of all the currently running processes.
-    static ref PROCESS_LIST: Mutex<BTreeMap<ProcessID, PCB>> = Mutex::new({
+    static ref PROCESS_LIST: Mutex<BTreeMap<ProcessID, SlabBox<PCB>>> = Mutex::new({
         let mut map = BTreeMap::new();
         map.insert(0.into(), PCB::idle_pcb());
 
         map
     });
+
+    /// Hands out the IDs for new processes.
+    ///
+    /// ID 0 belongs to the idle process.
+    static ref PID_ALLOCATOR: Mutex<IdAllocator<ProcessID>> = Mutex::new(IdAllocator::new(1));
+
+    /// The threads waiting for another thread to exit.
+    static ref THREAD_EXIT_QUEUE: WaitQueue = WaitQueue::new();
+
+    /// The threads waiting for a child process to become a zombie.
+    static ref CHILD_EXIT_QUEUE: WaitQueue = WaitQueue::new();
 }
 
-/// Finds an unused process ID.
-fn find_pid(list: &MutexGuard<BTreeMap<ProcessID, PCB>>) -> ProcessID {
-    // UNOPTIMIZED
-    let mut pid = 1;
-    while list.contains_key(&pid.into()) {
-        pid += 1;
-    }
-    pid.into()
+/// The errors that can occur when joining a thread.
+#[derive(Debug, PartialEq)]
+pub enum JoinError {
+    /// The thread doesn't belong to the current process or was already
+    /// joined.
+    NoSuchThread,
+    /// A thread tried to join itself.
+    SelfJoin,
+    /// The process of the joining thread was killed while waiting.
+    Killed
 }
 
-/// Creates a new process.
-pub fn create_process(address_space: AddressSpace, entry_address: VirtualAddress) -> ProcessID {
-    let mut pcb = PCB::new(address_space);
+/// The errors that can occur when waiting for a child process.
+#[derive(Debug, PartialEq)]
+pub enum WaitError {
+    /// The current process has no matching child.
+    NoSuchChild
+}
+
+/// The errors that can occur when killing a process.
+#[derive(Debug, PartialEq)]
+pub enum KillError {
+    /// The process doesn't exist.
+    NoSuchProcess,
+    /// The current process isn't allowed to kill the process.
+    NotPermitted,
+    /// The signal isn't supported.
+    InvalidSignal
+}
+
+/// The errors that can occur when changing the process group of a process.
+#[derive(Debug, PartialEq)]
+pub enum ProcessGroupError {
+    /// The process is neither the current process nor one of its children.
+    NoSuchProcess,
+    /// The group doesn't exist and isn't the one led by the process.
+    NotPermitted
+}
+
+/// The signal that interrupts a process.
+///
+/// There are no signal handlers yet, so it ends the process like `SIGKILL`.
+pub const SIGINT: usize = 2;
+
+/// The signal that ends a process unconditionally.
+pub const SIGKILL: usize = 9;
 
+/// The signal that ends a process after an invalid memory access.
+///
+/// It can't be sent by processes, the kernel uses it as the exit status.
+pub const SIGSEGV: usize = 11;
+
+/// The signal that asks a process to end.
+///
+/// There are no signal handlers yet, so it ends the process like `SIGKILL`.
+pub const SIGTERM: usize = 15;
+
+/// The number of bytes of kernel stack that must be left when entering a
+/// syscall.
+const KERNEL_STACK_RED_ZONE: usize = 0x1000;
+
+/// The ID of the init process, which adopts the children of dead processes.
+///
+/// The kernel relies on the init process being the first process it creates
+/// and on it never exiting, so that this ID can't be reused.
+const INIT_PID: ProcessID = ProcessID(1);
+
+/// Frees the ID of a process that was removed from the process list.
+///
+/// The ID can be reused afterwards.
+fn free_pid(pid: ProcessID) {
+    PID_ALLOCATOR.lock().free(pid);
+
+    // The process is gone, so its slabs might have become empty.
+    pcb::shrink_cache();
+    tcb::shrink_cache();
+}
+
+/// Creates a new process.
+///
+/// The name and the arguments are passed to the process as its argument
+/// vector, together with the auxiliary vector on its initial stack. The
+/// process gets the given file descriptor table, or shares the open files of
+/// its parent if there is none.
+/// Returns `None` if the first thread doesn't fit into the memory limit of
+/// the address space.
+pub fn create_process(
+    address_space: AddressSpace,
+    entry_address: VirtualAddress,
+    name: &str,
+    arguments: &[&str],
+    auxiliary_vector: &[AuxiliaryEntry],
+    fd_table: Option<FdTable>
+) -> Option<ProcessID> {
+    let parent = CURRENT_THREAD.lock().pid;
     let mut process_list = PROCESS_LIST.lock();
-    let id = find_pid(&process_list);
 
-    let first_tcb = TCB::in_process(id, 0.into(), entry_address, &mut pcb);
+    let id = PID_ALLOCATOR
+        .lock()
+        .allocate()
+        .expect("No more process IDs available.");
 
-    scheduler::READY_LIST.lock().push(first_tcb);
+    // Only the init process is created by the kernel itself.
+    assert!(
+        parent != ProcessID(0) || id == INIT_PID,
+        "The init process has to be the first process."
+    );
+
+    // New processes share the open files of their parent by default.
+    let fd_table = fd_table.unwrap_or_else(|| {
+        process_list
+            .get(&parent)
+            .map(|parent| parent.fd_table.clone())
+            .unwrap_or_else(FdTable::with_console)
+    });
+
+    // New processes start in the working directory of their parent.
+    let working_directory = process_list
+        .get(&parent)
+        .map(|parent| String::from(parent.working_directory()))
+        .unwrap_or_else(|| String::from("/"));
+
+    // New processes join the group of their parent. Processes started by the
+    // kernel lead their own groups instead of joining the idle process.
+    let process_group = match process_list.get(&parent) {
+        Some(parent) if parent.process_group() != ProcessID(0) => parent.process_group(),
+        _ => id
+    };
+
+    let mut pcb = PCB::new(
+        address_space,
+        parent,
+        process_group,
+        name,
+        working_directory,
+        fd_table
+    );
+
+    let first_tcb = TCB::main_thread(
+        id,
+        entry_address,
+        &mut pcb,
+        name,
+        arguments,
+        auxiliary_vector
+    );
+
+    let first_tcb = match first_tcb {
+        Some(tcb) => tcb,
+        None => {
+            // The process never ran, so it can be dropped right away.
+            pcb.remove_thread(0.into());
+            free_pid(id);
+            return None;
+        }
+    };
+
+    scheduler::enqueue(first_tcb);
 
     assert!(
         process_list.insert(id, pcb).is_none(),
@@ -89,7 +244,546 @@ pub fn create_process(address_space: AddressSpace, entry_address: VirtualAddress
         id
     );
 
-    id
+    Some(id)
+}
+
+/// Exits the current thread and switches to the next thread.
+///
+/// If this was the last thread of its process, the process is killed as well.
+/// The thread is only reclaimed once the scheduler switched away from it,
+/// which is also when it is removed from the thread count of its process.
+pub fn exit_current_thread() -> ! {
+    debug_assert!(
+        arch::Current::get_interrupt_state(),
+        "Exiting with interrupts disabled would never switch away."
+    );
+
+    let pid = {
+        let mut current_thread = CURRENT_THREAD.lock();
+        current_thread.kill();
+        current_thread.pid
+    };
+
+    let closed_files = {
+        let mut process_list = PROCESS_LIST.lock();
+        let mut closed_files = Vec::new();
+
+        let process_died = {
+            let pcb = process_list
+                .get_mut(&pid)
+                .expect("Process of the current thread doesn't exist.");
+
+            // Threads of killed processes exit here as well, once they unwound.
+            if pcb.thread_count() == 1 && !pcb.is_dead() {
+                debug!("Process {} exited.", pcb.get_name());
+                closed_files = pcb.kill(0);
+                true
+            } else {
+                false
+            }
+        };
+
+        if process_died {
+            reparent_children(&mut process_list, pid);
+        }
+
+        closed_files
+    };
+
+    // Closing pipe ends wakes threads, which needs the process list.
+    drop(closed_files);
+
+    // The dead thread is never scheduled again.
+    arch::schedule();
+
+    // The scheduling interrupt might not have arrived yet.
+    loop {
+        cpu_relax();
+    }
+}
+
+/// Exits the current thread if its process was killed while it was blocked.
+///
+/// Such threads are woken up to return to the syscall boundary, which calls
+/// this, so that the objects on their kernel stacks are dropped.
+pub fn exit_if_killed() {
+    let killed = CURRENT_THREAD.lock().killed;
+
+    if killed {
+        exit_current_thread();
+    }
+}
+
+/// Exits all threads of the current process and switches to the next thread.
+///
+/// Queued threads of the process are reclaimed immediately, while threads
+/// running on other CPUs are forced off them and reclaimed once they were
+/// switched out.
+pub fn exit_current_process(code: i32) -> ! {
+    debug_assert!(
+        arch::Current::get_interrupt_state(),
+        "Exiting with interrupts disabled would never switch away."
+    );
+
+    let pid = {
+        let mut current_thread = CURRENT_THREAD.lock();
+        current_thread.kill();
+        current_thread.pid
+    };
+
+    let closed_files = {
+        let mut process_list = PROCESS_LIST.lock();
+
+        let closed_files = {
+            let pcb = process_list
+                .get_mut(&pid)
+                .expect("Process of the current thread doesn't exist.");
+
+            debug!("Process {} exited with code {}.", pcb.get_name(), code);
+            pcb.kill(code)
+        };
+
+        reparent_children(&mut process_list, pid);
+
+        closed_files
+    };
+
+    // Closing pipe ends wakes threads, which needs the process list.
+    drop(closed_files);
+
+    // The threads of a dead process count as dead, so none of them runs again.
+    scheduler::reap_threads_of(pid);
+
+    arch::schedule();
+
+    // The scheduling interrupt might not have arrived yet.
+    loop {
+        cpu_relax();
+    }
+}
+
+/// Records the stack pointer for the high-water mark of the kernel stack of
+/// the current thread.
+///
+/// Returns false if the stack pointer is within `KERNEL_STACK_RED_ZONE` of
+/// the end of the stack.
+pub fn observe_kernel_stack() -> bool {
+    let stack_pointer = arch::Current::get_stack_pointer();
+    let mut current_thread = CURRENT_THREAD.lock();
+
+    current_thread.kernel_stack.observe(stack_pointer);
+    current_thread.kernel_stack.remaining(stack_pointer) >= KERNEL_STACK_RED_ZONE
+}
+
+/// Ends the current thread if its kernel stack is almost exhausted.
+///
+/// The process of the thread is killed, as if it received `SIGSEGV`. The
+/// kernel can't recover from exhausting the stack of a kernel thread, so it
+/// panics instead.
+pub fn check_kernel_stack() {
+    if observe_kernel_stack() {
+        return;
+    }
+
+    let (pid, id) = {
+        let current_thread = CURRENT_THREAD.lock();
+        (current_thread.pid, current_thread.id)
+    };
+
+    if pid == ProcessID(0) {
+        panic!("The kernel stack of {:?} is exhausted.", id);
+    }
+
+    error!("The kernel stack of {:?} in {:?} is exhausted.", id, pid);
+    exit_current_process(128 + SIGSEGV as i32);
+}
+
+/// Returns the most bytes of its kernel stack the current thread was
+/// observed to use.
+///
+/// The kernel stack is observed on syscall and interrupt entry.
+pub fn kernel_stack_high_water_mark() -> usize {
+    CURRENT_THREAD.lock().kernel_stack.high_water_mark()
+}
+
+/// Sends the signal to the process with the given ID.
+///
+/// A process may only signal itself and the processes it created, directly
+/// or through its children. The signal 0 only checks that the process could
+/// be signaled. Killing the current process doesn't return.
+pub fn kill_process(pid: ProcessID, signal: usize) -> Result<(), KillError> {
+    check_signal(signal)?;
+
+    let current_pid = CURRENT_THREAD.lock().pid;
+
+    {
+        let process_list = PROCESS_LIST.lock();
+
+        if !process_list.contains_key(&pid) {
+            return Err(KillError::NoSuchProcess);
+        }
+
+        if !may_signal(&process_list, pid, current_pid) {
+            return Err(KillError::NotPermitted);
+        }
+    }
+
+    if signal != 0 {
+        signal_process(pid, current_pid, signal);
+    }
+
+    Ok(())
+}
+
+/// Sends the signal to the processes in the given process group.
+///
+/// Only the members that `kill_process` could signal receive it, which
+/// must be at least one. If the current process is a member, it is killed
+/// last and this doesn't return.
+pub fn kill_process_group(process_group: ProcessID, signal: usize) -> Result<(), KillError> {
+    check_signal(signal)?;
+
+    let current_pid = CURRENT_THREAD.lock().pid;
+
+    let targets: Vec<ProcessID> = {
+        let process_list = PROCESS_LIST.lock();
+        let members: Vec<ProcessID> = process_list
+            .iter()
+            .filter(|&(_, pcb)| pcb.process_group() == process_group)
+            .map(|(&pid, _)| pid)
+            .collect();
+
+        if members.is_empty() {
+            return Err(KillError::NoSuchProcess);
+        }
+
+        members
+            .into_iter()
+            .filter(|&pid| may_signal(&process_list, pid, current_pid))
+            .collect()
+    };
+
+    if targets.is_empty() {
+        return Err(KillError::NotPermitted);
+    }
+
+    if signal != 0 {
+        for &pid in targets.iter().filter(|&&pid| pid != current_pid) {
+            signal_process(pid, current_pid, signal);
+        }
+
+        if targets.contains(&current_pid) {
+            signal_process(current_pid, current_pid, signal);
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the process with the given ID to the given process group.
+///
+/// Only the current process and its children can be moved. The group must
+/// either exist already or be led by the process, which creates it.
+pub fn set_process_group(
+    pid: ProcessID,
+    process_group: ProcessID
+) -> Result<(), ProcessGroupError> {
+    let current_pid = CURRENT_THREAD.lock().pid;
+    let mut process_list = PROCESS_LIST.lock();
+
+    let movable = match process_list.get(&pid) {
+        Some(pcb) => pid == current_pid || pcb.parent() == current_pid,
+        None => false
+    };
+
+    if !movable || pid == ProcessID(0) {
+        return Err(ProcessGroupError::NoSuchProcess);
+    }
+
+    let group_exists = process_list
+        .values()
+        .any(|pcb| !pcb.is_dead() && pcb.process_group() == process_group);
+
+    if process_group != pid && (!group_exists || process_group == ProcessID(0)) {
+        return Err(ProcessGroupError::NotPermitted);
+    }
+
+    process_list
+        .get_mut(&pid)
+        .unwrap()
+        .set_process_group(process_group);
+
+    Ok(())
+}
+
+/// Checks that the signal is supported.
+fn check_signal(signal: usize) -> Result<(), KillError> {
+    match signal {
+        0 | SIGINT | SIGKILL | SIGTERM => Ok(()),
+        _ => Err(KillError::InvalidSignal)
+    }
+}
+
+/// Checks if the current process may signal the process with the given ID.
+fn may_signal(
+    process_list: &BTreeMap<ProcessID, SlabBox<PCB>>,
+    pid: ProcessID,
+    current_pid: ProcessID
+) -> bool {
+    pid != ProcessID(0) && is_descendant_of(process_list, pid, current_pid)
+}
+
+/// Ends the process with the given ID because of the signal.
+///
+/// Processes that died in the meantime are skipped. Killing the current
+/// process doesn't return.
+fn signal_process(pid: ProcessID, current_pid: ProcessID, signal: usize) {
+    if pid == current_pid {
+        exit_current_process(128 + signal as i32);
+    }
+
+    let closed_files = {
+        let mut process_list = PROCESS_LIST.lock();
+
+        let closed_files = {
+            let pcb = match process_list.get_mut(&pid) {
+                Some(pcb) => pcb,
+                None => return
+            };
+
+            // Dead processes stay in the list until their threads are reclaimed.
+            if pcb.is_dead() {
+                return;
+            }
+
+            debug!("Process {} was killed by signal {}.", pcb.get_name(), signal);
+            pcb.kill(128 + signal as i32)
+        };
+
+        reparent_children(&mut process_list, pid);
+
+        closed_files
+    };
+
+    // Closing pipe ends wakes threads, which needs the process list.
+    drop(closed_files);
+
+    scheduler::reap_threads_of(pid);
+}
+
+/// Checks if the process with the given ID is the given ancestor or one of
+/// the processes it created.
+fn is_descendant_of(
+    process_list: &BTreeMap<ProcessID, SlabBox<PCB>>,
+    pid: ProcessID,
+    ancestor: ProcessID
+) -> bool {
+    let mut current = pid;
+
+    // Every step moves to an older process, so this visits each one at most once.
+    for _ in 0..process_list.len() {
+        if current == ancestor {
+            return true;
+        }
+
+        match process_list.get(&current) {
+            // The idle process is its own parent.
+            Some(pcb) if current != ProcessID(0) => current = pcb.parent(),
+            _ => return false
+        }
+    }
+
+    false
+}
+
+/// Hands the children of the given dead process over to the init process.
+///
+/// Children that are zombies already are reaped, as the init process
+/// doesn't wait for its children. The init process itself must never die.
+fn reparent_children(process_list: &mut BTreeMap<ProcessID, SlabBox<PCB>>, pid: ProcessID) {
+    assert!(pid != INIT_PID, "The init process exited.");
+
+    let mut zombies = Vec::new();
+
+    for (&child, pcb) in process_list.iter_mut() {
+        if pcb.parent() == pid {
+            pcb.set_parent(INIT_PID);
+
+            if pcb.is_zombie() {
+                zombies.push(child);
+            }
+        }
+    }
+
+    for zombie in zombies {
+        process_list.remove(&zombie);
+        free_pid(zombie);
+    }
+}
+
+/// Handles the process with the given ID after its last thread was
+/// reclaimed.
+///
+/// Processes whose parent is the init or the idle process are removed right
+/// away, as nobody waits for them. All others become zombies until their
+/// parent waits for them. Returns true if the process became a zombie.
+fn finish_process(
+    process_list: &mut BTreeMap<ProcessID, SlabBox<PCB>>,
+    pid: ProcessID
+) -> bool {
+    let parent = process_list
+        .get(&pid)
+        .expect("Finished process doesn't exist.")
+        .parent();
+
+    if parent == ProcessID(0) || parent == INIT_PID {
+        process_list.remove(&pid);
+        free_pid(pid);
+
+        false
+    } else {
+        process_list.get_mut(&pid).unwrap().make_zombie();
+
+        true
+    }
+}
+
+/// Waits for a child of the current process to exit and reaps it.
+///
+/// If a child ID is given, only that child is waited for. Returns the ID and
+/// the exit code of the reaped child. If no child exited before the optional
+/// deadline passed, `None` is returned.
+pub fn wait_for_child(
+    child: Option<ProcessID>,
+    deadline: Option<Timestamp>
+) -> Result<Option<(ProcessID, i32)>, WaitError> {
+    let current_pid = CURRENT_THREAD.lock().pid;
+    let mut result = Ok(None);
+
+    {
+        let condition = || {
+            let mut process_list = PROCESS_LIST.lock();
+
+            let (has_children, zombie) = {
+                let mut children = process_list.iter().filter(|&(&pid, pcb)| {
+                    pcb.parent() == current_pid && child.map_or(true, |child| child == pid)
+                });
+                let has_children = children.clone().next().is_some();
+                let zombie = children
+                    .find(|&(_, pcb)| pcb.is_zombie())
+                    .map(|(&pid, pcb)| (pid, pcb.exit_code()));
+
+                (has_children, zombie)
+            };
+
+            result = match zombie {
+                Some((pid, exit_code)) => {
+                    process_list.remove(&pid);
+                    free_pid(pid);
+
+                    Ok(Some((pid, exit_code)))
+                },
+                None if has_children => Ok(None),
+                None => Err(WaitError::NoSuchChild)
+            };
+
+            result != Ok(None)
+        };
+
+        // A timeout leaves the result at `None`, as does a kill, after which
+        // the thread exits anyway.
+        let _ = match deadline {
+            Some(deadline) => CHILD_EXIT_QUEUE.wait_until_timeout(condition, deadline),
+            None => CHILD_EXIT_QUEUE.wait_until(condition)
+        };
+    }
+
+    result
+}
+
+/// Returns the TCB of the current thread without locking `CURRENT_THREAD`.
+///
+/// This is only meant for the few places where the lock can't be held, which
+/// are the entry of the first thread and the internals of context switches.
+/// Everywhere else `CURRENT_THREAD.lock()` should be used.
+///
+/// # Safety
+/// Using this is only sound if all of the following hold:
+/// - Preemption is disabled for the whole lifetime of the reference, so the
+/// current thread can't change underneath it.
+/// - Nothing modifies the TCB through `CURRENT_THREAD.lock()` while the
+/// reference lives, which in particular means that this CPU doesn't.
+/// - No other CPU accesses the TCB, which holds as long as it is running on
+/// this CPU.
+pub unsafe fn current_tcb_unchecked() -> &'static TCB {
+    debug_assert!(
+        !arch::Current::get_interrupt_state(),
+        "The current TCB was accessed without locking, while preemption is enabled."
+    );
+
+    &**CURRENT_THREAD.without_locking()
+}
+
+/// Blocks until the thread with the given ID in the current process exited.
+///
+/// Returns immediately if the thread already exited. Joining frees the ID of
+/// the thread, so each thread can only be joined once.
+pub fn join_thread(id: ThreadID) -> Result<(), JoinError> {
+    if CURRENT_THREAD.lock().id == id {
+        return Err(JoinError::SelfJoin);
+    }
+
+    // The ID of a detached thread may be reused once it exits.
+    if get_current_process().is_detached(id) {
+        return Err(JoinError::NoSuchThread);
+    }
+
+    if THREAD_EXIT_QUEUE
+        .wait_until(|| !get_current_process().has_thread(id))
+        .is_err()
+    {
+        return Err(JoinError::Killed);
+    }
+
+    if get_current_process().reap_thread(id) {
+        Ok(())
+    } else {
+        Err(JoinError::NoSuchThread)
+    }
+}
+
+/// Detaches the thread with the given ID in the current process.
+///
+/// The ID of a detached thread is freed as soon as it exits, so it can't be
+/// joined anymore.
+pub fn detach_thread(id: ThreadID) -> Result<(), JoinError> {
+    if get_current_process().detach_thread(id) {
+        Ok(())
+    } else {
+        Err(JoinError::NoSuchThread)
+    }
+}
+
+/// Returns a snapshot of all the processes.
+pub fn list_processes() -> Vec<ProcessInfo> {
+    PROCESS_LIST
+        .lock()
+        .iter()
+        .map(|(&pid, pcb)| pcb.info(pid))
+        .collect()
+}
+
+/// Returns the IDs of the threads of the process with the given ID.
+pub fn list_threads(pid: ProcessID) -> Option<Vec<ThreadID>> {
+    PROCESS_LIST.lock().get(&pid).map(|pcb| pcb.threads().collect())
+}
+
+/// Returns the memory used by the process with the given ID.
+pub fn memory_usage(pid: ProcessID) -> Option<MemoryUsage> {
+    PROCESS_LIST
+        .lock()
+        .get(&pid)
+        .map(|pcb| pcb.address_space.memory_usage())
 }
 
 /// Returns the id of the current cpu.
@@ -1,32 +1,105 @@
 //! This module defines a process control block (PCB).
 
-use alloc::BTreeMap;
-use crate::arch::schedule;
-use core::cmp::max;
+use super::fd_table::{FdTable, SharedFile};
+use super::id_allocator::IdAllocator;
+use alloc::btree_set::{self, BTreeSet};
+use alloc::{BTreeMap, String, Vec};
+use core::iter::Cloned;
 use core::ops::{Deref, DerefMut};
 use crate::memory::address_space::AddressSpace;
+use crate::memory::slab::{SlabBox, SlabCache};
 use crate::multitasking::{get_cpu_num, ProcessID, ThreadID, CURRENT_THREAD, PROCESS_LIST};
-use crate::sync::mutex::MutexGuard;
+use crate::sync::mutex::{Mutex, MutexGuard};
+
+/// The maximum length of a process name in bytes.
+///
+/// Longer names are truncated.
+pub const MAX_PROCESS_NAME_LENGTH: usize = 32;
+
+lazy_static! {
+    /// The cache that all PCBs are allocated in.
+    static ref PCB_CACHE: Mutex<SlabCache<PCB>> = Mutex::new(SlabCache::new());
+}
+
+/// Returns the pages of the PCB cache that no longer hold any PCBs.
+pub fn shrink_cache() {
+    PCB_CACHE.lock().shrink();
+}
 
 /// Represents the states a process can have.
-#[derive(Debug, PartialEq)]
-enum ProcessState {
+#[repr(usize)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProcessState {
     /// The process is currently active.
-    Active,
-    /// The process is dead.
-    Dead
+    Active = 0,
+    /// The process is dead, but some of its threads weren't reclaimed yet.
+    Dead = 1,
+    /// All threads of the process were reclaimed and its parent didn't wait
+    /// for it yet.
+    Zombie = 2
+}
+
+/// A snapshot of the information about a process.
+///
+/// This is also the layout that is passed to user space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    /// The ID of the process.
+    pub pid: ProcessID,
+    /// The ID of the process that created this process.
+    pub parent: ProcessID,
+    /// The ID of the process group of the process.
+    pub process_group: ProcessID,
+    /// The amount of currently existing threads within the process.
+    pub thread_count: usize,
+    /// The state of the process.
+    pub state: ProcessState,
+    /// The name of the process, padded with zeros.
+    pub name: [u8; MAX_PROCESS_NAME_LENGTH],
+    /// The length of the name of the process.
+    pub name_length: usize
 }
 
 /// A process control block (PCB) holds all data required to manage a process.
 pub struct PCB {
     /// The address space of the process.
     pub address_space: AddressSpace,
-    /// The amount of currently existing threads within this process.
-    pub thread_count: usize,
+    /// The IDs of the currently existing threads within this process.
+    ///
+    /// A thread is in here from its creation until it is reclaimed.
+    threads: BTreeSet<ThreadID>,
+    /// The IDs of reclaimed threads that weren't joined yet.
+    ///
+    /// Their IDs stay reserved until they are joined, so a join can't end up
+    /// waiting for an unrelated thread that reused the ID.
+    exited_threads: BTreeSet<ThreadID>,
+    /// The IDs of existing threads that won't be joined.
+    ///
+    /// Their IDs are freed as soon as they are reclaimed.
+    detached_threads: BTreeSet<ThreadID>,
+    /// The open files of the process.
+    pub fd_table: FdTable,
     /// The state of the process.
     state: ProcessState,
-    /// The highest ID of a thread within this process.
-    highest_thread_id: ThreadID
+    /// The exit code of the process, once it is dead.
+    exit_code: i32,
+    /// The ID of the process that created this process.
+    parent: ProcessID,
+    /// The ID of the process group the process belongs to.
+    ///
+    /// Signals can be sent to all processes of a group at once.
+    process_group: ProcessID,
+    /// The name of the executable of the process.
+    name: String,
+    /// The normalized absolute path that relative paths are resolved
+    /// against.
+    working_directory: String,
+    /// Hands out the IDs for the threads within this process.
+    ///
+    /// Thread IDs are unique within a process and are reused once the thread
+    /// was reclaimed and joined.
+    thread_ids: IdAllocator<ThreadID>
 }
 
 impl Drop for PCB {
@@ -37,72 +110,271 @@ impl Drop for PCB {
 
 impl PCB {
     /// Creates a new PCB with the given parameters.
-    pub fn new(address_space: AddressSpace) -> PCB {
-        PCB {
+    ///
+    /// Names longer than `MAX_PROCESS_NAME_LENGTH` are truncated.
+    pub fn new(
+        address_space: AddressSpace,
+        parent: ProcessID,
+        process_group: ProcessID,
+        name: &str,
+        working_directory: String,
+        fd_table: FdTable
+    ) -> SlabBox<PCB> {
+        let mut threads = BTreeSet::new();
+        threads.insert(0.into());
+
+        let pcb = PCB {
             address_space,
-            thread_count: 1,
-            highest_thread_id: 0.into(),
-            state: ProcessState::Active
-        }
+            threads,
+            exited_threads: BTreeSet::new(),
+            detached_threads: BTreeSet::new(),
+            fd_table,
+            // ID 0 belongs to the first thread.
+            thread_ids: IdAllocator::new(1),
+            state: ProcessState::Active,
+            exit_code: 0,
+            parent,
+            process_group,
+            name: String::from(truncate_name(name)),
+            working_directory
+        };
+
+        SlabBox::new(pcb, &PCB_CACHE)
     }
 
     /// Creates a pcb for the idle threads.
-    pub fn idle_pcb() -> PCB {
+    pub fn idle_pcb() -> SlabBox<PCB> {
         assert_has_not_been_called!("There should only be one idle PCB.");
-        PCB {
+        let pcb = PCB {
             address_space: AddressSpace::idle_address_space(),
-            thread_count: get_cpu_num(),
-            highest_thread_id: (get_cpu_num() - 1).into(),
-            state: ProcessState::Active
+            threads: (0..get_cpu_num()).map(ThreadID::from).collect(),
+            exited_threads: BTreeSet::new(),
+            detached_threads: BTreeSet::new(),
+            // Passed on to the first processes.
+            fd_table: FdTable::with_console(),
+            // The idle thread of each CPU has the ID of that CPU.
+            thread_ids: IdAllocator::new(get_cpu_num()),
+            state: ProcessState::Active,
+            exit_code: 0,
+            parent: 0.into(),
+            process_group: 0.into(),
+            name: String::from("[idle]"),
+            working_directory: String::from("/")
+        };
+
+        SlabBox::new(pcb, &PCB_CACHE)
+    }
+
+    /// Returns the name of the process.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the working directory of the process.
+    pub fn working_directory(&self) -> &str {
+        &self.working_directory
+    }
+
+    /// Changes the working directory of the process.
+    ///
+    /// The path must be normalized and absolute.
+    pub fn set_working_directory(&mut self, path: String) {
+        self.working_directory = path;
+    }
+
+    /// Returns the ID of the process that created this process.
+    ///
+    /// Once that process died, this is the init process.
+    pub fn parent(&self) -> ProcessID {
+        self.parent
+    }
+
+    /// Hands this process over to the given parent.
+    pub fn set_parent(&mut self, parent: ProcessID) {
+        self.parent = parent;
+    }
+
+    /// Returns the ID of the process group of this process.
+    pub fn process_group(&self) -> ProcessID {
+        self.process_group
+    }
+
+    /// Moves this process to the given process group.
+    pub fn set_process_group(&mut self, process_group: ProcessID) {
+        self.process_group = process_group;
+    }
+
+    /// Returns a snapshot of the information about this process.
+    pub fn info(&self, pid: ProcessID) -> ProcessInfo {
+        let mut name = [0; MAX_PROCESS_NAME_LENGTH];
+        name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+
+        ProcessInfo {
+            pid,
+            parent: self.parent,
+            process_group: self.process_group,
+            thread_count: self.thread_count(),
+            state: self.state,
+            name,
+            name_length: self.name.len()
         }
     }
 
-    /// Finds an ID for a new thread in this process.
-    pub fn find_thread_id(&self) -> Option<ThreadID> {
-        // UNOPTIMIZED
-        self.highest_thread_id.0.checked_add(1).map(|new_id| new_id.into())
+    /// Allocates an ID for a new thread in this process.
+    pub fn allocate_thread_id(&mut self) -> Option<ThreadID> {
+        self.thread_ids.allocate()
     }
 
-    /// Adds a thread to the process.
+    /// Frees an ID from `allocate_thread_id` whose thread couldn't be
+    /// created.
+    pub fn free_thread_id(&mut self, id: ThreadID) {
+        debug_assert!(!self.threads.contains(&id), "{:?} was already added.", id);
+
+        self.thread_ids.free(id);
+    }
+
+    /// Adds the thread with the given ID to the process.
+    ///
+    /// The ID must have been allocated with `allocate_thread_id`.
     pub fn add_thread(&mut self, id: ThreadID) {
-        self.highest_thread_id = max(self.highest_thread_id, id);
+        debug_assert!(self.thread_ids.is_allocated(id));
+
+        let inserted = self.threads.insert(id);
+        debug_assert!(inserted, "{:?} was added twice.", id);
+    }
 
-        self.thread_count += 1;
+    /// Returns the amount of currently existing threads within this process.
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns an iterator over the IDs of the threads of this process.
+    pub fn threads(&self) -> Cloned<btree_set::Iter<ThreadID>> {
+        self.threads.iter().cloned()
+    }
+
+    /// Checks if the thread with the given ID exists in this process.
+    pub fn has_thread(&self, id: ThreadID) -> bool {
+        self.threads.contains(&id)
+    }
+
+    /// Removes a reclaimed thread from the process.
+    ///
+    /// Unless the thread was detached, its ID stays reserved until
+    /// `reap_thread` is called for it.
+    pub fn remove_thread(&mut self, id: ThreadID) {
+        let removed = self.threads.remove(&id);
+        debug_assert!(removed, "{:?} isn't a thread of the process.", id);
+
+        if self.detached_threads.remove(&id) {
+            self.thread_ids.free(id);
+        } else {
+            self.exited_threads.insert(id);
+        }
+    }
+
+    /// Detaches the thread with the given ID, so that its ID is freed when it
+    /// exits instead of when it is joined.
+    ///
+    /// A thread that already exited is reaped right away. Returns false if no
+    /// thread with the given ID can be joined.
+    pub fn detach_thread(&mut self, id: ThreadID) -> bool {
+        if self.threads.contains(&id) {
+            self.detached_threads.insert(id)
+        } else {
+            self.reap_thread(id)
+        }
+    }
+
+    /// Checks if the thread with the given ID was detached.
+    pub fn is_detached(&self, id: ThreadID) -> bool {
+        self.detached_threads.contains(&id)
+    }
+
+    /// Frees the ID of a removed thread, so it can be reused.
+    ///
+    /// Returns false if no thread with the given ID is waiting to be reaped.
+    pub fn reap_thread(&mut self, id: ThreadID) -> bool {
+        let removed = self.exited_threads.remove(&id);
+
+        if removed {
+            self.thread_ids.free(id);
+        }
+
+        removed
     }
 
     /// Returns true if the process is dead.
+    ///
+    /// This includes zombies.
     pub fn is_dead(&self) -> bool {
-        self.state == ProcessState::Dead
+        self.state != ProcessState::Active
     }
 
-    /// Marks this process as dead.
+    /// Returns true if the process is a zombie.
+    pub fn is_zombie(&self) -> bool {
+        self.state == ProcessState::Zombie
+    }
+
+    /// Returns the exit code of the process.
     ///
-    /// This will cause the scheduler to not schedule any threads of this
-    /// process anymore.
-    pub fn kill(&mut self) {
-        self.state = ProcessState::Dead;
+    /// This is only meaningful once the process is dead.
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
     }
 
-    /// Marks this process as dead.
+    /// Marks this process as dead with the given exit code.
     ///
     /// This will cause the scheduler to not schedule any threads of this
-    /// process anymore. The scheduler will be invoked immediately.
-    pub fn kill_immediately(&mut self) -> ! {
+    /// process anymore. Killing a dead process keeps its first exit code.
+    ///
+    /// The open files of the process are closed and returned. They must be
+    /// dropped after the process list was unlocked, as releasing a pipe end
+    /// wakes the threads waiting on it.
+    pub fn kill(&mut self, exit_code: i32) -> Vec<SharedFile> {
+        if self.is_dead() {
+            return Vec::new();
+        }
+
         self.state = ProcessState::Dead;
-        schedule();
-        unreachable!();
+        self.exit_code = exit_code;
+        self.fd_table.close_all()
+    }
+
+    /// Turns this dead process into a zombie.
+    ///
+    /// Only the exit code is kept until the parent waits for the process, so
+    /// its memory is released right away.
+    pub fn make_zombie(&mut self) {
+        debug_assert!(self.is_dead() && self.is_droppable());
+
+        self.state = ProcessState::Zombie;
+        self.address_space.clear();
     }
 
     /// Determines if this process can be dropped.
     pub fn is_droppable(&self) -> bool {
-        self.thread_count == 0
+        self.threads.is_empty()
     }
 }
 
+/// Truncates the name to at most `MAX_PROCESS_NAME_LENGTH` bytes.
+///
+/// The name is only cut at character boundaries.
+fn truncate_name(name: &str) -> &str {
+    let mut length = name.len().min(MAX_PROCESS_NAME_LENGTH);
+
+    while !name.is_char_boundary(length) {
+        length -= 1;
+    }
+
+    &name[..length]
+}
+
 /// Represents a lock on the process list.
 pub struct ProcessLock<'a> {
     /// The mutex guard that keeps the lock on the list.
-    guard: MutexGuard<'a, BTreeMap<ProcessID, PCB>>,
+    guard: MutexGuard<'a, BTreeMap<ProcessID, SlabBox<PCB>>>,
     /// The key to get the proccess out of the list.
     key: ProcessID
 }
@@ -131,3 +403,23 @@ pub fn get_current_process<'a>() -> ProcessLock<'a> {
         key: pid
     }
 }
+
+/// Tests for the process control block.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that long names are truncated at character boundaries.
+    #[test]
+    fn test_truncate_name() {
+        assert_eq!(truncate_name("/bin/init"), "/bin/init");
+
+        let long_name = "/bin/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        assert_eq!(truncate_name(long_name).len(), MAX_PROCESS_NAME_LENGTH);
+
+        let multibyte_name = "/bin/ääääääääääääääääää";
+        let truncated = truncate_name(multibyte_name);
+        assert_eq!(truncated.len(), MAX_PROCESS_NAME_LENGTH - 1);
+        assert!(multibyte_name.starts_with(truncated));
+    }
+}
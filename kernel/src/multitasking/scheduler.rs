@@ -1,59 +1,492 @@
 //! This module implements a scheduler.
+//!
+//! # Preemption policy
+//! A thread is preempted immediately when a thread with a higher priority
+//! becomes ready. Threads of equal priority only preempt the current thread
+//! once its timeslice has expired, which is only ever caused by the timer.
+//! Calls to `schedule` therefore don't make equal priority threads ping-pong,
+//! but they still round-robin at the granularity of timeslices.
+//!
+//! # Deferred preemption
+//! Interrupt handlers and the code they call never switch threads
+//! themselves. When a thread should be preempted, a per-CPU flag is set
+//! using `set_need_resched` instead, which is honored at the following safe
+//! points:
+//! - when an interrupt handler returns,
+//! - when a syscall returns to user mode, and
+//! - when a thread calls `schedule` itself, which reschedules right away.
+//!
+//! Setting the flag for another CPU also interrupts that CPU, so it reaches a
+//! safe point soon.
+//!
+//! # Timer ticks
+//! The timer interrupts at a fixed interval. Each tick first wakes the
+//! threads whose sleep or deadline passed, independent of the current
+//! thread. Only then it counts down the timeslice of the current thread,
+//! which lasts a number of ticks, and reschedules if it expired or if a
+//! woken thread may preempt the current one. The clock itself is advanced by
+//! the architecture, not by the tick.
+//!
+//! The length of a timeslice depends on the priority of the thread and can
+//! be changed for each priority level using `set_quantum`.
 
 use super::tcb::SleepTimeSortedTCB;
-use super::{ThreadState, TCB};
+use super::{get_cpu_id, get_cpu_num, ProcessID, ThreadID, ThreadState, TCB};
 use alloc::binary_heap::BinaryHeap;
-use crate::arch::{self, schedule, Architecture};
+use alloc::btree_map::BTreeMap;
+use alloc::Vec;
+use crate::arch::{self, schedule, schedule_on, Architecture};
+use crate::boot;
+use core::cmp::{max, min};
 use core::mem::swap;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use crate::memory::slab::SlabBox;
 use crate::sync::time::Timestamp;
 use crate::sync::Mutex;
-use crate::sync::{disable_preemption, enable_preemption, restore_preemption_state};
+use crate::sync::{cpu_relax, disable_preemption, enable_preemption, restore_preemption_state};
 use x86_64::instructions::halt;
 
 cpu_local! {
-    pub static ref READY_LIST: Mutex<BinaryHeap<TCB>> = |_| Mutex::new(BinaryHeap::new());
+    /// Holds the threads that are ready to run on the CPU.
+    ///
+    /// Outside of the scheduler, threads are added and removed using
+    /// `enqueue` and `dequeue`.
+    static ref READY_LIST: Mutex<ReadyList> = |_| Mutex::new(ReadyList::new());
 }
 
 lazy_static! {
     pub static ref SLEEPING_LIST: Mutex<BinaryHeap<SleepTimeSortedTCB>> =
         Mutex::new(BinaryHeap::new());
+
+    /// The priorities that threads inherited through the blocking mutexes
+    /// they hold.
+    static ref INHERITED_PRIORITIES: Mutex<InheritedPriorities> =
+        Mutex::new(InheritedPriorities::new());
+
+    /// Holds the threads that are blocked.
+    static ref BLOCKED_THREADS: Mutex<BlockedThreads> = Mutex::new(BlockedThreads {
+        threads: BTreeMap::new(),
+        pending_wakeups: Vec::new(),
+        deadlines: BTreeMap::new()
+    });
+
+    /// The timeslices of the priority levels that don't use the default.
+    static ref PRIORITY_QUANTA: Mutex<PriorityQuanta> = Mutex::new(PriorityQuanta::new());
+}
+
+/// A thread that can be put on a ready list.
+trait ReadyThread: Ord {
+    /// Returns the process ID and the thread ID of the thread.
+    fn identifiers(&self) -> (ProcessID, ThreadID);
+
+    /// Lets the thread run with its base priority raised to the inherited
+    /// priority.
+    fn apply_inherited_priority(&mut self, inherited_priority: Option<i32>);
+}
+
+impl ReadyThread for SlabBox<TCB> {
+    fn identifiers(&self) -> (ProcessID, ThreadID) {
+        (self.pid, self.id)
+    }
+
+    fn apply_inherited_priority(&mut self, inherited_priority: Option<i32>) {
+        self.priority = effective_priority(self.base_priority, inherited_priority);
+    }
+}
+
+/// The threads that are ready to run on a CPU.
+struct ReadyList<T = SlabBox<TCB>> {
+    /// The ready threads, with the thread to run next being the greatest.
+    threads: BinaryHeap<T>
+}
+
+impl<T: ReadyThread> ReadyList<T> {
+    /// Creates an empty ready list.
+    fn new() -> ReadyList<T> {
+        ReadyList {
+            threads: BinaryHeap::new()
+        }
+    }
+
+    /// Adds the thread to the list.
+    ///
+    /// The thread must have been marked as enqueued.
+    fn push(&mut self, thread: T) {
+        self.threads.push(thread);
+    }
+
+    /// Removes the thread to run next from the list.
+    fn pop(&mut self) -> Option<T> {
+        self.threads.pop()
+    }
+
+    /// Returns the thread to run next.
+    fn peek(&self) -> Option<&T> {
+        self.threads.peek()
+    }
+
+    /// Removes the threads matching the predicate from the list.
+    ///
+    /// The remaining threads keep their position.
+    fn remove_where<F: FnMut(&T) -> bool>(&mut self, predicate: F) -> Vec<T> {
+        let (removed, kept): (Vec<_>, Vec<_>) = self.threads.drain().partition(predicate);
+
+        self.threads.extend(kept);
+
+        removed
+    }
+
+    /// Applies the inherited priority to the given thread, if it is on the
+    /// list.
+    ///
+    /// Returns true if the thread was found.
+    fn update_priority(
+        &mut self,
+        thread: (ProcessID, ThreadID),
+        inherited_priority: Option<i32>
+    ) -> bool {
+        let threads = self.remove_where(|ready| ready.identifiers() == thread);
+        let found = !threads.is_empty();
+
+        // The thread is put back, as the order depends on the priority.
+        for mut ready in threads {
+            ready.apply_inherited_priority(inherited_priority);
+            self.push(ready);
+        }
+
+        found
+    }
+}
+
+/// The threads that are waiting to be woken up.
+struct BlockedThreads {
+    /// The blocked threads that were already switched out.
+    ///
+    /// Each thread is stored along with the CPU it was running on.
+    threads: BTreeMap<(ProcessID, ThreadID), (usize, SlabBox<TCB>)>,
+    /// The threads that were woken up before they were switched out.
+    pending_wakeups: Vec<(ProcessID, ThreadID)>,
+    /// The times at which blocked threads are woken up, even if nobody wakes
+    /// them up.
+    deadlines: BTreeMap<(ProcessID, ThreadID), Timestamp>
+}
+
+impl BlockedThreads {
+    /// Returns the earliest deadline of a blocked thread.
+    fn earliest_deadline(&self) -> Option<Timestamp> {
+        self.deadlines.values().min().cloned()
+    }
+
+    /// Returns the threads whose deadline passed.
+    fn expired(&self, now: Timestamp) -> Vec<(ProcessID, ThreadID)> {
+        self.deadlines
+            .iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&key, _)| key)
+            .collect()
+    }
+}
+
+/// Records the priorities threads inherited through the locks they hold.
+///
+/// Locks are identified by their address.
+struct InheritedPriorities {
+    /// The locks each thread inherited a priority through, along with the
+    /// highest inherited priority.
+    priorities: BTreeMap<(ProcessID, ThreadID), Vec<(usize, i32)>>
+}
+
+impl InheritedPriorities {
+    /// Creates an empty record.
+    fn new() -> InheritedPriorities {
+        InheritedPriorities {
+            priorities: BTreeMap::new()
+        }
+    }
+
+    /// Records that the thread inherits the priority through the lock.
+    fn inherit(&mut self, thread: (ProcessID, ThreadID), lock: usize, priority: i32) {
+        let locks = self.priorities.entry(thread).or_insert_with(Vec::new);
+
+        if let Some(index) = locks.iter().position(|&(held, _)| held == lock) {
+            locks[index].1 = max(locks[index].1, priority);
+        } else {
+            locks.push((lock, priority));
+        }
+    }
+
+    /// Removes the priority the thread inherited through the lock.
+    fn release(&mut self, thread: (ProcessID, ThreadID), lock: usize) {
+        let released_all = match self.priorities.get_mut(&thread) {
+            Some(locks) => {
+                locks.retain(|&(held, _)| held != lock);
+                locks.is_empty()
+            },
+            None => false
+        };
+
+        if released_all {
+            self.priorities.remove(&thread);
+        }
+    }
+
+    /// Removes all priorities the thread inherited.
+    fn forget(&mut self, thread: (ProcessID, ThreadID)) {
+        self.priorities.remove(&thread);
+    }
+
+    /// Returns the highest priority the thread inherited, if any.
+    fn highest(&self, thread: (ProcessID, ThreadID)) -> Option<i32> {
+        self.priorities
+            .get(&thread)
+            .and_then(|locks| locks.iter().map(|&(_, priority)| priority).max())
+    }
+}
+
+/// Returns the priority a thread with the given base priority runs with.
+fn effective_priority(base_priority: i32, inherited_priority: Option<i32>) -> i32 {
+    inherited_priority.map_or(base_priority, |inherited| max(base_priority, inherited))
+}
+
+/// The interval between two timer ticks in milliseconds.
+const TICK_INTERVAL_MS: u64 = 10;
+
+/// The timeslice of priority levels without a configured one in milliseconds.
+const DEFAULT_QUANTUM_MS: u64 = 150;
+
+/// The command line option that configures the timeslices of priority levels.
+const QUANTA_OPTION: &'static str = "quanta";
+
+cpu_local! {
+    /// The number of ticks the current thread can run before its timeslice expires.
+    static ref TIMESLICE_REMAINING: Mutex<u32> = |_| Mutex::new(1);
+}
+
+/// Returns the interval between two timer ticks.
+fn tick_interval() -> Duration {
+    Duration::from_millis(TICK_INTERVAL_MS)
+}
+
+/// Returns the number of ticks a timeslice of the given length lasts.
+///
+/// Every timeslice lasts at least one tick.
+fn timeslice_ticks(timeslice: Duration) -> u32 {
+    let millis = timeslice
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(timeslice.subsec_millis() as u64);
+
+    max(1, min(millis / TICK_INTERVAL_MS, u32::max_value() as u64)) as u32
+}
+
+/// Counts an elapsed tick against the remaining timeslice.
+///
+/// Returns true if the timeslice expired.
+fn count_tick(remaining: &mut u32) -> bool {
+    *remaining = remaining.saturating_sub(1);
+    *remaining == 0
+}
+
+/// What the scheduler does with the current thread.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ScheduleDecision {
+    /// The next ready thread replaces the current thread.
+    Switch,
+    /// The current thread keeps running with a new timeslice.
+    Renew,
+    /// The current thread keeps running with the rest of its timeslice.
+    Continue
+}
+
+/// Decides whether the current thread keeps running.
+///
+/// The next ready thread, given by its priority and timeslice, replaces the
+/// current thread if the current thread can't run anymore, if the next thread
+/// has a higher priority, or if it has the same priority and the timeslice
+/// expired. The remaining ticks are reset to the timeslice of the thread that
+/// runs afterwards, unless the current thread continues its timeslice.
+fn schedule_decision(
+    remaining: &mut u32,
+    current_priority: i32,
+    current_runnable: bool,
+    current_quantum: Duration,
+    next: Option<(i32, Duration)>
+) -> ScheduleDecision {
+    assert!(
+        current_runnable || next.is_some(),
+        "No thread is ready to replace the current thread."
+    );
+
+    let timeslice_expired = *remaining == 0;
+    let switch_to = next.filter(|&(next_priority, _)| {
+        !current_runnable
+            || next_priority > current_priority
+            || (timeslice_expired && next_priority == current_priority)
+    });
+
+    match switch_to {
+        Some((_, next_quantum)) => {
+            *remaining = timeslice_ticks(next_quantum);
+            ScheduleDecision::Switch
+        },
+        None if timeslice_expired => {
+            *remaining = timeslice_ticks(current_quantum);
+            ScheduleDecision::Renew
+        },
+        None => ScheduleDecision::Continue
+    }
+}
+
+/// The timeslices configured for priority levels.
+struct PriorityQuanta {
+    /// The timeslice of each configured priority level.
+    quanta: BTreeMap<i32, Duration>
+}
+
+impl PriorityQuanta {
+    /// Creates a configuration where every priority uses the default.
+    fn new() -> PriorityQuanta {
+        PriorityQuanta {
+            quanta: BTreeMap::new()
+        }
+    }
+
+    /// Returns the timeslice of threads with the given priority.
+    fn get(&self, priority: i32) -> Duration {
+        self.quanta
+            .get(&priority)
+            .cloned()
+            .unwrap_or(Duration::from_millis(DEFAULT_QUANTUM_MS))
+    }
+
+    /// Sets the timeslice of threads with the given priority.
+    ///
+    /// Passing `None` restores the default.
+    fn set(&mut self, priority: i32, quantum: Option<Duration>) {
+        match quantum {
+            Some(quantum) => self.quanta.insert(priority, quantum),
+            None => self.quanta.remove(&priority)
+        };
+    }
+}
+
+/// Returns the timeslice of threads with the given priority.
+pub fn get_quantum(priority: i32) -> Duration {
+    PRIORITY_QUANTA.lock().get(priority)
+}
+
+/// Sets the timeslice of threads with the given priority.
+///
+/// Passing `None` restores the default. Timeslices are rounded down to whole
+/// timer ticks, but last at least one tick. Running threads keep their
+/// current timeslice.
+pub fn set_quantum(priority: i32, quantum: Option<Duration>) {
+    PRIORITY_QUANTA.lock().set(priority, quantum);
+}
+
+/// Sets the timeslices given on the kernel command line.
+///
+/// The option has the form `quanta=<priority>:<milliseconds>,...`. An
+/// invalid option is ignored as a whole.
+pub fn configure_quanta() {
+    let option = match boot::get_command_line_option(QUANTA_OPTION) {
+        Some(option) => option,
+        None => return
+    };
+
+    match parse_quanta(option) {
+        Some(quanta) => {
+            for (priority, quantum) in quanta {
+                set_quantum(priority, Some(quantum));
+            }
+        },
+        None => warn!("Ignoring the invalid timeslices \"{}\".", option)
+    }
+}
+
+/// Parses the timeslices of priority levels, separated by commas.
+///
+/// Each timeslice has the form `<priority>:<milliseconds>`.
+fn parse_quanta(value: &str) -> Option<Vec<(i32, Duration)>> {
+    value
+        .split(',')
+        .map(|entry| {
+            let mut parts = entry.splitn(2, ':');
+            let priority = parts.next()?.parse::<i32>().ok()?;
+            let millis = parts.next()?.parse().ok()?;
+
+            Some((priority, Duration::from_millis(millis)))
+        })
+        .collect()
+}
+
+cpu_local! {
+    /// Whether the CPU should reschedule at its next safe point.
+    static ref NEED_RESCHED: AtomicBool = |_| AtomicBool::new(false);
 }
 
 cpu_local! {
     /// Holds the TCB of the currently running thread.
-    pub static ref CURRENT_THREAD: Mutex<TCB> = |cpu_id| Mutex::new(TCB::idle_tcb(cpu_id));
+    pub static ref CURRENT_THREAD: Mutex<SlabBox<TCB>> = |cpu_id| Mutex::new(TCB::idle_tcb(cpu_id));
 }
 
 cpu_local! {
     /// Holds the TCB of the previously running thread during context switches.
-    static mut ref OLD_THREAD: Option<TCB> = |_| None;
+    static mut ref OLD_THREAD: Option<SlabBox<TCB>> = |_| None;
 }
 
 /// Schedules the next thread to run and dispatches it.
 ///
 /// # Safety
 /// - This function should not be called directly. Rather call `arch::schedule`.
+/// - Preemption must be disabled, which is the case in the scheduling
+/// interrupt.
 pub unsafe fn schedule_next_thread() {
+    debug_assert!(
+        !arch::Current::get_interrupt_state(),
+        "The scheduler was called with preemption enabled."
+    );
+
     check_sleeping_processes();
 
     // No interrupts during scheduling (this essentially locks OLD_THREAD).
     let preemption_state = disable_preemption();
 
-    debug_assert!(OLD_THREAD.is_none());
+    // This is the reschedule that was requested.
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+
+    debug_assert!(
+        OLD_THREAD.is_none(),
+        "The scheduler was entered during a context switch."
+    );
+    debug_assert!(
+        {
+            let stack_pointer = arch::Current::get_stack_pointer();
+            CURRENT_THREAD.lock().kernel_stack.contains(stack_pointer)
+        },
+        "The scheduler isn't running on the kernel stack of the current thread."
+    );
 
     let mut ready_list = READY_LIST.lock();
 
-    // Scheduling is needed if:
-    // There is another thread to schedule.
-    let schedule_needed = ready_list.peek().is_some();
-    // And it has at least the same priority.
-    let schedule_needed = schedule_needed && ready_list.peek().unwrap() >= &CURRENT_THREAD.lock();
-    // Or the current thread can't run anymore.
-    let schedule_needed =
-        schedule_needed || !CURRENT_THREAD.lock().is_running() || CURRENT_THREAD.lock().is_dead();
+    let decision = {
+        let current_thread = CURRENT_THREAD.lock();
+        let next = ready_list
+            .peek()
+            .map(|next_thread| (next_thread.priority, next_thread.get_quantum()));
+
+        schedule_decision(
+            &mut TIMESLICE_REMAINING.lock(),
+            current_thread.priority,
+            current_thread.is_running() && !current_thread.is_dead(),
+            current_thread.get_quantum(),
+            next
+        )
+    };
 
     // Only switch if actually needed.
-    if schedule_needed {
+    if decision == ScheduleDecision::Switch {
         // Move the new thread to the temporary spot for old threads.
         (*OLD_THREAD).set(Some(ready_list.pop().unwrap()));
 
@@ -84,13 +517,18 @@ pub unsafe fn schedule_next_thread() {
         // This is where the actual switch happens.
         arch::Current::switch_context(
             &mut OLD_THREAD.as_mut().as_mut().unwrap().context,
-            &CURRENT_THREAD.without_locking().context
+            &super::current_tcb_unchecked().context
         );
 
         after_context_switch();
     } else {
         // Ensure that the correct drop order is used.
         drop(ready_list);
+
+        // Nobody else wanted to run, so the current thread got a new timeslice.
+        if decision == ScheduleDecision::Renew {
+            arch::Current::interrupt_in(tick_interval());
+        }
     }
 
     restore_preemption_state(&preemption_state);
@@ -100,29 +538,378 @@ pub unsafe fn schedule_next_thread() {
 /// clean up.
 pub fn after_context_switch() {
     if OLD_THREAD.is_some() {
-        if OLD_THREAD.as_ref().unwrap().is_dead() {
-            unsafe {
-                // Drop the old thread.
-                OLD_THREAD.as_mut().take();
-            }
-        } else {
-            let old_thread = unsafe { OLD_THREAD.as_mut().take().unwrap() };
+        let old_thread = unsafe { OLD_THREAD.as_mut().take().unwrap() };
+
+        if !old_thread.is_dead() {
             return_old_thread_to_queue(old_thread);
+        } else if old_thread.state == ThreadState::Blocked {
+            // The process was killed while the thread was about to block.
+            wake_killed_thread(get_cpu_id(), old_thread);
         }
+
+        // Otherwise the old thread is dropped here.
     }
-    arch::Current::interrupt_in(CURRENT_THREAD.lock().get_quantum());
+    arch::Current::interrupt_in(tick_interval());
 }
 
 /// Returns the old thread to the corresponding queue after switching the
 /// context.
-fn return_old_thread_to_queue(thread: TCB) {
+fn return_old_thread_to_queue(thread: SlabBox<TCB>) {
     match thread.state {
-        ThreadState::Ready => READY_LIST.lock().push(thread),
+        ThreadState::Ready => enqueue(thread),
         ThreadState::Sleeping(_) => SLEEPING_LIST.lock().push(SleepTimeSortedTCB(thread)),
+        ThreadState::Blocked => {
+            let mut thread = thread;
+            let mut blocked_threads = BLOCKED_THREADS.lock();
+            let key = (thread.pid, thread.id);
+            let pending_wakeup = blocked_threads
+                .pending_wakeups
+                .iter()
+                .position(|&pending| pending == key);
+            let deadline_passed = blocked_threads
+                .deadlines
+                .get(&key)
+                .map_or(false, |&deadline| deadline <= Timestamp::get_current());
+
+            if let Some(index) = pending_wakeup {
+                blocked_threads.pending_wakeups.swap_remove(index);
+                drop(blocked_threads);
+                thread.set_ready();
+                enqueue(thread);
+            } else if deadline_passed {
+                blocked_threads.deadlines.remove(&key);
+                drop(blocked_threads);
+                thread.set_ready();
+                enqueue(thread);
+            } else {
+                blocked_threads.threads.insert(key, (get_cpu_id(), thread));
+            }
+        },
         _ => panic!("Running or dead thread is being returned to a queue.")
     }
 }
 
+/// Makes the thread ready to run on the current CPU.
+///
+/// Threads of equal priority are run in the order they were enqueued. If the
+/// thread should preempt the current thread, a reschedule is requested.
+pub fn enqueue(thread: SlabBox<TCB>) {
+    unsafe {
+        // Stay on this CPU until the thread is on its ready list.
+        let preemption_state = disable_preemption();
+
+        push_ready(&READY_LIST, thread);
+        check_preemption(get_cpu_id());
+
+        restore_preemption_state(&preemption_state);
+    }
+}
+
+/// Removes the thread that would run next on the current CPU.
+///
+/// Returns `None` if no thread is ready to run.
+pub fn dequeue() -> Option<SlabBox<TCB>> {
+    unsafe {
+        // Stay on this CPU until the thread is taken off its ready list.
+        let preemption_state = disable_preemption();
+
+        let thread = READY_LIST.lock().pop();
+
+        restore_preemption_state(&preemption_state);
+
+        thread
+    }
+}
+
+/// Puts the thread on the given ready list.
+///
+/// Threads of equal priority are run in the order they were put on it.
+fn push_ready(ready_list: &Mutex<ReadyList>, mut thread: SlabBox<TCB>) {
+    let inherited_priority = INHERITED_PRIORITIES.lock().highest((thread.pid, thread.id));
+    thread.apply_inherited_priority(inherited_priority);
+    thread.mark_enqueued();
+    ready_list.lock().push(thread);
+}
+
+/// Requests a reschedule if the next ready thread of the given CPU has a
+/// higher priority than its current thread.
+///
+/// Idle threads have the lowest priority, so idle CPUs always reschedule.
+fn check_preemption(cpu_id: usize) {
+    let next_priority = READY_LIST
+        .get_specific(cpu_id)
+        .lock()
+        .peek()
+        .map(|thread| thread.priority);
+    let current_priority = CURRENT_THREAD.get_specific(cpu_id).lock().priority;
+
+    if next_priority.map_or(false, |priority| priority > current_priority) {
+        set_need_resched(cpu_id);
+    }
+}
+
+/// Makes the given CPU reschedule at its next safe point.
+///
+/// Other CPUs are interrupted, so that they reach a safe point soon.
+pub fn set_need_resched(cpu_id: usize) {
+    NEED_RESCHED.get_specific(cpu_id).store(true, Ordering::Release);
+
+    if cpu_id != get_cpu_id() {
+        schedule_on(cpu_id);
+    }
+}
+
+/// Reschedules if a reschedule of the current CPU was requested.
+///
+/// This must only be called at the safe points listed in the module
+/// documentation.
+pub fn reschedule_if_needed() {
+    let needed = unsafe {
+        // Stay on this CPU until its flag is taken.
+        let preemption_state = disable_preemption();
+        let needed = NEED_RESCHED.swap(false, Ordering::Acquire);
+        restore_preemption_state(&preemption_state);
+
+        needed
+    };
+
+    if needed {
+        schedule();
+    }
+}
+
+/// Removes the threads of the given dead process from the scheduler.
+///
+/// Ready and sleeping threads are reclaimed right away. Blocked threads are
+/// woken up instead, so that they return to the syscall boundary and exit
+/// there. CPUs running a thread of the process are made to reschedule, so
+/// that thread is reclaimed once it is switched out.
+pub fn reap_threads_of(pid: ProcessID) {
+    let mut reaped = Vec::new();
+    let mut killed = Vec::new();
+
+    for cpu_id in 0..get_cpu_num() {
+        if let Some(ready_list) = READY_LIST.try_get(cpu_id) {
+            reaped.extend(ready_list.lock().remove_where(|thread| thread.pid == pid));
+        }
+
+        if cpu_id != get_cpu_id() {
+            let running_process = CURRENT_THREAD
+                .try_get(cpu_id)
+                .map(|current_thread| current_thread.lock().pid);
+
+            if running_process == Some(pid) {
+                set_need_resched(cpu_id);
+            }
+        }
+    }
+
+    {
+        let mut sleeping_list = SLEEPING_LIST.lock();
+        let threads: Vec<_> = sleeping_list.drain().collect();
+
+        for thread in threads {
+            if thread.0.pid == pid {
+                reaped.push(thread.0);
+            } else {
+                sleeping_list.push(thread);
+            }
+        }
+    }
+
+    {
+        let mut blocked_threads = BLOCKED_THREADS.lock();
+        let keys: Vec<_> = blocked_threads
+            .threads
+            .keys()
+            .filter(|&&(thread_pid, _)| thread_pid == pid)
+            .cloned()
+            .collect();
+
+        for key in keys {
+            blocked_threads.deadlines.remove(&key);
+            killed.push(blocked_threads.threads.remove(&key).unwrap());
+        }
+
+        blocked_threads
+            .pending_wakeups
+            .retain(|&(thread_pid, _)| thread_pid != pid);
+    }
+
+    // Waking and reclaiming the threads locks the process list, so no other lock may be held.
+    for (cpu_id, thread) in killed {
+        wake_killed_thread(cpu_id, thread);
+    }
+    drop(reaped);
+}
+
+/// Wakes up a blocked thread of a dead process, so that it returns to the
+/// syscall boundary and exits there.
+///
+/// Reclaiming it right away would leak the objects on its kernel stack.
+fn wake_killed_thread(cpu_id: usize, mut thread: SlabBox<TCB>) {
+    thread.killed = true;
+    thread.set_ready();
+    make_ready_on(cpu_id, thread);
+}
+
+/// Lets the given thread run with at least the given priority while it holds
+/// the lock at the given address.
+///
+/// This avoids priority inversion: a thread waiting for the lock lends its
+/// priority to the holder, so threads with a priority in between can't keep
+/// the holder from releasing the lock. Inheritance isn't transitive, so the
+/// holder doesn't pass the priority on to the holders of locks it waits for.
+pub fn inherit_priority(pid: ProcessID, id: ThreadID, lock: usize, priority: i32) {
+    INHERITED_PRIORITIES.lock().inherit((pid, id), lock, priority);
+    update_priority(pid, id);
+}
+
+/// Removes the priority the given thread inherited through the lock at the
+/// given address.
+pub fn release_inherited_priority(pid: ProcessID, id: ThreadID, lock: usize) {
+    INHERITED_PRIORITIES.lock().release((pid, id), lock);
+    update_priority(pid, id);
+}
+
+/// Removes all priorities the given thread inherited.
+///
+/// This is called when the thread is reclaimed, so its ID can be reused.
+pub fn forget_inherited_priorities(pid: ProcessID, id: ThreadID) {
+    INHERITED_PRIORITIES.lock().forget((pid, id));
+}
+
+/// Applies the inherited priorities to the given thread, wherever it is.
+///
+/// A thread that is switched out is not found, but its priority is updated
+/// once it is put on a ready list.
+fn update_priority(pid: ProcessID, id: ThreadID) {
+    let is_thread = |thread: &TCB| thread.pid == pid && thread.id == id;
+    let inherited_priority = INHERITED_PRIORITIES.lock().highest((pid, id));
+    let priority_for = |base_priority| effective_priority(base_priority, inherited_priority);
+
+    for cpu_id in 0..get_cpu_num() {
+        if let Some(current_thread) = CURRENT_THREAD.try_get(cpu_id) {
+            let mut current_thread = current_thread.lock();
+
+            if is_thread(&current_thread) {
+                current_thread.priority = priority_for(current_thread.base_priority);
+                return;
+            }
+        }
+
+        if let Some(ready_list) = READY_LIST.try_get(cpu_id) {
+            if ready_list.lock().update_priority((pid, id), inherited_priority) {
+                // The thread might have to preempt the current thread now.
+                check_preemption(cpu_id);
+                return;
+            }
+        }
+    }
+
+    if let Some(entry) = BLOCKED_THREADS.lock().threads.get_mut(&(pid, id)) {
+        entry.1.priority = priority_for(entry.1.base_priority);
+        return;
+    }
+
+    let mut sleeping_list = SLEEPING_LIST.lock();
+
+    if sleeping_list.iter().any(|thread| is_thread(&thread.0)) {
+        let threads: Vec<_> = sleeping_list.drain().collect();
+
+        for mut thread in threads {
+            if is_thread(&thread.0) {
+                thread.0.priority = priority_for(thread.0.base_priority);
+            }
+            sleeping_list.push(thread);
+        }
+    }
+}
+
+/// Handles a timer tick.
+///
+/// This should only be called by the timer interrupt. It wakes the threads
+/// that are due and counts down the timeslice of the current thread. A
+/// reschedule is only requested if the timeslice expired or a woken thread
+/// should preempt the current thread.
+pub fn timer_tick() {
+    check_sleeping_processes();
+
+    let timeslice_expired = count_tick(&mut TIMESLICE_REMAINING.lock());
+
+    if timeslice_expired {
+        // The scheduler starts the next tick.
+        set_need_resched(get_cpu_id());
+    } else {
+        arch::Current::interrupt_in(tick_interval());
+    }
+}
+
+/// Blocks the current thread until it is woken up using `wake_thread`.
+///
+/// Spurious wakeups are possible, so callers should recheck their condition.
+pub fn block_current_thread() {
+    debug_assert!(
+        arch::Current::get_interrupt_state(),
+        "Blocking with interrupts disabled would never return."
+    );
+
+    CURRENT_THREAD.lock().state = ThreadState::Blocked;
+    schedule();
+
+    // The scheduling interrupt might not have arrived yet.
+    while CURRENT_THREAD.lock().state == ThreadState::Blocked {
+        cpu_relax();
+    }
+}
+
+/// Blocks the current thread until it is woken up or the deadline passed.
+///
+/// Spurious wakeups are possible, so callers should recheck their condition
+/// and the deadline.
+pub fn block_current_thread_until(deadline: Timestamp) {
+    let key = {
+        let current_thread = CURRENT_THREAD.lock();
+        (current_thread.pid, current_thread.id)
+    };
+
+    BLOCKED_THREADS.lock().deadlines.insert(key, deadline);
+
+    block_current_thread();
+
+    BLOCKED_THREADS.lock().deadlines.remove(&key);
+}
+
+/// Wakes up the given blocked thread.
+///
+/// If the thread wasn't switched out yet, it is woken up once it is.
+pub fn wake_thread(pid: ProcessID, id: ThreadID) {
+    let mut blocked_threads = BLOCKED_THREADS.lock();
+
+    blocked_threads.deadlines.remove(&(pid, id));
+
+    match blocked_threads.threads.remove(&(pid, id)) {
+        Some((cpu_id, mut thread)) => {
+            drop(blocked_threads);
+            if !thread.is_dead() {
+                thread.set_ready();
+                make_ready_on(cpu_id, thread);
+            }
+        },
+        None => blocked_threads.pending_wakeups.push((pid, id))
+    }
+}
+
+/// Adds the thread to the ready list of the given CPU.
+///
+/// If the thread should preempt the current thread of that CPU, or the CPU
+/// is idle, it is made to pick the thread up at its next safe point instead
+/// of waiting for its timeslice to expire.
+fn make_ready_on(cpu_id: usize, thread: SlabBox<TCB>) {
+    push_ready(READY_LIST.get_specific(cpu_id), thread);
+    check_preemption(cpu_id);
+}
+
 /// Updates the status for processes that were sleeping.
 fn check_sleeping_processes() {
     {
@@ -136,12 +923,33 @@ fn check_sleeping_processes() {
                 }
             };
             if wake_first {
-                READY_LIST.lock().push(sleeping_list.pop().unwrap().0);
+                enqueue(sleeping_list.pop().unwrap().0);
             } else {
                 break;
             }
         }
     }
+
+    let expired = BLOCKED_THREADS.lock().expired(Timestamp::get_current());
+
+    for (pid, id) in expired {
+        wake_thread(pid, id);
+    }
+}
+
+/// Returns the next time at which a sleeping or blocked thread has to be
+/// woken up.
+fn next_wake_time() -> Option<Timestamp> {
+    let sleeping = SLEEPING_LIST
+        .lock()
+        .peek()
+        .map(|thread| thread.get_wake_time());
+    let blocked = BLOCKED_THREADS.lock().earliest_deadline();
+
+    match (sleeping, blocked) {
+        (Some(sleeping), Some(blocked)) => Some(min(sleeping, blocked)),
+        (sleeping, blocked) => sleeping.or(blocked)
+    }
 }
 
 /// This function gets executed whenever there is nothing else to execute.
@@ -161,9 +969,8 @@ pub fn idle() -> ! {
         // TODO: Perform periodic cleanup here.
         unsafe {
             {
-                if let Some(next_wake_thread) = SLEEPING_LIST.lock().peek() {
+                if let Some(wake_time) = next_wake_time() {
                     let current_time = Timestamp::get_current();
-                    let wake_time = next_wake_thread.get_wake_time();
                     if let Some(sleep_duration) = wake_time.checked_sub(current_time) {
                         arch::Current::interrupt_in(sleep_duration);
                     } else {
@@ -175,3 +982,235 @@ pub fn idle() -> ! {
         }
     }
 }
+
+/// Tests for the scheduler.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::time::Duration;
+
+    /// Returns the identifiers of the thread with the given process ID.
+    fn thread(pid: usize) -> (ProcessID, ThreadID) {
+        (pid.into(), 0.into())
+    }
+
+    /// Tests that the highest priority inherited through any lock is used.
+    #[test]
+    fn test_inherit_priority() {
+        let mut inherited = InheritedPriorities::new();
+
+        inherited.inherit(thread(1), 0x1000, 3);
+        inherited.inherit(thread(1), 0x1000, 2);
+        inherited.inherit(thread(1), 0x2000, 5);
+        assert_eq!(inherited.highest(thread(1)), Some(5));
+        assert_eq!(inherited.highest(thread(2)), None);
+
+        inherited.release(thread(1), 0x2000);
+        assert_eq!(inherited.highest(thread(1)), Some(3));
+
+        inherited.release(thread(1), 0x1000);
+        assert_eq!(inherited.highest(thread(1)), None);
+        assert!(inherited.priorities.is_empty());
+    }
+
+    /// A ready thread that is ordered by its priority only.
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct ReadyMock {
+        priority: i32,
+        base_priority: i32,
+        thread: (ProcessID, ThreadID)
+    }
+
+    impl ReadyMock {
+        fn new(thread: (ProcessID, ThreadID), priority: i32) -> ReadyMock {
+            ReadyMock {
+                priority,
+                base_priority: priority,
+                thread
+            }
+        }
+    }
+
+    impl ReadyThread for ReadyMock {
+        fn identifiers(&self) -> (ProcessID, ThreadID) {
+            self.thread
+        }
+
+        fn apply_inherited_priority(&mut self, inherited_priority: Option<i32>) {
+            self.priority = effective_priority(self.base_priority, inherited_priority);
+        }
+    }
+
+    /// Tests that a low priority thread holding a lock that a high priority
+    /// thread waits for runs before a medium priority thread.
+    ///
+    /// The ready list and the inherited priorities are the ones the scheduler
+    /// uses. A test with three real threads is deferred, as self-tests run
+    /// before the first thread is entered and user space can't set thread
+    /// priorities yet.
+    #[test]
+    fn test_priority_inversion() {
+        let (low, medium, high) = (thread(1), thread(2), thread(3));
+        let lock = 0x1000;
+        let mut inherited = InheritedPriorities::new();
+        let mut ready_list = ReadyList::new();
+
+        ready_list.push(ReadyMock::new(low, 1));
+        ready_list.push(ReadyMock::new(medium, 2));
+        assert_eq!(ready_list.peek().map(|ready| ready.thread), Some(medium));
+
+        // The high priority thread blocks on the lock held by the low one.
+        inherited.inherit(low, lock, 3);
+        assert!(ready_list.update_priority(low, inherited.highest(low)));
+        assert!(!ready_list.update_priority(high, inherited.highest(high)));
+        assert_eq!(ready_list.peek().map(|ready| ready.thread), Some(low));
+
+        // Once the lock is released, the medium priority thread runs first again.
+        inherited.release(low, lock);
+        assert!(ready_list.update_priority(low, inherited.highest(low)));
+        assert_eq!(ready_list.pop().map(|ready| ready.thread), Some(medium));
+        assert_eq!(ready_list.pop().map(|ready| ready.thread), Some(low));
+        assert!(ready_list.pop().is_none());
+    }
+
+    /// Tests converting timeslices to timer ticks.
+    #[test]
+    fn test_timeslice_ticks() {
+        let tick = TICK_INTERVAL_MS;
+
+        assert_eq!(timeslice_ticks(Duration::from_millis(0)), 1);
+        assert_eq!(timeslice_ticks(Duration::from_millis(tick)), 1);
+        assert_eq!(timeslice_ticks(Duration::from_millis(tick * 3 + 1)), 3);
+        assert_eq!(timeslice_ticks(Duration::from_secs(u64::max_value())), u32::max_value());
+    }
+
+    /// Tests that priority levels use their configured timeslice.
+    #[test]
+    fn test_priority_quanta() {
+        let mut quanta = PriorityQuanta::new();
+        let default = Duration::from_millis(DEFAULT_QUANTUM_MS);
+
+        assert_eq!(quanta.get(1), default);
+
+        quanta.set(1, Some(Duration::from_millis(50)));
+        quanta.set(2, Some(Duration::from_millis(300)));
+        assert_eq!(quanta.get(0), default);
+        assert_eq!(quanta.get(1), Duration::from_millis(50));
+        assert_eq!(quanta.get(2), Duration::from_millis(300));
+
+        quanta.set(1, None);
+        assert_eq!(quanta.get(1), default);
+        assert_eq!(quanta.get(2), Duration::from_millis(300));
+    }
+
+    /// Tests that a CPU-bound thread isn't switched out more often than its
+    /// timeslice allows.
+    #[test]
+    fn test_cpu_bound_switches() {
+        let quantum = Duration::from_millis(DEFAULT_QUANTUM_MS);
+        let ticks_per_timeslice = timeslice_ticks(quantum);
+        let ticks = 1000;
+        let mut remaining = ticks_per_timeslice;
+        let mut switches = 0;
+
+        assert!(ticks_per_timeslice > 1);
+
+        for _ in 0..ticks {
+            // The timer tick only invokes the scheduler once the timeslice expired.
+            if count_tick(&mut remaining) {
+                // Another thread of the same priority is ready.
+                let next = Some((1, quantum));
+                let decision = schedule_decision(&mut remaining, 1, true, quantum, next);
+
+                assert_eq!(decision, ScheduleDecision::Switch);
+                switches += 1;
+            }
+        }
+
+        assert_eq!(switches, ticks / ticks_per_timeslice);
+    }
+
+    /// Tests when the current thread keeps running.
+    #[test]
+    fn test_schedule_decision() {
+        let short = Duration::from_millis(TICK_INTERVAL_MS * 2);
+        let long = Duration::from_millis(TICK_INTERVAL_MS * 5);
+        let mut remaining = 3;
+
+        // Threads with a lower or the same priority wait for the timeslice.
+        assert_eq!(
+            schedule_decision(&mut remaining, 2, true, long, Some((1, short))),
+            ScheduleDecision::Continue
+        );
+        assert_eq!(
+            schedule_decision(&mut remaining, 2, true, long, Some((2, short))),
+            ScheduleDecision::Continue
+        );
+        assert_eq!(remaining, 3);
+
+        // A thread with a higher priority preempts the current thread.
+        assert_eq!(
+            schedule_decision(&mut remaining, 2, true, long, Some((3, short))),
+            ScheduleDecision::Switch
+        );
+        assert_eq!(remaining, 2);
+
+        // Without other threads, the expired timeslice is renewed.
+        remaining = 0;
+        assert_eq!(
+            schedule_decision(&mut remaining, 2, true, long, Some((1, short))),
+            ScheduleDecision::Renew
+        );
+        assert_eq!(remaining, 5);
+
+        // A thread that can't run anymore is always replaced.
+        assert_eq!(
+            schedule_decision(&mut remaining, 2, false, long, Some((1, short))),
+            ScheduleDecision::Switch
+        );
+        assert_eq!(remaining, 2);
+    }
+
+    /// Tests parsing the timeslices given on the command line.
+    #[test]
+    fn test_parse_quanta() {
+        let ms = Duration::from_millis;
+
+        assert_eq!(&parse_quanta("1:50").unwrap()[..], &[(1, ms(50))]);
+        assert_eq!(&parse_quanta("-1:300,2:20").unwrap()[..], &[(-1, ms(300)), (2, ms(20))]);
+        assert_eq!(parse_quanta(""), None);
+        assert_eq!(parse_quanta("1"), None);
+        assert_eq!(parse_quanta("1:50,x:20"), None);
+        assert_eq!(parse_quanta("1:-50"), None);
+    }
+
+    /// Tests that inherited priorities never lower the base priority.
+    #[test]
+    fn test_effective_priority() {
+        assert_eq!(effective_priority(2, None), 2);
+        assert_eq!(effective_priority(2, Some(5)), 5);
+        assert_eq!(effective_priority(2, Some(1)), 2);
+    }
+
+    /// Tests finding the blocked threads whose deadline passed.
+    #[test]
+    fn test_expired_deadlines() {
+        let at = |millis| Timestamp::from_duration(Duration::from_millis(millis));
+        let mut blocked_threads = BlockedThreads {
+            threads: BTreeMap::new(),
+            pending_wakeups: Vec::new(),
+            deadlines: BTreeMap::new()
+        };
+
+        assert_eq!(blocked_threads.earliest_deadline(), None);
+
+        blocked_threads.deadlines.insert(thread(1), at(300));
+        blocked_threads.deadlines.insert(thread(2), at(100));
+        blocked_threads.deadlines.insert(thread(3), at(200));
+
+        assert_eq!(blocked_threads.earliest_deadline(), Some(at(100)));
+        assert!(blocked_threads.expired(at(50)).is_empty());
+        // A deadline that is reached exactly has passed.
+        assert_eq!(&blocked_threads.expired(at(200))[..], &[thread(2), thread(3)]);
+    }
+}
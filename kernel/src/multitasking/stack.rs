@@ -1,11 +1,47 @@
 //! Provides functionality to manage multiple stacks.
 
+use alloc::Vec;
 use crate::arch::{self, Architecture};
 use core::cmp::{max, min};
 use core::fmt;
 use core::mem::size_of;
+use core::slice;
 use crate::memory::address_space::{AddressSpace, Segment, SegmentType};
-use crate::memory::{MemoryArea, PageFlags, VirtualAddress};
+use crate::memory::{Address, MemoryArea, PageFlags, VirtualAddress};
+
+/// Ends the auxiliary vector.
+pub const AT_NULL: usize = 0;
+/// The address of the program headers in memory.
+pub const AT_PHDR: usize = 3;
+/// The size of a program header.
+pub const AT_PHENT: usize = 4;
+/// The number of program headers.
+pub const AT_PHNUM: usize = 5;
+/// The size of a page.
+pub const AT_PAGESZ: usize = 6;
+/// The entry address of the program.
+pub const AT_ENTRY: usize = 9;
+
+/// An entry of the auxiliary vector that is passed to a new process.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AuxiliaryEntry {
+    /// The type of the entry, one of the `AT_*` constants.
+    pub key: usize,
+    /// The value of the entry.
+    pub value: usize
+}
+
+/// Describes what a new process finds on its initial stack.
+#[derive(Debug)]
+pub struct ProcessStart {
+    /// The number of arguments.
+    pub argc: usize,
+    /// The address of the argument vector.
+    pub argv: VirtualAddress,
+    /// The address of the environment vector.
+    pub envp: VirtualAddress
+}
 
 // NOTE: For now only full descending stacks are supported.
 /// Represents the different types of stacks that exist.
@@ -40,6 +76,8 @@ pub struct Stack {
     max_size: usize,
     /// Represents the first address of the stack.
     pub base_stack_pointer: VirtualAddress,
+    /// The lowest stack pointer that was observed.
+    lowest_observed: VirtualAddress,
     /// The access type for this stack.
     access_type: AccessType
 }
@@ -48,8 +86,11 @@ impl fmt::Debug for Stack {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Bottom: {:?}, Top: {:?}, Max size: {:x}",
-            self.bottom_address, self.top_address, self.max_size
+            "Bottom: {:?}, Top: {:?}, Max size: {:x}, High-water mark: {:x}",
+            self.bottom_address,
+            self.top_address,
+            self.max_size,
+            self.high_water_mark()
         )
     }
 }
@@ -73,13 +114,120 @@ impl Stack {
             StackType::FullDescending => {
                 *stack_pointer -= size_of::<T>();
                 unsafe {
-                    address_space.write_val(value, *stack_pointer);
+                    address_space
+                        .write_val(value, *stack_pointer)
+                        .expect("A stack is a shared file mapping.");
+                }
+            },
+            _ => unimplemented!("Currently only Full Descending stacks are implemented")
+        }
+    }
+
+    /// Sets up the initial stack of a process in the given address space.
+    ///
+    /// The name of the program is the first argument, followed by the given
+    /// arguments, and the environment is empty. Starting at the stack
+    /// pointer, which is aligned to 16 bytes, the stack holds the following
+    /// words:
+    ///
+    /// - `argc`, the number of arguments.
+    /// - `argv[0]` to `argv[argc - 1]`, followed by a null pointer.
+    /// - The environment pointers, followed by a null pointer.
+    /// - The auxiliary vector as pairs of type and value, ending with an
+    /// `AT_NULL` pair.
+    ///
+    /// The strings the arguments point to are placed above these words.
+    pub fn push_process_start(
+        address_space: &mut AddressSpace,
+        stack_pointer: &mut VirtualAddress,
+        name: &str,
+        arguments: &[&str],
+        auxiliary_vector: &[AuxiliaryEntry]
+    ) -> ProcessStart {
+        match arch::Current::STACK_TYPE {
+            StackType::FullDescending => {
+                let mut argument_addresses = Vec::with_capacity(arguments.len() + 1);
+
+                for argument in Some(name).iter().chain(arguments.iter()) {
+                    *stack_pointer -= argument.len() + 1;
+
+                    let terminator = *stack_pointer + argument.len();
+
+                    address_space
+                        .write_to(argument.as_bytes(), *stack_pointer)
+                        .and_then(|_| address_space.write_to(&[0], terminator))
+                        .expect("A stack is a shared file mapping.");
+                    argument_addresses.push(*stack_pointer);
+                }
+
+                let words = process_start_words(&argument_addresses, auxiliary_vector);
+                let words_size = words.len() * size_of::<usize>();
+
+                *stack_pointer = VirtualAddress::from_usize(
+                    (stack_pointer.as_usize() - words_size) & !0xf
+                );
+
+                let buffer =
+                    unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, words_size) };
+                address_space
+                    .write_to(buffer, *stack_pointer)
+                    .expect("A stack is a shared file mapping.");
+
+                let argc = argument_addresses.len();
+                ProcessStart {
+                    argc,
+                    argv: *stack_pointer + size_of::<usize>(),
+                    envp: *stack_pointer + (argc + 2) * size_of::<usize>()
+                }
+            },
+            _ => unimplemented!("Currently only Full Descending stacks are implemented")
+        }
+    }
+
+    /// Checks if the address lies within the area reserved for this stack.
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        match arch::Current::STACK_TYPE {
+            StackType::FullDescending => {
+                self.top_address - self.max_size <= address && address <= self.top_address
+            },
+            _ => unimplemented!("Currently only Full Descending stacks are implemented")
+        }
+    }
+
+    /// Returns the number of usable bytes below the given stack pointer.
+    ///
+    /// Only the part of the stack that is mapped is usable.
+    pub fn remaining(&self, stack_pointer: VirtualAddress) -> usize {
+        match arch::Current::STACK_TYPE {
+            StackType::FullDescending => {
+                if stack_pointer > self.bottom_address {
+                    stack_pointer - self.bottom_address
+                } else {
+                    0
                 }
             },
             _ => unimplemented!("Currently only Full Descending stacks are implemented")
         }
     }
 
+    /// Records the given stack pointer for the high-water mark.
+    pub fn observe(&mut self, stack_pointer: VirtualAddress) {
+        if self.contains(stack_pointer) {
+            self.lowest_observed = min(self.lowest_observed, stack_pointer);
+        }
+    }
+
+    /// Returns the most bytes the stack was observed to use.
+    ///
+    /// The stack is only observed at certain points, so it may have been
+    /// used more in between.
+    pub fn high_water_mark(&self) -> usize {
+        match arch::Current::STACK_TYPE {
+            StackType::FullDescending => self.top_address - self.lowest_observed,
+            _ => unimplemented!("Currently only Full Descending stacks are implemented")
+        }
+    }
+
     /// Creates a new stack of size zero with the given start address.
     pub fn new(
         initial_size: usize,
@@ -94,6 +242,7 @@ impl Stack {
                 bottom_address: start_address + max_size,
                 max_size,
                 base_stack_pointer: start_address + max_size,
+                lowest_observed: start_address + max_size,
                 access_type
             },
             _ => unimplemented!("Currently only Full Descending stacks are implemented")
@@ -119,6 +268,46 @@ impl Stack {
         stack
     }
 
+    /// Creates a new stack in its own segment of the given address space.
+    ///
+    /// Returns `None` if the segment doesn't fit into the memory limit of the
+    /// address space.
+    pub fn in_address_space(
+        initial_size: usize,
+        max_size: usize,
+        start_address: VirtualAddress,
+        access_type: AccessType,
+        address_space: &mut AddressSpace
+    ) -> Option<Stack> {
+        if !address_space.can_reserve(MemoryArea::new(start_address, max_size)) {
+            return None;
+        }
+
+        Some(Stack::new(
+            initial_size,
+            max_size,
+            start_address,
+            access_type,
+            Some(address_space)
+        ))
+    }
+
+    /// Frees the stack and removes its segment from the given address space.
+    ///
+    /// This way the area can be used by a new stack.
+    pub fn release(&mut self, address_space: &mut AddressSpace) {
+        self.resize(0, Some(address_space));
+
+        let area = match arch::Current::STACK_TYPE {
+            StackType::FullDescending => {
+                MemoryArea::new(self.top_address - self.max_size, self.max_size)
+            },
+            _ => unimplemented!("Currently only Full Descending stacks are implemented")
+        };
+
+        address_space.remove_segment(area);
+    }
+
     /// Grows the stack by the given amount.
     pub fn grow(&mut self, amount: usize, mut address_space: Option<&mut AddressSpace>) {
         match arch::Current::STACK_TYPE {
@@ -196,3 +385,59 @@ impl Stack {
         }
     }
 }
+
+/// Returns the words at the bottom of the initial stack of a process.
+///
+/// The layout is described at `Stack::push_process_start`.
+fn process_start_words(
+    arguments: &[VirtualAddress],
+    auxiliary_vector: &[AuxiliaryEntry]
+) -> Vec<usize> {
+    let mut words = Vec::with_capacity(arguments.len() + 2 * auxiliary_vector.len() + 5);
+
+    words.push(arguments.len());
+    words.extend(arguments.iter().map(|argument| argument.as_usize()));
+    words.push(0);
+
+    // The environment is empty.
+    words.push(0);
+
+    for entry in auxiliary_vector {
+        words.push(entry.key);
+        words.push(entry.value);
+    }
+
+    words.push(AT_NULL);
+    words.push(0);
+
+    words
+}
+
+/// Tests for the initial stack of processes.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the order of the arguments, the environment and the auxiliary
+    /// vector.
+    #[test]
+    fn test_process_start_words() {
+        let arguments = [VirtualAddress::from_usize(0x7f80_005f_fff0)];
+        let auxiliary_vector = [
+            AuxiliaryEntry {
+                key: AT_PAGESZ,
+                value: 0x1000
+            },
+            AuxiliaryEntry {
+                key: AT_ENTRY,
+                value: 0x40_1000
+            }
+        ];
+
+        assert_eq!(
+            &process_start_words(&arguments, &auxiliary_vector)[..],
+            &[1, 0x7f80_005f_fff0, 0, 0, AT_PAGESZ, 0x1000, AT_ENTRY, 0x40_1000, AT_NULL, 0]
+        );
+        assert_eq!(&process_start_words(&[], &[])[..], &[0, 0, 0, AT_NULL, 0]);
+    }
+}
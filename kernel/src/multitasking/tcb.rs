@@ -1,13 +1,34 @@
 //! This module defines thread control blocks (TCBs).
 
-use super::stack::AccessType;
-use super::{ProcessID, Stack, ThreadID, PCB, PROCESS_LIST};
+use super::scheduler;
+use super::stack::{AccessType, AuxiliaryEntry};
+use super::{finish_process, ProcessID, Stack, ThreadID, PCB, PROCESS_LIST};
+use super::{CHILD_EXIT_QUEUE, THREAD_EXIT_QUEUE};
+use alloc::Vec;
 use crate::arch::{self, Architecture};
-use core::cmp::Ordering;
+use core::cmp::{Ordering, Reverse};
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering, ATOMIC_U64_INIT};
 use core::time::Duration;
-use crate::memory::{VirtualAddress, AddressSpaceManager};
+use crate::memory::slab::{SlabBox, SlabCache};
+use crate::memory::{Address, VirtualAddress, AddressSpaceManager};
+use crate::sync::blocking_mutex::{self, HeldMutex};
 use crate::sync::time::Timestamp;
+use crate::sync::wait_queue::{self, QueueLink};
+use crate::sync::Mutex;
+
+lazy_static! {
+    /// The cache that all TCBs are allocated in.
+    static ref TCB_CACHE: Mutex<SlabCache<TCB>> = Mutex::new(SlabCache::new());
+}
+
+/// Returns the pages of the TCB cache that no longer hold any TCBs.
+pub fn shrink_cache() {
+    TCB_CACHE.lock().shrink();
+}
+
+/// The sequence number the next thread put on a ready list gets.
+static NEXT_ENQUEUE_SEQ: AtomicU64 = ATOMIC_U64_INIT;
 
 /// Represents the possible states a thread can have.
 #[derive(Debug, PartialEq)]
@@ -20,6 +41,8 @@ pub enum ThreadState {
     ///
     /// The timestamp corresponds to the time the thread should wake up.
     Sleeping(Timestamp),
+    /// The thread is waiting to be woken up.
+    Blocked,
     /// The thread is dead.
     Dead
 }
@@ -36,8 +59,25 @@ pub struct TCB {
     pub user_stack: Stack,
     /// The state of the thread.
     pub state: ThreadState,
-    /// The priority of the thread.
+    /// The priority the thread runs with.
+    ///
+    /// This is the base priority, unless the thread inherited a higher one
+    /// from a thread waiting for a blocking mutex it holds.
     pub priority: i32,
+    /// The priority the thread was given, without inherited priorities.
+    pub base_priority: i32,
+    /// The sequence number of the last time the thread was put on a ready
+    /// list.
+    pub enqueue_seq: u64,
+    /// The wait queues the thread is currently waiting in.
+    pub wait_queues: Vec<QueueLink>,
+    /// Whether the thread was woken up because its process was killed.
+    ///
+    /// Such a thread keeps running until it returns to the syscall boundary,
+    /// so that the objects on its kernel stack are dropped.
+    pub killed: bool,
+    /// The blocking mutexes the thread holds.
+    pub held_mutexes: Vec<HeldMutex>,
     /// The architecture specific context of this thread.
     pub context: <arch::Current as Architecture>::Context
 }
@@ -58,16 +98,25 @@ impl fmt::Debug for TCB {
 
 impl PartialEq for TCB {
     fn eq(&self, other: &TCB) -> bool {
-        // This assumes that thread IDs are unique.
-        self.id == other.id
+        // Thread IDs are only unique within a process.
+        self.pid == other.pid && self.id == other.id
     }
 }
 
 impl Eq for TCB {}
 
+/// Orders the threads by the order they should run in.
+///
+/// A thread is greater than another one, if
+/// - it has a higher priority, or
+/// - it has the same priority and was put on a ready list earlier, or
+/// - both of the above are equal and it has the lower process and thread ID.
+///
+/// Only the same thread compares as equal, so the ready lists run threads in
+/// a reproducible order.
 impl Ord for TCB {
     fn cmp(&self, other: &TCB) -> Ordering {
-        self.priority.cmp(&other.priority)
+        self.schedule_key().cmp(&other.schedule_key())
     }
 }
 
@@ -79,35 +128,102 @@ impl PartialOrd for TCB {
 
 impl Drop for TCB {
     fn drop(&mut self) {
-        let mut process_list = PROCESS_LIST.lock();
+        // Notifications would otherwise keep trying to wake the thread.
+        wait_queue::leave_queues((self.pid, self.id), &mut self.wait_queues);
+
+        let became_zombie = {
+            let mut process_list = PROCESS_LIST.lock();
 
-        let drop_pcb = {
-            let pcb = process_list
-                .get_mut(&self.pid)
-                .expect("Process of the thread doesn't exist.");
+            let drop_pcb = {
+                let pcb = process_list
+                    .get_mut(&self.pid)
+                    .expect("Process of the thread doesn't exist.");
 
-            pcb.thread_count -= 1;
+                // The stack areas are reused by the next thread with this ID.
+                self.kernel_stack.release(&mut pcb.address_space);
+                self.user_stack.release(&mut pcb.address_space);
 
-            self.kernel_stack.resize(0, Some(&mut pcb.address_space));
-            self.user_stack.resize(0, Some(&mut pcb.address_space));
+                pcb.remove_thread(self.id);
 
-            pcb.is_droppable()
+                pcb.is_droppable()
+            };
+
+            drop_pcb && finish_process(&mut process_list, self.pid)
         };
 
-        if drop_pcb {
-            process_list.remove(&self.pid);
+        scheduler::forget_inherited_priorities(self.pid, self.id);
+        blocking_mutex::release_held_mutexes(&mut self.held_mutexes);
+
+        // Wake up the threads joining this one.
+        THREAD_EXIT_QUEUE.notify_all();
+
+        // Wake up the parent if it waits for the process.
+        if became_zombie {
+            CHILD_EXIT_QUEUE.notify_all();
         }
     }
 }
 
 impl TCB {
     /// Creates a new thread in the given process at the given start address.
-    pub fn in_process(pid: ProcessID, id: ThreadID, pc: VirtualAddress, pcb: &mut PCB) -> TCB {
+    ///
+    /// Returns `None` if the user stack of the thread doesn't fit into the
+    /// memory limit of the process.
+    pub fn in_process(
+        pid: ProcessID,
+        id: ThreadID,
+        pc: VirtualAddress,
+        pcb: &mut PCB
+    ) -> Option<SlabBox<TCB>> {
         TCB::in_process_with_arguments(pid, id, pc, pcb, 0, 0, 0, 0, 0)
     }
 
+    /// Creates the first thread of a process at the given start address.
+    ///
+    /// The user stack starts as described at `Stack::push_process_start`.
+    /// The number of arguments, the argument vector and the environment
+    /// vector are also passed as the first three arguments. Returns `None`
+    /// if the user stack doesn't fit into the memory limit of the process.
+    pub fn main_thread(
+        pid: ProcessID,
+        pc: VirtualAddress,
+        pcb: &mut PCB,
+        name: &str,
+        arguments: &[&str],
+        auxiliary_vector: &[AuxiliaryEntry]
+    ) -> Option<SlabBox<TCB>> {
+        let id = 0.into();
+        let user_stack = pcb.address_space.create_user_stack(id)?;
+        let mut stack_pointer = user_stack.base_stack_pointer;
+
+        let start = Stack::push_process_start(
+            &mut pcb.address_space,
+            &mut stack_pointer,
+            name,
+            arguments,
+            auxiliary_vector
+        );
+
+        Some(TCB::with_user_stack(
+            pid,
+            id,
+            pc,
+            pcb,
+            user_stack,
+            stack_pointer,
+            start.argc,
+            start.argv.as_usize(),
+            start.envp.as_usize(),
+            0,
+            0
+        ))
+    }
+
     /// Creates a new thread in the given process at the given start address
     /// with the given arguments.
+    ///
+    /// Returns `None` if the user stack of the thread doesn't fit into the
+    /// memory limit of the process.
     pub fn in_process_with_arguments(
         pid: ProcessID,
         id: ThreadID,
@@ -118,21 +234,55 @@ impl TCB {
         arg3: usize,
         arg4: usize,
         arg5: usize
-    ) -> TCB {
-        let kernel_stack = pcb.address_space.create_kernel_stack(id);
+    ) -> Option<SlabBox<TCB>> {
+        let user_stack = pcb.address_space.create_user_stack(id)?;
+        let stack_pointer = user_stack.base_stack_pointer;
 
-        let user_stack = pcb.address_space.create_user_stack(id);
+        Some(TCB::with_user_stack(
+            pid,
+            id,
+            pc,
+            pcb,
+            user_stack,
+            stack_pointer,
+            arg1,
+            arg2,
+            arg3,
+            arg4,
+            arg5
+        ))
+    }
 
-        let stack_pointer = user_stack.base_stack_pointer;
+    /// Creates a new thread with the given user stack, which starts at the
+    /// given stack pointer.
+    fn with_user_stack(
+        pid: ProcessID,
+        id: ThreadID,
+        pc: VirtualAddress,
+        pcb: &mut PCB,
+        user_stack: Stack,
+        stack_pointer: VirtualAddress,
+        arg1: usize,
+        arg2: usize,
+        arg3: usize,
+        arg4: usize,
+        arg5: usize
+    ) -> SlabBox<TCB> {
+        let kernel_stack = pcb.address_space.create_kernel_stack(id);
         let kernel_stack_pointer = kernel_stack.base_stack_pointer;
 
-        TCB {
+        let tcb = TCB {
             id,
             pid,
             kernel_stack,
             user_stack,
             state: ThreadState::Ready,
             priority: 1,
+            base_priority: 1,
+            enqueue_seq: 0,
+            wait_queues: Vec::new(),
+            killed: false,
+            held_mutexes: Vec::new(),
             context: <<arch::Current as Architecture>::Context as arch::Context>::new(
                 pc,
                 stack_pointer,
@@ -144,11 +294,13 @@ impl TCB {
                 arg4,
                 arg5
             )
-        }
+        };
+
+        SlabBox::new(tcb, &TCB_CACHE)
     }
 
     /// Creates a new TCB for an idle thread.
-    pub fn idle_tcb(cpu_id: usize) -> TCB {
+    pub fn idle_tcb(cpu_id: usize) -> SlabBox<TCB> {
         let id: ThreadID = cpu_id.into();
 
         // NOTE: This assumes that the idle address space is currently active.
@@ -156,7 +308,7 @@ impl TCB {
 
         let stack_pointer = kernel_stack.base_stack_pointer;
 
-        TCB {
+        let tcb = TCB {
             id,
             pid: 0.into(),
             kernel_stack,
@@ -169,20 +321,30 @@ impl TCB {
             ),
             state: ThreadState::Ready,
             priority: i32::min_value(),
+            base_priority: i32::min_value(),
+            enqueue_seq: 0,
+            wait_queues: Vec::new(),
+            killed: false,
+            held_mutexes: Vec::new(),
             context: <<arch::Current as Architecture>::Context as arch::Context>::idle(
                 stack_pointer
             )
-        }
+        };
+
+        SlabBox::new(tcb, &TCB_CACHE)
     }
 
     /// Returns true if the thread state is dead.
+    ///
+    /// Threads of dead processes count as dead, unless they were woken up to
+    /// return to the syscall boundary.
     pub fn is_dead(&self) -> bool {
         let process_list = PROCESS_LIST.lock();
         let process = process_list
             .get(&self.pid)
             .expect("Process of the thread doesn't exist.");
 
-        self.state == ThreadState::Dead || process.is_dead()
+        self.state == ThreadState::Dead || (process.is_dead() && !self.killed)
     }
 
     /// Returns true if the thread state is running.
@@ -211,14 +373,36 @@ impl TCB {
         self.state = ThreadState::Dead;
     }
 
+    /// Marks the thread as put on a ready list now.
+    ///
+    /// This must be called whenever the thread is put on a ready list.
+    pub fn mark_enqueued(&mut self) {
+        self.enqueue_seq = NEXT_ENQUEUE_SEQ.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Returns the key that determines the order in which threads run.
+    fn schedule_key(&self) -> ScheduleKey {
+        schedule_key(self.priority, self.enqueue_seq, self.pid, self.id)
+    }
+
     /// Returns the time quantum this process should run.
+    ///
+    /// It depends on the current priority of the thread.
     pub fn get_quantum(&self) -> Duration {
-        Duration::from_millis(150)
+        scheduler::get_quantum(self.priority)
     }
 }
 
+/// The key that orders threads on the ready lists, greatest first.
+type ScheduleKey = (i32, Reverse<u64>, Reverse<(ProcessID, ThreadID)>);
+
+/// Returns the key that orders a thread with the given properties.
+fn schedule_key(priority: i32, enqueue_seq: u64, pid: ProcessID, id: ThreadID) -> ScheduleKey {
+    (priority, Reverse(enqueue_seq), Reverse((pid, id)))
+}
+
 /// A TCB that is sorted by its sleep time (shortest first).
-pub struct SleepTimeSortedTCB(pub TCB);
+pub struct SleepTimeSortedTCB(pub SlabBox<TCB>);
 
 impl SleepTimeSortedTCB {
     /// Returns the sleep time for this TCB.
@@ -249,3 +433,77 @@ impl PartialOrd for SleepTimeSortedTCB {
         Some(self.cmp(other))
     }
 }
+
+/// Self-tests for the ordering of threads.
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftests {
+    use super::*;
+    use alloc::binary_heap::BinaryHeap;
+
+    /// Checks that a ready list returns threads in scheduling order.
+    fn test_ready_list_order() -> Result<(), &'static str> {
+        let expected = [
+            schedule_key(5, 7, 2.into(), 1.into()),
+            schedule_key(5, 7, 3.into(), 0.into()),
+            schedule_key(5, 9, 1.into(), 0.into()),
+            schedule_key(0, 1, 1.into(), 0.into()),
+            schedule_key(-3, 0, 1.into(), 0.into())
+        ];
+        let mut ready_list = BinaryHeap::new();
+
+        for &index in [3, 0, 4, 2, 1].iter() {
+            ready_list.push(expected[index]);
+        }
+
+        for key in expected.iter() {
+            if ready_list.pop() != Some(*key) {
+                return Err("The threads were returned out of order.");
+            }
+        }
+
+        Ok(())
+    }
+
+    register_selftest!(READY_LIST_ORDER, test_ready_list_order);
+}
+
+/// Tests for the ordering of threads.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::binary_heap::BinaryHeap;
+    use alloc::Vec;
+
+    /// Returns the key of a thread with the given properties.
+    fn key(priority: i32, enqueue_seq: u64, pid: usize, id: usize) -> ScheduleKey {
+        schedule_key(priority, enqueue_seq, pid.into(), id.into())
+    }
+
+    /// Tests that threads run by priority and then in the order they were
+    /// enqueued.
+    #[test]
+    fn test_run_order() {
+        let mut ready_list = BinaryHeap::new();
+
+        ready_list.push(key(1, 3, 1, 0));
+        ready_list.push(key(1, 1, 2, 0));
+        ready_list.push(key(5, 4, 1, 1));
+        ready_list.push(key(1, 2, 3, 0));
+        ready_list.push(key(i32::min_value(), 0, 0, 0));
+
+        let mut run_order = Vec::new();
+        while let Some((_, _, Reverse((pid, id)))) = ready_list.pop() {
+            run_order.push((usize::from(pid), usize::from(id)));
+        }
+
+        assert_eq!(&run_order[..], &[(1, 1), (2, 0), (3, 0), (1, 0), (0, 0)]);
+    }
+
+    /// Tests that the IDs break ties between otherwise equal threads.
+    #[test]
+    fn test_id_tiebreak() {
+        assert!(key(1, 0, 1, 2) > key(1, 0, 1, 3));
+        assert!(key(1, 0, 1, 2) > key(1, 0, 2, 0));
+        assert_eq!(key(1, 0, 1, 2).cmp(&key(1, 0, 1, 2)), Ordering::Equal);
+    }
+}
@@ -0,0 +1,131 @@
+//! Runs self-tests inside the running kernel.
+//!
+//! Self-tests check code that only works on a running kernel, like the heap
+//! or the page tables. They are registered with `register_selftest!`, which
+//! places them in the `.selftests` section of the kernel binary. The tests
+//! and the registrations are only compiled with the `selftest` feature, so
+//! they should be placed in a module guarded by
+//! `#[cfg(all(feature = "selftest", not(test)))]`.
+//!
+//! If the kernel command line contains the `selftest` flag, all registered
+//! tests are run once the kernel is initialized. The results are reported
+//! over the serial port and QEMU is exited with `qemu::SUCCESS` or
+//! `qemu::FAILURE`.
+//!
+//! A test fails by returning an error or by panicking, for example in a
+//! failed assertion. In the latter case the panic handler reports the test
+//! and exits QEMU instead of halting.
+
+#[cfg(feature = "selftest")]
+use crate::arch::qemu;
+use crate::boot;
+#[cfg(feature = "selftest")]
+use core::mem::size_of;
+#[cfg(feature = "selftest")]
+use core::slice;
+#[cfg(feature = "selftest")]
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+#[cfg(feature = "selftest")]
+use crate::memory::{Address, VirtualAddress};
+
+/// The command line flag that enables the self-test mode.
+const SELFTEST_FLAG: &str = "selftest";
+
+/// One more than the index of the running self-test, or zero if none runs.
+#[cfg(feature = "selftest")]
+static RUNNING_TEST: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A registered self-test.
+#[cfg(feature = "selftest")]
+pub struct SelfTest {
+    /// The name of the test.
+    pub name: &'static str,
+    /// Runs the test.
+    ///
+    /// Returns a description of the problem if the test fails. The test may
+    /// also panic to fail.
+    pub function: fn() -> Result<(), &'static str>
+}
+
+#[cfg(feature = "selftest")]
+extern "C" {
+    /// The start of the registered self-tests.
+    static SELFTESTS_START: VirtualAddress;
+    /// The end of the registered self-tests.
+    static SELFTESTS_END: VirtualAddress;
+}
+
+/// Returns true if the kernel was booted in self-test mode.
+pub fn is_enabled() -> bool {
+    boot::has_command_line_flag(SELFTEST_FLAG)
+}
+
+/// Returns the registered self-tests.
+#[cfg(feature = "selftest")]
+fn registered_tests() -> &'static [SelfTest] {
+    unsafe {
+        let length = (SELFTESTS_END - SELFTESTS_START) / size_of::<SelfTest>();
+
+        slice::from_raw_parts(SELFTESTS_START.as_ptr(), length)
+    }
+}
+
+/// Runs all registered self-tests and exits QEMU with the result.
+#[cfg(feature = "selftest")]
+pub fn run() -> ! {
+    let tests = registered_tests();
+    let mut failed = 0;
+
+    info!("Running {} self-tests...", tests.len());
+
+    for (index, test) in tests.iter().enumerate() {
+        RUNNING_TEST.store(index + 1, Ordering::SeqCst);
+        let result = (test.function)();
+        RUNNING_TEST.store(0, Ordering::SeqCst);
+
+        match result {
+            Ok(()) => info!("{} ... ok", test.name),
+            Err(problem) => {
+                error!("{} ... FAILED: {}", test.name, problem);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        info!("All {} self-tests passed.", tests.len());
+        qemu::exit(qemu::SUCCESS)
+    } else {
+        error!("{} of {} self-tests failed.", failed, tests.len());
+        qemu::exit(qemu::FAILURE)
+    }
+}
+
+/// Reports a panic of a running self-test and exits QEMU with
+/// `qemu::FAILURE`.
+///
+/// Returns if no self-test is running, so panics elsewhere are handled
+/// normally.
+#[cfg(feature = "selftest")]
+pub fn handle_panic() {
+    let running = RUNNING_TEST.load(Ordering::SeqCst);
+
+    if running != 0 {
+        error!("{} ... FAILED: panicked", registered_tests()[running - 1].name);
+        qemu::exit(qemu::FAILURE);
+    }
+}
+
+/// Handles a panic of a running self-test.
+///
+/// Without the selftest feature no self-test can run, so this does nothing.
+#[cfg(not(feature = "selftest"))]
+pub fn handle_panic() {}
+
+/// Reports that the self-tests weren't compiled into this kernel.
+///
+/// The kernel continues to boot normally.
+#[cfg(not(feature = "selftest"))]
+pub fn run() {
+    warn!("Self-tests were requested, but the kernel was built without the selftest feature.");
+}
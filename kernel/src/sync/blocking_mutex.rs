@@ -0,0 +1,319 @@
+//! Provides a mutex that blocks waiting threads instead of spinning.
+//!
+//! A thread that finds the mutex held spins for a short while first, since
+//! most critical sections are short and blocking means switching threads
+//! twice. Only if the mutex is still held afterwards, the thread blocks.
+//!
+//! The holder of a `BlockingMutex` keeps preemption enabled, unlike the
+//! holder of a `Mutex`. A low priority holder can therefore be preempted by
+//! medium priority threads, while a high priority thread waits for the
+//! mutex. To avoid this priority inversion, the holder inherits the priority
+//! of the threads waiting for it until it releases the mutex.
+//!
+//! The holder is kept in an atomic in the mutex, so an uncontended mutex is
+//! taken without any global lock. Each thread also lists the mutexes it
+//! holds, so that they can be released when it is reclaimed. Threads killed
+//! while running in the kernel don't unwind, so their guards are never
+//! dropped. The protected data might be left half updated then.
+
+use super::wait_queue::wait_on_any_unkillable;
+use super::{cpu_relax, WaitQueue};
+use alloc::Vec;
+use crate::arch::{self, Architecture};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut, Drop};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::multitasking::scheduler::{inherit_priority, release_inherited_priority};
+use crate::multitasking::{ProcessID, ThreadID, CURRENT_THREAD};
+
+/// The number of times a held mutex is checked before the waiting thread
+/// blocks.
+const SPIN_CHECKS: usize = 100;
+
+/// The holder value of a mutex that isn't held.
+const UNLOCKED: usize = 0;
+
+/// The number of bits each ID takes up in a holder value.
+const ID_BITS: usize = 32;
+
+/// The state of a `BlockingMutex` that doesn't depend on the protected data.
+pub struct MutexState {
+    /// The thread holding the mutex as encoded by `holder_value`, or
+    /// `UNLOCKED`.
+    holder: AtomicUsize,
+    /// Whether a waiting thread lent its priority to the holder.
+    priority_lent: AtomicBool,
+    /// The threads waiting for the mutex.
+    waiting: WaitQueue
+}
+
+/// A blocking mutex that a thread holds.
+///
+/// The guard of the mutex borrows it, and a thread reclaimed while holding
+/// the mutex never drops its guard, so the mutex outlives the thread.
+pub struct HeldMutex(*const MutexState);
+
+// The state is only used to release the mutex, which is done atomically.
+unsafe impl Send for HeldMutex {}
+
+/// A mutex that blocks the threads waiting for it.
+///
+/// It may only be used by threads with preemption enabled, so never in
+/// interrupt handlers or while holding a `Mutex`.
+pub struct BlockingMutex<T: ?Sized> {
+    /// The holder and the waiting threads.
+    state: MutexState,
+    /// The protected data.
+    data: UnsafeCell<T>
+}
+
+/// A guard through which the data protected by a `BlockingMutex` can be
+/// accessed.
+///
+/// When the guard falls out of scope it will release the mutex.
+pub struct BlockingMutexGuard<'a, T: ?Sized + 'a> {
+    /// The mutex that is held.
+    mutex: &'a BlockingMutex<T>
+}
+
+unsafe impl<T: ?Sized + Send> Sync for BlockingMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for BlockingMutex<T> {}
+
+impl<T> BlockingMutex<T> {
+    /// Creates a new blocking mutex wrapping the supplied data.
+    pub fn new(data: T) -> BlockingMutex<T> {
+        BlockingMutex {
+            state: MutexState {
+                holder: AtomicUsize::new(UNLOCKED),
+                priority_lent: AtomicBool::new(false),
+                waiting: WaitQueue::new()
+            },
+            data: UnsafeCell::new(data)
+        }
+    }
+}
+
+impl<T: ?Sized> BlockingMutex<T> {
+    /// Locks the mutex and returns a guard.
+    ///
+    /// While the current thread waits, the holder runs with at least the
+    /// priority of the current thread.
+    pub fn lock(&self) -> BlockingMutexGuard<T> {
+        debug_assert!(
+            arch::Current::get_interrupt_state(),
+            "A blocking mutex was locked with preemption disabled."
+        );
+
+        for _ in 0..SPIN_CHECKS {
+            if self.is_unlocked() && self.try_acquire() {
+                return BlockingMutexGuard { mutex: self };
+            }
+
+            cpu_relax();
+        }
+
+        let (current_holder, priority) = {
+            let current_thread = CURRENT_THREAD.lock();
+            (
+                holder_value(current_thread.pid, current_thread.id),
+                current_thread.priority
+            )
+        };
+
+        let condition = || {
+            let holder = self.state.holder.load(Ordering::SeqCst);
+
+            if holder == UNLOCKED {
+                return self.try_acquire();
+            }
+
+            debug_assert!(
+                holder != current_holder,
+                "A thread tried to lock a blocking mutex it holds."
+            );
+
+            self.lend_priority(holder, priority);
+            false
+        };
+
+        // The holder releases the mutex soon, even if this thread was killed.
+        wait_on_any_unkillable(&[&self.state.waiting], condition);
+
+        BlockingMutexGuard { mutex: self }
+    }
+
+    /// Tries to lock the mutex without blocking.
+    ///
+    /// Returns `None` if the mutex is held.
+    pub fn try_lock(&self) -> Option<BlockingMutexGuard<T>> {
+        if self.try_acquire() {
+            Some(BlockingMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the mutex isn't held at the moment.
+    fn is_unlocked(&self) -> bool {
+        self.state.holder.load(Ordering::Relaxed) == UNLOCKED
+    }
+
+    /// Makes the current thread the holder, if the mutex isn't held.
+    ///
+    /// Returns true if the thread holds the mutex now.
+    fn try_acquire(&self) -> bool {
+        // The thread can't be preempted and reclaimed before the mutex is in
+        // its list of held mutexes.
+        let mut current_thread = CURRENT_THREAD.lock();
+        let holder = holder_value(current_thread.pid, current_thread.id);
+
+        if self.state.holder.compare_and_swap(UNLOCKED, holder, Ordering::SeqCst) != UNLOCKED {
+            return false;
+        }
+
+        current_thread
+            .held_mutexes
+            .push(HeldMutex(&self.state as *const MutexState));
+
+        true
+    }
+
+    /// Lets the given holder run with at least the given priority, while it
+    /// holds the mutex.
+    fn lend_priority(&self, holder: usize, priority: i32) {
+        let (pid, id) = holder_thread(holder);
+
+        inherit_priority(pid, id, self.address(), priority);
+        self.state.priority_lent.store(true, Ordering::SeqCst);
+
+        // The holder only takes back the lent priority if it releases the
+        // mutex after the flag was set.
+        if self.state.holder.load(Ordering::SeqCst) != holder {
+            release_inherited_priority(pid, id, self.address());
+        }
+    }
+
+    /// Releases the mutex and wakes up a waiting thread.
+    fn unlock(&self) {
+        let holder = {
+            let mut current_thread = CURRENT_THREAD.lock();
+            let state = &self.state as *const MutexState;
+            let index = current_thread
+                .held_mutexes
+                .iter()
+                .position(|&HeldMutex(held)| held == state);
+
+            if let Some(index) = index {
+                current_thread.held_mutexes.remove(index);
+            }
+
+            // The mutex is released before the thread can be preempted, so a
+            // reclaimed thread never holds mutexes that aren't listed.
+            self.state.holder.swap(UNLOCKED, Ordering::SeqCst)
+        };
+
+        if self.state.priority_lent.swap(false, Ordering::SeqCst) {
+            let (pid, id) = holder_thread(holder);
+            release_inherited_priority(pid, id, self.address());
+        }
+
+        // Waiting threads can't be killed, so the woken up thread takes the
+        // mutex, unless another thread was faster.
+        self.state.waiting.notify_one();
+    }
+
+    /// Returns the address that identifies this mutex.
+    fn address(&self) -> usize {
+        self as *const BlockingMutex<T> as *const u8 as usize
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for BlockingMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.state.holder.load(Ordering::Relaxed) {
+            UNLOCKED => write!(f, "BlockingMutex {{ <unlocked> }}"),
+            holder => write!(f, "BlockingMutex {{ <held by {:?}> }}", holder_thread(holder))
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for BlockingMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for BlockingMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for BlockingMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Releases the given blocking mutexes of a thread that is reclaimed.
+///
+/// A killed thread never drops its guards. Reclaiming is rare, so all
+/// threads waiting for the mutexes are woken up.
+pub fn release_held_mutexes(held_mutexes: &mut Vec<HeldMutex>) {
+    for HeldMutex(state) in held_mutexes.drain(..) {
+        let state = unsafe { &*state };
+
+        // The priorities of the thread are forgotten separately.
+        state.priority_lent.store(false, Ordering::SeqCst);
+        state.holder.store(UNLOCKED, Ordering::SeqCst);
+        state.waiting.notify_all();
+    }
+}
+
+/// Encodes the given thread as the holder of a mutex.
+///
+/// Both IDs are kept in one word, so that the holder can be changed
+/// atomically. The process ID is offset by one, so that no thread is encoded
+/// as `UNLOCKED`.
+fn holder_value(pid: ProcessID, id: ThreadID) -> usize {
+    let pid: usize = pid.into();
+    let id: usize = id.into();
+
+    assert!(
+        pid < (1 << ID_BITS) - 1 && id < 1 << ID_BITS,
+        "The IDs of a thread locking a blocking mutex are too large."
+    );
+
+    (pid + 1) << ID_BITS | id
+}
+
+/// Returns the thread encoded in the given holder value.
+fn holder_thread(holder: usize) -> (ProcessID, ThreadID) {
+    let pid = (holder >> ID_BITS) - 1;
+    let id = holder & ((1 << ID_BITS) - 1);
+
+    (pid.into(), id.into())
+}
+
+/// Tests for the encoding of mutex holders.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that holders are decoded to the thread they were encoded from.
+    #[test]
+    fn test_holder_encoding() {
+        let threads: [(usize, usize); 4] =
+            [(0, 0), (0, 3), (7, 1), ((1 << ID_BITS) - 2, (1 << ID_BITS) - 1)];
+
+        for &(pid, id) in threads.iter() {
+            let holder = holder_value(pid.into(), id.into());
+
+            assert_ne!(holder, UNLOCKED);
+            assert_eq!(holder_thread(holder), (pid.into(), id.into()));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Records the locks held by each CPU.
+//!
+//! There is no unwinding, so a thread that panics while holding a lock never
+//! releases it and everybody else waiting for the lock spins forever. To make
+//! such deadlocks easier to debug, the panic handler prints the locks held by
+//! the panicking CPU.
+//!
+//! Locks are identified by their address. The records are only kept in debug
+//! builds.
+
+use crate::arch::{self, Architecture};
+
+/// The maximum number of CPUs whose locks are recorded.
+const MAX_CPUS: usize = 64;
+
+/// The maximum number of locks recorded per CPU.
+const MAX_RECORDED_LOCKS: usize = 16;
+
+/// The locks held by a CPU.
+#[derive(Clone, Copy)]
+struct HeldLocks {
+    /// The number of held locks, which may exceed the number of recorded ones.
+    count: usize,
+    /// The addresses of the held locks, in the order they were acquired.
+    locks: [usize; MAX_RECORDED_LOCKS]
+}
+
+impl HeldLocks {
+    /// Creates an empty record.
+    const fn new() -> HeldLocks {
+        HeldLocks {
+            count: 0,
+            locks: [0; MAX_RECORDED_LOCKS]
+        }
+    }
+
+    /// Records that the lock was acquired.
+    fn push(&mut self, lock: usize) {
+        if self.count < MAX_RECORDED_LOCKS {
+            self.locks[self.count] = lock;
+        }
+        self.count += 1;
+    }
+
+    /// Records that the lock was released.
+    ///
+    /// Locks don't have to be released in the order they were acquired.
+    fn remove(&mut self, lock: usize) {
+        let recorded = self.count.min(MAX_RECORDED_LOCKS);
+
+        if let Some(index) = self.locks[..recorded].iter().rposition(|&held| held == lock) {
+            for i in index..recorded - 1 {
+                self.locks[i] = self.locks[i + 1];
+            }
+            self.locks[recorded - 1] = 0;
+        } else if self.count <= MAX_RECORDED_LOCKS {
+            // The lock wasn't recorded, because it was forcibly unlocked.
+            return;
+        }
+
+        self.count -= 1;
+    }
+
+    /// Returns the recorded locks.
+    fn recorded(&self) -> &[usize] {
+        &self.locks[..self.count.min(MAX_RECORDED_LOCKS)]
+    }
+}
+
+/// The locks held by each CPU.
+///
+/// Each CPU only accesses its own entry, while it has preemption disabled.
+static mut HELD_LOCKS: [HeldLocks; MAX_CPUS] = [HeldLocks::new(); MAX_CPUS];
+
+/// Returns the record of the current CPU.
+///
+/// # Safety
+/// - Preemption must be disabled while the record is used.
+unsafe fn current_cpu_locks() -> Option<&'static mut HeldLocks> {
+    HELD_LOCKS.get_mut(arch::Current::get_cached_cpu_id())
+}
+
+/// Records that the current CPU acquired the lock at the given address.
+///
+/// # Safety
+/// - Preemption must be disabled.
+pub unsafe fn acquired(lock: usize) {
+    if cfg!(debug_assertions) {
+        if let Some(held_locks) = current_cpu_locks() {
+            held_locks.push(lock);
+        }
+    }
+}
+
+/// Records that the current CPU released the lock at the given address.
+///
+/// # Safety
+/// - Preemption must be disabled.
+pub unsafe fn released(lock: usize) {
+    if cfg!(debug_assertions) {
+        if let Some(held_locks) = current_cpu_locks() {
+            held_locks.remove(lock);
+        }
+    }
+}
+
+/// Prints the locks held by the current CPU.
+///
+/// # Safety
+/// - Preemption must be disabled.
+pub unsafe fn print_held_locks() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    if let Some(held_locks) = current_cpu_locks() {
+        // Printing itself takes locks, so copy the record first.
+        let held_locks = *held_locks;
+
+        error!("CPU {} holds {} locks:", arch::Current::get_cached_cpu_id(), held_locks.count);
+        for lock in held_locks.recorded() {
+            error!("    {:#x}", lock);
+        }
+        if held_locks.count > MAX_RECORDED_LOCKS {
+            error!("    ... and {} more", held_locks.count - MAX_RECORDED_LOCKS);
+        }
+    }
+}
+
+/// Tests for the lock records.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that locks released out of order are removed.
+    #[test]
+    fn test_out_of_order_release() {
+        let mut held_locks = HeldLocks::new();
+
+        held_locks.push(1);
+        held_locks.push(2);
+        held_locks.push(3);
+        held_locks.remove(2);
+
+        assert_eq!(held_locks.recorded(), &[1, 3]);
+        assert_eq!(held_locks.locks[2], 0);
+    }
+
+    /// Tests that holding more locks than can be recorded is handled.
+    #[test]
+    fn test_overflow() {
+        let mut held_locks = HeldLocks::new();
+
+        for lock in 0..MAX_RECORDED_LOCKS + 2 {
+            held_locks.push(lock);
+        }
+        assert_eq!(held_locks.recorded().len(), MAX_RECORDED_LOCKS);
+
+        held_locks.remove(MAX_RECORDED_LOCKS + 1);
+        held_locks.remove(MAX_RECORDED_LOCKS);
+        held_locks.remove(0);
+
+        assert_eq!(held_locks.count, MAX_RECORDED_LOCKS - 1);
+        assert_eq!(held_locks.recorded()[0], 1);
+    }
+}
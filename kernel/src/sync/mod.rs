@@ -1,9 +1,25 @@
 //! Handles synchronization within the kernel.
+//!
+//! # Choosing a lock
+//! `Mutex` spins with preemption disabled. It is the only lock that can be
+//! used by interrupt handlers and the scheduler, and while holding another
+//! `Mutex`. It is used for the scheduler lists, the process list, memory
+//! management and the device drivers, whose critical sections are short.
+//!
+//! `BlockingMutex` spins briefly and then blocks the waiting thread, while
+//! the holder stays preemptible. It may only be used by threads with
+//! preemption enabled and suits critical sections that can take a while.
+//! It is used for the open files of processes.
 
+pub mod blocking_mutex;
+pub mod held_locks;
 pub mod mutex;
 pub mod time;
+pub mod wait_queue;
 
+pub use self::blocking_mutex::BlockingMutex;
 pub use self::mutex::Mutex;
+pub use self::wait_queue::{Interrupted, WaitQueue};
 use crate::arch::{self, Architecture};
 
 /// Saves the state when disabling preemtion, so it can be restored later.
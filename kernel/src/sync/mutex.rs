@@ -3,8 +3,10 @@
 //! This is a modification of the Mutex code from the spin crate (see
 //! https://crates.io/crates/spin).
 
+use super::held_locks;
 use super::{cpu_relax, disable_preemption, restore_preemption_state, PreemptionState};
 use core::cell::UnsafeCell;
+use core::cmp::min;
 use core::default::Default;
 use core::fmt;
 use core::marker::Sync;
@@ -12,6 +14,27 @@ use core::ops::{Deref, DerefMut, Drop};
 use core::option::Option::{self, None, Some};
 use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 
+/// The number of times a contended lock is checked before backing off.
+const BACKOFF_THRESHOLD: usize = 64;
+
+/// The maximum number of pauses between checks of a contended lock.
+const MAX_BACKOFF_PAUSES: usize = 256;
+
+/// Returns the number of pauses before checking a contended lock again.
+///
+/// The lock is checked after every pause at first, so it is acquired
+/// promptly if it's only held briefly. Afterwards the delay doubles with every
+/// check, which reduces the traffic on the cache line of the lock.
+fn backoff_pauses(checks: usize) -> usize {
+    if checks < BACKOFF_THRESHOLD {
+        1
+    } else {
+        let exponent = min(checks - BACKOFF_THRESHOLD, 31) as u32;
+
+        min(1usize << exponent, MAX_BACKOFF_PAUSES)
+    }
+}
+
 /// This type provides MUTual EXclusion based on spinning.
 ///
 /// # Description
@@ -25,7 +48,8 @@ use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 /// - When the runtime is present, it will call the deschedule function when
 /// appropriate
 /// - No lock poisoning. When a fail occurs when the lock is held, no
-/// guarantees are made
+/// guarantees are made, but the panic handler reports the held locks in
+/// debug builds
 ///
 /// When calling rust functions from bare threads, such as C `pthread`s, this
 /// lock will be very
@@ -91,16 +115,26 @@ impl<T: ?Sized> Mutex<T> {
             }
 
             // Wait until the lock looks unlocked before retrying
+            let mut checks = 0;
             while self.lock.load(Ordering::Relaxed) {
-                cpu_relax();
+                for _ in 0..backoff_pauses(checks) {
+                    cpu_relax();
+                }
+                checks += 1;
             }
         }
 
         unsafe {
             *self.preemption_state.get() = preemption_state;
+            held_locks::acquired(self.address());
         }
     }
 
+    /// Returns the address that identifies this lock.
+    fn address(&self) -> usize {
+        &self.lock as *const AtomicBool as usize
+    }
+
     /// Locks the spinlock and returns a guard.
     ///
     /// The returned value may be dereferenced for data access
@@ -127,6 +161,16 @@ impl<T: ?Sized> Mutex<T> {
         &*self.data.get()
     }
 
+    /// Releases the lock, even though it is held by someone else.
+    ///
+    /// # Safety
+    /// - Only use this if the holder of the lock will never run again, like
+    /// on the panic path.
+    /// - The preemption state of the holder is not restored.
+    pub unsafe fn force_unlock(&self) {
+        self.lock.store(false, Ordering::Release);
+    }
+
     /// Tries to lock the mutex. If it is already locked, it will return None.
     /// Otherwise it returns
     /// a guard within Some.
@@ -137,6 +181,7 @@ impl<T: ?Sized> Mutex<T> {
         if lock_switch {
             unsafe {
                 *self.preemption_state.get() = preemption_state;
+                held_locks::acquired(self.address());
             }
             Some(MutexGuard {
                 lock: &self.lock,
@@ -184,9 +229,30 @@ impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
     /// The dropping of the MutexGuard will release the lock it was created
     /// from.
     fn drop(&mut self) {
+        unsafe {
+            held_locks::released(self.lock as *const AtomicBool as usize);
+        }
         self.lock.store(false, Ordering::Release);
         unsafe {
             restore_preemption_state(self.preemption_state);
         }
     }
 }
+
+/// Tests for the mutex.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the backoff only starts after the threshold and is capped.
+    #[test]
+    fn test_backoff_pauses() {
+        assert_eq!(backoff_pauses(0), 1);
+        assert_eq!(backoff_pauses(BACKOFF_THRESHOLD - 1), 1);
+        assert_eq!(backoff_pauses(BACKOFF_THRESHOLD), 1);
+        assert_eq!(backoff_pauses(BACKOFF_THRESHOLD + 1), 2);
+        assert_eq!(backoff_pauses(BACKOFF_THRESHOLD + 4), 16);
+        assert_eq!(backoff_pauses(BACKOFF_THRESHOLD + 100), MAX_BACKOFF_PAUSES);
+        assert_eq!(backoff_pauses(usize::max_value()), MAX_BACKOFF_PAUSES);
+    }
+}
@@ -0,0 +1,225 @@
+//! Provides queues for threads waiting on events.
+
+use alloc::vec_deque::VecDeque;
+use alloc::Vec;
+use crate::multitasking::scheduler::{block_current_thread, block_current_thread_until, wake_thread};
+use crate::multitasking::{ProcessID, ThreadID, CURRENT_THREAD};
+use crate::sync::time::Timestamp;
+use crate::sync::Mutex;
+
+/// The reasons a wait can end without its condition becoming true.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Interrupted {
+    /// The deadline of the wait passed.
+    TimedOut,
+    /// The process of the waiting thread was killed.
+    ///
+    /// The thread has to return to the syscall boundary, where it exits.
+    Killed
+}
+
+/// A wait queue that a thread is waiting in.
+///
+/// Threads only wait while the function that borrowed the queue runs. A
+/// thread reclaimed during the wait never returns from that function, so the
+/// queue outlives the thread.
+pub struct QueueLink(*const WaitQueue);
+
+// The queue is only used to remove the thread from it, which locks the queue.
+unsafe impl Send for QueueLink {}
+
+/// A queue of threads waiting for an event.
+pub struct WaitQueue {
+    /// The threads waiting in this queue, in the order they started waiting.
+    waiting: Mutex<VecDeque<(ProcessID, ThreadID)>>
+}
+
+impl WaitQueue {
+    /// Creates a new empty wait queue.
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiting: Mutex::new(VecDeque::new())
+        }
+    }
+
+    /// Blocks the current thread until the condition is true.
+    ///
+    /// The condition is checked after the thread was added to the queue, so no
+    /// notification can be missed in between. Waiting ends early if the
+    /// process of the thread is killed.
+    ///
+    /// # Note
+    /// No locks may be held while waiting.
+    pub fn wait_until<F>(&self, condition: F) -> Result<(), Interrupted>
+    where
+        F: FnMut() -> bool
+    {
+        self.wait(condition, None)
+    }
+
+    /// Blocks the current thread until the condition is true or the deadline
+    /// passed.
+    ///
+    /// The condition is checked once more after the deadline passed, so an
+    /// event happening right at the deadline isn't reported as a timeout.
+    ///
+    /// # Note
+    /// No locks may be held while waiting.
+    pub fn wait_until_timeout<F>(
+        &self,
+        condition: F,
+        deadline: Timestamp
+    ) -> Result<(), Interrupted>
+    where
+        F: FnMut() -> bool
+    {
+        self.wait(condition, Some(deadline))
+    }
+
+    /// Blocks the current thread until the condition is true or the optional
+    /// deadline passed.
+    fn wait<F>(&self, condition: F, deadline: Option<Timestamp>) -> Result<(), Interrupted>
+    where
+        F: FnMut() -> bool
+    {
+        wait_on_any(&[self], condition, deadline)
+    }
+
+    /// Wakes up the thread that has been waiting the longest.
+    ///
+    /// Returns true if a thread was woken up.
+    pub fn notify_one(&self) -> bool {
+        let thread = self.waiting.lock().pop_front();
+
+        match thread {
+            Some((pid, id)) => {
+                wake_thread(pid, id);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Wakes up all waiting threads.
+    pub fn notify_all(&self) {
+        while self.notify_one() {}
+    }
+
+    /// Removes the given thread from the queue, if it's still in there.
+    fn remove(&self, thread: (ProcessID, ThreadID)) {
+        let mut waiting = self.waiting.lock();
+
+        if let Some(index) = waiting.iter().position(|&waiting_thread| waiting_thread == thread) {
+            waiting.remove(index);
+        }
+    }
+}
+
+/// Blocks the current thread until the condition is true or the optional
+/// deadline passed.
+///
+/// The thread waits in all given queues at once, so a notification on any of
+/// them makes it recheck the condition. A queue must not be given twice.
+/// Waiting ends early if the process of the thread is killed.
+///
+/// # Note
+/// No locks may be held while waiting.
+pub fn wait_on_any<F>(
+    queues: &[&WaitQueue],
+    condition: F,
+    deadline: Option<Timestamp>
+) -> Result<(), Interrupted>
+where
+    F: FnMut() -> bool
+{
+    wait_on_queues(queues, condition, deadline, true)
+}
+
+/// Blocks the current thread until the condition is true, even if its
+/// process is killed in the meantime.
+///
+/// This is only meant for waits that end soon anyway, like waiting for a
+/// lock. See `wait_on_any` for the rest.
+pub fn wait_on_any_unkillable<F>(queues: &[&WaitQueue], condition: F)
+where
+    F: FnMut() -> bool
+{
+    wait_on_queues(queues, condition, None, false)
+        .expect("An unkillable wait without a deadline was interrupted.");
+}
+
+/// Blocks the current thread until the condition is true, the optional
+/// deadline passed or, if the wait is killable, its process was killed.
+fn wait_on_queues<F>(
+    queues: &[&WaitQueue],
+    mut condition: F,
+    deadline: Option<Timestamp>,
+    killable: bool
+) -> Result<(), Interrupted>
+where
+    F: FnMut() -> bool
+{
+    let thread = current_thread();
+    let remove_from_all = || {
+        for queue in queues {
+            queue.remove(thread);
+        }
+    };
+
+    // The thread is removed from the queues if it is reclaimed while waiting.
+    CURRENT_THREAD.lock().wait_queues.extend(
+        queues
+            .iter()
+            .map(|&queue| QueueLink(queue as *const WaitQueue))
+    );
+
+    let result = loop {
+        for queue in queues {
+            queue.waiting.lock().push_back(thread);
+        }
+
+        if condition() {
+            break Ok(());
+        }
+
+        if killable && CURRENT_THREAD.lock().killed {
+            break Err(Interrupted::Killed);
+        }
+
+        match deadline {
+            Some(deadline) => {
+                if Timestamp::get_current() >= deadline {
+                    break Err(Interrupted::TimedOut);
+                }
+
+                block_current_thread_until(deadline);
+            },
+            None => block_current_thread()
+        }
+
+        // Only one of the queues woke the thread up.
+        remove_from_all();
+    };
+
+    remove_from_all();
+    CURRENT_THREAD.lock().wait_queues.clear();
+
+    result
+}
+
+/// Removes the given thread from the queues it was waiting in.
+///
+/// This is called when the thread is reclaimed, so that notifications don't
+/// try to wake up a thread that doesn't exist anymore.
+pub fn leave_queues(thread: (ProcessID, ThreadID), wait_queues: &mut Vec<QueueLink>) {
+    for QueueLink(queue) in wait_queues.drain(..) {
+        unsafe { (*queue).remove(thread) };
+    }
+}
+
+/// Returns the identifiers of the current thread.
+fn current_thread() -> (ProcessID, ThreadID) {
+    let thread = CURRENT_THREAD.lock();
+
+    (thread.pid, thread.id)
+}
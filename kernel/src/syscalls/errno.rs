@@ -0,0 +1,74 @@
+//! Defines the error numbers returned by system calls.
+//!
+//! Failing system calls return the negated error number. The numbers match
+//! the ones used by Linux.
+
+use crate::file_handle::FileError;
+use crate::multitasking::fd_table::FdError;
+
+/// The operation isn't permitted.
+pub const EPERM: isize = 1;
+
+/// The file or directory doesn't exist.
+pub const ENOENT: isize = 2;
+
+/// The process doesn't exist.
+pub const ESRCH: isize = 3;
+
+/// A blocking call was interrupted, because the process was killed.
+pub const EINTR: isize = 4;
+
+/// The arguments of a new process are too long.
+pub const E2BIG: isize = 7;
+
+/// The file descriptor isn't open.
+pub const EBADF: isize = 9;
+
+/// The process has no matching child.
+pub const ECHILD: isize = 10;
+
+/// There is not enough memory, or the memory limit was reached.
+pub const ENOMEM: isize = 12;
+
+/// An address passed to the kernel is invalid.
+pub const EFAULT: isize = 14;
+
+/// A path component isn't a directory.
+pub const ENOTDIR: isize = 20;
+
+/// An argument is invalid.
+pub const EINVAL: isize = 22;
+
+/// The process has too many open files.
+pub const EMFILE: isize = 24;
+
+/// The read end of the pipe is closed.
+pub const EPIPE: isize = 32;
+
+/// The buffer is too small for the result.
+pub const ERANGE: isize = 34;
+
+/// The deadline of a blocking operation passed.
+pub const ETIMEDOUT: isize = 110;
+
+/// Returns the negated error number of the file descriptor error.
+pub fn from_fd_error(error: FdError) -> isize {
+    match error {
+        FdError::BadDescriptor => -EBADF,
+        FdError::TooManyFiles => -EMFILE
+    }
+}
+
+/// Returns the negated error number of the file error.
+pub fn from_file_error(error: FileError) -> isize {
+    match error {
+        FileError::FileNotFound => -ENOENT,
+        FileError::NotADirectory => -ENOTDIR,
+        FileError::ReadOnly => -EPERM,
+        FileError::SeekBeforeStart
+        | FileError::SeekPastEnd
+        | FileError::InvalidFilesystem
+        | FileError::InvalidPath
+        | FileError::AlreadyMounted => -EINVAL
+    }
+}
@@ -1,14 +1,41 @@
 //! This module handles system calls.
 
-use crate::arch::schedule;
+pub mod errno;
+
+use alloc::arc::Arc;
+use alloc::{String, Vec};
+use crate::arch::{self, schedule, Architecture};
+use core::cmp::min;
+use core::mem::{align_of, size_of};
+use core::slice;
 use core::time::Duration;
 use crate::elf;
+use crate::interrupts::InterruptCount;
+use crate::elf::ElfError;
+use crate::file_handle::{FileError, FileHandle, SeekFrom};
+use crate::fs::vfs::{self, DirectoryEntryInfo};
+use crate::initramfs;
+use crate::io;
+use crate::io::line_discipline;
+use crate::io::pipe::PipeError;
+use crate::io::poll::{PollEvents, PollSource};
+use crate::memory::address_space::{AddressSpace, Mapping, MemoryUsage};
 use crate::memory::{Address, MemoryArea, VirtualAddress};
-use crate::multitasking::scheduler::READY_LIST;
-use crate::multitasking::{get_current_process, CURRENT_THREAD, TCB};
+use crate::multitasking;
+use crate::multitasking::fd_table::{FdTable, OpenFile, MAX_FILE_DESCRIPTORS};
+use crate::multitasking::scheduler;
+use crate::multitasking::{
+    get_current_process, list_processes as process_list, KillError, ProcessGroupError, ProcessInfo,
+    ThreadID, WaitError, CURRENT_THREAD, SIGKILL, TCB
+};
 use crate::sync::time::Timestamp;
+use crate::sync::{BlockingMutex, Interrupted};
 
 /// This function accepts the syscalls and calls the corresponding handlers.
+///
+/// A thread whose kernel stack is almost exhausted doesn't get to run the
+/// syscall. Before returning to user mode, a requested reschedule is
+/// performed.
 pub fn syscall_handler(
     num: u16,
     arg1: usize,
@@ -17,12 +44,41 @@ pub fn syscall_handler(
     arg4: usize,
     arg5: usize,
     arg6: usize
+) -> isize {
+    multitasking::check_kernel_stack();
+
+    let result = dispatch_syscall(num, arg1, arg2, arg3, arg4, arg5, arg6);
+
+    // Threads of killed processes only run to unwind their syscall.
+    multitasking::exit_if_killed();
+
+    scheduler::reschedule_if_needed();
+
+    result
+}
+
+/// Calls the handler of the given syscall.
+fn dispatch_syscall(
+    num: u16,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    arg6: usize
 ) -> isize {
     match num {
         0 => print_char(arg1 as u8 as char),
-        1 => kill_process(),
+        1 => multitasking::exit_current_thread(),
         2 => return_pid(),
-        3 => exec(VirtualAddress::from_usize(arg1), arg2),
+        3 => exec(
+            VirtualAddress::from_usize(arg1),
+            arg2,
+            VirtualAddress::from_usize(arg3),
+            arg4,
+            VirtualAddress::from_usize(arg5),
+            arg6
+        ),
         4 => sleep(arg1, arg2),
         5 => create_thread(
             VirtualAddress::from_usize(arg1),
@@ -33,6 +89,44 @@ pub fn syscall_handler(
             arg6
         ),
         6 => kill_thread(),
+        7 => read(arg1, VirtualAddress::from_usize(arg2), arg3),
+        8 => set_raw_mode(arg1 != 0),
+        9 => list_processes(VirtualAddress::from_usize(arg1), arg2),
+        10 => return_tid(),
+        11 => join_thread(arg1),
+        12 => dup(arg1),
+        13 => dup2(arg1, arg2),
+        14 => pipe(VirtualAddress::from_usize(arg1)),
+        15 => write(arg1, VirtualAddress::from_usize(arg2), arg3),
+        16 => close(arg1),
+        17 => set_tls_base(VirtualAddress::from_usize(arg1)),
+        18 => get_tls_base(),
+        19 => multitasking::exit_current_process(arg1 as i32),
+        20 => map_initramfs_file(
+            VirtualAddress::from_usize(arg1),
+            arg2,
+            VirtualAddress::from_usize(arg3)
+        ),
+        21 => memory_usage(arg1, VirtualAddress::from_usize(arg2)),
+        22 => set_memory_limit(arg1),
+        23 => kill(arg1 as isize, arg2),
+        24 => set_process_group(arg1, arg2),
+        25 => wait(arg1, VirtualAddress::from_usize(arg2), arg3, arg4),
+        26 => read_timeout(arg1, VirtualAddress::from_usize(arg2), arg3, arg4),
+        27 => poll(VirtualAddress::from_usize(arg1), arg2, arg3 as isize),
+        28 => list_mappings(VirtualAddress::from_usize(arg1), arg2),
+        29 => interrupt_counts(VirtualAddress::from_usize(arg1), arg2),
+        30 => chdir(VirtualAddress::from_usize(arg1), arg2),
+        31 => getcwd(VirtualAddress::from_usize(arg1), arg2),
+        32 => read_directory(
+            VirtualAddress::from_usize(arg1),
+            arg2,
+            VirtualAddress::from_usize(arg3),
+            arg4,
+            arg5
+        ),
+        33 => list_threads(arg1, VirtualAddress::from_usize(arg2), arg3),
+        34 => detach_thread(arg1),
         _ => unknown_syscall(num)
     }
 }
@@ -42,13 +136,593 @@ fn print_char(character: char) -> isize {
     0
 }
 
-fn kill_process() -> isize {
-    get_current_process().kill();
+/// The maximum number of bytes a single read or write transfers.
+///
+/// The data goes through a kernel buffer, so longer transfers are shortened.
+const MAX_IO_LENGTH: usize = 0x10000;
+
+fn read(fd: usize, buffer_ptr: VirtualAddress, length: usize) -> isize {
+    read_with_deadline(fd, buffer_ptr, length, None)
+}
+
+/// Reads like `read`, but fails with `ETIMEDOUT` if no data arrived within
+/// the given number of milliseconds.
+fn read_timeout(fd: usize, buffer_ptr: VirtualAddress, length: usize, timeout_ms: usize) -> isize {
+    read_with_deadline(fd, buffer_ptr, length, deadline_after(timeout_ms))
+}
+
+/// Reads from the file descriptor, waiting at most until the optional
+/// deadline.
+fn read_with_deadline(
+    fd: usize,
+    buffer_ptr: VirtualAddress,
+    length: usize,
+    deadline: Option<Timestamp>
+) -> isize {
+    let (buffer_valid, file) = {
+        let pcb = get_current_process();
+
+        (
+            is_writable_user_area(&pcb.address_space, MemoryArea::new(buffer_ptr, length)),
+            pcb.fd_table.get(fd)
+        )
+    };
+
+    let file = match file {
+        Ok(file) if buffer_valid => file,
+        Ok(_) => return -errno::EFAULT,
+        Err(_) => return -1
+    };
+
+    // The user memory may change while the thread blocks, so the data is
+    // read into a kernel buffer first.
+    let mut buffer = Vec::with_capacity(min(length, MAX_IO_LENGTH));
+    buffer.resize(min(length, MAX_IO_LENGTH), 0);
+    let mut file = file.lock();
+
+    let pipe = match *file {
+        OpenFile::Console => None,
+        OpenFile::File(ref mut handle) => {
+            let count = read_file(&mut **handle, &mut buffer);
+            return copy_read_bytes(buffer_ptr, &buffer, count);
+        },
+        OpenFile::PipeReader(ref reader) => Some(reader.pipe()),
+        OpenFile::PipeWriter(_) => return -errno::EBADF
+    };
+
+    // Reading blocks, so it happens without holding the lock.
+    drop(file);
+
+    let result = match pipe {
+        Some(pipe) => pipe.read_with_deadline(&mut buffer, deadline),
+        None => line_discipline::read_with_deadline(&mut buffer, deadline)
+    };
+
+    match result {
+        Ok(count) => copy_read_bytes(buffer_ptr, &buffer, count as isize),
+        Err(Interrupted::TimedOut) => -errno::ETIMEDOUT,
+        Err(Interrupted::Killed) => -errno::EINTR
+    }
+}
+
+/// Copies the first `count` bytes that were read into the buffer to the user
+/// buffer at the given address.
+///
+/// Returns `count`, which is returned as it is if it's an error.
+fn copy_read_bytes(buffer_ptr: VirtualAddress, buffer: &[u8], count: isize) -> isize {
+    if count <= 0 {
+        return count;
+    }
+
+    if write_user_bytes(buffer_ptr, &buffer[..count as usize]) {
+        count
+    } else {
+        -errno::EFAULT
+    }
+}
+
+fn write(fd: usize, buffer_ptr: VirtualAddress, length: usize) -> isize {
+    let file = match get_current_process().fd_table.get(fd) {
+        Ok(file) => file,
+        Err(error) => return errno::from_fd_error(error)
+    };
+
+    // The user memory may change while the thread blocks, so the data is
+    // copied first.
+    let buffer = match read_user_bytes(MemoryArea::new(buffer_ptr, min(length, MAX_IO_LENGTH))) {
+        Ok(buffer) => buffer,
+        Err(error) => return error
+    };
+    let mut file = file.lock();
+
+    let pipe = match *file {
+        OpenFile::Console => {
+            // Multi-byte characters have to be printed as a whole.
+            print!("{}", String::from_utf8_lossy(&buffer));
+            return buffer.len() as isize;
+        },
+        OpenFile::File(ref mut handle) => {
+            return match handle.write(&buffer) {
+                Ok(()) => buffer.len() as isize,
+                Err(_) => -1
+            }
+        },
+        OpenFile::PipeWriter(ref writer) => writer.pipe(),
+        OpenFile::PipeReader(_) => return -errno::EBADF
+    };
+
+    // Writing blocks, so it happens without holding the lock.
+    drop(file);
+
+    match pipe.write(&buffer) {
+        Ok(written) => written as isize,
+        Err(PipeError::BrokenPipe) => -errno::EPIPE
+    }
+}
+
+fn pipe(fds_ptr: VirtualAddress) -> isize {
+    let mut pcb = get_current_process();
+    let fds_area = MemoryArea::new(fds_ptr, 2 * size_of::<usize>());
+
+    if !is_writable_user_area(&pcb.address_space, fds_area) {
+        return -errno::EFAULT;
+    }
+
+    if fds_ptr.as_usize() % align_of::<usize>() != 0 {
+        return -1;
+    }
+
+    let (reader, writer) = io::pipe::pipe();
+
+    let read_fd = match pcb
+        .fd_table
+        .alloc_fd(Arc::new(BlockingMutex::new(OpenFile::PipeReader(reader))))
+    {
+        Ok(fd) => fd,
+        Err(error) => return errno::from_fd_error(error)
+    };
+    let write_fd = match pcb
+        .fd_table
+        .alloc_fd(Arc::new(BlockingMutex::new(OpenFile::PipeWriter(writer))))
+    {
+        Ok(fd) => fd,
+        Err(error) => {
+            // Nobody can wait on the new pipe yet, so dropping the read end
+            // while the process is locked wakes no one.
+            pcb.fd_table.close(read_fd).unwrap();
+            return errno::from_fd_error(error);
+        }
+    };
+
+    let fds = unsafe { slice::from_raw_parts_mut(fds_ptr.as_mut_ptr::<usize>(), 2) };
+    fds[0] = read_fd;
+    fds[1] = write_fd;
+
+    0
+}
+
+/// An entry of the array passed to `poll`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PollFd {
+    /// The file descriptor to poll.
+    fd: u32,
+    /// The requested events.
+    events: u16,
+    /// The events that are ready, filled in by the kernel.
+    ready_events: u16
+}
+
+/// Waits until any of the given file descriptors is ready.
+///
+/// A negative timeout waits forever. Returns the number of entries with
+/// ready events, which is 0 if the timeout expired.
+fn poll(fds_ptr: VirtualAddress, count: usize, timeout_ms: isize) -> isize {
+    if count > MAX_FILE_DESCRIPTORS {
+        return -errno::EINVAL;
+    }
+
+    let fds_area = MemoryArea::new(fds_ptr, count * size_of::<PollFd>());
+    let mut fds = Vec::with_capacity(count);
+    let files: Vec<_> = {
+        let mut pcb = get_current_process();
+
+        if !is_writable_user_area(&pcb.address_space, fds_area)
+            || fds_ptr.as_usize() % align_of::<PollFd>() != 0
+        {
+            return -errno::EFAULT;
+        }
+
+        // The entries are copied, so they can't change while polling.
+        for index in 0..count {
+            let address = fds_ptr + index * size_of::<PollFd>();
+
+            match unsafe { pcb.address_space.read_val::<PollFd>(address) } {
+                Some(poll_fd) => fds.push(poll_fd),
+                None => return -errno::EFAULT
+            }
+        }
+
+        fds.iter()
+            .map(|poll_fd| pcb.fd_table.get(poll_fd.fd as usize).ok())
+            .collect()
+    };
+
+    let sources: Vec<_> = files
+        .iter()
+        .zip(fds.iter())
+        .map(|(file, poll_fd)| {
+            let source = match *file {
+                Some(ref file) => file.lock().poll_source(),
+                None => PollSource::Invalid
+            };
+
+            (source, PollEvents::from_bits_truncate(poll_fd.events))
+        })
+        .collect();
+
+    // Only the sources are kept while polling. Keeping the open files would
+    // keep the ends of a pipe open, if their descriptors are closed meanwhile.
+    drop(files);
+
+    let deadline = if timeout_ms < 0 {
+        None
+    } else {
+        deadline_after(timeout_ms as usize)
+    };
+
+    let ready = io::poll::poll(&sources, deadline);
+
+    // The memory is checked again, as it might have been unmapped meanwhile.
+    for (index, (poll_fd, events)) in fds.iter_mut().zip(ready.iter()).enumerate() {
+        poll_fd.ready_events = events.bits();
+
+        if !write_user_value(fds_ptr + index * size_of::<PollFd>(), *poll_fd) {
+            return -errno::EFAULT;
+        }
+    }
+
+    ready.iter().filter(|events| !events.is_empty()).count() as isize
+}
+
+fn close(fd: usize) -> isize {
+    // The file is dropped after the process is unlocked, as closing the last
+    // end of a pipe wakes the threads waiting on it.
+    let result = get_current_process().fd_table.close(fd);
+
+    match result {
+        Ok(_) => 0,
+        Err(error) => errno::from_fd_error(error)
+    }
+}
+
+/// Reads as many bytes as are left in the file into the buffer.
+fn read_file(handle: &mut FileHandle, buffer: &mut [u8]) -> isize {
+    let position = match handle.seek(SeekFrom::Current(0)) {
+        Ok(position) => position,
+        Err(_) => return -1
+    };
+    let remaining = handle.len().saturating_sub(position);
+    let length = min(remaining, buffer.len() as u64) as usize;
+
+    match handle.read_at(&mut buffer[..length], position) {
+        Ok(()) => {
+            // Reading doesn't advance the seek position by itself.
+            let _ = handle.seek(SeekFrom::Start(position + length as u64));
+            length as isize
+        },
+        Err(_) => -1
+    }
+}
+
+fn dup(fd: usize) -> isize {
+    let mut pcb = get_current_process();
+
+    let file = match pcb.fd_table.get(fd) {
+        Ok(file) => file,
+        Err(error) => return errno::from_fd_error(error)
+    };
+
+    match pcb.fd_table.alloc_fd(file) {
+        Ok(new_fd) => new_fd as isize,
+        Err(error) => errno::from_fd_error(error)
+    }
+}
+
+fn dup2(old_fd: usize, new_fd: usize) -> isize {
+    let result = {
+        let mut pcb = get_current_process();
+
+        let file = match pcb.fd_table.get(old_fd) {
+            Ok(file) => file,
+            Err(error) => return errno::from_fd_error(error)
+        };
+
+        // Replacing the descriptor with itself would close nothing.
+        if old_fd == new_fd {
+            return new_fd as isize;
+        }
+
+        pcb.fd_table.replace(new_fd, file)
+    };
+
+    // Like in `close`, the replaced file is only dropped here.
+    match result {
+        Ok(_) => new_fd as isize,
+        Err(error) => errno::from_fd_error(error)
+    }
+}
+
+fn set_raw_mode(raw: bool) -> isize {
+    line_discipline::set_raw_mode(raw);
+    0
+}
+
+fn list_processes(buffer_ptr: VirtualAddress, count: usize) -> isize {
+    let buffer_size = match count.checked_mul(size_of::<ProcessInfo>()) {
+        Some(size) => size,
+        None => return -1
+    };
+    let buffer_valid = is_writable_user_area(
+        &get_current_process().address_space,
+        MemoryArea::new(buffer_ptr, buffer_size)
+    );
+
+    if !buffer_valid {
+        return -errno::EFAULT;
+    }
+
+    if buffer_ptr.as_usize() % align_of::<ProcessInfo>() != 0 {
+        return -1;
+    }
+
+    let processes = process_list();
+    let buffer =
+        unsafe { slice::from_raw_parts_mut(buffer_ptr.as_mut_ptr::<ProcessInfo>(), count) };
+
+    for (entry, info) in buffer.iter_mut().zip(processes.iter()) {
+        *entry = *info;
+    }
+
+    processes.len() as isize
+}
+
+/// Fills the buffer with the IDs of the threads of the process with the given
+/// ID.
+///
+/// Returns the total number of threads, which may be larger than the buffer.
+fn list_threads(pid: usize, buffer_ptr: VirtualAddress, count: usize) -> isize {
+    let buffer_size = match count.checked_mul(size_of::<ThreadID>()) {
+        Some(size) => size,
+        None => return -errno::EINVAL
+    };
+    let buffer_valid = is_writable_user_area(
+        &get_current_process().address_space,
+        MemoryArea::new(buffer_ptr, buffer_size)
+    );
+
+    if !buffer_valid || buffer_ptr.as_usize() % align_of::<ThreadID>() != 0 {
+        return -errno::EFAULT;
+    }
+
+    let threads = match multitasking::list_threads(pid.into()) {
+        Some(threads) => threads,
+        None => return -errno::ESRCH
+    };
+
+    for (index, &id) in threads.iter().take(count).enumerate() {
+        if !write_user_value(buffer_ptr + index * size_of::<ThreadID>(), id) {
+            return -errno::EFAULT;
+        }
+    }
+
+    threads.len() as isize
+}
+
+/// Stores the memory usage of the process with the given ID at `usage_ptr`.
+fn memory_usage(pid: usize, usage_ptr: VirtualAddress) -> isize {
+    let pointer_valid = is_writable_user_area(
+        &get_current_process().address_space,
+        MemoryArea::new(usage_ptr, size_of::<MemoryUsage>())
+    );
+
+    if !pointer_valid {
+        return -errno::EFAULT;
+    }
+
+    if usage_ptr.as_usize() % align_of::<MemoryUsage>() != 0 {
+        return -1;
+    }
+
+    match multitasking::memory_usage(pid.into()) {
+        Some(usage) => {
+            unsafe {
+                *usage_ptr.as_mut_ptr::<MemoryUsage>() = usage;
+            }
+
+            0
+        },
+        None => -1
+    }
+}
+
+/// Fills the buffer with the mapped ranges of the current process.
+///
+/// Returns the total number of ranges, which may be larger than the buffer.
+fn list_mappings(buffer_ptr: VirtualAddress, count: usize) -> isize {
+    let buffer_size = match count.checked_mul(size_of::<Mapping>()) {
+        Some(size) => size,
+        None => return -errno::EINVAL
+    };
+    let buffer_valid = is_writable_user_area(
+        &get_current_process().address_space,
+        MemoryArea::new(buffer_ptr, buffer_size)
+    );
+
+    if !buffer_valid {
+        return -errno::EFAULT;
+    }
+
+    if buffer_ptr.as_usize() % align_of::<Mapping>() != 0 {
+        return -errno::EFAULT;
+    }
+
+    // The process lock must not be held while writing to user memory.
+    let mappings = get_current_process().address_space.mappings();
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer_ptr.as_mut_ptr::<Mapping>(), count) };
+
+    for (entry, mapping) in buffer.iter_mut().zip(mappings.iter()) {
+        *entry = *mapping;
+    }
+
+    mappings.len() as isize
+}
+
+/// Fills the buffer with the interrupt counts of all CPUs.
+///
+/// Returns the total number of counts, which may be larger than the buffer.
+fn interrupt_counts(buffer_ptr: VirtualAddress, count: usize) -> isize {
+    let buffer_size = match count.checked_mul(size_of::<InterruptCount>()) {
+        Some(size) => size,
+        None => return -errno::EINVAL
+    };
+    let buffer_valid = is_writable_user_area(
+        &get_current_process().address_space,
+        MemoryArea::new(buffer_ptr, buffer_size)
+    );
+
+    if !buffer_valid {
+        return -errno::EFAULT;
+    }
+
+    if buffer_ptr.as_usize() % align_of::<InterruptCount>() != 0 {
+        return -errno::EFAULT;
+    }
+
+    let counts = arch::Current::interrupt_counts();
+    let buffer =
+        unsafe { slice::from_raw_parts_mut(buffer_ptr.as_mut_ptr::<InterruptCount>(), count) };
+
+    for (entry, interrupt_count) in buffer.iter_mut().zip(counts.iter()) {
+        *entry = *interrupt_count;
+    }
+
+    counts.len() as isize
+}
+
+/// Sets the memory the current process may reserve in total, in bytes.
+///
+/// The limit can only be lowered, so a process can't lift a limit it was
+/// given.
+fn set_memory_limit(limit: usize) -> isize {
+    let mut pcb = get_current_process();
+
+    if limit > pcb.address_space.memory_limit() {
+        return -errno::EPERM;
+    }
+
+    pcb.address_space.set_memory_limit(limit);
 
-    schedule();
     0
 }
 
+/// Sends the signal to the process with the given ID.
+///
+/// A negative ID addresses the process group with the negated ID.
+fn kill(pid: isize, signal: usize) -> isize {
+    let result = if pid < 0 {
+        multitasking::kill_process_group((pid.wrapping_neg() as usize).into(), signal)
+    } else {
+        multitasking::kill_process((pid as usize).into(), signal)
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(KillError::NoSuchProcess) => -errno::ESRCH,
+        Err(KillError::NotPermitted) => -errno::EPERM,
+        Err(KillError::InvalidSignal) => -errno::EINVAL
+    }
+}
+
+/// Makes `wait` return immediately if no child exited yet.
+const WAIT_NO_HANG: usize = 1;
+
+/// Makes `wait` give up after the given number of milliseconds.
+const WAIT_TIMEOUT: usize = 2;
+
+/// Waits for the child with the given ID to exit and reaps it.
+///
+/// The ID 0 stands for any child. The exit code is stored at `status_ptr`,
+/// unless it is null. Returns the ID of the reaped child, 0 if `WAIT_NO_HANG`
+/// is given and no child exited yet, or `ETIMEDOUT` if `WAIT_TIMEOUT` is
+/// given and no child exited within `timeout_ms` milliseconds.
+fn wait(pid: usize, status_ptr: VirtualAddress, options: usize, timeout_ms: usize) -> isize {
+    if options & !(WAIT_NO_HANG | WAIT_TIMEOUT) != 0
+        || options & WAIT_NO_HANG != 0 && options & WAIT_TIMEOUT != 0
+    {
+        return -errno::EINVAL;
+    }
+
+    if status_ptr.as_usize() != 0 {
+        let pointer_valid = is_writable_user_area(
+            &get_current_process().address_space,
+            MemoryArea::new(status_ptr, size_of::<i32>())
+        );
+
+        if !pointer_valid || status_ptr.as_usize() % align_of::<i32>() != 0 {
+            return -errno::EFAULT;
+        }
+    }
+
+    let child = if pid == 0 { None } else { Some(pid.into()) };
+    let deadline = if options & WAIT_NO_HANG != 0 {
+        Some(Timestamp::get_current())
+    } else if options & WAIT_TIMEOUT != 0 {
+        deadline_after(timeout_ms)
+    } else {
+        None
+    };
+
+    match multitasking::wait_for_child(child, deadline) {
+        Ok(Some((pid, exit_code))) => {
+            // The memory may have changed while waiting.
+            if status_ptr.as_usize() != 0 && !write_user_value(status_ptr, exit_code) {
+                return -errno::EFAULT;
+            }
+
+            let pid: usize = pid.into();
+
+            pid as isize
+        },
+        Ok(None) if options & WAIT_TIMEOUT != 0 => -errno::ETIMEDOUT,
+        Ok(None) => 0,
+        Err(WaitError::NoSuchChild) => -errno::ECHILD
+    }
+}
+
+/// Moves the process with the given ID to the given process group.
+///
+/// The ID 0 stands for the current process and the group 0 for the group
+/// led by the process.
+fn set_process_group(pid: usize, process_group: usize) -> isize {
+    let pid = if pid == 0 {
+        CURRENT_THREAD.lock().pid
+    } else {
+        pid.into()
+    };
+    let process_group = if process_group == 0 {
+        pid
+    } else {
+        process_group.into()
+    };
+
+    match multitasking::set_process_group(pid, process_group) {
+        Ok(()) => 0,
+        Err(ProcessGroupError::NoSuchProcess) => -errno::ESRCH,
+        Err(ProcessGroupError::NotPermitted) => -errno::EPERM
+    }
+}
+
 fn return_pid() -> isize {
     let pid = CURRENT_THREAD.lock().pid;
     let pid: usize = pid.into();
@@ -56,34 +730,369 @@ fn return_pid() -> isize {
     pid as isize
 }
 
-fn exec(name_ptr: VirtualAddress, name_length: usize) -> isize {
-    let name_ptr_valid = {
-        let pcb = get_current_process();
+fn return_tid() -> isize {
+    let tid = CURRENT_THREAD.lock().id;
+    let tid: usize = tid.into();
+
+    tid as isize
+}
+
+fn join_thread(id: usize) -> isize {
+    match multitasking::join_thread(id.into()) {
+        Ok(()) => 0,
+        Err(_) => -1
+    }
+}
+
+/// Detaches the thread with the given ID in the current process.
+fn detach_thread(id: usize) -> isize {
+    match multitasking::detach_thread(id.into()) {
+        Ok(()) => 0,
+        Err(_) => -errno::ESRCH
+    }
+}
+
+/// The maximum number of arguments a new process is passed besides its name.
+const MAX_EXEC_ARGUMENTS: usize = 16;
+
+/// The maximum total length of the arguments of a new process in bytes.
+///
+/// The arguments have to fit into the initial user stack.
+const MAX_EXEC_ARGUMENTS_LENGTH: usize = 0x400;
+
+/// A string in user memory, as passed in the argument array of `exec`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UserStr {
+    /// The address of the first byte.
+    address: usize,
+    /// The length in bytes.
+    length: usize
+}
+
+/// A file descriptor of a new process that refers to another open file than
+/// in the current process.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FdMapping {
+    /// The file descriptor of the open file in the current process.
+    fd: u32,
+    /// The file descriptor of the open file in the new process.
+    target: u32
+}
+
+/// Starts a new process from the initramfs file with the given name.
+///
+/// Relative names are resolved against the working directory. The process
+/// gets the resolved name as its first argument, followed by the
+/// `argument_count` strings described at `arguments_ptr`. It inherits the
+/// memory limit and the open files of the current process, except for the
+/// `mapping_count` file descriptors described at `mappings_ptr`.
+fn exec(
+    name_ptr: VirtualAddress,
+    name_length: usize,
+    arguments_ptr: VirtualAddress,
+    argument_count: usize,
+    mappings_ptr: VirtualAddress,
+    mapping_count: usize
+) -> isize {
+    let name = match read_user_string(name_ptr, name_length) {
+        Ok(name) => name,
+        Err(error) => return error
+    };
+
+    let arguments = match exec_arguments(arguments_ptr, argument_count) {
+        Ok(arguments) => arguments,
+        Err(error) => return error
+    };
+
+    let fd_table = match exec_fd_table(mappings_ptr, mapping_count) {
+        Ok(fd_table) => fd_table,
+        Err(error) => return error
+    };
+
+    let path = match current_absolute_path(&name) {
+        Ok(path) => path,
+        Err(error) => return errno::from_file_error(error)
+    };
+
+    let memory_limit = get_current_process().address_space.memory_limit();
+    let arguments: Vec<&str> = arguments.iter().map(|argument| argument.as_str()).collect();
+
+    match elf::process_from_initramfs_file(&path, &arguments, memory_limit, Some(fd_table)) {
+        Ok(process_id) => {
+            let pid: usize = process_id.into();
 
-        pcb.address_space
-            .contains_area(MemoryArea::new(name_ptr, name_length))
+            assert!(pid as isize > 0, "Process ID too large.");
+
+            pid as isize
+        },
+        Err(ElfError::ExceedsMemoryLimit) => -errno::ENOMEM,
+        Err(_) => -1
+    }
+}
+
+/// Returns the absolute path of the given path of the current process.
+///
+/// Relative paths are resolved against its working directory.
+fn current_absolute_path(path: &str) -> Result<String, FileError> {
+    vfs::absolute_path(get_current_process().working_directory(), path)
+}
+
+/// Changes the working directory of the current process to the given
+/// directory.
+fn chdir(path_ptr: VirtualAddress, path_length: usize) -> isize {
+    let path = match read_user_string(path_ptr, path_length) {
+        Ok(path) => path,
+        Err(error) => return error
     };
 
-    if name_ptr_valid {
-        let name = from_raw_str!(name_ptr, name_length);
+    let path = match current_absolute_path(&path) {
+        Ok(path) => path,
+        Err(error) => return errno::from_file_error(error)
+    };
+
+    // The process list isn't kept locked while the filesystem is accessed.
+    if let Err(error) = vfs::check_directory(&path) {
+        return errno::from_file_error(error);
+    }
+
+    get_current_process().set_working_directory(path);
+
+    0
+}
+
+/// Copies the working directory of the current process into the buffer.
+///
+/// Returns the length of the path, which isn't terminated. Fails with
+/// `ERANGE` if the buffer is too small.
+fn getcwd(buffer_ptr: VirtualAddress, length: usize) -> isize {
+    // The path is copied, since writing it locks the process again.
+    let path = String::from(get_current_process().working_directory());
+
+    if path.len() > length {
+        return -errno::ERANGE;
+    }
+
+    if !write_user_bytes(buffer_ptr, path.as_bytes()) {
+        return -errno::EFAULT;
+    }
+
+    path.len() as isize
+}
+
+/// Reads the arguments for a new process from the memory of the current
+/// process.
+///
+/// The strings are copied, so the process can't change them while the new
+/// process is created. Returns the negated error number if the arguments are
+/// invalid.
+fn exec_arguments(arguments_ptr: VirtualAddress, count: usize) -> Result<Vec<String>, isize> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    if count > MAX_EXEC_ARGUMENTS {
+        return Err(-errno::E2BIG);
+    }
+
+    let user_strs = {
+        let mut pcb = get_current_process();
+        let area = MemoryArea::new(arguments_ptr, count * size_of::<UserStr>());
 
-        if let Ok(name) = name {
-            let process_id = elf::process_from_initramfs_file(name);
+        if !is_valid_user_area(&pcb.address_space, area)
+            || arguments_ptr.as_usize() % align_of::<UserStr>() != 0
+        {
+            return Err(-errno::EFAULT);
+        }
 
-            if let Ok(process_id) = process_id {
-                let pid: usize = process_id.into();
+        let mut user_strs = Vec::with_capacity(count);
 
-                assert!(pid as isize > 0, "Process ID too large.");
+        for index in 0..count {
+            let address = arguments_ptr + index * size_of::<UserStr>();
 
-                pid as isize
-            } else {
-                -1
+            match unsafe { pcb.address_space.read_val::<UserStr>(address) } {
+                Some(user_str) => user_strs.push(user_str),
+                None => return Err(-errno::EFAULT)
             }
-        } else {
-            -1
         }
-    } else {
-        -1
+
+        user_strs
+    };
+
+    let total_length = user_strs
+        .iter()
+        .fold(0usize, |total, user_str| total.saturating_add(user_str.length));
+
+    if total_length > MAX_EXEC_ARGUMENTS_LENGTH {
+        return Err(-errno::E2BIG);
+    }
+
+    let mut arguments = Vec::with_capacity(count);
+
+    // Empty strings aren't read, so their address doesn't matter.
+    for user_str in user_strs {
+        let address = VirtualAddress::from_usize(user_str.address);
+
+        arguments.push(read_user_string(address, user_str.length)?);
+    }
+
+    Ok(arguments)
+}
+
+/// Returns the file descriptor table for a new process.
+///
+/// The table shares the open files of the current process, except for the
+/// `count` file descriptors described at `mappings_ptr`. Returns the negated
+/// error number if the mappings are invalid.
+fn exec_fd_table(mappings_ptr: VirtualAddress, count: usize) -> Result<FdTable, isize> {
+    if count > MAX_FILE_DESCRIPTORS {
+        return Err(-errno::EINVAL);
+    }
+
+    let mut pcb = get_current_process();
+    let mut fd_table = pcb.fd_table.clone();
+
+    if count == 0 {
+        return Ok(fd_table);
+    }
+
+    let area = MemoryArea::new(mappings_ptr, count * size_of::<FdMapping>());
+
+    if !is_valid_user_area(&pcb.address_space, area)
+        || mappings_ptr.as_usize() % align_of::<FdMapping>() != 0
+    {
+        return Err(-errno::EFAULT);
+    }
+
+    // The files are looked up in the table of the current process, so the
+    // order of the mappings doesn't matter. The current process still refers
+    // to every file the new table drops, so no file is released while the
+    // process is locked.
+    for index in 0..count {
+        let address = mappings_ptr + index * size_of::<FdMapping>();
+        let mapping = match unsafe { pcb.address_space.read_val::<FdMapping>(address) } {
+            Some(mapping) => mapping,
+            None => return Err(-errno::EFAULT)
+        };
+
+        let result = pcb
+            .fd_table
+            .get(mapping.fd as usize)
+            .and_then(|file| fd_table.replace(mapping.target as usize, file));
+
+        if let Err(error) = result {
+            return Err(errno::from_fd_error(error));
+        }
+    }
+
+    Ok(fd_table)
+}
+
+/// Fills the buffer with the entries of the directory at the given path.
+///
+/// The entries are listed starting at the given index. Returns the number of
+/// entries written, which is 0 once the end of the directory was reached.
+fn read_directory(
+    path_ptr: VirtualAddress,
+    path_length: usize,
+    buffer_ptr: VirtualAddress,
+    count: usize,
+    start_index: usize
+) -> isize {
+    let buffer_size = match count.checked_mul(size_of::<DirectoryEntryInfo>()) {
+        Some(size) => size,
+        None => return -errno::EINVAL
+    };
+
+    if buffer_ptr.as_usize() % align_of::<DirectoryEntryInfo>() != 0 {
+        return -errno::EFAULT;
+    }
+
+    let path = match read_user_string(path_ptr, path_length) {
+        Ok(path) => path,
+        Err(error) => return error
+    };
+
+    let path = match current_absolute_path(&path) {
+        Ok(path) => path,
+        Err(error) => return errno::from_file_error(error)
+    };
+
+    let entries = match vfs::readdir(&path) {
+        Ok(entries) => entries,
+        Err(error) => return errno::from_file_error(error)
+    };
+
+    // The process isn't kept locked while the filesystem is accessed.
+    let mut pcb = get_current_process();
+
+    if !is_writable_user_area(&pcb.address_space, MemoryArea::new(buffer_ptr, buffer_size)) {
+        return -errno::EFAULT;
+    }
+
+    let remaining = entries.iter().skip(start_index).take(count);
+    let mut written = 0;
+
+    for entry in remaining {
+        let address = buffer_ptr + written * size_of::<DirectoryEntryInfo>();
+
+        if unsafe { pcb.address_space.write_val(entry.info(), address) }.is_err() {
+            return -errno::EFAULT;
+        }
+
+        written += 1;
+    }
+
+    written as isize
+}
+
+/// Maps the initramfs file with the given name read-only into the current
+/// process.
+///
+/// The frames of the file are shared, not copied. Returns the address of
+/// the mapping and stores the length of the file at `length_ptr`.
+fn map_initramfs_file(
+    name_ptr: VirtualAddress,
+    name_length: usize,
+    length_ptr: VirtualAddress
+) -> isize {
+    let name = match read_user_string(name_ptr, name_length) {
+        Ok(name) => name,
+        Err(error) => return error
+    };
+
+    let mut pcb = get_current_process();
+    let length_valid =
+        is_writable_user_area(&pcb.address_space, MemoryArea::new(length_ptr, size_of::<usize>()));
+
+    if !length_valid {
+        return -errno::EFAULT;
+    }
+
+    if length_ptr.as_usize() % align_of::<usize>() != 0 {
+        return -1;
+    }
+
+    let path = match vfs::absolute_path(pcb.working_directory(), &name) {
+        Ok(path) => path,
+        Err(error) => return errno::from_file_error(error)
+    };
+
+    let area = match initramfs::file_area(&path) {
+        Ok(area) => area,
+        Err(_) => return -1
+    };
+
+    let address = match pcb.address_space.map_shared_area(area) {
+        Some(address) => address,
+        None => return -errno::ENOMEM
+    };
+
+    // The length is written through the address space, which doesn't fault.
+    match unsafe { pcb.address_space.write_val(area.length(), length_ptr) } {
+        Ok(()) => address.as_usize() as isize,
+        Err(_) => -errno::EFAULT
     }
 }
 
@@ -95,9 +1104,13 @@ fn create_thread(
     arg4: usize,
     arg5: usize
 ) -> isize {
+    if !arch::Current::is_userspace_address(start_address) {
+        return -errno::EFAULT;
+    }
+
     let pid = CURRENT_THREAD.lock().pid;
     let mut pcb = get_current_process();
-    let id = pcb.find_thread_id();
+    let id = pcb.allocate_thread_id();
 
     match id {
         Some(id) => {
@@ -113,9 +1126,17 @@ fn create_thread(
                 arg5
             );
 
+            let thread = match thread {
+                Some(thread) => thread,
+                None => {
+                    pcb.free_thread_id(id);
+                    return -errno::ENOMEM;
+                }
+            };
+
             pcb.add_thread(id);
 
-            READY_LIST.lock().push(thread);
+            scheduler::enqueue(thread);
 
             let tid: usize = id.into();
 
@@ -125,6 +1146,23 @@ fn create_thread(
     }
 }
 
+fn set_tls_base(base: VirtualAddress) -> isize {
+    if !arch::Current::is_userspace_address(base) {
+        return -errno::EFAULT;
+    }
+
+    // The base is saved in the context of the thread when it is switched out.
+    unsafe {
+        arch::Current::set_tls_base(base);
+    }
+
+    0
+}
+
+fn get_tls_base() -> isize {
+    arch::Current::get_tls_base().as_usize() as isize
+}
+
 fn kill_thread() -> isize {
     CURRENT_THREAD.lock().kill();
 
@@ -143,7 +1181,7 @@ fn sleep(seconds: usize, nanoseconds: usize) -> isize {
     {
         // The wake time overflowed
         // TODO: handle this in a more useful way
-        get_current_process().kill_immediately();
+        multitasking::exit_current_process(128 + SIGKILL as i32);
     } else {
         // If the duration was valid, return it
         Duration::new(seconds, nanoseconds)
@@ -154,7 +1192,7 @@ fn sleep(seconds: usize, nanoseconds: usize) -> isize {
     } else {
         // The wake time overflowed
         // TODO: handle this in a more useful way
-        get_current_process().kill_immediately();
+        multitasking::exit_current_process(128 + SIGKILL as i32);
     };
 
     CURRENT_THREAD.lock().state = crate::multitasking::ThreadState::Sleeping(wake_time);
@@ -162,11 +1200,154 @@ fn sleep(seconds: usize, nanoseconds: usize) -> isize {
     0
 }
 
+/// Returns the time the given number of milliseconds from now.
+///
+/// Timeouts too long to be represented never expire.
+fn deadline_after(milliseconds: usize) -> Option<Timestamp> {
+    Timestamp::get_current().offset(Duration::from_millis(milliseconds as u64))
+}
+
+/// Checks whether user space may pass the given memory area to the kernel.
+///
+/// The area must lie completely in the user half of the address space and
+/// within a single segment of the given address space. Kernel stacks are
+/// segments too, so the first check is what keeps them out of reach.
+fn is_valid_user_area(address_space: &AddressSpace, area: MemoryArea<VirtualAddress>) -> bool {
+    is_user_area(area) && address_space.contains_area(area)
+}
+
+/// Copies the memory area of the current process into a kernel buffer.
+///
+/// The memory is read through the address space while the process is
+/// locked, so the kernel never faults on it. Returns the negated error
+/// number if the area isn't valid.
+fn read_user_bytes(area: MemoryArea<VirtualAddress>) -> Result<Vec<u8>, isize> {
+    let mut buffer = Vec::new();
+
+    // Empty areas aren't read, so their address doesn't matter.
+    if area.is_empty() {
+        return Ok(buffer);
+    }
+
+    let mut pcb = get_current_process();
+
+    if !is_valid_user_area(&pcb.address_space, area) {
+        return Err(-errno::EFAULT);
+    }
+
+    buffer.resize(area.length(), 0);
+
+    match pcb.address_space.read_from(&mut buffer, area.start_address()) {
+        Ok(()) => Ok(buffer),
+        Err(_) => Err(-errno::EFAULT)
+    }
+}
+
+/// Copies the string at the given address of the current process.
+///
+/// Returns the negated error number if the memory isn't valid or the string
+/// isn't valid UTF-8.
+fn read_user_string(address: VirtualAddress, length: usize) -> Result<String, isize> {
+    let bytes = read_user_bytes(MemoryArea::new(address, length))?;
+
+    String::from_utf8(bytes).map_err(|_| -errno::EINVAL)
+}
+
+/// Copies the buffer to the given address of the current process.
+///
+/// Like `write_user_value`, the area is checked while the process is locked
+/// and written through the address space. Returns false if the area isn't
+/// writable.
+fn write_user_bytes(address: VirtualAddress, buffer: &[u8]) -> bool {
+    if buffer.is_empty() {
+        return true;
+    }
+
+    let mut pcb = get_current_process();
+
+    if !is_writable_user_area(&pcb.address_space, MemoryArea::new(address, buffer.len())) {
+        return false;
+    }
+
+    pcb.address_space.write_to(buffer, address).is_ok()
+}
+
+/// Checks whether the kernel may write to the memory area on behalf of the
+/// user.
+///
+/// In addition to being a valid user area, the segment containing it must be
+/// writable. Otherwise the write would fault in the kernel.
+fn is_writable_user_area(address_space: &AddressSpace, area: MemoryArea<VirtualAddress>) -> bool {
+    is_valid_user_area(address_space, area) && address_space.is_writable_area(area)
+}
+
+/// Writes the value to the given address of the current process.
+///
+/// The address is checked while the process is locked and the value is
+/// written through the address space, so the memory can't change in between.
+/// Returns false if the address isn't writable or isn't aligned.
+fn write_user_value<T>(address: VirtualAddress, value: T) -> bool {
+    let mut pcb = get_current_process();
+    let area = MemoryArea::new(address, size_of::<T>());
+
+    if !is_writable_user_area(&pcb.address_space, area)
+        || address.as_usize() % align_of::<T>() != 0
+    {
+        return false;
+    }
+
+    unsafe { pcb.address_space.write_val(value, address).is_ok() }
+}
+
+/// Checks whether the memory area lies completely in the user half of the
+/// address space.
+fn is_user_area(area: MemoryArea<VirtualAddress>) -> bool {
+    let start = area.start_address().as_usize();
+
+    match start.checked_add(area.length()) {
+        Some(end) => {
+            let last = if area.is_empty() { start } else { end - 1 };
+
+            arch::Current::is_userspace_address(area.start_address())
+                && arch::Current::is_userspace_address(VirtualAddress::from_usize(last))
+        },
+        None => false
+    }
+}
+
 fn unknown_syscall(num: u16) -> ! {
     if cfg!(debug) {
         panic!("The syscall {} is not known.", num);
     } else {
         // TODO: Handle this better
-        get_current_process().kill_immediately();
+        multitasking::exit_current_process(128 + SIGKILL as i32);
+    }
+}
+
+/// Tests for the validation of syscall arguments.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a virtual memory area.
+    fn area(start: usize, length: usize) -> MemoryArea<VirtualAddress> {
+        MemoryArea::new(VirtualAddress::from_usize(start), length)
+    }
+
+    /// Tests that areas in the user half are accepted.
+    #[test]
+    fn test_user_area() {
+        assert!(is_user_area(area(0x40_0000, 0x1000)));
+        assert!(is_user_area(area(0x0000_7fff_ffff_f000, 0x1000)));
+        assert!(is_user_area(area(0x40_0000, 0)));
+    }
+
+    /// Tests that kernel addresses are refused.
+    #[test]
+    fn test_kernel_area() {
+        assert!(!is_user_area(area(0xffff_8000_0000_0000, 0x10)));
+        assert!(!is_user_area(area(0xffff_fe00_0000_0000, 0x1000)));
+        assert!(!is_user_area(area(0x0000_7fff_ffff_f000, 0x1001)));
+        assert!(!is_user_area(area(0x40_0000, usize::max_value())));
     }
 }
@@ -0,0 +1,14 @@
+TARGET_FILES += $(TARGET_DIR)/bin/shell
+BUILD_DIRS += shell/target
+INITRAMFS_FILES += /bin/shell
+FMT_DIRS += shell
+
+$(TARGET_DIR)/bin/shell: target/$(BUILD_TARGET)/$(BUILD_TYPE)/shell
+	@mkdir -p $(shell dirname $@)
+	cp $< $@
+
+target/$(BUILD_TARGET)/$(BUILD_TYPE)/shell: target/$(BUILD_TARGET)/$(BUILD_TYPE)/libshell.a
+	$(LINKER) $(LINKER_FLAGS) $< -o $@
+
+target/$(BUILD_TARGET)/$(BUILD_TYPE)/libshell.a: $(shell find shell/src -name "*.rs") shell/Cargo.toml $(STD_FILES)
+	cd shell && $(RUST_COMPILER) build $(RUST_COMPILER_FLAGS)
@@ -0,0 +1,234 @@
+//! A minimal interactive shell.
+//!
+//! The shell reads commands from the console line by line. The supported
+//! commands are:
+//!
+//! - `help`: Lists the commands.
+//! - `ps`: Lists the running processes and the IDs of their threads.
+//! - `pid`: Prints the ID of the shell process.
+//! - `ls [path]`: Lists the directory at the given path, or the working
+//! directory.
+//! - `exec <path>`: Starts the program at the given path of the initramfs.
+//! - `kill <pid>`: Ends the process with the given ID.
+//!
+//! Any other command is looked up as a program in `/bin`.
+
+#![no_std]
+
+#[macro_use]
+extern crate veos_std;
+#[allow(unused_extern_crates)]
+extern crate rlibc;
+
+use veos_std::io::{read, read_directory, DirectoryEntry, FileKind, STDIN};
+use veos_std::process::{
+    exec, get_pid, kill, list_processes, list_threads, try_wait, ProcessInfo, ProcessState,
+    SIGTERM,
+};
+
+/// The maximum length of a command line.
+const MAX_LINE_LENGTH: usize = 128;
+
+/// The maximum number of processes listed by `ps`.
+const MAX_LISTED_PROCESSES: usize = 32;
+
+/// The maximum number of thread IDs listed per process by `ps`.
+const MAX_LISTED_THREADS: usize = 8;
+
+/// The number of directory entries `ls` reads at once.
+const DIRECTORY_ENTRIES_PER_READ: usize = 16;
+
+/// The directory that programs are looked up in.
+const PROGRAM_DIRECTORY: &str = "/bin/";
+
+#[no_mangle]
+pub fn main() {
+    println!("VeOS shell, type \"help\" for a list of commands.");
+
+    let mut line = [0u8; MAX_LINE_LENGTH];
+
+    loop {
+        reap_finished_programs();
+
+        print!("> ");
+
+        let length = match read(STDIN, &mut line) {
+            Ok(length) => length,
+            Err(error) => {
+                println!("Reading the command failed: {:?}", error);
+                continue;
+            }
+        };
+
+        match core::str::from_utf8(&line[..length]) {
+            Ok(command) => run_command(command.trim()),
+            Err(_) => println!("The command is not valid UTF-8."),
+        }
+    }
+}
+
+/// Runs the given command line.
+fn run_command(command_line: &str) {
+    let mut words = command_line.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (None, _) => (),
+        (Some("help"), None) => help(),
+        (Some("ps"), None) => ps(),
+        (Some("pid"), None) => println!("{}", get_pid()),
+        (Some("ls"), None) => list_directory("."),
+        (Some("ls"), Some(path)) => list_directory(path),
+        (Some("exec"), Some(path)) => run_program(path),
+        (Some("kill"), Some(pid)) => kill_process(pid),
+        (Some(name), None) if !name.contains('/') => run_program_in_bin(name),
+        _ => println!(
+            "Unknown command \"{}\", type \"help\" for a list.",
+            command_line
+        ),
+    }
+}
+
+/// Prints the available commands.
+fn help() {
+    println!("help          Lists the commands.");
+    println!("ps            Lists the running processes and their threads.");
+    println!("pid           Prints the ID of the shell.");
+    println!("ls [path]     Lists the directory at the path.");
+    println!("exec <path>   Starts the program at the path.");
+    println!("kill <pid>    Ends the process with the ID.");
+    println!("<name>        Starts the program /bin/<name>.");
+}
+
+/// Prints the running processes.
+fn ps() {
+    let mut processes = [ProcessInfo {
+        pid: 0,
+        parent: 0,
+        process_group: 0,
+        thread_count: 0,
+        state: ProcessState::Dead,
+        name: [0; veos_std::process::MAX_PROCESS_NAME_LENGTH],
+        name_length: 0,
+    }; MAX_LISTED_PROCESSES];
+
+    let count = match list_processes(&mut processes) {
+        Ok(count) => count,
+        Err(error) => {
+            println!("Listing the processes failed: {:?}", error);
+            return;
+        }
+    };
+
+    println!("  PID  PARENT   PGID  THREADS  NAME              TIDS");
+    for process in processes.iter().take(count) {
+        print!(
+            "{:>5}  {:>6}  {:>5}  {:>7}  {:<16} ",
+            process.pid,
+            process.parent,
+            process.process_group,
+            process.thread_count,
+            process.name()
+        );
+        print_thread_ids(process.pid);
+    }
+
+    if count > MAX_LISTED_PROCESSES {
+        println!("... and {} more", count - MAX_LISTED_PROCESSES);
+    }
+}
+
+/// Prints the IDs of the threads of the process with the given ID on one line.
+fn print_thread_ids(pid: u64) {
+    let mut thread_ids = [0; MAX_LISTED_THREADS];
+
+    // The process may have ended since it was listed.
+    let count = match list_threads(pid, &mut thread_ids) {
+        Ok(count) => count,
+        Err(_) => {
+            println!(" -");
+            return;
+        }
+    };
+
+    for id in thread_ids.iter().take(count) {
+        print!(" {}", id);
+    }
+
+    if count > MAX_LISTED_THREADS {
+        print!(" ...");
+    }
+
+    println!("");
+}
+
+/// Prints the entries of the directory at the given path.
+///
+/// Directories are marked with a trailing slash.
+fn list_directory(path: &str) {
+    let mut entries = [DirectoryEntry::empty(); DIRECTORY_ENTRIES_PER_READ];
+    let mut start_index = 0;
+
+    loop {
+        let count = match read_directory(path, start_index, &mut entries) {
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(error) => {
+                println!("Listing {} failed: {:?}", path, error);
+                return;
+            }
+        };
+
+        for entry in entries.iter().take(count) {
+            match entry.kind {
+                FileKind::Directory => println!("{}/", entry.name()),
+                FileKind::File => println!("{}", entry.name()),
+            }
+        }
+
+        start_index += count;
+    }
+}
+
+/// Starts the program with the given name in the program directory.
+fn run_program_in_bin(name: &str) {
+    let mut path = [0u8; MAX_LINE_LENGTH + 5];
+    let length = PROGRAM_DIRECTORY.len() + name.len();
+
+    path[..PROGRAM_DIRECTORY.len()].copy_from_slice(PROGRAM_DIRECTORY.as_bytes());
+    path[PROGRAM_DIRECTORY.len()..length].copy_from_slice(name.as_bytes());
+
+    // Both parts are valid UTF-8, so the concatenation is as well.
+    run_program(core::str::from_utf8(&path[..length]).unwrap());
+}
+
+/// Starts the program at the given path.
+fn run_program(path: &str) {
+    match exec(path) {
+        Ok(pid) => println!("Started {} with PID {}.", path, pid),
+        Err(_) => println!("Could not start {}.", path),
+    }
+}
+
+/// Reports the programs that exited since the last command.
+///
+/// This also reaps them, so their process IDs can be reused.
+fn reap_finished_programs() {
+    while let Ok(Some((pid, exit_code))) = try_wait(0) {
+        println!("Process {} exited with code {}.", pid, exit_code);
+    }
+}
+
+/// Ends the process with the given ID.
+fn kill_process(pid: &str) {
+    let pid = match pid.parse() {
+        Ok(pid) => pid,
+        Err(_) => {
+            println!("\"{}\" is not a process ID.", pid);
+            return;
+        }
+    };
+
+    if kill(pid, SIGTERM).is_err() {
+        println!("Could not kill process {}.", pid);
+    }
+}
@@ -0,0 +1,182 @@
+//! Provides the error number of the last failed syscall, like `errno` in C.
+//!
+//! Each thread has its own error number. It is kept in a block that the TLS
+//! base of the thread points to, which is set up before the thread runs any
+//! code of the program. A thread that changes its TLS base can't use the
+//! error number anymore, so it is neither read nor written then. The block is
+//! found through FS-relative reads, so the TLS base must always point to
+//! readable memory.
+
+use core::ptr;
+use thread::SET_TLS_BASE_SYSCALL_NUM;
+
+/// Identifies the thread blocks of the standard library.
+const THREAD_BLOCK_MAGIC: u64 = 0x6572_726e_6f42_6c6b;
+
+/// An error number of a failed syscall.
+///
+/// The numbers match the ones used by Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// No syscall of the thread failed yet.
+    NoError,
+    /// The operation isn't permitted.
+    EPERM,
+    /// The file or directory doesn't exist.
+    ENOENT,
+    /// The process doesn't exist.
+    ESRCH,
+    /// The argument list is too long.
+    E2BIG,
+    /// The file descriptor isn't open.
+    EBADF,
+    /// The process has no matching child.
+    ECHILD,
+    /// There is not enough memory, or the memory limit was reached.
+    ENOMEM,
+    /// An address passed to the kernel is invalid.
+    EFAULT,
+    /// A path component isn't a directory.
+    ENOTDIR,
+    /// An argument is invalid.
+    EINVAL,
+    /// The process has too many open files.
+    EMFILE,
+    /// The read end of the pipe is closed.
+    EPIPE,
+    /// The buffer is too small for the result.
+    ERANGE,
+    /// The deadline of a blocking operation passed.
+    ETIMEDOUT,
+    /// An error number that isn't known to the standard library.
+    Unknown(i64),
+}
+
+impl Errno {
+    /// Returns the error with the given number.
+    pub fn from_code(code: i64) -> Errno {
+        match code {
+            0 => Errno::NoError,
+            1 => Errno::EPERM,
+            2 => Errno::ENOENT,
+            3 => Errno::ESRCH,
+            7 => Errno::E2BIG,
+            9 => Errno::EBADF,
+            10 => Errno::ECHILD,
+            12 => Errno::ENOMEM,
+            14 => Errno::EFAULT,
+            20 => Errno::ENOTDIR,
+            22 => Errno::EINVAL,
+            24 => Errno::EMFILE,
+            32 => Errno::EPIPE,
+            34 => Errno::ERANGE,
+            110 => Errno::ETIMEDOUT,
+            code => Errno::Unknown(code),
+        }
+    }
+
+    /// Returns the number of the error.
+    pub fn code(self) -> i64 {
+        match self {
+            Errno::NoError => 0,
+            Errno::EPERM => 1,
+            Errno::ENOENT => 2,
+            Errno::ESRCH => 3,
+            Errno::E2BIG => 7,
+            Errno::EBADF => 9,
+            Errno::ECHILD => 10,
+            Errno::ENOMEM => 12,
+            Errno::EFAULT => 14,
+            Errno::ENOTDIR => 20,
+            Errno::EINVAL => 22,
+            Errno::EMFILE => 24,
+            Errno::EPIPE => 32,
+            Errno::ERANGE => 34,
+            Errno::ETIMEDOUT => 110,
+            Errno::Unknown(code) => code,
+        }
+    }
+}
+
+/// The block that the TLS base of each thread points to.
+#[repr(C)]
+pub(crate) struct ThreadBlock {
+    /// Points to the block itself, as the x86_64 TLS ABI requires.
+    self_pointer: *const ThreadBlock,
+    /// The error number of the last failed syscall of the thread.
+    errno: i64,
+    /// Marks the block as a block of the standard library.
+    magic: u64,
+}
+
+impl ThreadBlock {
+    /// Creates a block without an error.
+    pub(crate) fn new() -> ThreadBlock {
+        ThreadBlock {
+            self_pointer: ptr::null(),
+            errno: 0,
+            magic: THREAD_BLOCK_MAGIC,
+        }
+    }
+}
+
+/// Makes the TLS base of the current thread point to the given block.
+///
+/// # Safety
+/// - The block must stay valid as long as the thread runs.
+pub(crate) unsafe fn init_thread(block: &mut ThreadBlock) {
+    block.self_pointer = block;
+
+    // This can't fail for a block on the stack of the thread.
+    syscall!(SET_TLS_BASE_SYSCALL_NUM, block as *mut ThreadBlock as u64);
+}
+
+/// Returns the block the TLS base of the current thread points to.
+///
+/// Returns `None` if the thread moved its TLS base somewhere else.
+fn current_block() -> Option<*mut ThreadBlock> {
+    let self_pointer: u64;
+    let magic: u64;
+
+    // The block is read relative to the TLS base, so that errno doesn't need
+    // a syscall to find it.
+    unsafe {
+        asm!("mov $0, fs:[0]" : "=r"(self_pointer) : : : "intel", "volatile");
+        asm!("mov $0, fs:[16]" : "=r"(magic) : : : "intel", "volatile");
+    }
+
+    // Only the blocks of the standard library are marked, so other TLS blocks
+    // pointing to themselves are left alone.
+    if magic == THREAD_BLOCK_MAGIC && self_pointer != 0 {
+        Some(self_pointer as *mut ThreadBlock)
+    } else {
+        None
+    }
+}
+
+/// Returns the error of the last failed syscall of the current thread.
+///
+/// Returns `Errno::NoError` if the thread moved its TLS base.
+pub fn errno() -> Errno {
+    match current_block() {
+        Some(block) => Errno::from_code(unsafe { (*block).errno }),
+        None => Errno::NoError,
+    }
+}
+
+/// Sets the error of the current thread.
+///
+/// Nothing is set if the thread moved its TLS base.
+pub fn set_errno(error: Errno) {
+    if let Some(block) = current_block() {
+        unsafe {
+            ptr::write_volatile(&mut (*block).errno, error.code());
+        }
+    }
+}
+
+/// Sets the error of the current thread from the negative result of a
+/// syscall.
+pub(crate) fn set_errno_from_result(result: i64) {
+    set_errno(Errno::from_code(-result));
+}
@@ -2,10 +2,166 @@
 
 use core::fmt;
 use core::fmt::Write;
+use core::slice;
+use core::time::Duration;
+use errno::set_errno_from_result;
 
 /// The number of the print char syscall.
 const PRINT_CHAR_SYSCALL: u64 = 0;
 
+/// The number of the read syscall.
+const READ_SYSCALL: u64 = 7;
+
+/// The number of the set raw mode syscall.
+const SET_RAW_MODE_SYSCALL: u64 = 8;
+
+/// The number of the dup syscall.
+const DUP_SYSCALL: u64 = 12;
+
+/// The number of the dup2 syscall.
+const DUP2_SYSCALL: u64 = 13;
+
+/// The number of the write syscall.
+const WRITE_SYSCALL: u64 = 15;
+
+/// The number of the close syscall.
+const CLOSE_SYSCALL: u64 = 16;
+
+/// The number of the map initramfs file syscall.
+const MAP_INITRAMFS_FILE_SYSCALL: u64 = 20;
+
+/// The number of the read timeout syscall.
+const READ_TIMEOUT_SYSCALL: u64 = 26;
+
+/// The number of the poll syscall.
+const POLL_SYSCALL: u64 = 27;
+
+/// The number of the read_directory syscall.
+const READ_DIRECTORY_SYSCALL: u64 = 32;
+
+/// The error number for file descriptors that aren't open.
+const EBADF: i64 = 9;
+
+/// The error number for pipes without a read end.
+const EPIPE: i64 = 32;
+
+/// The error number for operations whose timeout expired.
+const ETIMEDOUT: i64 = 110;
+
+/// The file descriptor of the standard input.
+pub const STDIN: u64 = 0;
+
+/// The file descriptor of the standard output.
+pub const STDOUT: u64 = 1;
+
+/// The poll event for file descriptors that can be read without blocking.
+pub const POLL_READABLE: u16 = 1 << 0;
+
+/// The poll event for file descriptors that can be written without blocking.
+pub const POLL_WRITABLE: u16 = 1 << 2;
+
+/// The poll event for pipes whose read ends were closed while writing.
+pub const POLL_ERROR: u16 = 1 << 3;
+
+/// The poll event for pipes whose write ends were closed while reading.
+pub const POLL_HANG_UP: u16 = 1 << 4;
+
+/// The poll event for file descriptors that aren't open.
+pub const POLL_INVALID: u16 = 1 << 5;
+
+/// A file descriptor to poll, together with the events of interest.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollFd {
+    /// The file descriptor to poll.
+    pub fd: u32,
+    /// The events to wait for.
+    pub events: u16,
+    /// The events that are ready, filled in by `poll`.
+    ///
+    /// Errors, hang ups and invalid file descriptors are always reported.
+    pub ready_events: u16,
+}
+
+impl PollFd {
+    /// Creates an entry that waits for the given events on the file descriptor.
+    pub fn new(fd: u64, events: u16) -> PollFd {
+        PollFd {
+            fd: fd as u32,
+            events,
+            ready_events: 0,
+        }
+    }
+}
+
+/// The maximum length of a name in a `DirectoryEntry` in bytes.
+///
+/// Longer names are truncated.
+pub const MAX_FILE_NAME_LENGTH: usize = 64;
+
+/// The kinds of entries in a directory.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A regular file.
+    File = 0,
+    /// A directory.
+    Directory = 1,
+}
+
+/// An entry in a directory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DirectoryEntry {
+    /// The kind of the entry.
+    pub kind: FileKind,
+    /// The name of the entry, padded with zeros.
+    pub name: [u8; MAX_FILE_NAME_LENGTH],
+    /// The length of the name of the entry.
+    pub name_length: u64,
+}
+
+impl DirectoryEntry {
+    /// Creates an entry without a name, to be filled in by `read_directory`.
+    pub fn empty() -> DirectoryEntry {
+        DirectoryEntry {
+            kind: FileKind::File,
+            name: [0; MAX_FILE_NAME_LENGTH],
+            name_length: 0,
+        }
+    }
+
+    /// Returns the name of the entry.
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length as usize]).unwrap_or("")
+    }
+}
+
+/// The possible types of errors that are IO related.
+#[derive(Debug)]
+pub enum IoError {
+    /// The error is not further specified.
+    Unspecified,
+    /// The file descriptor isn't open.
+    BadDescriptor,
+    /// The read end of the pipe was closed.
+    BrokenPipe,
+    /// The timeout expired before the operation could complete.
+    TimedOut,
+}
+
+impl IoError {
+    /// Returns the error for the negative result of a syscall.
+    fn from_result(result: i64) -> IoError {
+        match -result {
+            EBADF => IoError::BadDescriptor,
+            EPIPE => IoError::BrokenPipe,
+            ETIMEDOUT => IoError::TimedOut,
+            _ => IoError::Unspecified,
+        }
+    }
+}
+
 /// A dummy struct to implement fmt::Write on.
 struct StdOut;
 
@@ -44,3 +200,199 @@ fn print_char(character: char) {
         syscall!(PRINT_CHAR_SYSCALL, character as u64);
     }
 }
+
+/// Reads from the given file descriptor into the buffer.
+///
+/// Reading from standard input blocks until a line was entered.
+/// Returns the number of bytes read.
+pub fn read(fd: u64, buffer: &mut [u8]) -> Result<usize, IoError> {
+    let result = unsafe {
+        syscall!(
+            READ_SYSCALL,
+            fd,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::Unspecified)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Reads from the given file descriptor into the buffer, waiting at most for
+/// the given timeout.
+///
+/// Returns the number of bytes read, or `IoError::TimedOut` if no data
+/// arrived in time.
+pub fn read_timeout(fd: u64, buffer: &mut [u8], timeout: Duration) -> Result<usize, IoError> {
+    let result = unsafe {
+        syscall!(
+            READ_TIMEOUT_SYSCALL,
+            fd,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64,
+            duration_to_millis(timeout)
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Waits until any of the file descriptors has one of its events ready.
+///
+/// Without a timeout this waits forever. Returns the number of entries with
+/// ready events, which is 0 if the timeout expired.
+pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>) -> Result<usize, IoError> {
+    let timeout_ms = match timeout {
+        // Negative timeouts wait forever, so the largest one has to be capped.
+        Some(timeout) => duration_to_millis(timeout).min(i64::max_value() as u64),
+        None => -1i64 as u64,
+    };
+    let result = unsafe {
+        syscall!(
+            POLL_SYSCALL,
+            fds.as_mut_ptr() as u64,
+            fds.len() as u64,
+            timeout_ms
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Returns the duration in whole milliseconds, saturating on overflow.
+pub(crate) fn duration_to_millis(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(duration.subsec_millis()))
+}
+
+/// Writes the buffer to the given file descriptor.
+///
+/// Writing to a full pipe blocks until it was read from.
+/// Returns the number of bytes written.
+pub fn write(fd: u64, buffer: &[u8]) -> Result<usize, IoError> {
+    let result = unsafe {
+        syscall!(
+            WRITE_SYSCALL,
+            fd,
+            buffer.as_ptr() as u64,
+            buffer.len() as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Closes the given file descriptor.
+pub fn close(fd: u64) -> Result<(), IoError> {
+    let result = unsafe { syscall!(CLOSE_SYSCALL, fd) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(())
+    }
+}
+
+/// Enables or disables raw mode for the console.
+///
+/// In raw mode typed characters are readable immediately and aren't echoed.
+pub fn set_raw_mode(raw: bool) {
+    unsafe {
+        syscall!(SET_RAW_MODE_SYSCALL, raw as u64);
+    }
+}
+
+/// Duplicates the file descriptor to the lowest free file descriptor.
+///
+/// Returns the new file descriptor.
+pub fn dup(fd: u64) -> Result<u64, IoError> {
+    let result = unsafe { syscall!(DUP_SYSCALL, fd) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(result as u64)
+    }
+}
+
+/// Makes `new_fd` refer to the same file as `old_fd`.
+///
+/// A file that was open on `new_fd` is closed first.
+pub fn dup2(old_fd: u64, new_fd: u64) -> Result<u64, IoError> {
+    let result = unsafe { syscall!(DUP2_SYSCALL, old_fd, new_fd) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(result as u64)
+    }
+}
+
+/// Fills the buffer with the entries of the directory at the given path.
+///
+/// The entries are listed starting at the given index, so a directory can be
+/// read in several calls. Returns the number of entries written, which is 0
+/// once the end of the directory was reached. Relative paths are resolved
+/// against the working directory.
+pub fn read_directory(
+    path: &str,
+    start_index: usize,
+    buffer: &mut [DirectoryEntry],
+) -> Result<usize, IoError> {
+    let result = unsafe {
+        syscall!(
+            READ_DIRECTORY_SYSCALL,
+            path.as_ptr() as u64,
+            path.len() as u64,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64,
+            start_index as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Maps the initramfs file with the given name into the address space.
+///
+/// The mapping shares the memory of the initramfs instead of copying it, so
+/// it is read-only and stays valid until the process ends.
+pub fn map_initramfs_file(name: &str) -> Result<&'static [u8], IoError> {
+    let mut length: usize = 0;
+    let result = unsafe {
+        syscall!(
+            MAP_INITRAMFS_FILE_SYSCALL,
+            name.as_ptr() as u64,
+            name.len() as u64,
+            &mut length as *mut usize as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(IoError::from_result(result))
+    } else {
+        Ok(unsafe { slice::from_raw_parts(result as *const u8, length) })
+    }
+}
@@ -12,7 +12,7 @@ macro_rules! syscall {
         asm!("syscall" :
                                 "={rax}"(result) :
                                 "{rax}"($num)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
@@ -22,7 +22,7 @@ macro_rules! syscall {
                                 "={rax}"(result) :
                                 "{rax}"($num),
                                 "{rdi}"($arg1)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
@@ -33,7 +33,7 @@ macro_rules! syscall {
                                 "{rax}"($num),
                                 "{rdi}"($arg1),
                                 "{rsi}"($arg2)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
@@ -45,7 +45,7 @@ macro_rules! syscall {
                                 "{rdi}"($arg1),
                                 "{rsi}"($arg2),
                                 "{rdx}"($arg3)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
@@ -58,7 +58,7 @@ macro_rules! syscall {
                                 "{rsi}"($arg2),
                                 "{rdx}"($arg3),
                                 "{r10}"($arg4)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
@@ -72,7 +72,7 @@ macro_rules! syscall {
                                 "{rdx}"($arg3),
                                 "{r10}"($arg4),
                                 "{r8}"($arg5)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
@@ -87,19 +87,22 @@ macro_rules! syscall {
                                 "{r10}"($arg4),
                                 "{r8}"($arg5),
                                 "{r9}"($arg6)
-                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r12", "r11", "rcx"
+                                : "rax", "rdi", "rsi", "rdx", "r10", "r8", "r9", "r11", "rcx"
                                 : "intel", "volatile");
         result
     }};
 }
 
+pub mod errno;
 #[macro_use]
 pub mod io;
 pub mod process;
+pub mod system;
 pub mod thread;
 
 use core::panic::PanicInfo;
-use process::exit;
+use errno::{init_thread, ThreadBlock};
+use process::{exit, init_arguments};
 
 extern "Rust" {
     /// The function that the program provides as a start.
@@ -111,8 +114,11 @@ extern "Rust" {
 /// This should perform initialization and call main. After main returns, it should exit.
 #[start]
 #[no_mangle]
-pub fn _start(_: isize, _: *const *const u8) -> isize {
+pub fn _start(argc: isize, argv: *const *const u8) -> isize {
+    let mut thread_block = ThreadBlock::new();
     unsafe {
+        init_thread(&mut thread_block);
+        init_arguments(argc as usize, argv);
         main();
     }
     exit();
@@ -1,7 +1,13 @@
 //! Handles process related system calls.
 
-/// The number of the exit syscall.
-const EXIT_SYSCALL_NUM: u64 = 1;
+use core::time::Duration;
+use core::{slice, str};
+use errno::{set_errno, set_errno_from_result, Errno};
+use io;
+use io::duration_to_millis;
+
+/// The number of the exit_group syscall.
+const EXIT_GROUP_SYSCALL_NUM: u64 = 19;
 
 /// The number of the get_pid syscall.
 const GET_PID_SYSCALL_NUM: u64 = 2;
@@ -9,6 +15,59 @@ const GET_PID_SYSCALL_NUM: u64 = 2;
 /// The number of the exec syscall.
 const EXEC_SYSCALL_NUM: u64 = 3;
 
+/// The number of the list_processes syscall.
+const LIST_PROCESSES_SYSCALL_NUM: u64 = 9;
+
+/// The number of the pipe syscall.
+const PIPE_SYSCALL_NUM: u64 = 14;
+
+/// The number of the memory_usage syscall.
+const MEMORY_USAGE_SYSCALL_NUM: u64 = 21;
+
+/// The number of the set_memory_limit syscall.
+///
+/// The kernel only lets the limit be lowered.
+const SET_MEMORY_LIMIT_SYSCALL_NUM: u64 = 22;
+
+/// The number of the list_mappings syscall.
+const LIST_MAPPINGS_SYSCALL_NUM: u64 = 28;
+
+/// The number of the kill syscall.
+const KILL_SYSCALL_NUM: u64 = 23;
+
+/// The number of the set_process_group syscall.
+const SET_PROCESS_GROUP_SYSCALL_NUM: u64 = 24;
+
+/// The number of the wait syscall.
+const WAIT_SYSCALL_NUM: u64 = 25;
+
+/// The number of the chdir syscall.
+const CHDIR_SYSCALL_NUM: u64 = 30;
+
+/// The number of the getcwd syscall.
+const GETCWD_SYSCALL_NUM: u64 = 31;
+
+/// The number of the list_threads syscall.
+const LIST_THREADS_SYSCALL_NUM: u64 = 33;
+
+/// Makes the wait syscall return immediately if no child exited yet.
+const WAIT_NO_HANG: u64 = 1;
+
+/// Makes the wait syscall give up after the given timeout.
+const WAIT_TIMEOUT: u64 = 2;
+
+/// The error number for waits whose timeout expired.
+const ETIMEDOUT: i64 = 110;
+
+/// The signal that interrupts a process.
+pub const SIGINT: u64 = 2;
+
+/// The signal that ends a process unconditionally.
+pub const SIGKILL: u64 = 9;
+
+/// The signal that asks a process to end.
+pub const SIGTERM: u64 = 15;
+
 /// The possible types of errors that are process related.
 #[derive(Debug)]
 pub enum ProcessError {
@@ -18,8 +77,15 @@ pub enum ProcessError {
 
 /// Exits the current process.
 pub fn exit() -> ! {
+    exit_group(0)
+}
+
+/// Exits all threads of the current process with the given exit code.
+///
+/// To only exit the current thread, use `thread::kill_thread`.
+pub fn exit_group(code: i32) -> ! {
     unsafe {
-        syscall!(EXIT_SYSCALL_NUM);
+        syscall!(EXIT_GROUP_SYSCALL_NUM, code as u64);
     }
     unreachable!();
 }
@@ -29,16 +95,586 @@ pub fn get_pid() -> u64 {
     unsafe { syscall!(GET_PID_SYSCALL_NUM) as u64 }
 }
 
+/// The number of arguments of the current process.
+static mut ARGUMENT_COUNT: usize = 0;
+
+/// The address of the argument pointers of the current process.
+static mut ARGUMENT_POINTERS: usize = 0;
+
+/// Remembers the arguments that the current process was started with.
+///
+/// # Safety
+/// - This must only be called by `_start`, before any other thread exists.
+pub(crate) unsafe fn init_arguments(argc: usize, argv: *const *const u8) {
+    ARGUMENT_COUNT = argc;
+    ARGUMENT_POINTERS = argv as usize;
+}
+
+/// Returns an iterator over the arguments of the current process.
+///
+/// The first argument is the name of the program.
+pub fn args() -> Args {
+    Args { index: 0 }
+}
+
+/// An iterator over the arguments of the current process.
+#[derive(Debug)]
+pub struct Args {
+    /// The index of the next argument.
+    index: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        let (count, pointers) = unsafe { (ARGUMENT_COUNT, ARGUMENT_POINTERS) };
+        if self.index >= count {
+            return None;
+        }
+
+        let argument = unsafe {
+            let start = *(pointers as *const *const u8).add(self.index);
+            let mut length = 0;
+            while *start.add(length) != 0 {
+                length += 1;
+            }
+            slice::from_raw_parts(start, length)
+        };
+        self.index += 1;
+
+        // The kernel only passes valid UTF-8.
+        Some(str::from_utf8(argument).unwrap_or(""))
+    }
+}
+
+/// The maximum number of arguments that can be passed to a new process.
+pub const MAX_ARGUMENTS: usize = 16;
+
+/// An argument as the exec syscall expects it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawArgument {
+    /// The address of the string.
+    address: u64,
+    /// The length of the string in bytes.
+    length: u64,
+}
+
 /// Creates a new process from the given executable.
 pub fn exec(name: &str) -> Result<u64, ProcessError> {
+    exec_with_arguments(name, &[])
+}
+
+/// A file descriptor of a new process that refers to another open file than
+/// in the current process, as the exec syscall expects it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FdMapping {
+    /// The file descriptor of the open file in the current process.
+    fd: u32,
+    /// The file descriptor of the open file in the new process.
+    target: u32,
+}
+
+/// Creates a new process from the given executable with the given arguments.
+///
+/// Relative names are resolved against the working directory. The new
+/// process receives the resolved name as its first argument, followed by the
+/// given ones. At most `MAX_ARGUMENTS` arguments with 1KiB in total can be
+/// passed.
+pub fn exec_with_arguments(name: &str, arguments: &[&str]) -> Result<u64, ProcessError> {
+    exec_with_fd_mappings(name, arguments, &[])
+}
+
+/// Creates a new process like `exec_with_arguments`, whose file descriptors
+/// are changed by the given mappings.
+fn exec_with_fd_mappings(
+    name: &str,
+    arguments: &[&str],
+    fd_mappings: &[FdMapping],
+) -> Result<u64, ProcessError> {
+    if arguments.len() > MAX_ARGUMENTS {
+        set_errno(Errno::E2BIG);
+        return Err(ProcessError::Unspecified);
+    }
+
+    let mut raw_arguments = [RawArgument {
+        address: 0,
+        length: 0,
+    }; MAX_ARGUMENTS];
+    for (raw_argument, argument) in raw_arguments.iter_mut().zip(arguments) {
+        raw_argument.address = argument.as_ptr() as u64;
+        raw_argument.length = argument.len() as u64;
+    }
+
     // An intermediate pointer is required here in order to make sure that *const str pointer
     // is aligned, since *const usize has a more string alignment.
     // See https://wiki.sei.cmu.edu/confluence/display/c/EXP36-C.+Do+not+cast+pointers+into+more+strictly+aligned+pointer+types
     let name_ptr = name as *const str as *const usize as u64;
-    let result = unsafe { syscall!(EXEC_SYSCALL_NUM, name_ptr, name.len() as u64) as i64 };
+    let result = unsafe {
+        syscall!(
+            EXEC_SYSCALL_NUM,
+            name_ptr,
+            name.len() as u64,
+            raw_arguments.as_ptr() as u64,
+            arguments.len() as u64,
+            fd_mappings.as_ptr() as u64,
+            fd_mappings.len() as u64
+        ) as i64
+    };
     if result < 0 {
+        set_errno_from_result(result);
         Err(ProcessError::Unspecified)
     } else {
         Ok(result as u64)
     }
 }
+
+/// Changes the working directory of the current process.
+///
+/// Relative paths passed to `exec` and `chdir` are resolved against it.
+/// New processes start in the working directory of their parent.
+pub fn chdir(path: &str) -> Result<(), ProcessError> {
+    let result =
+        unsafe { syscall!(CHDIR_SYSCALL_NUM, path.as_ptr() as u64, path.len() as u64) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes the working directory of the current process into the buffer.
+///
+/// Returns the part of the buffer that holds the path. Fails with `ERANGE`
+/// if the buffer is too small.
+pub fn getcwd(buffer: &mut [u8]) -> Result<&str, ProcessError> {
+    let result = unsafe {
+        syscall!(GETCWD_SYSCALL_NUM, buffer.as_mut_ptr() as u64, buffer.len() as u64) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        str::from_utf8(&buffer[..result as usize]).map_err(|_| ProcessError::Unspecified)
+    }
+}
+
+/// Creates a pipe and returns the file descriptors of its read and write end.
+///
+/// The descriptors are inherited by processes created afterwards.
+pub fn pipe() -> Result<(u64, u64), ProcessError> {
+    let mut fds = [0u64; 2];
+    let result = unsafe { syscall!(PIPE_SYSCALL_NUM, fds.as_mut_ptr() as u64) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok((fds[0], fds[1]))
+    }
+}
+
+/// The maximum length of a process name in bytes.
+pub const MAX_PROCESS_NAME_LENGTH: usize = 32;
+
+/// The state of a process.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    /// The process is currently active.
+    Active = 0,
+    /// The process is dead, but some of its threads still exist.
+    Dead = 1,
+    /// The process ended and its parent didn't wait for it yet.
+    Zombie = 2,
+}
+
+/// Information about a process.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    /// The ID of the process.
+    pub pid: u64,
+    /// The ID of the process that created this process.
+    pub parent: u64,
+    /// The ID of the process group of the process.
+    pub process_group: u64,
+    /// The number of threads in the process.
+    pub thread_count: u64,
+    /// The state of the process.
+    pub state: ProcessState,
+    /// The name of the process, padded with zeros.
+    pub name: [u8; MAX_PROCESS_NAME_LENGTH],
+    /// The length of the name of the process.
+    pub name_length: u64,
+}
+
+impl ProcessInfo {
+    /// Returns the name of the process.
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length as usize]).unwrap_or("")
+    }
+}
+
+/// Fills the buffer with information about the running processes.
+///
+/// Returns the total number of processes, which may be larger than the buffer.
+pub fn list_processes(buffer: &mut [ProcessInfo]) -> Result<usize, ProcessError> {
+    let result = unsafe {
+        syscall!(
+            LIST_PROCESSES_SYSCALL_NUM,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Fills the buffer with the IDs of the threads of the process with the given ID.
+///
+/// Returns the total number of threads, which may be larger than the buffer.
+pub fn list_threads(pid: u64, buffer: &mut [u64]) -> Result<usize, ProcessError> {
+    let result = unsafe {
+        syscall!(
+            LIST_THREADS_SYSCALL_NUM,
+            pid,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// The memory used by a process, counted in pages.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemoryUsage {
+    /// The pages that map frames owned by the process.
+    pub resident_pages: u64,
+    /// The pages that map frames shared with other mappings.
+    pub shared_pages: u64,
+    /// The pages reserved by the process, whether they are mapped or not.
+    pub virtual_pages: u64,
+}
+
+/// Returns the memory used by the process with the given ID.
+pub fn memory_usage(pid: u64) -> Result<MemoryUsage, ProcessError> {
+    let mut usage = MemoryUsage::default();
+    let result = unsafe {
+        syscall!(
+            MEMORY_USAGE_SYSCALL_NUM,
+            pid,
+            &mut usage as *mut MemoryUsage as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(usage)
+    }
+}
+
+/// Set if the pages of a mapping can be read from.
+pub const MAPPING_READABLE: u8 = 1 << 0;
+
+/// Set if the pages of a mapping can be written to.
+pub const MAPPING_WRITABLE: u8 = 1 << 1;
+
+/// Set if code on the pages of a mapping can be executed.
+pub const MAPPING_EXECUTABLE: u8 = 1 << 2;
+
+/// Set if the pages of a mapping are accessible from user mode.
+pub const MAPPING_USER_ACCESSIBLE: u8 = 1 << 4;
+
+/// A contiguous range of mapped pages that share the same flags.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    /// The address of the first page.
+    pub start: u64,
+    /// The length of the range in bytes.
+    pub length: u64,
+    /// The `MAPPING_*` flags of the pages.
+    pub flags: u8,
+}
+
+impl Mapping {
+    /// Returns true if the address lies within the range.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address - self.start < self.length
+    }
+}
+
+/// Fills the buffer with the mapped ranges of the current process, sorted by address.
+///
+/// Returns the total number of ranges, which may be larger than the buffer.
+pub fn list_mappings(buffer: &mut [Mapping]) -> Result<usize, ProcessError> {
+    let result = unsafe {
+        syscall!(
+            LIST_MAPPINGS_SYSCALL_NUM,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Limits the user memory the current process may reserve to the given number of bytes.
+///
+/// Reservations that would exceed the limit fail, memory that is already reserved stays. The
+/// limit can only be lowered and is inherited by the processes started afterwards. Raising it
+/// fails with `EPERM`.
+pub fn set_memory_limit(limit: u64) -> Result<(), ProcessError> {
+    let result = unsafe { syscall!(SET_MEMORY_LIMIT_SYSCALL_NUM, limit) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
+    }
+}
+
+/// Sends the signal to the process with the given ID.
+///
+/// Only the current process and the processes it created can be signaled. The signal 0 only
+/// checks whether the process could be signaled.
+pub fn kill(pid: u64, signal: u64) -> Result<(), ProcessError> {
+    let result = unsafe { syscall!(KILL_SYSCALL_NUM, pid, signal) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
+    }
+}
+
+/// Sends the signal to all processes in the given process group.
+///
+/// Only the members that `kill` could signal receive it.
+pub fn kill_process_group(process_group: u64, signal: u64) -> Result<(), ProcessError> {
+    let pid = (process_group as i64).wrapping_neg() as u64;
+    kill(pid, signal)
+}
+
+/// Moves the process with the given ID to the given process group.
+///
+/// The ID 0 stands for the current process and the group 0 creates a new group led by the
+/// process. Only the current process and its children can be moved.
+pub fn set_process_group(pid: u64, process_group: u64) -> Result<(), ProcessError> {
+    let result = unsafe { syscall!(SET_PROCESS_GROUP_SYSCALL_NUM, pid, process_group) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
+    }
+}
+
+/// Waits for the child with the given ID to exit and reaps it.
+///
+/// The ID 0 stands for any child. Returns the ID and the exit code of the reaped child. Children
+/// killed by a signal exit with 128 plus the signal number.
+pub fn wait(pid: u64) -> Result<(u64, i32), ProcessError> {
+    match wait_with_options(pid, 0, 0)? {
+        Some(child) => Ok(child),
+        None => Err(ProcessError::Unspecified),
+    }
+}
+
+/// Reaps the child with the given ID if it exited already.
+///
+/// The ID 0 stands for any child. Returns `None` if no matching child exited yet.
+pub fn try_wait(pid: u64) -> Result<Option<(u64, i32)>, ProcessError> {
+    wait_with_options(pid, WAIT_NO_HANG, 0)
+}
+
+/// Waits at most for the given timeout for the child with the given ID to
+/// exit and reaps it.
+///
+/// The ID 0 stands for any child. Returns `None` if no matching child exited
+/// in time.
+pub fn wait_timeout(pid: u64, timeout: Duration) -> Result<Option<(u64, i32)>, ProcessError> {
+    wait_with_options(pid, WAIT_TIMEOUT, duration_to_millis(timeout))
+}
+
+/// Calls the wait syscall with the given options.
+fn wait_with_options(
+    pid: u64,
+    options: u64,
+    timeout_ms: u64,
+) -> Result<Option<(u64, i32)>, ProcessError> {
+    let mut exit_code: i32 = 0;
+    let result = unsafe {
+        syscall!(
+            WAIT_SYSCALL_NUM,
+            pid,
+            &mut exit_code as *mut i32 as u64,
+            options,
+            timeout_ms
+        ) as i64
+    };
+    if result == -ETIMEDOUT {
+        Ok(None)
+    } else if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else if result == 0 {
+        Ok(None)
+    } else {
+        Ok(Some((result as u64, exit_code)))
+    }
+}
+
+/// A builder for new processes.
+///
+/// Unlike `exec`, it can pass arguments and redirect the standard input and
+/// output of the new process.
+#[derive(Debug)]
+pub struct Command<'a> {
+    /// The name of the executable.
+    name: &'a str,
+    /// The arguments passed after the name.
+    arguments: [&'a str; MAX_ARGUMENTS],
+    /// The number of used entries in `arguments`.
+    argument_count: usize,
+    /// Set if more than `MAX_ARGUMENTS` arguments were added.
+    too_many_arguments: bool,
+    /// The file descriptor to use as the standard input of the new process.
+    stdin: Option<u64>,
+    /// The file descriptor to use as the standard output of the new process.
+    stdout: Option<u64>,
+}
+
+impl<'a> Command<'a> {
+    /// Creates a builder for a process of the given executable.
+    pub fn new(name: &'a str) -> Command<'a> {
+        Command {
+            name,
+            arguments: [""; MAX_ARGUMENTS],
+            argument_count: 0,
+            too_many_arguments: false,
+            stdin: None,
+            stdout: None,
+        }
+    }
+
+    /// Adds an argument for the new process.
+    pub fn arg(&mut self, argument: &'a str) -> &mut Command<'a> {
+        if self.argument_count < MAX_ARGUMENTS {
+            self.arguments[self.argument_count] = argument;
+            self.argument_count += 1;
+        } else {
+            self.too_many_arguments = true;
+        }
+        self
+    }
+
+    /// Adds several arguments for the new process.
+    pub fn args(&mut self, arguments: &[&'a str]) -> &mut Command<'a> {
+        for &argument in arguments {
+            self.arg(argument);
+        }
+        self
+    }
+
+    /// Makes the given file descriptor the standard input of the new process.
+    pub fn stdin(&mut self, fd: u64) -> &mut Command<'a> {
+        self.stdin = Some(fd);
+        self
+    }
+
+    /// Makes the given file descriptor the standard output of the new process.
+    pub fn stdout(&mut self, fd: u64) -> &mut Command<'a> {
+        self.stdout = Some(fd);
+        self
+    }
+
+    /// Creates the new process.
+    ///
+    /// The new process inherits all other open file descriptors. The file
+    /// descriptors of the current process are left unchanged.
+    pub fn spawn(&self) -> Result<Child, ProcessError> {
+        if self.too_many_arguments {
+            set_errno(Errno::E2BIG);
+            return Err(ProcessError::Unspecified);
+        }
+
+        let redirections = [(self.stdin, io::STDIN), (self.stdout, io::STDOUT)];
+        let mut fd_mappings = [FdMapping { fd: 0, target: 0 }; 2];
+        let mut mapping_count = 0;
+
+        for &(fd, target) in &redirections {
+            if let Some(fd) = fd {
+                if fd > u32::max_value() as u64 {
+                    set_errno(Errno::EBADF);
+                    return Err(ProcessError::Unspecified);
+                }
+
+                fd_mappings[mapping_count] = FdMapping {
+                    fd: fd as u32,
+                    target: target as u32,
+                };
+                mapping_count += 1;
+            }
+        }
+
+        let pid = exec_with_fd_mappings(
+            self.name,
+            &self.arguments[..self.argument_count],
+            &fd_mappings[..mapping_count],
+        )?;
+
+        Ok(Child { pid })
+    }
+}
+
+/// A process created by `Command::spawn`.
+#[derive(Debug)]
+pub struct Child {
+    /// The ID of the process.
+    pid: u64,
+}
+
+impl Child {
+    /// Returns the ID of the process.
+    pub fn id(&self) -> u64 {
+        self.pid
+    }
+
+    /// Waits for the process to exit and returns its exit code.
+    ///
+    /// A process killed by a signal exits with 128 plus the signal number.
+    pub fn wait(&mut self) -> Result<i32, ProcessError> {
+        wait(self.pid).map(|(_, exit_code)| exit_code)
+    }
+
+    /// Returns the exit code of the process if it exited already.
+    pub fn try_wait(&mut self) -> Result<Option<i32>, ProcessError> {
+        try_wait(self.pid).map(|child| child.map(|(_, exit_code)| exit_code))
+    }
+
+    /// Kills the process.
+    ///
+    /// The process still has to be waited for afterwards.
+    pub fn kill(&mut self) -> Result<(), ProcessError> {
+        kill(self.pid, SIGKILL)
+    }
+}
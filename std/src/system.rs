@@ -0,0 +1,39 @@
+//! Handles system calls that describe the whole system.
+
+use errno::set_errno_from_result;
+use process::ProcessError;
+
+/// The number of the interrupt_counts syscall.
+const INTERRUPT_COUNTS_SYSCALL_NUM: u64 = 29;
+
+/// How often an interrupt vector fired on a CPU.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InterruptCount {
+    /// The CPU the interrupts arrived on.
+    pub cpu_id: u32,
+    /// The interrupt vector.
+    pub vector: u32,
+    /// The number of interrupts.
+    pub count: u64,
+}
+
+/// Fills the buffer with the interrupt counts of all CPUs, like `/proc/interrupts`.
+///
+/// Vectors that never fired on a CPU are left out. Returns the total number of counts, which
+/// may be larger than the buffer.
+pub fn interrupt_counts(buffer: &mut [InterruptCount]) -> Result<usize, ProcessError> {
+    let result = unsafe {
+        syscall!(
+            INTERRUPT_COUNTS_SYSCALL_NUM,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u64
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(result as usize)
+    }
+}
@@ -1,6 +1,8 @@
 //! Handles thread related syscalls.
 
 use core::time::Duration;
+use errno::{init_thread, set_errno_from_result, ThreadBlock};
+use process::ProcessError;
 
 /// The number of the exit syscall.
 const SLEEP_SYSCALL_NUM: u64 = 4;
@@ -11,6 +13,21 @@ const NEW_THREAD_SYSCALL_NUM: u64 = 5;
 /// Kills the current thread.
 const KILL_THREAD_SYSCALL_NUM: u64 = 6;
 
+/// The number of the get_tid syscall.
+const GET_TID_SYSCALL_NUM: u64 = 10;
+
+/// The number of the join syscall.
+const JOIN_SYSCALL_NUM: u64 = 11;
+
+/// The number of the detach syscall.
+const DETACH_SYSCALL_NUM: u64 = 34;
+
+/// The number of the syscall to set the thread local storage base.
+pub(crate) const SET_TLS_BASE_SYSCALL_NUM: u64 = 17;
+
+/// The number of the syscall to get the thread local storage base.
+pub(crate) const GET_TLS_BASE_SYSCALL_NUM: u64 = 18;
+
 /// Lets the current thread sleep for `ms` milliseconds.
 pub fn sleep(duration: Duration) {
     unsafe {
@@ -23,8 +40,16 @@ pub fn sleep(duration: Duration) {
 }
 
 /// Creates a new thread passing it the given arguments.
-pub fn new_thread(function: fn(u64, u64, u64, u64), arg1: u64, arg2: u64, arg3: u64, arg4: u64) {
-    unsafe {
+///
+/// Returns the ID of the new thread.
+pub fn new_thread(
+    function: fn(u64, u64, u64, u64),
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+) -> Result<u64, ProcessError> {
+    let result = unsafe {
         syscall!(
             NEW_THREAD_SYSCALL_NUM,
             new_thread_creator as u64,
@@ -33,10 +58,82 @@ pub fn new_thread(function: fn(u64, u64, u64, u64), arg1: u64, arg2: u64, arg3:
             arg2,
             arg3,
             arg4
-        );
+        ) as i64
+    };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(result as u64)
+    }
+}
+
+/// Waits until the thread with the given ID in the current process exited.
+///
+/// Fails if the thread doesn't belong to the current process, was already joined or is the
+/// current thread.
+///
+/// The ID of the thread isn't reused before it was joined.
+pub fn join(tid: u64) -> Result<(), ProcessError> {
+    let result = unsafe { syscall!(JOIN_SYSCALL_NUM, tid) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
+    }
+}
+
+/// Detaches the thread with the given ID in the current process.
+///
+/// The ID of a detached thread is freed as soon as it exits, so it can't be joined anymore.
+/// Threads that are never joined should be detached, otherwise the kernel keeps a record of
+/// their exit until the process ends.
+///
+/// Fails if the thread doesn't belong to the current process or was already joined or detached.
+pub fn detach(tid: u64) -> Result<(), ProcessError> {
+    let result = unsafe { syscall!(DETACH_SYSCALL_NUM, tid) as i64 };
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the ID of the current thread.
+///
+/// Thread IDs are only unique within a process.
+pub fn get_tid() -> u64 {
+    unsafe { syscall!(GET_TID_SYSCALL_NUM) as u64 }
+}
+
+/// Sets the base address of the thread local storage of the current thread.
+///
+/// On x86_64 this is the base of the FS segment. The standard library keeps
+/// the `errno` of the thread at the initial base, so `errno` can't be used
+/// after changing it.
+///
+/// # Safety
+/// - Unless the base is 0, the memory it points to must stay readable while
+/// it is the base. The standard library reads the first word to check
+/// whether the base still points to its block, and if that word points to the
+/// base itself, also the third word.
+pub unsafe fn set_tls_base(base: u64) -> Result<(), ProcessError> {
+    let result = syscall!(SET_TLS_BASE_SYSCALL_NUM, base) as i64;
+    if result < 0 {
+        set_errno_from_result(result);
+        Err(ProcessError::Unspecified)
+    } else {
+        Ok(())
     }
 }
 
+/// Returns the base address of the thread local storage of the current thread.
+pub fn get_tls_base() -> u64 {
+    unsafe { syscall!(GET_TLS_BASE_SYSCALL_NUM) as u64 }
+}
+
 /// Kills the current thread.
 pub fn kill_thread() {
     unsafe {
@@ -52,6 +149,11 @@ extern "C" fn new_thread_creator(
     arg3: u64,
     arg4: u64,
 ) {
+    let mut thread_block = ThreadBlock::new();
+    unsafe {
+        init_thread(&mut thread_block);
+    }
+
     function(arg1, arg2, arg3, arg4);
 
     kill_thread();
@@ -1,16 +1,819 @@
 #![no_std]
+#![feature(asm)]
 
 #[macro_use]
 extern crate veos_std;
 #[allow(unused_extern_crates)]
 extern crate rlibc;
 
+use core::{ptr, slice};
 use core::time::Duration;
+use veos_std::errno::{errno, set_errno, Errno};
+use veos_std::io::{DirectoryEntry, FileKind};
+use veos_std::process::{Command, ProcessInfo, ProcessState};
+use veos_std::{io, process, system, thread};
+
+/// The values the threads of the TLS test point their TLS base at.
+///
+/// Thread `i` starts with value `i` and switches to value `i + 2` halfway.
+static TLS_VALUES: [u64; 4] = [0x1111, 0x2222, 0x3333, 0x4444];
+
+/// The name of this program, which the process group test starts copies of.
+const PROGRAM_NAME: &str = "/bin/test";
+
+/// The maximum number of processes the tests look through.
+const MAX_LISTED_PROCESSES: usize = 32;
+
+/// The maximum number of mappings the tests look through.
+const MAX_LISTED_MAPPINGS: usize = 64;
+
+/// The maximum number of interrupt counts the tests look through.
+const MAX_LISTED_INTERRUPT_COUNTS: usize = 64;
+
+/// A file descriptor that is never open.
+const UNUSED_FD: u64 = 1000;
 
 #[no_mangle]
 pub fn main() {
-    loop {
-        veos_std::thread::sleep(Duration::from_millis(1000));
-        println!("Nest");
+    // The copies started by the command test do what their arguments say.
+    let mut arguments = process::args().skip(1);
+    match (arguments.next(), arguments.next()) {
+        (Some("exit"), Some(code)) => process::exit_group(code.parse().unwrap_or(1)),
+        (Some("echo"), Some(text)) => {
+            io::write(io::STDOUT, text.as_bytes()).unwrap();
+            process::exit_group(0);
+        },
+        (Some("cwd"), Some(expected)) => {
+            let mut buffer = [0u8; 64];
+            let matches = process::getcwd(&mut buffer).ok() == Some(expected);
+            process::exit_group(if matches { 0 } else { 1 });
+        },
+        (Some("memory_limit"), None) => process::exit_group(check_memory_limit()),
+        (Some("null"), None) => {
+            // The null page is never mapped, so this must end the process.
+            unsafe { ptr::read_volatile(ptr::null::<u8>()) };
+            process::exit_group(0);
+        },
+        _ => (),
+    }
+
+    // The copies started by the process group test only wait to be killed.
+    if is_started_by_test() {
+        loop {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    test_tls_base();
+    test_join();
+    test_list_threads();
+    test_errno();
+    test_map_initramfs_file();
+    test_zeroed_frames();
+    test_memory_usage();
+    test_list_mappings();
+    test_interrupt_counts();
+    test_memory_limit();
+    test_null_page();
+    test_kill();
+    test_process_groups();
+    test_wait();
+    test_pipe();
+    test_command();
+    test_working_directory();
+    test_read_directory();
+    test_timeouts();
+    test_poll();
+
+    // This has to be the last test, as it ends the process.
+    test_exit_group();
+}
+
+/// Checks that each thread keeps its own TLS base across context switches.
+fn test_tls_base() {
+    let main_base = thread::get_tls_base();
+    let first = thread::new_thread(check_tls_base, 0, 0, 0, 0).unwrap();
+    let second = thread::new_thread(check_tls_base, 1, 0, 0, 0).unwrap();
+
+    thread::join(first).unwrap();
+    thread::join(second).unwrap();
+
+    if thread::get_tls_base() != main_base {
+        println!("TLS test failed: the base of another thread leaked into the main thread.");
+    }
+}
+
+/// Points the TLS base at one of the `TLS_VALUES` and checks it repeatedly.
+fn check_tls_base(index: u64, _: u64, _: u64, _: u64) {
+    for round in 0..10 {
+        // Changing the base later must replace the one saved on context switches.
+        let expected = &TLS_VALUES[index as usize + if round < 5 { 0 } else { 2 }];
+        let base = expected as *const u64 as u64;
+
+        if round == 0 || round == 5 {
+            unsafe {
+                thread::set_tls_base(base).unwrap();
+            }
+
+            // The error number must not be written to the new base.
+            if io::close(u64::max_value()).is_ok() || errno() != Errno::NoError {
+                println!("TLS test failed: a moved base was used for errno.");
+                return;
+            }
+        }
+
+        // Give the other thread the chance to run with its own base.
+        thread::sleep(Duration::from_millis(10));
+
+        let value: u64;
+        unsafe {
+            asm!("mov $0, fs:[0]" : "=r"(value) : : : "intel", "volatile");
+        }
+
+        if thread::get_tls_base() != base || value != *expected {
+            println!("TLS test failed in thread {}.", index);
+            return;
+        }
+    }
+
+    println!("TLS test passed in thread {}.", index);
+}
+
+/// Checks that thread IDs stay reserved until they are joined or detached and can only be joined
+/// once.
+fn test_join() {
+    let first = thread::new_thread(do_nothing, 0, 0, 0, 0).unwrap();
+    let second = thread::new_thread(do_nothing, 0, 0, 0, 0).unwrap();
+    let detached = thread::new_thread(do_nothing, 0, 0, 0, 0).unwrap();
+
+    if first == second {
+        println!("Join test failed: the ID of an unjoined thread was reused.");
+    } else if thread::join(first).is_err() || thread::join(second).is_err() {
+        println!("Join test failed: joining an exited thread failed.");
+    } else if thread::join(first).is_ok() {
+        println!("Join test failed: a thread could be joined twice.");
+    } else if thread::detach(detached).is_err() {
+        println!("Join test failed: detaching a thread failed.");
+    } else if thread::join(detached).is_ok() || thread::detach(detached).is_ok() {
+        println!("Join test failed: a detached thread could be joined or detached again.");
+    } else {
+        println!("Join test passed.");
+    }
+}
+
+/// A thread entry point that returns right away.
+fn do_nothing(_: u64, _: u64, _: u64, _: u64) {}
+
+/// Checks that the thread listing contains the current thread and a running sibling.
+fn test_list_threads() {
+    let sibling = thread::new_thread(sleep_briefly, 0, 0, 0, 0).unwrap();
+    let mut thread_ids = [0; 8];
+    let result = process::list_threads(process::get_pid(), &mut thread_ids);
+    thread::join(sibling).unwrap();
+
+    let count = match result {
+        Ok(count) if count <= thread_ids.len() => count,
+        Ok(count) => {
+            println!("Thread listing test failed: {} threads are listed.", count);
+            return;
+        },
+        Err(error) => {
+            println!("Thread listing test failed: {:?}", error);
+            return;
+        },
+    };
+    let listed = &thread_ids[..count];
+
+    if !listed.contains(&thread::get_tid()) || !listed.contains(&sibling) {
+        println!("Thread listing test failed: a thread is missing.");
+    } else if process::list_threads(u64::max_value(), &mut thread_ids).is_ok() {
+        println!("Thread listing test failed: a missing process has threads.");
+    } else {
+        println!("Thread listing test passed.");
+    }
+}
+
+/// A thread entry point that sleeps for a short while.
+fn sleep_briefly(_: u64, _: u64, _: u64, _: u64) {
+    thread::sleep(Duration::from_millis(50));
+}
+
+/// Checks that failing syscalls set `errno` and that each thread has its own.
+fn test_errno() {
+    if errno() != Errno::NoError {
+        println!("Errno test failed: errno was set before any syscall failed.");
+        return;
+    }
+
+    let closed = io::close(UNUSED_FD);
+    let other = thread::new_thread(check_errno, 0, 0, 0, 0).unwrap();
+    thread::join(other).unwrap();
+
+    if closed.is_ok() || errno() != Errno::EBADF {
+        println!("Errno test failed: closing an unused fd left errno at {:?}.", errno());
+    } else {
+        println!("Errno test passed.");
+    }
+
+    set_errno(Errno::NoError);
+}
+
+/// Checks that a new thread starts without an error and sets its own.
+fn check_errno(_: u64, _: u64, _: u64, _: u64) {
+    if errno() != Errno::NoError {
+        println!("Errno test failed: a new thread started with {:?}.", errno());
+    }
+
+    set_errno(Errno::EINVAL);
+}
+
+/// Checks that an initramfs file can be read through a shared mapping.
+fn test_map_initramfs_file() {
+    let first = io::map_initramfs_file("/bin/test").unwrap();
+    let second = io::map_initramfs_file("/bin/test").unwrap();
+
+    if &first[..4] != b"\x7fELF" {
+        println!("Mapping test failed: /bin/test doesn't start with the ELF magic.");
+    } else if first.as_ptr() == second.as_ptr() || first != second {
+        println!("Mapping test failed: mapping the same file twice differs.");
+    } else if io::map_initramfs_file("/bin/does_not_exist").is_ok() {
+        println!("Mapping test failed: a missing file was mapped.");
+    } else {
+        println!("Mapping test passed.");
+    }
+}
+
+/// Checks that the memory after the end of the program reads as zeros.
+///
+/// The loader never writes the rest of the last page, so it shows what the
+/// frame held before it was allocated.
+fn test_zeroed_frames() {
+    extern "C" {
+        /// The end of the program, provided by the linker.
+        static _end: u8;
+    }
+
+    let end = unsafe { &_end as *const u8 as usize };
+    let page_end = (end + 0xfff) & !0xfff;
+    let rest = unsafe { slice::from_raw_parts(end as *const u8, page_end - end) };
+
+    if rest.iter().all(|&byte| byte == 0) {
+        println!("Zeroed frame test passed.");
+    } else {
+        println!("Zeroed frame test failed: a new page held old data.");
+    }
+}
+
+/// Checks that mapping a file is accounted as shared memory.
+fn test_memory_usage() {
+    let pid = process::get_pid();
+    let before = process::memory_usage(pid).unwrap();
+    let file = io::map_initramfs_file("/bin/test").unwrap();
+    let mapped = process::memory_usage(pid).unwrap();
+    let file_pages = (file.len() as u64 + 0xfff) / 0x1000;
+
+    // The pages of the file are only mapped once they are accessed.
+    for index in (0..file.len()).step_by(0x1000).chain(Some(file.len() - 1)) {
+        unsafe {
+            ptr::read_volatile(&file[index]);
+        }
+    }
+
+    let touched = process::memory_usage(pid).unwrap();
+
+    if before.resident_pages == 0 || before.virtual_pages < before.resident_pages {
+        println!("Memory usage test failed: {:?}", before);
+    } else if mapped.virtual_pages < before.virtual_pages + file_pages {
+        println!("Memory usage test failed: mapping a file wasn't counted.");
+    } else if mapped.shared_pages != before.shared_pages {
+        println!("Memory usage test failed: the file was mapped before it was accessed.");
+    } else if touched.shared_pages < mapped.shared_pages + file_pages {
+        println!("Memory usage test failed: accessing the file didn't map it.");
+    } else if process::memory_usage(0xffff_ffff).is_ok() {
+        println!("Memory usage test failed: a missing process was found.");
+    } else {
+        println!("Memory usage test passed.");
+    }
+}
+
+/// Checks that the stack and the code are listed with their flags.
+fn test_list_mappings() {
+    let mut mappings = [process::Mapping::default(); MAX_LISTED_MAPPINGS];
+    let count = process::list_mappings(&mut mappings).unwrap();
+    let mappings = &mappings[..count.min(MAX_LISTED_MAPPINGS)];
+    let local = 0u8;
+    let find = |address| mappings.iter().find(|mapping| mapping.contains(address));
+    let stack = find(&local as *const u8 as u64);
+    let code = find(test_list_mappings as usize as u64);
+
+    let user_rw = process::MAPPING_READABLE
+        | process::MAPPING_WRITABLE
+        | process::MAPPING_USER_ACCESSIBLE;
+
+    if mappings
+        .windows(2)
+        .any(|pair| pair[0].start + pair[0].length > pair[1].start)
+    {
+        println!("Mapping list test failed: the mappings aren't sorted.");
+    } else if stack.map(|mapping| mapping.flags & !process::MAPPING_EXECUTABLE) != Some(user_rw) {
+        println!("Mapping list test failed: the stack is listed as {:?}.", stack);
+    } else if code.map_or(true, |mapping| mapping.flags & process::MAPPING_EXECUTABLE == 0) {
+        println!("Mapping list test failed: the code is listed as {:?}.", code);
+    } else {
+        println!("Mapping list test passed.");
+    }
+}
+
+/// Checks that interrupts keep arriving while the test sleeps.
+fn test_interrupt_counts() {
+    let total = || {
+        let mut counts = [system::InterruptCount::default(); MAX_LISTED_INTERRUPT_COUNTS];
+        let count = system::interrupt_counts(&mut counts).unwrap();
+
+        counts[..count.min(MAX_LISTED_INTERRUPT_COUNTS)]
+            .iter()
+            .map(|interrupt_count| interrupt_count.count)
+            .sum::<u64>()
+    };
+
+    let before = total();
+    thread::sleep(Duration::from_millis(50));
+    let after = total();
+
+    if before == 0 {
+        println!("Interrupt count test failed: no interrupts were counted.");
+    } else if after <= before {
+        println!("Interrupt count test failed: the count stayed at {}.", before);
+    } else {
+        println!("Interrupt count test passed.");
+    }
+}
+
+/// Checks that reservations beyond the memory limit fail without ending the process.
+///
+/// The limit can't be raised again, so the checks run in a child process.
+fn test_memory_limit() {
+    let result = Command::new(PROGRAM_NAME)
+        .arg("memory_limit")
+        .spawn()
+        .and_then(|mut child| child.wait());
+
+    match result {
+        Ok(0) => println!("Memory limit test passed."),
+        Ok(1) => println!("Memory limit test failed: a file was mapped beyond the limit."),
+        Ok(2) => println!("Memory limit test failed: a thread was created beyond the limit."),
+        Ok(3) => println!("Memory limit test failed: the limit was raised."),
+        Ok(4) => println!("Memory limit test failed: a child escaped the limit."),
+        other => println!("Memory limit test failed: the child exited with {:?}.", other),
+    }
+}
+
+/// Checks that dereferencing a null pointer ends the process as if it received `SIGSEGV`.
+fn test_null_page() {
+    let result = Command::new(PROGRAM_NAME)
+        .arg("null")
+        .spawn()
+        .and_then(|mut child| child.wait());
+
+    match result {
+        Ok(139) => println!("Null page test passed."),
+        Ok(0) => println!("Null page test failed: the null pointer could be read."),
+        other => println!("Null page test failed: the child exited with {:?}.", other),
+    }
+}
+
+/// Lowers the memory limit of this process below what it uses and returns the number of the
+/// first check that failed, or 0.
+fn check_memory_limit() -> i32 {
+    process::set_memory_limit(0x1000).unwrap();
+
+    if io::map_initramfs_file("/bin/test").is_ok() {
+        1
+    } else if thread::new_thread(spin, 0, 0, 0, 0).is_ok() {
+        2
+    } else if process::set_memory_limit(256 * 1024 * 1024).is_ok() || errno() != Errno::EPERM {
+        3
+    } else if process::exec(PROGRAM_NAME).is_ok() || errno() != Errno::ENOMEM {
+        4
+    } else {
+        0
+    }
+}
+
+/// Checks that processes can only be signaled if they exist and may be killed, and that killing
+/// a process closes its files.
+fn test_kill() {
+    // The child holds the only write end, so killing it ends the data.
+    let (read_fd, write_fd) = process::pipe().unwrap();
+    let mut child = Command::new(PROGRAM_NAME).stdout(write_fd).spawn().unwrap();
+    io::close(write_fd).unwrap();
+    thread::detach(thread::new_thread(kill_after_delay, child.id(), 0, 0, 0).unwrap()).unwrap();
+    let mut buffer = [0u8; 1];
+    let end_result = io::read(read_fd, &mut buffer);
+    io::close(read_fd).unwrap();
+    child.wait().unwrap();
+
+    if end_result.as_ref().ok() != Some(&0) {
+        println!("Kill test failed: reading from a killed writer returned {:?}.", end_result);
+    } else if process::kill(process::get_pid(), 0).is_err() {
+        println!("Kill test failed: the process can't signal itself.");
+    } else if process::kill(0xffff_ffff, process::SIGKILL).is_ok() {
+        println!("Kill test failed: a missing process was killed.");
+    } else if process::kill(0, process::SIGKILL).is_ok() {
+        println!("Kill test failed: the idle process was killed.");
+    } else if process::kill(process::get_pid(), 64).is_ok() {
+        println!("Kill test failed: an unknown signal was accepted.");
+    } else {
+        println!("Kill test passed.");
+    }
+}
+
+/// Checks that children join the group of their parent and that a group can be killed.
+fn test_process_groups() {
+    let pid = process::get_pid();
+
+    process::set_process_group(0, 0).unwrap();
+    let first = process::exec(PROGRAM_NAME).unwrap();
+    let second = process::exec(PROGRAM_NAME).unwrap();
+    process::set_process_group(first, 0).unwrap();
+
+    let own_group = find_process(pid).map(|info| info.process_group);
+    let first_group = find_process(first).map(|info| info.process_group);
+    let second_group = find_process(second).map(|info| info.process_group);
+    let killed = process::kill_process_group(first, process::SIGKILL);
+    let first_alive = is_alive(first);
+    let second_alive = is_alive(second);
+    process::kill(second, process::SIGKILL).unwrap();
+    process::wait(first).unwrap();
+    process::wait(second).unwrap();
+
+    if own_group != Some(pid) {
+        println!("Process group test failed: the process doesn't lead its new group.");
+    } else if first_group != Some(first) || second_group != Some(pid) {
+        println!("Process group test failed: the children are in the wrong groups.");
+    } else if process::set_process_group(0, 0xffff_ffff).is_ok() {
+        println!("Process group test failed: a missing group was joined.");
+    } else if killed.is_err() || first_alive || !second_alive {
+        println!("Process group test failed: killing a group didn't end exactly its members.");
+    } else if process::kill_process_group(0xffff_ffff, process::SIGKILL).is_ok() {
+        println!("Process group test failed: a missing group was killed.");
+    } else {
+        println!("Process group test passed.");
+    }
+}
+
+/// Checks that exit codes are kept until the parent waits for its children.
+fn test_wait() {
+    // The child exits before it is waited for.
+    let first = process::exec(PROGRAM_NAME).unwrap();
+    process::kill(first, process::SIGKILL).unwrap();
+    let first_zombie = wait_for_zombie(first);
+    let first_result = process::wait(first);
+
+    // The child exits while it is waited for.
+    let second = process::exec(PROGRAM_NAME).unwrap();
+    thread::detach(thread::new_thread(kill_after_delay, second, 0, 0, 0).unwrap()).unwrap();
+    let second_result = process::wait(0);
+
+    let expected_code = 128 + process::SIGKILL as i32;
+
+    if !first_zombie {
+        println!("Wait test failed: the exited child didn't become a zombie.");
+    } else if first_result.as_ref().ok() != Some(&(first, expected_code)) {
+        println!("Wait test failed: waiting for an exited child returned {:?}.", first_result);
+    } else if second_result.as_ref().ok() != Some(&(second, expected_code)) {
+        println!("Wait test failed: waiting for a running child returned {:?}.", second_result);
+    } else if find_process(first).is_some() || find_process(second).is_some() {
+        println!("Wait test failed: the children weren't reaped.");
+    } else if process::wait(0).is_ok() || process::try_wait(0).is_ok() {
+        println!("Wait test failed: waiting without children succeeded.");
+    } else {
+        println!("Wait test passed.");
+    }
+}
+
+/// Checks that closing one end of a pipe wakes the threads blocked on the other end.
+fn test_pipe() {
+    let mut buffer = [0u8; 1];
+    let (read_fd, write_fd) = process::pipe().unwrap();
+    thread::detach(thread::new_thread(close_after_delay, write_fd, 0, 0, 0).unwrap()).unwrap();
+    let end_result = io::read(read_fd, &mut buffer);
+    io::close(read_fd).unwrap();
+
+    // The pipe fills up, so the write blocks until the read end is closed.
+    let data = [0u8; 8192];
+    let (read_fd, write_fd) = process::pipe().unwrap();
+    thread::detach(thread::new_thread(close_after_delay, read_fd, 0, 0, 0).unwrap()).unwrap();
+    let blocked_result = io::write(write_fd, &data);
+    let broken = io::write(write_fd, &data).is_err() && errno() == Errno::EPIPE;
+    io::close(write_fd).unwrap();
+
+    if end_result.as_ref().ok() != Some(&0) {
+        println!("Pipe test failed: reading from a closed pipe returned {:?}.", end_result);
+    } else if blocked_result.as_ref().ok().map_or(true, |&written| written >= data.len()) {
+        println!("Pipe test failed: a blocked write returned {:?}.", blocked_result);
+    } else if !broken {
+        println!("Pipe test failed: writing without a read end didn't fail with EPIPE.");
+    } else {
+        println!("Pipe test passed.");
+    }
+}
+
+/// Checks that processes created by `Command` receive their arguments and
+/// redirections.
+fn test_command() {
+    let exit_result = Command::new(PROGRAM_NAME)
+        .args(&["exit", "7"])
+        .spawn()
+        .and_then(|mut child| child.wait());
+
+    let (read_fd, write_fd) = process::pipe().unwrap();
+    let echo_result = Command::new(PROGRAM_NAME)
+        .arg("echo")
+        .arg("hello")
+        .stdout(write_fd)
+        .spawn()
+        .and_then(|mut child| child.wait());
+    io::close(write_fd).unwrap();
+    let mut buffer = [0u8; 8];
+    let read_result = io::read(read_fd, &mut buffer);
+    io::close(read_fd).unwrap();
+
+    let mut looping = Command::new(PROGRAM_NAME).spawn().unwrap();
+    looping.kill().unwrap();
+    let kill_result = looping.wait();
+
+    let mut too_many = Command::new(PROGRAM_NAME);
+    for _ in 0..process::MAX_ARGUMENTS + 1 {
+        too_many.arg("x");
+    }
+    let too_many_failed = too_many.spawn().is_err() && errno() == Errno::E2BIG;
+
+    let bad_fd_failed =
+        Command::new(PROGRAM_NAME).stdin(1000).spawn().is_err() && errno() == Errno::EBADF;
+
+    if process::args().next() != Some(PROGRAM_NAME) {
+        println!("Command test failed: the first argument isn't the program name.");
+    } else if exit_result.as_ref().ok() != Some(&7) {
+        println!("Command test failed: the child exited with {:?}.", exit_result);
+    } else if echo_result.ok() != Some(0) || read_result.as_ref().ok() != Some(&5) {
+        println!("Command test failed: reading the redirected output returned {:?}.", read_result);
+    } else if &buffer[..5] != b"hello" {
+        println!("Command test failed: the redirected output was wrong.");
+    } else if kill_result.as_ref().ok() != Some(&(128 + process::SIGKILL as i32)) {
+        println!("Command test failed: the killed child exited with {:?}.", kill_result);
+    } else if !too_many_failed {
+        println!("Command test failed: too many arguments were accepted.");
+    } else if !bad_fd_failed {
+        println!("Command test failed: redirecting from a closed descriptor was accepted.");
+    } else {
+        println!("Command test passed.");
+    }
+}
+
+/// Checks that relative paths are resolved against the working directory.
+fn test_working_directory() {
+    let mut buffer = [0u8; 64];
+
+    let starts_at_root = process::getcwd(&mut buffer).ok() == Some("/");
+    let entered =
+        process::chdir("bin").is_ok() && process::getcwd(&mut buffer).ok() == Some("/bin");
+    let child_result = Command::new("test")
+        .args(&["cwd", "/bin"])
+        .spawn()
+        .and_then(|mut child| child.wait());
+    let missing_failed = process::chdir("missing").is_err() && errno() == Errno::ENOENT;
+    let file_failed = process::chdir("test").is_err() && errno() == Errno::ENOTDIR;
+    let too_small_failed = process::getcwd(&mut buffer[..2]).is_err() && errno() == Errno::ERANGE;
+    let clamped =
+        process::chdir("../../..").is_ok() && process::getcwd(&mut buffer).ok() == Some("/");
+
+    if !starts_at_root {
+        println!("Working directory test failed: the process didn't start at the root.");
+    } else if !entered {
+        println!("Working directory test failed: changing to a relative directory failed.");
+    } else if child_result.as_ref().ok() != Some(&0) {
+        println!("Working directory test failed: the child returned {:?}.", child_result);
+    } else if !missing_failed || !file_failed {
+        println!("Working directory test failed: an invalid directory was accepted.");
+    } else if !too_small_failed {
+        println!("Working directory test failed: a too small buffer was accepted.");
+    } else if !clamped {
+        println!("Working directory test failed: leaving the root wasn't clamped.");
+    } else {
+        println!("Working directory test passed.");
+    }
+}
+
+/// Checks that the directories of the initramfs can be listed.
+fn test_read_directory() {
+    let mut entries = [DirectoryEntry::empty(); 4];
+
+    let root_count = io::read_directory("/", 0, &mut entries).unwrap_or(0);
+    let has_bin = entries[..root_count]
+        .iter()
+        .any(|entry| entry.name() == "bin" && entry.kind == FileKind::Directory);
+
+    // Reading one entry at a time has to find the test program and the end.
+    let mut found_test = false;
+    let mut start_index = 0;
+    let end_result = loop {
+        match io::read_directory("bin", start_index, &mut entries[..1]) {
+            Ok(1) => {
+                found_test |= entries[0].name() == "test" && entries[0].kind == FileKind::File;
+                start_index += 1;
+            },
+            result => break result,
+        }
+    };
+
+    let missing_failed =
+        io::read_directory("/missing", 0, &mut entries).is_err() && errno() == Errno::ENOENT;
+    let file_failed =
+        io::read_directory(PROGRAM_NAME, 0, &mut entries).is_err() && errno() == Errno::ENOTDIR;
+
+    if !has_bin {
+        println!("Directory test failed: the root doesn't contain the bin directory.");
+    } else if !found_test || end_result.as_ref().ok() != Some(&0) {
+        println!("Directory test failed: listing /bin returned {:?}.", end_result);
+    } else if !missing_failed || !file_failed {
+        println!("Directory test failed: an invalid directory was listed.");
+    } else {
+        println!("Directory test passed.");
     }
 }
+
+/// Checks that blocking reads and waits give up once their timeout expired.
+fn test_timeouts() {
+    let timeout = Duration::from_millis(100);
+    let (read_fd, write_fd) = process::pipe().unwrap();
+    let mut buffer = [0u8; 4];
+
+    let empty_result = io::read_timeout(read_fd, &mut buffer, timeout);
+    io::write(write_fd, b"ok").unwrap();
+    let filled_result = io::read_timeout(read_fd, &mut buffer, timeout);
+    io::close(read_fd).unwrap();
+    io::close(write_fd).unwrap();
+
+    let child = process::exec(PROGRAM_NAME).unwrap();
+    let running_result = process::wait_timeout(child, timeout);
+    process::kill(child, process::SIGKILL).unwrap();
+    let exited_result = process::wait_timeout(child, Duration::from_secs(10));
+
+    let expected_code = 128 + process::SIGKILL as i32;
+
+    match empty_result {
+        Err(io::IoError::TimedOut) => (),
+        _ => {
+            println!("Timeout test failed: reading an empty pipe returned {:?}.", empty_result);
+            return;
+        },
+    }
+
+    if filled_result.as_ref().ok() != Some(&2) || &buffer[..2] != b"ok" {
+        println!("Timeout test failed: reading a filled pipe returned {:?}.", filled_result);
+    } else if running_result.as_ref().ok() != Some(&None) {
+        println!("Timeout test failed: waiting for a running child returned {:?}.", running_result);
+    } else if exited_result.as_ref().ok() != Some(&Some((child, expected_code))) {
+        println!("Timeout test failed: waiting for an exited child returned {:?}.", exited_result);
+    } else {
+        println!("Timeout test passed.");
+    }
+}
+
+/// Checks that polling reports ready pipe ends and closed write ends.
+fn test_poll() {
+    let timeout = Some(Duration::from_millis(100));
+    let (read_fd, write_fd) = process::pipe().unwrap();
+    let mut fds = [
+        io::PollFd::new(read_fd, io::POLL_READABLE),
+        io::PollFd::new(write_fd, io::POLL_WRITABLE),
+    ];
+
+    let writable_count = io::poll(&mut fds, timeout);
+    let write_ready = fds[1].ready_events;
+
+    let mut read_fds = [io::PollFd::new(read_fd, io::POLL_READABLE)];
+    let empty_count = io::poll(&mut read_fds, timeout);
+    io::write(write_fd, b"x").unwrap();
+    let filled_count = io::poll(&mut read_fds, timeout);
+    let read_ready = read_fds[0].ready_events;
+
+    // The write end is closed while both ends are polled.
+    let mut buffer = [0u8; 1];
+    io::read(read_fd, &mut buffer).unwrap();
+    let mut both_fds = [
+        io::PollFd::new(read_fd, io::POLL_READABLE),
+        io::PollFd::new(write_fd, 0),
+    ];
+    thread::detach(thread::new_thread(close_after_delay, write_fd, 0, 0, 0).unwrap()).unwrap();
+    let closed_count = io::poll(&mut both_fds, None);
+    let closed_ready = both_fds[0].ready_events;
+    io::close(read_fd).unwrap();
+
+    let mut invalid_fds = [io::PollFd::new(read_fd, io::POLL_READABLE)];
+    let invalid_count = io::poll(&mut invalid_fds, None);
+
+    if writable_count.ok() != Some(1) || write_ready != io::POLL_WRITABLE {
+        println!("Poll test failed: an empty pipe wasn't only writable.");
+    } else if empty_count.ok() != Some(0) || filled_count.ok() != Some(1) {
+        println!("Poll test failed: the read end didn't become ready when written to.");
+    } else if read_ready != io::POLL_READABLE {
+        println!("Poll test failed: the read end reported {:#x}.", read_ready);
+    } else if closed_count.ok() != Some(1) || closed_ready != io::POLL_HANG_UP {
+        println!("Poll test failed: closing the write end wasn't reported.");
+    } else if invalid_count.ok() != Some(1) || invalid_fds[0].ready_events != io::POLL_INVALID {
+        println!("Poll test failed: a closed descriptor wasn't reported as invalid.");
+    } else {
+        println!("Poll test passed.");
+    }
+}
+
+/// Closes the given file descriptor after a short delay.
+fn close_after_delay(fd: u64, _: u64, _: u64, _: u64) {
+    thread::sleep(Duration::from_millis(100));
+    io::close(fd).unwrap();
+}
+
+/// Kills the process with the given ID after a short delay.
+fn kill_after_delay(pid: u64, _: u64, _: u64, _: u64) {
+    thread::sleep(Duration::from_millis(100));
+    process::kill(pid, process::SIGKILL).unwrap();
+}
+
+/// Waits until the process with the given ID is a zombie.
+///
+/// Returns false if it doesn't become one within a second.
+fn wait_for_zombie(pid: u64) -> bool {
+    for _ in 0..1000 {
+        match find_process(pid).map(|info| info.state) {
+            Some(ProcessState::Zombie) => return true,
+            Some(_) => thread::sleep(Duration::from_millis(1)),
+            None => return false,
+        }
+    }
+
+    false
+}
+
+/// Returns the information about the process with the given ID.
+fn find_process(pid: u64) -> Option<ProcessInfo> {
+    let mut processes = [ProcessInfo {
+        pid: 0,
+        parent: 0,
+        process_group: 0,
+        thread_count: 0,
+        state: ProcessState::Dead,
+        name: [0; process::MAX_PROCESS_NAME_LENGTH],
+        name_length: 0,
+    }; MAX_LISTED_PROCESSES];
+    let count = process::list_processes(&mut processes).ok()?;
+
+    processes
+        .iter()
+        .take(count)
+        .find(|info| info.pid == pid)
+        .cloned()
+}
+
+/// Checks whether the process with the given ID exists and is still running.
+fn is_alive(pid: u64) -> bool {
+    find_process(pid).map_or(false, |info| info.state == ProcessState::Active)
+}
+
+/// Checks whether this process is a copy started by the process group test.
+fn is_started_by_test() -> bool {
+    find_process(process::get_pid())
+        .and_then(|info| find_process(info.parent))
+        .map_or(false, |parent| parent.name() == PROGRAM_NAME)
+}
+
+/// Checks that `exit_group` called by one thread ends all other threads.
+fn test_exit_group() -> ! {
+    thread::new_thread(spin, 0, 0, 0, 0).unwrap();
+    thread::new_thread(spin, 1, 0, 0, 0).unwrap();
+    thread::new_thread(call_exit_group, 0, 0, 0, 0).unwrap();
+
+    // The exiting thread also ends this one, which is not supposed to return.
+    thread::sleep(Duration::from_millis(500));
+    println!("exit_group test failed: the main thread is still running.");
+
+    process::exit_group(1);
+}
+
+/// Keeps running until the process is ended.
+fn spin(index: u64, _: u64, _: u64, _: u64) {
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    println!("exit_group test failed: thread {} is still running.", index);
+}
+
+/// Ends the whole process from a thread other than the main thread.
+fn call_exit_group(_: u64, _: u64, _: u64, _: u64) {
+    thread::sleep(Duration::from_millis(50));
+    println!("exit_group test done, no failures may follow.");
+
+    process::exit_group(0);
+}